@@ -0,0 +1,410 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! A non-blocking facade over [`PushManager`] for Rust consumers with an async runtime.
+//!
+//! [`PushManager`] does its network and storage work synchronously, which blocks an
+//! async executor. [`AsyncPushManager`] mirrors its API, but runs each call on a
+//! background thread and returns a [`BlockingTask`] future that resolves with the result.
+//! It doesn't depend on any particular runtime.
+
+use std::{collections::HashMap, sync::Arc};
+
+pub use worker_support::BlockingTask;
+use worker_support::WorkerPool;
+
+use crate::{
+    ApiResult, BatchDecryptResult, ChannelIdGenerator, ConfigSummary, ConnectionDiff,
+    DecryptResponse, DecryptedMessage, PreparedKeys, PushConfiguration, PushManager, PushMetrics,
+    PushObserver, PushSubscriptionChanged, ReRegisterResult, StorageHealth, SubscriptionResponse,
+    UnsubscribeResult,
+};
+
+/// Non-blocking version of [`PushManager`], see the module docs.
+///
+/// Each method behaves exactly like the [`PushManager`] method of the same name.
+/// Calls run one at a time on a single background thread shared by all the clones,
+/// since [`PushManager`] only runs one at a time anyway.
+#[derive(Clone)]
+pub struct AsyncPushManager {
+    inner: Arc<PushManager>,
+    pool: Arc<WorkerPool>,
+}
+
+impl AsyncPushManager {
+    /// Creates a new [`AsyncPushManager`], see [`PushManager::new`].
+    ///
+    /// This opens the database, so is best called before entering the async runtime.
+    pub fn new(config: PushConfiguration) -> ApiResult<Self> {
+        Ok(Self::from(PushManager::new(config)?))
+    }
+
+    fn spawn<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&PushManager) -> T + Send + 'static,
+    ) -> BlockingTask<T> {
+        let inner = self.inner.clone();
+        self.pool.spawn(move || f(&inner))
+    }
+
+    pub fn subscribe(
+        &self,
+        scope: &str,
+        server_key: Option<&str>,
+        sender_id: Option<&str>,
+    ) -> BlockingTask<ApiResult<SubscriptionResponse>> {
+        let scope = scope.to_string();
+        let server_key = server_key.map(str::to_string);
+        let sender_id = sender_id.map(str::to_string);
+        self.spawn(move |pm| pm.subscribe(&scope, &server_key, &sender_id))
+    }
+
+    pub fn subscribe_with_raw_server_key(
+        &self,
+        scope: &str,
+        server_key: &[u8],
+        sender_id: Option<&str>,
+    ) -> BlockingTask<ApiResult<SubscriptionResponse>> {
+        let scope = scope.to_string();
+        let server_key = server_key.to_vec();
+        let sender_id = sender_id.map(str::to_string);
+        self.spawn(move |pm| pm.subscribe_with_raw_server_key(&scope, &server_key, &sender_id))
+    }
+
+    pub fn prepare_keys(&self) -> BlockingTask<ApiResult<Arc<PreparedKeys>>> {
+        self.spawn(|pm| pm.prepare_keys())
+    }
+
+    pub fn subscribe_with_keys(
+        &self,
+        scope: &str,
+        server_key: Option<&str>,
+        sender_id: Option<&str>,
+        keys: Arc<PreparedKeys>,
+    ) -> BlockingTask<ApiResult<SubscriptionResponse>> {
+        let scope = scope.to_string();
+        let server_key = server_key.map(str::to_string);
+        let sender_id = sender_id.map(str::to_string);
+        self.spawn(move |pm| pm.subscribe_with_keys(&scope, &server_key, &sender_id, keys))
+    }
+
+    pub fn get_subscription(
+        &self,
+        scope: &str,
+    ) -> BlockingTask<ApiResult<Option<SubscriptionResponse>>> {
+        let scope = scope.to_string();
+        self.spawn(move |pm| pm.get_subscription(&scope))
+    }
+
+    pub fn unsubscribe(&self, channel_id: &str) -> BlockingTask<ApiResult<bool>> {
+        let channel_id = channel_id.to_string();
+        self.spawn(move |pm| pm.unsubscribe(&channel_id))
+    }
+
+    pub fn flush_pending_unregisters(&self) -> BlockingTask<ApiResult<u64>> {
+        self.spawn(|pm| pm.flush_pending_unregisters())
+    }
+
+    pub fn unsubscribe_by_scope(&self, scope: &str) -> BlockingTask<ApiResult<u64>> {
+        let scope = scope.to_string();
+        self.spawn(move |pm| pm.unsubscribe_by_scope(&scope))
+    }
+
+    pub fn unsubscribe_many(
+        &self,
+        channel_ids: &[String],
+    ) -> BlockingTask<ApiResult<Vec<UnsubscribeResult>>> {
+        let channel_ids = channel_ids.to_vec();
+        self.spawn(move |pm| pm.unsubscribe_many(&channel_ids))
+    }
+
+    pub fn prune_subscriptions(&self, older_than_ms: i64) -> BlockingTask<ApiResult<Vec<String>>> {
+        self.spawn(move |pm| pm.prune_subscriptions(older_than_ms))
+    }
+
+    pub fn refresh_subscription(
+        &self,
+        channel_id: &str,
+    ) -> BlockingTask<ApiResult<SubscriptionResponse>> {
+        let channel_id = channel_id.to_string();
+        self.spawn(move |pm| pm.refresh_subscription(&channel_id))
+    }
+
+    pub fn rotate_keys(&self, channel_id: &str) -> BlockingTask<ApiResult<SubscriptionResponse>> {
+        let channel_id = channel_id.to_string();
+        self.spawn(move |pm| pm.rotate_keys(&channel_id))
+    }
+
+    pub fn unsubscribe_all(&self) -> BlockingTask<ApiResult<()>> {
+        self.spawn(|pm| pm.unsubscribe_all())
+    }
+
+    pub fn update(&self, new_token: &str) -> BlockingTask<ApiResult<()>> {
+        let new_token = new_token.to_string();
+        self.spawn(move |pm| pm.update(&new_token))
+    }
+
+    pub fn re_register(&self, new_token: &str) -> BlockingTask<ApiResult<ReRegisterResult>> {
+        let new_token = new_token.to_string();
+        self.spawn(move |pm| pm.re_register(&new_token))
+    }
+
+    pub fn handle_token_refresh(
+        &self,
+        new_token: &str,
+    ) -> BlockingTask<ApiResult<ReRegisterResult>> {
+        let new_token = new_token.to_string();
+        self.spawn(move |pm| pm.handle_token_refresh(&new_token))
+    }
+
+    pub fn set_bridge_type(
+        &self,
+        bridge_type: &str,
+        registration_id: &str,
+    ) -> BlockingTask<ApiResult<bool>> {
+        let bridge_type = bridge_type.to_string();
+        let registration_id = registration_id.to_string();
+        self.spawn(move |pm| pm.set_bridge_type(&bridge_type, &registration_id))
+    }
+
+    pub fn verify_connection(
+        &self,
+        force_verify: bool,
+    ) -> BlockingTask<ApiResult<Vec<PushSubscriptionChanged>>> {
+        self.spawn(move |pm| pm.verify_connection(force_verify))
+    }
+
+    pub fn verify_connection_dry_run(
+        &self,
+    ) -> BlockingTask<ApiResult<Vec<PushSubscriptionChanged>>> {
+        self.spawn(|pm| pm.verify_connection_dry_run())
+    }
+
+    pub fn connection_diff(&self) -> BlockingTask<ApiResult<ConnectionDiff>> {
+        self.spawn(|pm| pm.connection_diff())
+    }
+
+    pub fn get_stale_subscriptions(&self) -> BlockingTask<ApiResult<Vec<PushSubscriptionChanged>>> {
+        self.spawn(|pm| pm.get_stale_subscriptions())
+    }
+
+    pub fn last_verified(&self) -> BlockingTask<ApiResult<Option<i64>>> {
+        self.spawn(|pm| pm.last_verified())
+    }
+
+    pub fn needs_verification(&self, max_age_ms: i64) -> BlockingTask<ApiResult<bool>> {
+        self.spawn(move |pm| pm.needs_verification(max_age_ms))
+    }
+
+    /// This doesn't block, so unlike the rest of the API it isn't async.
+    pub fn config_summary(&self) -> ConfigSummary {
+        self.inner.config_summary()
+    }
+
+    pub fn check_connection(&self) -> BlockingTask<ApiResult<bool>> {
+        self.spawn(|pm| pm.check_connection())
+    }
+
+    /// This doesn't block, so unlike the rest of the API it isn't async.
+    pub fn set_observer(&self, observer: Option<Box<dyn PushObserver>>) {
+        self.inner.set_observer(observer)
+    }
+
+    /// This doesn't block either.
+    pub fn set_channel_id_generator(&self, generator: Box<dyn ChannelIdGenerator>) {
+        self.inner.set_channel_id_generator(generator)
+    }
+
+    pub fn metrics_snapshot(&self) -> BlockingTask<PushMetrics> {
+        self.spawn(|pm| pm.metrics_snapshot())
+    }
+
+    pub fn reset_metrics(&self) -> BlockingTask<()> {
+        self.spawn(|pm| pm.reset_metrics())
+    }
+
+    pub fn maintenance(&self) -> BlockingTask<ApiResult<()>> {
+        self.spawn(|pm| pm.maintenance())
+    }
+
+    pub fn relocate_database(&self, new_path: &str) -> BlockingTask<ApiResult<()>> {
+        let new_path = new_path.to_string();
+        self.spawn(move |pm| pm.relocate_database(&new_path))
+    }
+
+    pub fn verify_storage_integrity(&self) -> BlockingTask<ApiResult<StorageHealth>> {
+        self.spawn(|pm| pm.verify_storage_integrity())
+    }
+
+    pub fn support_dump(&self) -> BlockingTask<ApiResult<String>> {
+        self.spawn(|pm| pm.support_dump())
+    }
+
+    pub fn self_test(&self, channel_id: &str) -> BlockingTask<ApiResult<bool>> {
+        let channel_id = channel_id.to_string();
+        self.spawn(move |pm| pm.self_test(&channel_id))
+    }
+
+    pub fn get_uaid(&self) -> BlockingTask<ApiResult<Option<String>>> {
+        self.spawn(|pm| pm.get_uaid())
+    }
+
+    pub fn get_endpoint(&self, channel_id: &str) -> BlockingTask<ApiResult<Option<String>>> {
+        let channel_id = channel_id.to_string();
+        self.spawn(move |pm| pm.get_endpoint(&channel_id))
+    }
+
+    pub fn contains_subscription(&self, channel_id: &str) -> BlockingTask<ApiResult<bool>> {
+        let channel_id = channel_id.to_string();
+        self.spawn(move |pm| pm.contains_subscription(&channel_id))
+    }
+
+    pub fn get_subscriptions_page(
+        &self,
+        offset: u64,
+        limit: u64,
+    ) -> BlockingTask<ApiResult<Vec<SubscriptionResponse>>> {
+        self.spawn(move |pm| pm.get_subscriptions_page(offset, limit))
+    }
+
+    pub fn is_subscribed_with_key(
+        &self,
+        channel_id: &str,
+        server_key: &str,
+    ) -> BlockingTask<ApiResult<bool>> {
+        let channel_id = channel_id.to_string();
+        let server_key = server_key.to_string();
+        self.spawn(move |pm| pm.is_subscribed_with_key(&channel_id, &server_key))
+    }
+
+    pub fn set_subscription_metadata(
+        &self,
+        channel_id: &str,
+        key: &str,
+        value: &str,
+    ) -> BlockingTask<ApiResult<()>> {
+        let channel_id = channel_id.to_string();
+        let key = key.to_string();
+        let value = value.to_string();
+        self.spawn(move |pm| pm.set_subscription_metadata(&channel_id, &key, &value))
+    }
+
+    pub fn is_duplicate_message(
+        &self,
+        channel_id: &str,
+        message_id: &str,
+    ) -> BlockingTask<ApiResult<bool>> {
+        let channel_id = channel_id.to_string();
+        let message_id = message_id.to_string();
+        self.spawn(move |pm| pm.is_duplicate_message(&channel_id, &message_id))
+    }
+
+    pub fn get_subscription_metadata(
+        &self,
+        channel_id: &str,
+        key: &str,
+    ) -> BlockingTask<ApiResult<Option<String>>> {
+        let channel_id = channel_id.to_string();
+        let key = key.to_string();
+        self.spawn(move |pm| pm.get_subscription_metadata(&channel_id, &key))
+    }
+
+    pub fn decrypt(
+        &self,
+        payload: HashMap<String, String>,
+    ) -> BlockingTask<ApiResult<DecryptResponse>> {
+        self.spawn(move |pm| pm.decrypt(payload))
+    }
+
+    pub fn decrypt_message(
+        &self,
+        payload: HashMap<String, String>,
+    ) -> BlockingTask<ApiResult<DecryptedMessage>> {
+        self.spawn(move |pm| pm.decrypt_message(payload))
+    }
+
+    pub fn decrypt_for_channel(
+        &self,
+        channel_id: &str,
+        payload: HashMap<String, String>,
+    ) -> BlockingTask<ApiResult<DecryptResponse>> {
+        let channel_id = channel_id.to_string();
+        self.spawn(move |pm| pm.decrypt_for_channel(&channel_id, payload))
+    }
+
+    pub fn decrypt_bytes(
+        &self,
+        channel_id: &str,
+        body: &[u8],
+        encoding: &str,
+        salt: &[u8],
+        dh: &[u8],
+    ) -> BlockingTask<ApiResult<DecryptResponse>> {
+        let channel_id = channel_id.to_string();
+        let body = body.to_vec();
+        let encoding = encoding.to_string();
+        let salt = salt.to_vec();
+        let dh = dh.to_vec();
+        self.spawn(move |pm| pm.decrypt_bytes(&channel_id, &body, &encoding, &salt, &dh))
+    }
+
+    pub fn decrypt_envelope(&self, envelope: &str) -> BlockingTask<ApiResult<DecryptResponse>> {
+        let envelope = envelope.to_string();
+        self.spawn(move |pm| pm.decrypt_envelope(&envelope))
+    }
+
+    pub fn decrypt_batch(
+        &self,
+        payloads: Vec<HashMap<String, String>>,
+    ) -> BlockingTask<Vec<BatchDecryptResult>> {
+        self.spawn(move |pm| pm.decrypt_batch(payloads))
+    }
+}
+
+impl From<PushManager> for AsyncPushManager {
+    fn from(push_manager: PushManager) -> Self {
+        Self {
+            inner: Arc::new(push_manager),
+            pool: Arc::new(WorkerPool::new("push", 1)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::future::Future;
+    use std::task::{Context, Poll, Wake, Waker};
+    use std::thread;
+
+    struct ThreadWaker(thread::Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    // We don't have an async runtime in our dev-dependencies, so this is the simplest
+    // executor that works.
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = Box::pin(future);
+        let waker: Waker = Arc::new(ThreadWaker(thread::current())).into();
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            match future.as_mut().poll(&mut cx) {
+                Poll::Ready(output) => return output,
+                Poll::Pending => thread::park(),
+            }
+        }
+    }
+
+    #[test]
+    fn test_async_push_manager() {
+        let pm = AsyncPushManager::new(PushConfiguration::default()).unwrap();
+        assert_eq!(block_on(pm.get_uaid()).unwrap(), None);
+        assert!(!block_on(pm.contains_subscription("deadbeef")).unwrap());
+        assert!(!block_on(pm.check_connection()).unwrap());
+    }
+}
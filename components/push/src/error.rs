@@ -2,6 +2,8 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
+use std::time::Duration;
+
 use error_support::{ErrorHandling, GetErrorHandling};
 
 pub type Result<T, E = PushError> = std::result::Result<T, E>;
@@ -18,6 +20,70 @@ pub enum PushApiError {
     #[error("No record for chid {0}")]
     RecordNotFoundError(String),
 
+    /// The VAPID server key supplied to `subscribe` is malformed
+    #[error("Invalid server key: {0}")]
+    InvalidServerKey(String),
+
+    /// The scope is already subscribed with a different VAPID server key
+    #[error("Scope {0} is already subscribed with a different server key")]
+    ServerKeyMismatch(String),
+
+    /// The scope is already subscribed with a different sender id
+    #[error("Scope {0} is already subscribed with a different sender id")]
+    SenderIdMismatch(String),
+
+    /// Subscribing would exceed the configured `max_subscriptions`
+    #[error("Subscription limit of {0} reached")]
+    SubscriptionLimitReached(u32),
+
+    /// The stored keys for a subscription are corrupt, so it needs to be recreated
+    #[error("Corrupt key for chid {0}")]
+    CorruptKeyError(String),
+
+    /// The subscription for the chid was recently removed
+    #[error("Subscription for chid {0} was unsubscribed")]
+    SubscriptionUnsubscribed(String),
+
+    /// A message is for a channel we have no subscription for
+    #[error("No subscription for chid {0}")]
+    UnknownChannel(String),
+
+    /// The chid in a message isn't the channel it was expected to be for, so it may
+    /// have been routed to the wrong subscription
+    #[error("Message for chid {actual} doesn't match expected chid {expected}")]
+    ChannelMismatch { expected: String, actual: String },
+
+    /// A field of a message is missing or malformed, so the message should be dropped
+    #[error("Malformed message field: {field}")]
+    MalformedInput { field: String },
+
+    /// A well-formed message couldn't be decrypted with the subscription's keys
+    #[error("Decryption failed: {0}")]
+    DecryptionFailed(String),
+
+    /// A message decrypted, but its padding wasn't valid, so it may have been truncated
+    #[error("Invalid message padding")]
+    InvalidPadding,
+
+    /// The subscription has no stored keys, so it needs to be recreated
+    #[error("No keys for chid {0}")]
+    RecordMissingKeys(String),
+
+    /// A message couldn't be decrypted after the subscription's keys were rotated, so
+    /// it was most likely encrypted with the old keys
+    #[error("Keys for chid {0} were rotated")]
+    KeysRotated(String),
+
+    /// The autopush server is throttling us, so the app should back off, for at least
+    /// `retry_after` if the server said how long
+    #[error("Throttled by the server{}", retry_after_hint(.retry_after))]
+    Throttled { retry_after: Option<Duration> },
+
+    /// The autopush server responded with an error `status`, so the app can tell
+    /// a server outage (5xx) from a rejected request (4xx)
+    #[error("HTTP status {status}")]
+    HttpStatus { status: u16 },
+
     /// Internal Error
     #[error("Internal Error: {0}")]
     InternalError(String),
@@ -40,6 +106,18 @@ pub enum PushError {
     #[error("Communication Server Error: {0}")]
     CommunicationServerError(String),
 
+    /// The registration server responded with a non-success status
+    #[error("HTTP status {status} for {}", redact_url(.url))]
+    HttpStatusError {
+        status: u16,
+        url: String,
+        body: Option<String>,
+    },
+
+    /// The registration server is throttling us, and may have said for how long
+    #[error("Throttled by the server{}", retry_after_hint(.retry_after))]
+    Throttled { retry_after: Option<Duration> },
+
     /// Channel is already registered, generate new channelID
     #[error("Channel already registered.")]
     AlreadyRegisteredError,
@@ -51,6 +129,63 @@ pub enum PushError {
     #[error("No record for chid {0:?}")]
     RecordNotFoundError(String),
 
+    /// The VAPID server key is not a base64url encoded P-256 public key
+    #[error("Invalid server key: {0}")]
+    InvalidServerKey(String),
+
+    /// The scope is already subscribed with a different VAPID server key
+    #[error("Scope {0} is already subscribed with a different server key")]
+    ServerKeyMismatch(String),
+
+    /// The scope is already subscribed with a different sender id
+    #[error("Scope {0} is already subscribed with a different sender id")]
+    SenderIdMismatch(String),
+
+    /// Subscribing would exceed the configured `max_subscriptions`
+    #[error("Subscription limit of {0} reached")]
+    SubscriptionLimitReached(u32),
+
+    /// The stored keys for a subscription couldn't be deserialized
+    #[error("Corrupt key for chid {0:?}")]
+    CorruptKeyError(String),
+
+    /// The subscription for the chid was removed within the tombstone retention window
+    #[error("Subscription for chid {0:?} was unsubscribed")]
+    SubscriptionUnsubscribed(String),
+
+    /// A message is for a channel we have no subscription for
+    #[error("No subscription for chid {0:?}")]
+    UnknownChannel(String),
+
+    /// The chid in a message isn't the one the caller expected
+    #[error("Message for chid {actual:?} doesn't match expected chid {expected:?}")]
+    ChannelMismatch { expected: String, actual: String },
+
+    /// A field of a message is missing or isn't valid
+    #[error("Malformed message field: {field}")]
+    MalformedInput { field: String },
+
+    /// The message is well-formed, but decrypting it with the subscription's keys failed
+    #[error("Decryption failed: {0}")]
+    DecryptionFailed(String),
+
+    /// The decrypted message's padding is inconsistent (RFC 8188 section 2 / RFC 8291)
+    #[error("Invalid message padding")]
+    InvalidPadding,
+
+    /// The stored record for the chid has no keys
+    #[error("No keys for chid {0:?}")]
+    RecordMissingKeys(String),
+
+    /// A message couldn't be decrypted after `rotate_keys`, so it was most likely
+    /// encrypted with the old keys
+    #[error("Couldn't decrypt message for chid {0:?}, its keys were rotated")]
+    KeysRotated(String),
+
+    /// A VAPID `Authorization` header couldn't be created or isn't valid
+    #[error("VAPID error: {0}")]
+    VapidError(String),
+
     /// A failure to encode data to/from storage.
     #[error("Error executing SQL: {0}")]
     StorageSqlError(#[from] rusqlite::Error),
@@ -79,6 +214,31 @@ pub enum PushError {
     OpenDatabaseError(#[from] sql_support::open_database::Error),
 }
 
+/// The path of an autopush url includes our uaid and channel ids, which we'd rather
+/// not leak into logs and error reports.
+fn redact_url(url: &str) -> String {
+    let mut redact_next = false;
+    url.split('/')
+        .map(|segment| {
+            let redacted = if redact_next && !segment.is_empty() {
+                "[redacted]"
+            } else {
+                segment
+            };
+            redact_next = matches!(segment, "registration" | "subscription");
+            redacted
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn retry_after_hint(retry_after: &Option<Duration>) -> String {
+    match retry_after {
+        Some(retry_after) => format!(", retry after {}s", retry_after.as_secs()),
+        None => String::new(),
+    }
+}
+
 impl From<bincode::Error> for PushError {
     fn from(value: bincode::Error) -> Self {
         PushError::TranscodingError(format!("bincode error: {value}"))
@@ -109,6 +269,54 @@ impl GetErrorHandling for PushError {
             Self::RecordNotFoundError(s) => {
                 ErrorHandling::convert(PushApiError::RecordNotFoundError(s.clone()))
             }
+            Self::InvalidServerKey(s) => {
+                ErrorHandling::convert(PushApiError::InvalidServerKey(s.clone()))
+            }
+            Self::ServerKeyMismatch(s) => {
+                ErrorHandling::convert(PushApiError::ServerKeyMismatch(s.clone()))
+            }
+            Self::SenderIdMismatch(s) => {
+                ErrorHandling::convert(PushApiError::SenderIdMismatch(s.clone()))
+            }
+            Self::SubscriptionLimitReached(limit) => {
+                ErrorHandling::convert(PushApiError::SubscriptionLimitReached(*limit))
+            }
+            Self::CorruptKeyError(s) => {
+                ErrorHandling::convert(PushApiError::CorruptKeyError(s.clone()))
+                    .report_error("corrupt-push-key")
+            }
+            Self::SubscriptionUnsubscribed(s) => {
+                ErrorHandling::convert(PushApiError::SubscriptionUnsubscribed(s.clone()))
+            }
+            Self::UnknownChannel(s) => {
+                ErrorHandling::convert(PushApiError::UnknownChannel(s.clone()))
+            }
+            Self::ChannelMismatch { expected, actual } => {
+                ErrorHandling::convert(PushApiError::ChannelMismatch {
+                    expected: expected.clone(),
+                    actual: actual.clone(),
+                })
+            }
+            Self::MalformedInput { field } => {
+                ErrorHandling::convert(PushApiError::MalformedInput {
+                    field: field.clone(),
+                })
+            }
+            Self::DecryptionFailed(s) => {
+                ErrorHandling::convert(PushApiError::DecryptionFailed(s.clone()))
+            }
+            Self::InvalidPadding => ErrorHandling::convert(PushApiError::InvalidPadding),
+            Self::RecordMissingKeys(s) => {
+                ErrorHandling::convert(PushApiError::RecordMissingKeys(s.clone()))
+                    .report_error("push-record-missing-keys")
+            }
+            Self::KeysRotated(s) => ErrorHandling::convert(PushApiError::KeysRotated(s.clone())),
+            Self::Throttled { retry_after } => ErrorHandling::convert(PushApiError::Throttled {
+                retry_after: *retry_after,
+            }),
+            Self::HttpStatusError { status, .. } => {
+                ErrorHandling::convert(PushApiError::HttpStatus { status: *status })
+            }
 
             _ => ErrorHandling::convert(PushApiError::InternalError(self.to_string())),
         }
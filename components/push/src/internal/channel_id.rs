@@ -0,0 +1,57 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use crate::error;
+use crate::internal::crypto::get_random_bytes;
+
+/// Generates the channel ids for subscriptions we create ourselves, rather than
+/// getting from autopush, see [`PushConfiguration::offline`](crate::PushConfiguration::offline).
+///
+/// The default, [`RandomChannelIds`], generates random UUIDv4s. Tests and replay
+/// tools can supply their own to get predictable ids, and closures returning a
+/// `String` can be used directly.
+pub trait ChannelIdGenerator: Send + Sync {
+    fn generate(&self) -> error::Result<String>;
+}
+
+impl<F: Fn() -> String + Send + Sync> ChannelIdGenerator for F {
+    fn generate(&self) -> error::Result<String> {
+        Ok(self())
+    }
+}
+
+/// A [`ChannelIdGenerator`] which generates random UUIDv4s, formatted without
+/// dashes like the channel ids autopush hands out.
+#[derive(Debug, Default)]
+pub struct RandomChannelIds;
+
+impl ChannelIdGenerator for RandomChannelIds {
+    fn generate(&self) -> error::Result<String> {
+        let mut bytes = get_random_bytes(16)?;
+        // Set the version (4) and variant (RFC 4122) bits.
+        bytes[6] = (bytes[6] & 0x0f) | 0x40;
+        bytes[8] = (bytes[8] & 0x3f) | 0x80;
+        Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_random_channel_ids() {
+        let id = RandomChannelIds.generate().unwrap();
+        assert_eq!(id.len(), 32);
+        assert_eq!(&id[12..13], "4");
+        assert!(matches!(&id[16..17], "8" | "9" | "a" | "b"));
+        assert_ne!(id, RandomChannelIds.generate().unwrap());
+
+        let fixed = || "deadbeef00000000decafbad00000000".to_string();
+        assert_eq!(
+            fixed.generate().unwrap(),
+            "deadbeef00000000decafbad00000000"
+        );
+    }
+}
@@ -0,0 +1,35 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use viaduct::{Request, Response};
+
+use crate::error;
+
+/// Sends the HTTP requests a [`Connection`](super::Connection) makes to autopush.
+///
+/// The default, [`ViaductClient`], sends them with whichever viaduct backend the
+/// app has configured, or a [`viaduct::Client`] when requests need settings of
+/// their own, like a proxy. Apps can supply their own to reuse an existing connection
+/// pool, and tests can return canned autopush responses without a real socket.
+pub trait PushHttpClient: Send + Sync {
+    /// Sends `request`, returning the response whatever its status. Only failing
+    /// to get a response at all should be an error.
+    fn send(&self, request: Request) -> error::Result<Response>;
+}
+
+/// A [`PushHttpClient`] which sends requests via viaduct.
+#[derive(Debug, Default)]
+pub struct ViaductClient;
+
+impl PushHttpClient for ViaductClient {
+    fn send(&self, request: Request) -> error::Result<Response> {
+        Ok(request.send()?)
+    }
+}
+
+impl PushHttpClient for viaduct::Client {
+    fn send(&self, request: Request) -> error::Result<Response> {
+        Ok(viaduct::Client::send(self, request)?)
+    }
+}
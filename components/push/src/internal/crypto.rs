@@ -49,15 +49,31 @@ impl FromStr for CryptoEncoding {
             "aesgcm" => Self::Aesgcm,
             "aes128gcm" => Self::Aes128gcm,
             _ => {
-                return Err(PushError::CryptoError(format!(
-                    "Invalid crypto encoding {}",
-                    s
-                )))
+                log::warn!("Invalid crypto encoding {}", s);
+                return Err(malformed("con"));
             }
         })
     }
 }
 
+/// The error for a message which couldn't be decrypted. Both encodings pad the
+/// plaintext, and the padding is checked to be well-formed as it's stripped;
+/// we report that separately, as it means the sender is broken rather than that
+/// we have the wrong keys.
+fn decryption_failed(e: ece::Error) -> PushError {
+    match e {
+        ece::Error::DecryptPadding => PushError::InvalidPadding,
+        e => PushError::DecryptionFailed(e.to_string()),
+    }
+}
+
+/// The error for a missing or invalid field of a push message.
+fn malformed(field: &str) -> PushError {
+    PushError::MalformedInput {
+        field: field.to_string(),
+    }
+}
+
 impl Display for CryptoEncoding {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
@@ -126,6 +142,10 @@ pub trait Cryptography: Default {
     // Clippy complains here although the lifetime is needed, seems like a bug with automock
     fn decrypt<'a>(key: &Key, push_payload: PushPayload<'a>) -> error::Result<Decrypted>;
 
+    /// Like `decrypt`, but for a payload whose fields have already been base64 decoded.
+    #[allow(clippy::needless_lifetimes)]
+    fn decrypt_bytes<'a>(key: &Key, payload: RawPushPayload<'a>) -> error::Result<Decrypted>;
+
     /// Decrypt the obsolete "aesgcm" format (which is still used by a number of providers)
     fn decrypt_aesgcm(
         key: &Key,
@@ -149,6 +169,42 @@ pub fn get_random_bytes(size: usize) -> error::Result<Vec<u8>> {
     Ok(bytes)
 }
 
+/// Encrypts `plaintext` to `key` in the "aes128gcm" format, as a sender would.
+/// We only need this to check that we can decrypt our own messages.
+pub fn encrypt_to_key(key: &Key, plaintext: &[u8]) -> error::Result<Vec<u8>> {
+    rc_crypto::ensure_initialized();
+    Ok(ece::encrypt(
+        key.public_key(),
+        key.auth_secret(),
+        plaintext,
+    )?)
+}
+
+/// Length of an uncompressed P-256 public key (0x04 || X || Y)
+const UNCOMPRESSED_P256_KEY_LENGTH: usize = 65;
+
+/// Checks that a VAPID application server key is a base64url encoded, uncompressed
+/// P-256 public key, so a typo is caught before we send it to autopush.
+/// Some senders hand out keys with trailing `=` padding, which we tolerate.
+pub fn validate_server_key(server_key: &str) -> error::Result<()> {
+    let raw = URL_SAFE_NO_PAD
+        .decode(server_key.trim_end_matches('='))
+        .map_err(|e| PushError::InvalidServerKey(format!("not base64url: {}", e)))?;
+    if raw.len() != UNCOMPRESSED_P256_KEY_LENGTH {
+        return Err(PushError::InvalidServerKey(format!(
+            "expected {} bytes, got {}",
+            UNCOMPRESSED_P256_KEY_LENGTH,
+            raw.len()
+        )));
+    }
+    if raw[0] != 0x04 {
+        return Err(PushError::InvalidServerKey(
+            "not an uncompressed P-256 point".to_string(),
+        ));
+    }
+    Ok(())
+}
+
 /// Extract the sub-value from the header.
 /// Sub values have the form of `label=value`. Due to a bug in some push providers, treat ',' and ';' as
 /// equivalent.
@@ -194,15 +250,35 @@ impl Cryptography for Crypto {
     }
 
     fn decrypt(key: &Key, push_payload: PushPayload<'_>) -> error::Result<Decrypted> {
+        let d_salt = extract_value(push_payload.salt, "salt").unwrap_or_default();
+        let d_dh = extract_value(push_payload.dh, "dh").unwrap_or_default();
+        let d_body = URL_SAFE_NO_PAD
+            .decode(push_payload.body)
+            .map_err(|_| malformed("body"))?;
+
+        Self::decrypt_bytes(
+            key,
+            RawPushPayload {
+                body: &d_body,
+                encoding: push_payload.encoding,
+                salt: &d_salt,
+                dh: &d_dh,
+            },
+        )
+    }
+
+    fn decrypt_bytes(key: &Key, payload: RawPushPayload<'_>) -> error::Result<Decrypted> {
         rc_crypto::ensure_initialized();
-        // convert the private key into something useful.
-        let d_salt = extract_value(push_payload.salt, "salt");
-        let d_dh = extract_value(push_payload.dh, "dh");
-        let d_body = URL_SAFE_NO_PAD.decode(push_payload.body)?;
-
-        match CryptoEncoding::from_str(push_payload.encoding)? {
-            CryptoEncoding::Aesgcm => Self::decrypt_aesgcm(key, &d_body, d_salt, d_dh),
-            CryptoEncoding::Aes128gcm => Self::decrypt_aes128gcm(key, &d_body),
+        // An empty salt or dh means the message didn't carry one.
+        let non_empty = |v: &[u8]| (!v.is_empty()).then(|| v.to_vec());
+        match CryptoEncoding::from_str(payload.encoding)? {
+            CryptoEncoding::Aesgcm => Self::decrypt_aesgcm(
+                key,
+                payload.body,
+                non_empty(payload.salt),
+                non_empty(payload.dh),
+            ),
+            CryptoEncoding::Aes128gcm => Self::decrypt_aes128gcm(key, payload.body),
         }
     }
 
@@ -212,22 +288,120 @@ impl Cryptography for Crypto {
         salt: Option<Vec<u8>>,
         crypto_key: Option<Vec<u8>>,
     ) -> error::Result<Decrypted> {
-        let dh = crypto_key
-            .ok_or_else(|| error::PushError::CryptoError("Missing public key".to_string()))?;
-        let salt = salt.ok_or_else(|| error::PushError::CryptoError("Missing salt".to_string()))?;
-        let block = ece::legacy::AesGcmEncryptedBlock::new(&dh, &salt, 4096, content.to_vec())?;
-        Ok(ece::legacy::decrypt_aesgcm(
-            key.key_pair(),
-            key.auth_secret(),
-            &block,
-        )?)
+        let dh = crypto_key.ok_or_else(|| malformed("cryptokey"))?;
+        let salt = salt.ok_or_else(|| malformed("enc"))?;
+        let block = ece::legacy::AesGcmEncryptedBlock::new(&dh, &salt, 4096, content.to_vec())
+            .map_err(|_| malformed("body"))?;
+        ece::legacy::decrypt_aesgcm(key.key_pair(), key.auth_secret(), &block)
+            .map_err(decryption_failed)
     }
 
     fn decrypt_aes128gcm(key: &Key, content: &[u8]) -> error::Result<Vec<u8>> {
-        Ok(ece::decrypt(key.key_pair(), key.auth_secret(), content)?)
+        // We don't use `ece::decrypt`, which only handles messages that fit in a
+        // single record.
+        let header = Aes128gcmHeader::parse(content)
+            .ok_or_else(|| PushError::DecryptionFailed("Invalid aes128gcm header".to_string()))?;
+        decrypt_webpush_records(key, &header).map_err(decryption_failed)
     }
 }
 
+/// Size of the "aes128gcm" header before the key id: salt (16), rs (4) and idlen (1).
+const AES128GCM_HEADER_LENGTH: usize = 21;
+/// Size of the AES-GCM authentication tag at the end of each record.
+const AES128GCM_TAG_LENGTH: usize = 16;
+
+/// The header of an "aes128gcm" message, as described in RFC 8188 section 2.1,
+/// along with the encrypted records that follow it.
+struct Aes128gcmHeader<'a> {
+    salt: &'a [u8],
+    rs: usize,
+    keyid: &'a [u8],
+    records: &'a [u8],
+}
+
+impl<'a> Aes128gcmHeader<'a> {
+    /// Returns `None` if the header is truncated, there are no records, or the
+    /// record size is too small to hold a tag and a delimiter.
+    fn parse(content: &'a [u8]) -> Option<Self> {
+        if content.len() < AES128GCM_HEADER_LENGTH {
+            return None;
+        }
+        let rs = u32::from_be_bytes(content[16..20].try_into().ok()?) as usize;
+        if rs <= AES128GCM_TAG_LENGTH + 1 {
+            return None;
+        }
+        let idlen = content[20] as usize;
+        let records = content.get(AES128GCM_HEADER_LENGTH + idlen..)?;
+        if records.is_empty() {
+            return None;
+        }
+        Some(Self {
+            salt: &content[..16],
+            rs,
+            keyid: &content[AES128GCM_HEADER_LENGTH..AES128GCM_HEADER_LENGTH + idlen],
+            records,
+        })
+    }
+}
+
+/// Decrypts a web push message (RFC 8291), where the key id in the header is the
+/// sender's public key.
+fn decrypt_webpush_records(key: &Key, header: &Aes128gcmHeader<'_>) -> Result<Vec<u8>, ece::Error> {
+    use rc_crypto::agreement::{Curve, EcKey, PrivateKey, Static, UnparsedPublicKey, ECDH_P256};
+    let private_key = PrivateKey::<Static>::import(&EcKey::new(
+        Curve::P256,
+        key.private_key(),
+        key.public_key(),
+    ))?;
+    let ecdh_secret = private_key
+        .agree_static(&UnparsedPublicKey::new(&ECDH_P256, header.keyid))?
+        .derive(|z| z.to_vec());
+    let mut info = b"WebPush: info\0".to_vec();
+    info.extend_from_slice(key.public_key());
+    info.extend_from_slice(header.keyid);
+    let ikm = hkdf_sha256(key.auth_secret(), &ecdh_secret, &info, 32)?;
+    decrypt_records(&ikm, header)
+}
+
+/// Decrypts and reassembles the records of an "aes128gcm" message (RFC 8188).
+fn decrypt_records(ikm: &[u8], header: &Aes128gcmHeader<'_>) -> Result<Vec<u8>, ece::Error> {
+    use rc_crypto::aead::{self, Aad, Nonce, OpeningKey, AES_128_GCM};
+    let cek = hkdf_sha256(header.salt, ikm, b"Content-Encoding: aes128gcm\0", 16)?;
+    let base_nonce = hkdf_sha256(header.salt, ikm, b"Content-Encoding: nonce\0", 12)?;
+    let opening_key = OpeningKey::new(&AES_128_GCM, &cek)?;
+    let records: Vec<&[u8]> = header.records.chunks(header.rs).collect();
+    let mut plaintext = Vec::with_capacity(header.records.len());
+    for (seq, record) in records.iter().enumerate() {
+        // The nonce for each record is the base nonce XORed with its sequence number.
+        let mut nonce = base_nonce.clone();
+        for (n, s) in nonce[4..].iter_mut().zip((seq as u64).to_be_bytes()) {
+            *n ^= s;
+        }
+        let nonce = Nonce::try_assume_unique_for_key(&AES_128_GCM, &nonce)?;
+        let mut data = aead::open(&opening_key, nonce, Aad::empty(), record)?;
+        // Strip the zero padding; the delimiter before it is 2 for the last
+        // record and 1 for all the others.
+        let delimiter = if seq == records.len() - 1 { 2 } else { 1 };
+        let end = data
+            .iter()
+            .rposition(|&b| b != 0)
+            .ok_or(ece::Error::DecryptPadding)?;
+        if data[end] != delimiter {
+            return Err(ece::Error::DecryptPadding);
+        }
+        data.truncate(end);
+        plaintext.extend_from_slice(&data);
+    }
+    Ok(plaintext)
+}
+
+fn hkdf_sha256(salt: &[u8], secret: &[u8], info: &[u8], len: usize) -> Result<Vec<u8>, ece::Error> {
+    let salt = rc_crypto::hmac::SigningKey::new(&rc_crypto::digest::SHA256, salt);
+    let mut out = vec![0u8; len];
+    rc_crypto::hkdf::extract_and_expand(&salt, secret, info, &mut out)?;
+    Ok(out)
+}
+
 #[derive(Debug, Deserialize)]
 pub struct PushPayload<'a> {
     pub(crate) channel_id: &'a str,
@@ -237,16 +411,23 @@ pub struct PushPayload<'a> {
     pub(crate) dh: &'a str,
 }
 
+/// A push message whose body, salt and dh have already been base64 decoded, as
+/// some native bridges deliver them. For "aes128gcm" the salt and dh are part of
+/// the body, so they are left empty.
+#[derive(Debug)]
+pub struct RawPushPayload<'a> {
+    pub(crate) body: &'a [u8],
+    pub(crate) encoding: &'a str,
+    pub(crate) salt: &'a [u8],
+    pub(crate) dh: &'a [u8],
+}
+
 impl<'a> TryFrom<&'a HashMap<String, String>> for PushPayload<'a> {
     type Error = PushError;
 
     fn try_from(value: &'a HashMap<String, String>) -> Result<Self, Self::Error> {
-        let channel_id = value
-            .get("chid")
-            .ok_or_else(|| PushError::CryptoError("Invalid Push payload".to_string()))?;
-        let body = value
-            .get("body")
-            .ok_or_else(|| PushError::CryptoError("Invalid Push payload".to_string()))?;
+        let channel_id = value.get("chid").ok_or_else(|| malformed("chid"))?;
+        let body = value.get("body").ok_or_else(|| malformed("body"))?;
         let encoding = value.get("con").map(|s| s.as_str()).unwrap_or("aes128gcm");
         let salt = value.get("enc").map(|s| s.as_str()).unwrap_or("");
         let dh = value.get("cryptokey").map(|s| s.as_str()).unwrap_or("");
@@ -325,6 +506,61 @@ mod crypto_tests {
         decrypter(ciphertext, "aesgcm", salt, dh).expect_err("Failed to abort, bad salt");
     }
 
+    fn raw_decrypter(
+        ciphertext: &str,
+        encoding: &str,
+        salt: &str,
+        dh: &str,
+    ) -> error::Result<Vec<u8>> {
+        let key = test_key(
+            "qJkxxWGVVxy7BKvraNY3hg8Gs-Y8qi0lRaXWJ3R3aJ8",
+            "BBcJdfs1GtMyymFTtty6lIGWRFXrEtJP40Df0gOvRDR4D8CKVgqE6vlYR7tCYksIRdKD1MxDPhQVmKLnzuife50",
+            "LsuUOBKVQRY6-l7_Ajo-Ag",
+        );
+        Crypto::decrypt_bytes(
+            &key,
+            RawPushPayload {
+                body: &URL_SAFE_NO_PAD.decode(ciphertext).unwrap(),
+                encoding,
+                salt: &extract_value(salt, "salt").unwrap_or_default(),
+                dh: &extract_value(dh, "dh").unwrap_or_default(),
+            },
+        )
+    }
+
+    #[test]
+    fn test_decrypt_bytes_matches_decrypt() {
+        let ciphertext = "BNKu5uTFhjyS-06eECU9-6O61int3Rr7ARbm-xPhFuyDO5sfxVs-HywGaVonvzkarvfvXE9IRT_YNA81Og2uSqDasdMuw\
+                          qm1zd0O3f7049IkQep3RJ2pEZTy5DqvI7kwMLDLzea9nroq3EMH5hYhvQtQgtKXeWieEL_3yVDQVg";
+        let dh = "dh=BMOebOMWSRisAhWpRK9ZPszJC8BL9MiWvLZBoBU6pG6Kh6vUFSW4BHFMh0b83xCg3_7IgfQZXwmVuyu27vwiv5c";
+        let salt = "salt=tSf2qu43C9BD0zkvRW5eUg";
+        let decrypted = raw_decrypter(ciphertext, "aesgcm", salt, dh).unwrap();
+        assert_eq!(
+            decrypted,
+            decrypter(ciphertext, "aesgcm", salt, dh).unwrap()
+        );
+        assert_eq!(String::from_utf8(decrypted).unwrap(), PLAINTEXT.to_string());
+
+        for ciphertext in [PADDED, UNPADDED] {
+            let decrypted = raw_decrypter(ciphertext, "aes128gcm", "", "").unwrap();
+            assert_eq!(
+                decrypted,
+                decrypter(ciphertext, "aes128gcm", "", "").unwrap()
+            );
+            assert_eq!(decrypted, b"Hello, padding");
+        }
+
+        // The same fields are reported missing.
+        assert!(matches!(
+            raw_decrypter(ciphertext, "aesgcm", "", dh),
+            Err(PushError::MalformedInput { field }) if field == "enc"
+        ));
+        assert!(matches!(
+            raw_decrypter(ciphertext, "aesgcm", salt, ""),
+            Err(PushError::MalformedInput { field }) if field == "cryptokey"
+        ));
+    }
+
     #[test]
     fn test_decrypt_aes128gcm() {
         let ciphertext = "Ek7iQgliMqS9kjFoiVOqRgAAEABBBFirfBtF6XTeHVPABFDveb1iu7uO1XVA_MYJeAo-\
@@ -335,4 +571,131 @@ mod crypto_tests {
         let decrypted = decrypter(ciphertext, "aes128gcm", "", "").unwrap();
         assert_eq!(String::from_utf8(decrypted).unwrap(), PLAINTEXT.to_string());
     }
+
+    #[test]
+    fn test_decrypt_errors() {
+        let malformed = |result: error::Result<Vec<u8>>| match result {
+            Err(PushError::MalformedInput { field }) => field,
+            other => panic!("unexpected result {:?}", other),
+        };
+        assert_eq!(
+            malformed(decrypter("not base64!", "aes128gcm", "", "")),
+            "body"
+        );
+        assert_eq!(malformed(decrypter("AAAA", "rot13", "", "")), "con");
+        let dh = "dh=BMOebOMWSRisAhWpRK9ZPszJC8BL9MiWvLZBoBU6pG6Kh6vUFSW4BHFMh0b83xCg3_7IgfQZXwmVuyu27vwiv5c";
+        assert_eq!(malformed(decrypter("AAAA", "aesgcm", "", dh)), "enc");
+        assert_eq!(
+            malformed(decrypter(
+                "AAAA",
+                "aesgcm",
+                "salt=tSf2qu43C9BD0zkvRW5eUg",
+                ""
+            )),
+            "cryptokey"
+        );
+
+        // Well formed, but tampered with.
+        let ciphertext = "Ek7iQgliMqS9kjFoiVOqRgAAEABBBFirfBtF6XTeHVPABFDveb1iu7uO1XVA_MYJeAo-\
+             4ih8WYUsXSTIYmkKMv5_UB3tZuQI7BQ2EVpYYQfvOCrWZVMRL8fJCuB5wVXcoRoTaFJw\
+             TlJ5hnw7IMSiaMqGVlc8drX7Hzy-ugzzAKRhGPV2x-gdsp58DZh9Ww5vHpHyT1xwVkXz\
+             x3KTyeBZu4gl_zR0Q00li17g0xGsE6Dg3xlkKEmaalgyUyObl6_a8RA6Ko1Rc6RhAy2jdyY1LQbBUnA";
+        assert!(matches!(
+            decrypter(ciphertext, "aes128gcm", "", ""),
+            Err(PushError::DecryptionFailed(_))
+        ));
+        // Too short to hold a header.
+        assert!(matches!(
+            decrypter("Ek7iQgliMqS9kjFoiVOqRgAAEAA", "aes128gcm", "", ""),
+            Err(PushError::DecryptionFailed(_))
+        ));
+    }
+
+    // Messages encrypted for our test key with different padding, with plaintext
+    // "Hello, padding".
+    const PADDED: &str = "5LHvv44Q1m37w6GmZgRoBQAAEABBBE8rBrbm5Y97KmPAhBkh54eoCtpQP_B7zED7A3VOvSuutPWlb\
+        pR8heCMMUpKZ0EPAX7nnbixOYOVQFmB3M4SRbYNMKCGe3m2eDkxS8jQIybPq-gblAscX7xnSlvizPpe7KOeBNu9uFGmG\
+        YZfIfC_RA";
+    const UNPADDED: &str =
+        "-TyUkwdqQ547CoG1w4h0JQAAEABBBAnR3GJNgc3ejFzEz6kxhBUFfFBKcVdBedrb6qT0OpTQBAs\
+        gbVMVchOyuk0rZ7zlgqBd-0PivnR9_gYV3EMbZiDgEjALBt9Ovk1S8QpyW2869u1pNU_IxVttU5Sc8YfP";
+    // The delimiter is 0x01, which is only valid for records other than the last.
+    const BAD_DELIMITER: &str =
+        "aFHrRyaGlXXMIr-vheekBgAAEABBBO8JD09vX4u_8SjeHpRElMq9_1XDf6NmOd78kRMePT7W1\
+        APJHvEUIMIMMlN6TfxYTcYg7ibtG_Q-Y3myurl-YikMWfEYS6ZU_GQbtDZTilATheFWcm3ev6XbysclM5J2awlJuQ";
+    // Zero padding, but no delimiter at all.
+    const NO_DELIMITER: &str =
+        "58KNv2R-Y1u9QkqqlWIkgQAAEABBBPQeHJJYX8PSYrQWtbtrMWgeZz0mC4PdAPbhu_JhT21Wt\
+        UemVm2YNV9ZdACUUpV5EjCDwu0CnO1roqOhryVkLceXcjrovz1Vd9lt9B-QXfUvYmXsMlBbi9kqnmlrXKEpP_0z";
+
+    #[test]
+    fn test_decrypt_padding() {
+        for ciphertext in [PADDED, UNPADDED] {
+            let decrypted = decrypter(ciphertext, "aes128gcm", "", "").unwrap();
+            assert_eq!(decrypted, b"Hello, padding");
+        }
+        for ciphertext in [BAD_DELIMITER, NO_DELIMITER] {
+            assert!(matches!(
+                decrypter(ciphertext, "aes128gcm", "", ""),
+                Err(PushError::InvalidPadding)
+            ));
+        }
+    }
+
+    #[test]
+    fn test_decrypt_multiple_records() {
+        // The example from RFC 8188 section 3.2: "I am the walrus" split into two
+        // 25 byte records, the first of which has a byte of padding.
+        let content = URL_SAFE_NO_PAD
+            .decode(
+                "uNCkWiNYzKTnBN9ji3-qWAAAABkCYTHOG8chz_gnvgOqdGYovxyjuqRyJFjEDyoF\
+                 1Fvkj6hQPdPHI51OEUKEpgz3SsLWIqS_uA",
+            )
+            .unwrap();
+        let ikm = URL_SAFE_NO_PAD.decode("BO3ZVPxUlnLORbVGMpbT1Q").unwrap();
+        let header = Aes128gcmHeader::parse(&content).unwrap();
+        assert_eq!(header.rs, 25);
+        assert_eq!(header.keyid, b"a1");
+        assert_eq!(header.records.len(), 50);
+        assert_eq!(decrypt_records(&ikm, &header).unwrap(), b"I am the walrus");
+
+        // Dropping the last record leaves a message that ends with a non-final
+        // delimiter.
+        let truncated = Aes128gcmHeader {
+            records: &header.records[..25],
+            ..header
+        };
+        assert!(matches!(
+            decrypt_records(&ikm, &truncated),
+            Err(ece::Error::DecryptPadding)
+        ));
+    }
+
+    #[test]
+    fn test_encrypt_to_key() {
+        // We encrypt with the ece crate but decrypt ourselves, so this checks they agree.
+        let key = Crypto::generate_key().unwrap();
+        let ciphertext = encrypt_to_key(&key, PLAINTEXT.as_bytes()).unwrap();
+        let decrypted = Crypto::decrypt_aes128gcm(&key, &ciphertext).unwrap();
+        assert_eq!(decrypted, PLAINTEXT.as_bytes());
+    }
+
+    const SERVER_KEY: &str =
+        "BBcJdfs1GtMyymFTtty6lIGWRFXrEtJP40Df0gOvRDR4D8CKVgqE6vlYR7tCYksIRdKD1MxDPhQVmKLnzuife50";
+
+    #[test]
+    fn test_validate_server_key() {
+        validate_server_key(SERVER_KEY).unwrap();
+    }
+
+    #[test]
+    fn test_validate_padded_server_key() {
+        validate_server_key(&format!("{}=", SERVER_KEY)).unwrap();
+    }
+
+    #[test]
+    fn test_validate_truncated_server_key() {
+        let err = validate_server_key(&SERVER_KEY[..40]).unwrap_err();
+        assert!(matches!(err, PushError::InvalidServerKey(_)));
+    }
 }
@@ -0,0 +1,33 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! An optional sink for structured events from the push internals, so an embedding
+//! app can capture them into its own telemetry. Without a sink, events are dropped
+//! and only the `log` facade sees what's going on.
+//!
+//! Events must never carry decrypted message contents or key material.
+
+use std::sync::{Arc, PoisonError, RwLock};
+
+use crate::{PushLog, PushLogEvent};
+
+// A sink which panicked mid-`log` can't have left this inconsistent, so we
+// carry on through a poisoned lock.
+static PUSH_LOGGER: RwLock<Option<Arc<dyn PushLog>>> = RwLock::new(None);
+
+pub fn set_push_logger(sink: Option<Box<dyn PushLog>>) {
+    *PUSH_LOGGER.write().unwrap_or_else(PoisonError::into_inner) = sink.map(Arc::from);
+}
+
+pub fn log_event(event: PushLogEvent) {
+    // Don't hold the lock while calling the sink, which may be slow, or log
+    // events or swap the sink itself.
+    let sink = PUSH_LOGGER
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone();
+    if let Some(sink) = sink {
+        sink.log(event);
+    }
+}
@@ -0,0 +1,140 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! In-memory counters of what a [`PushManager`](crate::PushManager) has done, so apps
+//! can report push health without hooking every call. They're never persisted, so
+//! start from zero with each new manager.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use viaduct::{Request, Response};
+
+use super::communications::PushHttpClient;
+use crate::{error, PushMetrics};
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    subscriptions_created: AtomicU64,
+    unsubscribes: AtomicU64,
+    decrypt_successes: AtomicU64,
+    decrypt_failures: AtomicU64,
+    client_errors: AtomicU64,
+    server_errors: AtomicU64,
+    retries: AtomicU64,
+}
+
+fn add(counter: &AtomicU64, n: usize) {
+    counter.fetch_add(n as u64, Ordering::Relaxed);
+}
+
+impl Metrics {
+    pub fn subscription_created(&self) {
+        add(&self.subscriptions_created, 1);
+    }
+
+    pub fn unsubscribed(&self, count: usize) {
+        add(&self.unsubscribes, count);
+    }
+
+    pub fn decrypted(&self, succeeded: bool) {
+        if succeeded {
+            add(&self.decrypt_successes, 1);
+        } else {
+            add(&self.decrypt_failures, 1);
+        }
+    }
+
+    pub fn response(&self, status: u16) {
+        match status {
+            400..=499 => add(&self.client_errors, 1),
+            500..=599 => add(&self.server_errors, 1),
+            _ => (),
+        }
+    }
+
+    pub fn retried(&self) {
+        add(&self.retries, 1);
+    }
+
+    pub fn snapshot(&self) -> PushMetrics {
+        let get = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        PushMetrics {
+            subscriptions_created: get(&self.subscriptions_created),
+            unsubscribes: get(&self.unsubscribes),
+            decrypt_successes: get(&self.decrypt_successes),
+            decrypt_failures: get(&self.decrypt_failures),
+            client_errors: get(&self.client_errors),
+            server_errors: get(&self.server_errors),
+            retries: get(&self.retries),
+        }
+    }
+
+    pub fn reset(&self) {
+        for counter in [
+            &self.subscriptions_created,
+            &self.unsubscribes,
+            &self.decrypt_successes,
+            &self.decrypt_failures,
+            &self.client_errors,
+            &self.server_errors,
+            &self.retries,
+        ] {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Counts the error responses to every request sent through `inner`.
+pub struct MeteredHttpClient {
+    inner: Box<dyn PushHttpClient>,
+    metrics: Arc<Metrics>,
+}
+
+impl MeteredHttpClient {
+    pub fn new(inner: Box<dyn PushHttpClient>, metrics: Arc<Metrics>) -> Self {
+        Self { inner, metrics }
+    }
+}
+
+impl PushHttpClient for MeteredHttpClient {
+    fn send(&self, request: Request) -> error::Result<Response> {
+        let response = self.inner.send(request)?;
+        self.metrics.response(response.status);
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_metrics() {
+        let metrics = Metrics::default();
+        metrics.subscription_created();
+        metrics.unsubscribed(3);
+        metrics.decrypted(true);
+        metrics.decrypted(false);
+        metrics.decrypted(false);
+        for status in [200, 204, 404, 410, 500, 503] {
+            metrics.response(status);
+        }
+        metrics.retried();
+        assert_eq!(
+            metrics.snapshot(),
+            PushMetrics {
+                subscriptions_created: 1,
+                unsubscribes: 3,
+                decrypt_successes: 1,
+                decrypt_failures: 2,
+                client_errors: 2,
+                server_errors: 2,
+                retries: 1,
+            }
+        );
+        metrics.reset();
+        assert_eq!(metrics.snapshot(), PushMetrics::default());
+    }
+}
@@ -2,9 +2,12 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
+pub mod channel_id;
 pub mod communications;
 pub mod config;
 pub mod crypto;
+pub mod logger;
+pub mod metrics;
 pub mod push_manager;
 pub mod storage;
 
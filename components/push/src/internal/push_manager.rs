@@ -13,25 +13,63 @@
 //! - routinely check subscriptions to make sure they are in a good state.
 
 use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use types::Timestamp;
 
 use crate::error::{self, PushError, Result};
-use crate::internal::communications::{Connection, PersistedRateLimiter};
-use crate::internal::config::PushConfiguration;
+use crate::internal::communications::{
+    default_http_client, Connection, PersistedRateLimiter, PushHttpClient, ViaductClient,
+};
+use crate::internal::config::{BridgeType, PushConfiguration};
 use crate::internal::crypto::KeyV1 as Key;
-use crate::internal::storage::{PushRecord, Storage};
-use crate::{KeyInfo, PushSubscriptionChanged, SubscriptionInfo, SubscriptionResponse};
+use crate::internal::logger::log_event;
+use crate::internal::metrics::{MeteredHttpClient, Metrics};
+use crate::internal::storage::{PushRecord, Storage, Store};
+use crate::{
+    ConfigSummary, ConnectionDiff, KeyInfo, PushLogEvent, PushMetrics, PushObserver,
+    PushSubscriptionChanged, ReRegisterResult, StorageHealth, SubscriptionInfo,
+    SubscriptionResponse,
+};
 
-use super::crypto::{Cryptography, PushPayload};
+use super::channel_id::{ChannelIdGenerator, RandomChannelIds};
+use super::crypto::{
+    encrypt_to_key, validate_server_key, Cryptography, PushPayload, RawPushPayload,
+};
 const UPDATE_RATE_LIMITER_INTERVAL: u64 = 24 * 60 * 60; // 24 hours.
 const UPDATE_RATE_LIMITER_MAX_CALLS: u16 = 500; // 500
 
+/// Where offline subscriptions claim messages should be sent. `.invalid` never
+/// resolves, so nothing can accidentally be delivered to it.
+const OFFLINE_ENDPOINT_BASE: &str = "https://push.offline.invalid/wpush/v1";
+
+/// The uaid we had before the current one, so we can tell the observer what changed
+/// when we next register.
+const PREVIOUS_UAID_META_KEY: &str = "previous_uaid";
+
+/// When `verify_connection` last compared our channels with the server's, in ms.
+const LAST_VERIFIED_META_KEY: &str = "last_verified";
+
+/// The subscriptions `verify_connection` dropped which haven't been resubscribed yet,
+/// as a JSON object mapping their scopes to their old channel ids.
+const STALE_SUBSCRIPTIONS_META_KEY: &str = "stale_subscriptions";
+
+/// Channels `unsubscribe` removed locally but couldn't unsubscribe from the server, as
+/// a JSON object mapping their channel ids to their sender ids, see `flush_pending_unregisters`.
+const PENDING_UNREGISTERS_META_KEY: &str = "pending_unregisters";
+
 impl From<Key> for KeyInfo {
     fn from(key: Key) -> Self {
-        KeyInfo {
+        let info = KeyInfo {
             auth: URL_SAFE_NO_PAD.encode(key.auth_secret()),
             p256dh: URL_SAFE_NO_PAD.encode(key.public_key()),
-        }
+        };
+        debug_assert_eq!(info.auth_bytes().ok().as_deref(), Some(key.auth_secret()));
+        debug_assert_eq!(info.p256dh_bytes().ok().as_deref(), Some(key.public_key()));
+        info
     }
 }
 
@@ -61,6 +99,228 @@ impl TryFrom<PushRecord> for SubscriptionResponse {
 pub struct DecryptResponse {
     pub result: Vec<i8>,
     pub scope: String,
+    pub ttl: Option<u64>,
+    pub urgency: Option<String>,
+}
+
+impl DecryptResponse {
+    /// Some bridges forward the `TTL` and `Urgency` headers of the original message
+    /// alongside the encrypted body, which we pass through so the app can drop
+    /// messages that have already expired.
+    fn with_delivery_headers(mut self, payload: &HashMap<String, String>) -> Self {
+        self.ttl = payload.get("ttl").and_then(|ttl| match ttl.parse() {
+            Ok(ttl) => Some(ttl),
+            Err(_) => {
+                log::warn!("Ignoring malformed message TTL: {:?}", ttl);
+                None
+            }
+        });
+        self.urgency = payload.get("urgency").cloned();
+        self
+    }
+}
+
+/// A decrypted message as returned by `decrypt_message`. Unlike [`DecryptResponse`],
+/// a channel without a scope has a `scope` of `None` rather than "".
+#[derive(Debug)]
+pub struct DecryptedMessage {
+    pub result: Vec<i8>,
+    pub scope: Option<String>,
+    pub ttl: Option<u64>,
+    pub urgency: Option<String>,
+}
+
+impl From<DecryptResponse> for DecryptedMessage {
+    fn from(value: DecryptResponse) -> Self {
+        DecryptedMessage {
+            result: value.result,
+            scope: (!value.scope.is_empty()).then_some(value.scope),
+            ttl: value.ttl,
+            urgency: value.urgency,
+        }
+    }
+}
+
+/// The outcome of decrypting a single message passed to `decrypt_batch`
+#[derive(Debug)]
+pub enum BatchDecryptResult {
+    Decrypted { response: DecryptResponse },
+    Failed { reason: String },
+}
+
+impl From<Result<DecryptResponse>> for BatchDecryptResult {
+    fn from(value: Result<DecryptResponse>) -> Self {
+        match value {
+            Ok(response) => BatchDecryptResult::Decrypted { response },
+            Err(e) => {
+                log::warn!("Failed to decrypt a batched message: {}", e);
+                BatchDecryptResult::Failed {
+                    reason: e.to_string(),
+                }
+            }
+        }
+    }
+}
+
+/// Why `record` can't be used, if it can't.
+fn record_problem(record: &PushRecord) -> Option<String> {
+    if record.key.is_empty() {
+        return Some("missing keys".to_string());
+    }
+    if let Err(e) = Key::deserialize(&record.key) {
+        return Some(format!("corrupt keys: {}", e));
+    }
+    if let Err(e) = url::Url::parse(&record.endpoint) {
+        return Some(format!("invalid endpoint: {}", e));
+    }
+    None
+}
+
+/// The message `self_test` encrypts to a subscription's keys.
+const SELF_TEST_PLAINTEXT: &[u8] = b"push self test";
+
+/// Whether a request that failed with `e` is worth retrying later.
+fn is_transient(e: &PushError) -> bool {
+    match e {
+        PushError::CommunicationError(_)
+        | PushError::RequestError(_)
+        | PushError::Throttled { .. } => true,
+        PushError::HttpStatusError { status, .. } => *status >= 500,
+        _ => false,
+    }
+}
+
+/// Whether two VAPID server keys are the same, ignoring base64 padding.
+fn same_server_key(a: Option<&str>, b: Option<&str>) -> bool {
+    let trim = |key: &str| key.trim_end_matches('=').to_string();
+    a.map(trim) == b.map(trim)
+}
+
+/// Tombstones from before the returned time have outlived `retention`.
+fn tombstone_cutoff(retention: Option<Duration>) -> Timestamp {
+    let now = Timestamp::now();
+    retention
+        .and_then(|retention| now.checked_sub(retention))
+        .unwrap_or(now)
+}
+
+/// A stable offset within `window` for `uaid`, so that devices verify at different
+/// times, but each device always at the same point in the window.
+fn verification_jitter(uaid: &str, window: Duration) -> Result<Duration> {
+    let window_ms = u64::try_from(window.as_millis()).unwrap_or(u64::MAX);
+    if window_ms == 0 {
+        return Ok(Duration::ZERO);
+    }
+    let digest = rc_crypto::digest::digest(&rc_crypto::digest::SHA256, uaid.as_bytes())
+        .map_err(|e| PushError::CryptoError(e.to_string()))?;
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&digest.as_ref()[..8]);
+    Ok(Duration::from_millis(u64::from_be_bytes(bytes) % window_ms))
+}
+
+/// Removes the database at `path`, along with its WAL and shared memory files.
+/// Failures are only logged, as by now we've stopped using it.
+fn remove_database_files(path: &str) {
+    for suffix in ["", "-wal", "-shm"] {
+        let file = format!("{}{}", path, suffix);
+        match std::fs::remove_file(&file) {
+            Ok(()) => (),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+            Err(e) => log::warn!("Failed to remove {}: {}", file, e),
+        }
+    }
+}
+
+/// The fields of a push message which `decrypt` uses.
+const PUSH_MESSAGE_FIELDS: &[&str] = &["chid", "con", "enc", "cryptokey", "body", "ttl", "urgency"];
+
+/// Keys each native platform adds to the messages it delivers, which never hold the
+/// push message fields.
+fn is_platform_metadata(bridge_type: BridgeType, name: &str) -> bool {
+    match bridge_type {
+        BridgeType::Fcm => {
+            matches!(name, "from" | "collapse_key" | "message_type")
+                || name.starts_with("google.")
+                || name.starts_with("gcm.")
+        }
+        BridgeType::Adm => name.starts_with("adm_"),
+        BridgeType::Apns => name == "aps",
+    }
+}
+
+/// Extracts the payload `decrypt` expects from a message as delivered by the native
+/// platform for `bridge_type`. Autopush normally puts the fields at the top level,
+/// alongside the platform's own metadata, but as FCM and ADM only deliver string
+/// values, a sub-hash arrives as a JSON string in one of them.
+pub fn native_message_payload(
+    bridge_type: BridgeType,
+    message: &HashMap<String, String>,
+) -> Result<HashMap<String, String>> {
+    let fields = if message.contains_key("chid") {
+        message.clone()
+    } else {
+        message
+            .iter()
+            .filter(|(name, _)| !is_platform_metadata(bridge_type, name))
+            .find_map(|(_, value)| envelope_payload(value).ok())
+            .ok_or_else(|| PushError::MalformedInput {
+                field: "message".to_string(),
+            })?
+    };
+    Ok(fields
+        .into_iter()
+        .filter_map(|(name, value)| {
+            let name = normalize_field_name(&name);
+            PUSH_MESSAGE_FIELDS
+                .contains(&name)
+                .then(|| (name.to_string(), value))
+        })
+        .collect())
+}
+
+/// The envelope uses the header name, where `decrypt` doesn't.
+fn normalize_field_name(name: &str) -> &str {
+    match name {
+        "crypto-key" => "cryptokey",
+        name => name,
+    }
+}
+
+/// Extracts the payload `decrypt` expects from a JSON message envelope. The payload
+/// fields may be at the top level, intermingled with other data, or in a sub-hash.
+fn envelope_payload(envelope: &str) -> Result<HashMap<String, String>> {
+    let invalid = || PushError::MalformedInput {
+        field: "envelope".to_string(),
+    };
+    let envelope: serde_json::Value = serde_json::from_str(envelope)?;
+    let envelope = envelope.as_object().ok_or_else(invalid)?;
+    let fields = if envelope.contains_key("chid") {
+        envelope
+    } else {
+        envelope
+            .values()
+            .filter_map(|v| v.as_object())
+            .find(|v| v.contains_key("chid"))
+            .ok_or_else(invalid)?
+    };
+    Ok(fields
+        .iter()
+        .filter_map(|(name, value)| {
+            let value = match value {
+                serde_json::Value::String(s) => s.clone(),
+                serde_json::Value::Number(n) => n.to_string(),
+                _ => return None,
+            };
+            Some((normalize_field_name(name).to_string(), value))
+        })
+        .collect())
+}
+
+/// How our channels compare with the ones the autopush server knows about
+enum ChannelListState {
+    Matching,
+    Mismatched,
+    UaidNotRecognized,
 }
 
 pub struct PushManager<Co, Cr, S> {
@@ -69,17 +329,72 @@ pub struct PushManager<Co, Cr, S> {
     uaid: Option<String>,
     auth: Option<String>,
     registration_id: Option<String>,
+    bridge_type: BridgeType,
+    max_subscriptions: Option<u32>,
+    tombstone_retention: Option<Duration>,
+    seen_message_retention: Option<u32>,
+    verification_jitter_window: Option<Duration>,
+    offline: bool,
+    /// Where `store` was opened from, or None if the app gave us its connection.
+    database_path: Option<String>,
     store: S,
     update_rate_limiter: PersistedRateLimiter,
     verify_connection_rate_limiter: PersistedRateLimiter,
+    observer: Option<Box<dyn PushObserver>>,
+    channel_id_generator: Box<dyn ChannelIdGenerator>,
+    config_summary: ConfigSummary,
+    metrics: Arc<Metrics>,
 }
 
 impl<Co: Connection, Cr: Cryptography, S: Storage> PushManager<Co, Cr, S> {
     pub fn new(config: PushConfiguration) -> Result<Self> {
-        let store = S::open(&config.database_path)?;
+        let http_client = default_http_client(&config)?;
+        Self::new_with_db(config, http_client, None)
+    }
+
+    /// Sends requests with `http_client`, which is responsible for its own proxying
+    /// and connection pooling, so `config` can't have a `proxy_url` or `pool_idle_timeout`.
+    pub fn new_with_http_client(
+        config: PushConfiguration,
+        http_client: Box<dyn PushHttpClient>,
+    ) -> Result<Self> {
+        if config.proxy_url.is_some() || config.pool_idle_timeout.is_some() {
+            return Err(PushError::GeneralError(
+                "proxy_url and pool_idle_timeout can't be used with a custom http client"
+                    .to_string(),
+            ));
+        }
+        Self::new_with_db(config, http_client, None)
+    }
+
+    /// Uses `db` for storage rather than opening `database_path`.
+    pub fn with_connection(db: rusqlite::Connection, config: PushConfiguration) -> Result<Self> {
+        let http_client = default_http_client(&config)?;
+        Self::new_with_db(config, http_client, Some(db))
+    }
+
+    fn new_with_db(
+        config: PushConfiguration,
+        http_client: Box<dyn PushHttpClient>,
+        db: Option<rusqlite::Connection>,
+    ) -> Result<Self> {
+        log::debug!("autopush API base url: {}", config.api_base_url()?);
+        config.validate_registration_attributes()?;
+        let config_summary = config.summary()?;
+        let metrics = Arc::new(Metrics::default());
+        let (store, database_path) = match db {
+            Some(db) => (S::from_connection(db)?, None),
+            None => (
+                S::open(&config.database_path)?,
+                Some(config.database_path.clone()),
+            ),
+        };
         let uaid = store.get_uaid()?;
         let auth = store.get_auth()?;
         let registration_id = store.get_registration_id()?;
+        let tombstone_retention = config.tombstone_retention.map(Duration::from_secs);
+        // Without a retention window we don't keep tombstones at all.
+        store.purge_tombstones(tombstone_cutoff(tombstone_retention))?;
         let verify_connection_rate_limiter = PersistedRateLimiter::new(
             "verify_connection",
             config
@@ -95,7 +410,17 @@ impl<Co: Connection, Cr: Cryptography, S: Storage> PushManager<Co, Cr, S> {
         );
 
         Ok(Self {
-            connection: Co::connect(config),
+            bridge_type: config.bridge_type,
+            max_subscriptions: config.max_subscriptions,
+            tombstone_retention,
+            seen_message_retention: config.seen_message_retention,
+            verification_jitter_window: config.verification_jitter_window.map(Duration::from_secs),
+            offline: config.offline,
+            database_path,
+            connection: Co::connect(
+                config,
+                Box::new(MeteredHttpClient::new(http_client, Arc::clone(&metrics))),
+            ),
             _crypo: Default::default(),
             uaid,
             auth,
@@ -103,9 +428,39 @@ impl<Co: Connection, Cr: Cryptography, S: Storage> PushManager<Co, Cr, S> {
             store,
             update_rate_limiter,
             verify_connection_rate_limiter,
+            observer: None,
+            channel_id_generator: Box::new(RandomChannelIds),
+            config_summary,
+            metrics,
         })
     }
 
+    pub fn set_observer(&mut self, observer: Option<Box<dyn PushObserver>>) {
+        self.observer = observer;
+    }
+
+    pub fn set_channel_id_generator(&mut self, generator: Box<dyn ChannelIdGenerator>) {
+        self.channel_id_generator = generator;
+    }
+
+    fn notify_subscriptions_changed(&self, changes: &[PushSubscriptionChanged]) {
+        if let Some(observer) = &self.observer {
+            for change in changes {
+                observer.on_subscription_changed(change.clone());
+            }
+        }
+    }
+
+    /// The error for a message whose channel we have no record of.
+    fn record_not_found(&self, channel_id: &str) -> PushError {
+        let since = tombstone_cutoff(self.tombstone_retention);
+        match self.store.has_tombstone(channel_id, since) {
+            Ok(true) => PushError::SubscriptionUnsubscribed(channel_id.to_string()),
+            Ok(false) => PushError::UnknownChannel(channel_id.to_string()),
+            Err(e) => e,
+        }
+    }
+
     fn ensure_auth_pair(&self) -> Result<(&str, &str)> {
         if let (Some(uaid), Some(auth)) = (&self.uaid, &self.auth) {
             Ok((uaid, auth))
@@ -120,6 +475,39 @@ impl<Co: Connection, Cr: Cryptography, S: Storage> PushManager<Co, Cr, S> {
         &mut self,
         scope: &str,
         server_key: Option<&str>,
+        sender_id: Option<&str>,
+    ) -> Result<SubscriptionResponse> {
+        self.subscribe_with_keys(scope, server_key, sender_id, None)
+    }
+
+    /// Like [`Self::subscribe`], but with the raw bytes of the VAPID `server_key`.
+    pub fn subscribe_with_raw_server_key(
+        &mut self,
+        scope: &str,
+        server_key: &[u8],
+        sender_id: Option<&str>,
+    ) -> Result<SubscriptionResponse> {
+        // We store and send the key base64url encoded, so convert it up front and
+        // let `subscribe` validate it as usual.
+        let server_key = (!server_key.is_empty()).then(|| URL_SAFE_NO_PAD.encode(server_key));
+        self.subscribe(scope, server_key.as_deref(), sender_id)
+    }
+
+    /// Generates the keys for a subscription ahead of [`Self::subscribe_with_keys`],
+    /// as that's the slow part of subscribing. It doesn't touch our state, so can
+    /// run without holding the manager's lock.
+    pub fn prepare_keys() -> Result<Key> {
+        Cr::generate_key()
+    }
+
+    /// Like [`Self::subscribe`], but uses `keys` for a new subscription instead of
+    /// generating them. They're ignored if the `scope` is already subscribed.
+    pub fn subscribe_with_keys(
+        &mut self,
+        scope: &str,
+        server_key: Option<&str>,
+        sender_id: Option<&str>,
+        keys: Option<Key>,
     ) -> Result<SubscriptionResponse> {
         // While potentially an error, a misconfigured system may use "" as
         // an application key. In that case, we drop the application key.
@@ -128,25 +516,63 @@ impl<Co: Connection, Cr: Cryptography, S: Storage> PushManager<Co, Cr, S> {
         } else {
             server_key
         };
+        if let Some(key) = server_key {
+            validate_server_key(key)?;
+        }
         // Don't fetch the subscription from the server if we've already got one.
         if let Some(record) = self.store.get_record_by_scope(scope)? {
-            if self.uaid.is_none() {
+            if self.uaid.is_none() && !self.offline {
                 // should be impossible - we should delete all records when we lose our uiad.
                 return Err(PushError::StorageError(
                     "DB has a subscription but no UAID".to_string(),
                 ));
             }
+            // Like the Push API, we won't silently swap the server key of an existing
+            // subscription - the caller needs to unsubscribe first.
+            if !same_server_key(record.app_server_key.as_deref(), server_key) {
+                return Err(PushError::ServerKeyMismatch(scope.to_string()));
+            }
+            // Nor which sender it's routed through.
+            if record.sender_id.as_deref() != sender_id {
+                return Err(PushError::SenderIdMismatch(scope.to_string()));
+            }
             log::debug!("returning existing subscription for '{}'", scope);
             return record.try_into();
         }
 
+        if let Some(limit) = self.max_subscriptions {
+            if self.store.get_channel_list()?.len() >= limit as usize {
+                return Err(PushError::SubscriptionLimitReached(limit));
+            }
+        }
+
+        let subscription_key = match keys {
+            Some(keys) => keys,
+            None => Cr::generate_key()?,
+        };
+        if self.offline {
+            let response =
+                self.subscribe_offline(scope, server_key, sender_id, subscription_key)?;
+            self.metrics.subscription_created();
+            return Ok(response);
+        }
+
         let registration_id = self
             .registration_id
             .as_ref()
             .ok_or_else(|| PushError::CommunicationError("No native id".to_string()))?
             .clone();
 
-        self.impl_subscribe(scope, &registration_id, server_key)
+        let response = self.impl_subscribe_with_key(
+            scope,
+            &registration_id,
+            server_key,
+            sender_id,
+            subscription_key,
+        )?;
+        self.metrics.subscription_created();
+        self.clear_stale_subscription(scope)?;
+        Ok(response)
     }
 
     pub fn get_subscription(&self, scope: &str) -> Result<Option<SubscriptionResponse>> {
@@ -157,23 +583,261 @@ impl<Co: Connection, Cr: Cryptography, S: Storage> PushManager<Co, Cr, S> {
     }
 
     pub fn unsubscribe(&mut self, scope: &str) -> Result<bool> {
-        let (uaid, auth) = self.ensure_auth_pair()?;
+        let auth_pair = if self.offline {
+            None
+        } else {
+            Some(self.ensure_auth_pair()?)
+        };
         let record = self.store.get_record_by_scope(scope)?;
         if let Some(record) = record {
-            self.connection
-                .unsubscribe(&record.channel_id, uaid, auth)?;
-            self.store.delete_record(&record.channel_id)?;
+            if let Some((uaid, auth)) = auth_pair {
+                match self
+                    .connection
+                    .unsubscribe(&record.channel_id, uaid, auth, &record.sender_id)
+                {
+                    Ok(()) => {}
+                    // The app is done with it either way, so drop it now and
+                    // unsubscribe from the server later.
+                    Err(e) if is_transient(&e) => {
+                        log::warn!("Failed to unsubscribe '{}', will retry: {}", scope, e);
+                        self.queue_pending_unregister(&record)?;
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            self.store
+                .delete_records(&[record], self.tombstone_retention.is_some())?;
+            self.metrics.unsubscribed(1);
             Ok(true)
         } else {
+            // The app no longer wants it, so it isn't waiting to be resubscribed.
+            self.clear_stale_subscription(scope)?;
             Ok(false)
         }
     }
 
-    pub fn unsubscribe_all(&mut self) -> Result<()> {
+    pub fn flush_pending_unregisters(&self) -> Result<usize> {
+        let mut pending = self.pending_unregisters()?;
+        if pending.is_empty() || self.offline {
+            return Ok(0);
+        }
+        let (uaid, auth) = self.ensure_auth_pair()?;
+        let mut flushed = 0;
+        for (channel_id, sender_id) in pending.clone() {
+            self.metrics.retried();
+            match self
+                .connection
+                .unsubscribe(&channel_id, uaid, auth, &sender_id)
+            {
+                // If the server has already forgotten the channel, there's nothing to do.
+                Ok(()) | Err(PushError::HttpStatusError { status: 404, .. }) => flushed += 1,
+                Err(e) if is_transient(&e) => {
+                    // The rest are likely to fail the same way, so try again later.
+                    log::warn!("Failed to flush pending unsubscribes: {}", e);
+                    break;
+                }
+                Err(e) => log::warn!("Giving up unsubscribing {}: {}", channel_id, e),
+            }
+            pending.remove(&channel_id);
+        }
+        self.set_pending_unregisters(&pending)?;
+        Ok(flushed)
+    }
+
+    pub fn unsubscribe_by_scope(&mut self, scope: &str) -> Result<u64> {
+        // An empty scope would match everything, use `unsubscribe_all` for that.
+        if scope.is_empty() {
+            return Ok(0);
+        }
+        let records = self.store.get_records_by_scope_prefix(scope)?;
+        if records.is_empty() {
+            return Ok(0);
+        }
+        let auth_pair = if self.offline {
+            None
+        } else {
+            Some(self.ensure_auth_pair()?)
+        };
+        // autopush has no batch unsubscribe, so we unsubscribe one at a time and
+        // then drop whichever ones the server has forgotten about in one go.
+        let mut unsubscribed = Vec::with_capacity(records.len());
+        for record in records {
+            if let Some((uaid, auth)) = auth_pair {
+                match self
+                    .connection
+                    .unsubscribe(&record.channel_id, uaid, auth, &record.sender_id)
+                {
+                    Ok(()) | Err(PushError::HttpStatusError { status: 404, .. }) => (),
+                    Err(e) if is_transient(&e) => {
+                        log::warn!(
+                            "Failed to unsubscribe '{}', will retry: {}",
+                            record.scope,
+                            e
+                        );
+                        self.queue_pending_unregister(&record)?;
+                    }
+                    Err(e) => {
+                        log::warn!("Failed to unsubscribe '{}': {}", record.scope, e);
+                        continue;
+                    }
+                }
+            }
+            unsubscribed.push(record);
+        }
+        let removed = self
+            .store
+            .delete_records(&unsubscribed, self.tombstone_retention.is_some())?;
+        self.metrics.unsubscribed(unsubscribed.len());
+        Ok(removed)
+    }
+
+    pub fn unsubscribe_many(&mut self, channel_ids: &[String]) -> Result<Vec<(String, bool)>> {
+        let mut records = Vec::with_capacity(channel_ids.len());
+        for channel_id in channel_ids {
+            records.push(self.store.get_record(channel_id)?);
+        }
+        // Only insist on a uaid if there's something to unsubscribe.
+        let auth_pair = if self.offline || records.iter().all(Option::is_none) {
+            None
+        } else {
+            Some(self.ensure_auth_pair()?)
+        };
+        let mut results = Vec::with_capacity(channel_ids.len());
+        let mut unsubscribed = Vec::new();
+        for (channel_id, record) in channel_ids.iter().zip(records) {
+            let Some(record) = record else {
+                results.push((channel_id.clone(), false));
+                continue;
+            };
+            let succeeded = match auth_pair {
+                None => true,
+                Some((uaid, auth)) => {
+                    match self.connection.unsubscribe(
+                        &record.channel_id,
+                        uaid,
+                        auth,
+                        &record.sender_id,
+                    ) {
+                        // If the server has already forgotten the channel, there's nothing to drop.
+                        Ok(()) | Err(PushError::HttpStatusError { status: 404, .. }) => true,
+                        // Like `unsubscribe`, drop it now and unsubscribe from the server later.
+                        Err(e) if is_transient(&e) => {
+                            log::warn!(
+                                "Failed to unsubscribe '{}', will retry: {}",
+                                record.scope,
+                                e
+                            );
+                            self.queue_pending_unregister(&record)?;
+                            true
+                        }
+                        Err(e) => {
+                            log::warn!("Failed to unsubscribe '{}': {}", record.scope, e);
+                            false
+                        }
+                    }
+                }
+            };
+            if succeeded {
+                unsubscribed.push(record);
+            }
+            results.push((channel_id.clone(), succeeded));
+        }
+        self.store
+            .delete_records(&unsubscribed, self.tombstone_retention.is_some())?;
+        self.metrics.unsubscribed(unsubscribed.len());
+        Ok(results)
+    }
+
+    pub fn prune_subscriptions(&mut self, older_than_ms: i64) -> Result<Vec<String>> {
+        let cutoff = Timestamp::now()
+            .as_millis_i64()
+            .saturating_sub(older_than_ms.max(0));
+        let chids: Vec<String> = self
+            .store
+            .get_records_created_before(Timestamp::from(cutoff.max(0) as u64))?
+            .into_iter()
+            .map(|record| record.channel_id)
+            .collect();
+        if chids.is_empty() {
+            return Ok(Vec::new());
+        }
+        log::info!("pruning {} old subscriptions", chids.len());
+        Ok(self
+            .unsubscribe_many(&chids)?
+            .into_iter()
+            .filter_map(|(chid, unsubscribed)| unsubscribed.then_some(chid))
+            .collect())
+    }
+
+    pub fn refresh_subscription(&mut self, channel_id: &str) -> Result<SubscriptionResponse> {
+        let old = self
+            .store
+            .get_record(channel_id)?
+            .ok_or_else(|| PushError::RecordNotFoundError(channel_id.to_string()))?;
+        let registration_id = self
+            .registration_id
+            .as_ref()
+            .ok_or_else(|| PushError::CommunicationError("No native id".to_string()))?;
         let (uaid, auth) = self.ensure_auth_pair()?;
+        // We keep the existing keys so only the endpoint changes for the app. Until we
+        // have the new channel, the old one is still the app's subscription.
+        let key = Key::deserialize(&old.key)?;
+        let record = self.new_channel_record(
+            &old.scope,
+            uaid,
+            auth,
+            registration_id,
+            old.app_server_key.as_deref(),
+            old.sender_id.as_deref(),
+            &key,
+        )?;
+        self.store.replace_record(&old.channel_id, &record)?;
+        log::debug!("refreshed subscription for '{}'", record.scope);
+        log_event(PushLogEvent::EndpointChanged {
+            channel_id: record.channel_id.clone(),
+            scope: record.scope.clone(),
+        });
+
+        // The new subscription works whatever happens to the old channel.
+        match self
+            .connection
+            .unsubscribe(&old.channel_id, uaid, auth, &old.sender_id)
+        {
+            // If the server has already forgotten the channel, there's nothing to drop.
+            Ok(()) | Err(PushError::HttpStatusError { status: 404, .. }) => (),
+            Err(e) if is_transient(&e) => {
+                log::warn!("Failed to unsubscribe refreshed channel, will retry: {}", e);
+                self.queue_pending_unregister(&old)?;
+            }
+            Err(e) => log::warn!("Giving up unsubscribing {}: {}", old.channel_id, e),
+        }
+        record.try_into()
+    }
+
+    pub fn rotate_keys(&self, channel_id: &str) -> Result<SubscriptionResponse> {
+        let mut record = self
+            .store
+            .get_record(channel_id)?
+            .ok_or_else(|| PushError::RecordNotFoundError(channel_id.to_string()))?;
+        // autopush never sees our keys, so there's nothing to tell the server; the
+        // channel and endpoint stay the same.
+        record.key = Cr::generate_key()?.serialize()?;
+        if !self.store.rotate_key(&record.channel_id, &record.key)? {
+            return Err(PushError::RecordNotFoundError(channel_id.to_string()));
+        }
+        log::debug!("rotated keys for '{}'", record.scope);
+        record.try_into()
+    }
 
-        self.connection.unsubscribe_all(uaid, auth)?;
+    pub fn unsubscribe_all(&mut self) -> Result<()> {
+        if !self.offline {
+            let (uaid, auth) = self.ensure_auth_pair()?;
+            self.connection.unsubscribe_all(uaid, auth)?;
+        }
+        self.metrics
+            .unsubscribed(self.store.get_channel_list()?.len());
         self.wipe_local_registrations()?;
+        self.set_stale_subscriptions(&BTreeMap::new())?;
         Ok(())
     }
 
@@ -219,120 +883,823 @@ impl<Co: Connection, Cr: Cryptography, S: Storage> PushManager<Co, Cr, S> {
         Ok(())
     }
 
+    pub fn re_register(&mut self, new_token: &str) -> Result<ReRegisterResult> {
+        let mut records = Vec::new();
+        for channel in self.store.get_channel_list()? {
+            if let Some(record) = self.store.get_record(&channel)? {
+                records.push(record);
+            }
+        }
+
+        // Drop our old registration, unless the server already has.
+        if let (Some(uaid), Some(auth)) = (&self.uaid, &self.auth) {
+            match self.connection.unsubscribe_all(uaid, auth) {
+                Ok(()) | Err(PushError::UAIDNotRecognizedError(_)) => (),
+                Err(e) => return Err(e),
+            }
+        }
+        self.forget_uaid()?;
+        self.store.set_registration_id(new_token)?;
+        self.registration_id = Some(new_token.to_string());
+
+        // The first subscription gets us a new uaid, the rest use it. Scopes are unique,
+        // so each new record replaces the old one for its scope.
+        let mut result = ReRegisterResult::default();
+        for record in records {
+            let response = Key::deserialize(&record.key).and_then(|key| {
+                self.impl_subscribe_with_key(
+                    &record.scope,
+                    new_token,
+                    record.app_server_key.as_deref(),
+                    record.sender_id.as_deref(),
+                    key,
+                )
+            });
+            match response {
+                Ok(response) => result.subscriptions.push(PushSubscriptionChanged {
+                    channel_id: response.channel_id,
+                    scope: record.scope,
+                }),
+                // Keep the rest of the records, so calling us again re-creates them.
+                Err(e) if is_transient(&e) => return Err(e),
+                Err(e) => {
+                    // The old channel belonged to the old uaid, so is useless now.
+                    log::warn!(
+                        "Failed to re-create subscription for {}: {}",
+                        record.scope,
+                        e
+                    );
+                    self.store.delete_record(&record.channel_id)?;
+                    result.failed_scopes.push(record.scope);
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    pub fn handle_token_refresh(&mut self, new_token: &str) -> Result<ReRegisterResult> {
+        // Nothing to tell the server, so nothing to recover.
+        if self.uaid.is_none() || self.registration_id.as_deref() == Some(new_token) {
+            self.update(new_token)?;
+            return Ok(ReRegisterResult::default());
+        }
+
+        // Unlike `update()`, we don't rate limit this, as the old token no longer works.
+        let (uaid, auth) = self.ensure_auth_pair()?;
+        match self.connection.update(new_token, uaid, auth) {
+            Ok(()) => (),
+            Err(PushError::UAIDNotRecognizedError(_)) => {
+                log::info!(
+                    "updating our token indicated our subscriptions are gone, re-registering"
+                );
+                let result = self.re_register(new_token)?;
+                self.notify_subscriptions_changed(&result.subscriptions);
+                return Ok(result);
+            }
+            Err(e) => return Err(e),
+        }
+
+        self.store.set_registration_id(new_token)?;
+        self.registration_id = Some(new_token.to_string());
+        Ok(ReRegisterResult::default())
+    }
+
+    pub fn set_bridge_type(&mut self, bridge_type: &str, registration_id: &str) -> Result<bool> {
+        let bridge_type = BridgeType::from_str(bridge_type)?;
+        if self.bridge_type == bridge_type
+            && self.registration_id.as_deref() == Some(registration_id)
+        {
+            return Ok(false);
+        }
+
+        let previous_bridge_type = self.bridge_type;
+        self.connection.set_bridge_type(bridge_type);
+        // Without a uaid the server doesn't know about us yet; we'll use the new
+        // bridge when we first subscribe.
+        if let (Some(uaid), Some(auth)) = (&self.uaid, &self.auth) {
+            if let Err(e) = self.connection.update(registration_id, uaid, auth) {
+                match e {
+                    PushError::UAIDNotRecognizedError(_) => {
+                        // As with `update()`, the existing mechanisms will re-register us,
+                        // and will do so on the new bridge.
+                        log::info!("switching bridges indicated our subscriptions are gone");
+                    }
+                    _ => {
+                        self.connection.set_bridge_type(previous_bridge_type);
+                        return Err(e);
+                    }
+                }
+            }
+        }
+
+        self.bridge_type = bridge_type;
+        self.store.set_registration_id(registration_id)?;
+        self.registration_id = Some(registration_id.to_string());
+        Ok(true)
+    }
+
     pub fn verify_connection(
         &mut self,
         force_verify: bool,
     ) -> Result<Vec<PushSubscriptionChanged>> {
+        // There's no server to disagree with.
+        if self.offline {
+            return Ok(vec![]);
+        }
         if force_verify {
             self.verify_connection_rate_limiter.reset(&self.store);
         }
         if !self.verify_connection_rate_limiter.check(&self.store) {
             return Ok(vec![]);
         }
-        let channels = self.store.get_channel_list()?;
-        let (uaid, auth) = self.ensure_auth_pair()?;
-
-        let local_channels: HashSet<String> = channels.into_iter().collect();
-        let remote_channels = match self.connection.channel_list(uaid, auth) {
-            Ok(v) => Some(HashSet::from_iter(v)),
-            Err(e) => match e {
-                PushError::UAIDNotRecognizedError(_) => {
-                    // We do not unsubscribe, because the server already lost our UAID
-                    None
-                }
-                _ => return Err(e),
-            },
-        };
-
-        // verify both lists match. Either side could have lost its mind.
-        match remote_channels {
+        let (state, local_channels) = self.compare_channel_lists()?;
+        match state {
             // Everything is OK! Lets return early
-            Some(channels) if channels == local_channels => return Ok(Vec::new()),
-            Some(_) => {
+            ChannelListState::Matching => {
+                self.set_last_verified()?;
+                return Ok(Vec::new());
+            }
+            ChannelListState::Mismatched => {
                 log::info!("verify_connection found a mismatch - unsubscribing");
+                let (uaid, auth) = self.ensure_auth_pair()?;
                 // Unsubscribe all the channels (just to be sure and avoid a loop).
                 self.connection.unsubscribe_all(uaid, auth)?;
             }
             // Means the server lost our UAID, lets not unsubscribe,
             // as that operation will fail
-            None => (),
+            ChannelListState::UaidNotRecognized => (),
         };
 
-        let mut subscriptions: Vec<PushSubscriptionChanged> = Vec::new();
-        for channel in local_channels {
-            if let Some(record) = self.store.get_record(&channel)? {
-                subscriptions.push(record.into());
-            }
-        }
+        let subscriptions = self.local_subscriptions(local_channels)?;
         // we wipe all existing subscriptions and the UAID if there is a mismatch; the next
         // `subscribe()` call will get a new UAID.
         self.wipe_local_registrations()?;
+        let mut stale = self.stale_subscriptions()?;
+        stale.extend(
+            subscriptions
+                .iter()
+                .map(|s| (s.scope.clone(), s.channel_id.clone())),
+        );
+        self.set_stale_subscriptions(&stale)?;
+        self.set_last_verified()?;
+        self.notify_subscriptions_changed(&subscriptions);
         Ok(subscriptions)
     }
 
-    pub fn decrypt(&self, payload: HashMap<String, String>) -> Result<DecryptResponse> {
-        let payload = PushPayload::try_from(&payload)?;
-        let val = self
-            .store
-            .get_record(payload.channel_id)?
-            .ok_or_else(|| PushError::RecordNotFoundError(payload.channel_id.to_string()))?;
-        let key = Key::deserialize(&val.key)?;
-        let decrypted = Cr::decrypt(&key, payload)?;
-        // NOTE: this returns a `Vec<i8>` since the kotlin consumer is expecting
-        // signed bytes.
-        Ok(DecryptResponse {
-            result: decrypted.into_iter().map(|ub| ub as i8).collect(),
-            scope: val.scope,
-        })
-    }
-
-    fn wipe_local_registrations(&mut self) -> error::Result<()> {
-        self.store.delete_all_records()?;
-        self.auth = None;
-        self.uaid = None;
-        Ok(())
+    pub fn verify_connection_dry_run(&self) -> Result<Vec<PushSubscriptionChanged>> {
+        // Same as `verify_connection`, minus the rate limiter and any changes to our state.
+        if self.offline {
+            return Ok(vec![]);
+        }
+        let (state, local_channels) = self.compare_channel_lists()?;
+        match state {
+            ChannelListState::Matching => Ok(Vec::new()),
+            ChannelListState::Mismatched | ChannelListState::UaidNotRecognized => {
+                self.local_subscriptions(local_channels)
+            }
+        }
     }
 
-    fn impl_subscribe(
-        &mut self,
-        scope: &str,
-        registration_id: &str,
-        server_key: Option<&str>,
-    ) -> error::Result<SubscriptionResponse> {
-        if let (Some(uaid), Some(auth)) = (&self.uaid, &self.auth) {
-            self.subscribe_with_uaid(scope, uaid, auth, registration_id, server_key)
-        } else {
-            self.register(scope, registration_id, server_key)
+    pub fn connection_diff(&self) -> Result<ConnectionDiff> {
+        if self.offline {
+            return Ok(ConnectionDiff {
+                uaid_recognized: true,
+                server_only: Vec::new(),
+                local_only: Vec::new(),
+            });
         }
+        let local: BTreeSet<String> = self.store.get_channel_list()?.into_iter().collect();
+        let (uaid, auth) = self.ensure_auth_pair()?;
+        let (uaid_recognized, server) = match self.connection.channel_list(uaid, auth) {
+            Ok(v) => (true, v.into_iter().collect::<BTreeSet<String>>()),
+            // The server has forgotten all our channels.
+            Err(PushError::UAIDNotRecognizedError(_)) => (false, BTreeSet::new()),
+            Err(e) => return Err(e),
+        };
+        Ok(ConnectionDiff {
+            uaid_recognized,
+            server_only: server.difference(&local).cloned().collect(),
+            local_only: local.difference(&server).cloned().collect(),
+        })
     }
 
-    fn subscribe_with_uaid(
+    /// Compares our channels with those the server knows about, returning our channels too.
+    fn compare_channel_lists(&self) -> Result<(ChannelListState, HashSet<String>)> {
+        let channels = self.store.get_channel_list()?;
+        let (uaid, auth) = self.ensure_auth_pair()?;
+
+        let local_channels: HashSet<String> = channels.into_iter().collect();
+        let state = match self.connection.channel_list(uaid, auth) {
+            // verify both lists match. Either side could have lost its mind.
+            Ok(v) => {
+                if HashSet::from_iter(v) == local_channels {
+                    ChannelListState::Matching
+                } else {
+                    ChannelListState::Mismatched
+                }
+            }
+            Err(PushError::UAIDNotRecognizedError(_)) => ChannelListState::UaidNotRecognized,
+            Err(e) => return Err(e),
+        };
+        Ok((state, local_channels))
+    }
+
+    fn local_subscriptions(
+        &self,
+        channels: HashSet<String>,
+    ) -> Result<Vec<PushSubscriptionChanged>> {
+        let mut subscriptions: Vec<PushSubscriptionChanged> = Vec::new();
+        for channel in channels {
+            if let Some(record) = self.store.get_record(&channel)? {
+                subscriptions.push(record.into());
+            }
+        }
+        Ok(subscriptions)
+    }
+
+    pub fn get_stale_subscriptions(&self) -> Result<Vec<PushSubscriptionChanged>> {
+        Ok(self
+            .stale_subscriptions()?
+            .into_iter()
+            .map(|(scope, channel_id)| PushSubscriptionChanged { channel_id, scope })
+            .collect())
+    }
+
+    fn stale_subscriptions(&self) -> Result<BTreeMap<String, String>> {
+        Ok(self
+            .store
+            .get_meta(STALE_SUBSCRIPTIONS_META_KEY)?
+            .and_then(|v| match serde_json::from_str(&v) {
+                Ok(v) => Some(v),
+                Err(e) => {
+                    log::warn!("Ignoring malformed stale subscriptions: {}", e);
+                    None
+                }
+            })
+            .unwrap_or_default())
+    }
+
+    fn set_stale_subscriptions(&self, stale: &BTreeMap<String, String>) -> Result<()> {
+        self.store
+            .set_meta(STALE_SUBSCRIPTIONS_META_KEY, &serde_json::to_string(stale)?)
+    }
+
+    fn pending_unregisters(&self) -> Result<BTreeMap<String, Option<String>>> {
+        Ok(self
+            .store
+            .get_meta(PENDING_UNREGISTERS_META_KEY)?
+            .and_then(|v| match serde_json::from_str(&v) {
+                Ok(v) => Some(v),
+                Err(e) => {
+                    log::warn!("Ignoring malformed pending unsubscribes: {}", e);
+                    None
+                }
+            })
+            .unwrap_or_default())
+    }
+
+    fn set_pending_unregisters(&self, pending: &BTreeMap<String, Option<String>>) -> Result<()> {
+        self.store.set_meta(
+            PENDING_UNREGISTERS_META_KEY,
+            &serde_json::to_string(pending)?,
+        )
+    }
+
+    /// Remembers to unsubscribe `record` from the server later, see `flush_pending_unregisters`.
+    fn queue_pending_unregister(&self, record: &PushRecord) -> Result<()> {
+        let mut pending = self.pending_unregisters()?;
+        pending.insert(record.channel_id.clone(), record.sender_id.clone());
+        self.set_pending_unregisters(&pending)
+    }
+
+    fn clear_stale_subscription(&self, scope: &str) -> Result<()> {
+        let mut stale = self.stale_subscriptions()?;
+        if stale.remove(scope).is_some() {
+            self.set_stale_subscriptions(&stale)?;
+        }
+        Ok(())
+    }
+
+    pub fn last_verified(&self) -> Result<Option<i64>> {
+        Ok(self
+            .store
+            .get_meta(LAST_VERIFIED_META_KEY)?
+            .and_then(|v| match v.parse() {
+                Ok(v) => Some(v),
+                Err(_) => {
+                    log::warn!("Ignoring malformed last verified time: {:?}", v);
+                    None
+                }
+            }))
+    }
+
+    // Only once we've acted on what the server told us, so a failure part way
+    // through means we try again.
+    fn set_last_verified(&self) -> Result<()> {
+        self.store.set_meta(
+            LAST_VERIFIED_META_KEY,
+            &Timestamp::now().as_millis_i64().to_string(),
+        )
+    }
+
+    pub fn needs_verification(&self, max_age_ms: i64) -> Result<bool> {
+        // There's no server to verify against.
+        if self.offline {
+            return Ok(false);
+        }
+        let jitter = match (&self.uaid, self.verification_jitter_window) {
+            (Some(uaid), Some(window)) => verification_jitter(uaid, window)?,
+            _ => Duration::ZERO,
+        };
+        let max_age_ms = max_age_ms.saturating_add(jitter.as_millis() as i64);
+        Ok(match self.last_verified()? {
+            Some(last_verified) => Timestamp::now().as_millis_i64() - last_verified >= max_age_ms,
+            None => true,
+        })
+    }
+
+    pub fn config_summary(&self) -> ConfigSummary {
+        ConfigSummary {
+            bridge_type: self.bridge_type,
+            ..self.config_summary.clone()
+        }
+    }
+
+    pub fn check_connection(&self) -> Result<bool> {
+        match (&self.uaid, &self.auth) {
+            (Some(uaid), Some(auth)) => self.connection.check_uaid(uaid, auth),
+            // We've never registered, so there's nothing the server could know about.
+            _ => Ok(false),
+        }
+    }
+
+    pub fn verify_storage_integrity(&self) -> Result<StorageHealth> {
+        let integrity_errors = self.store.integrity_check()?;
+        let mut invalid_channel_ids = Vec::new();
+        for channel_id in self.store.get_channel_list()? {
+            let problem = match self.store.get_record(&channel_id) {
+                Ok(Some(record)) => record_problem(&record),
+                // It was there a moment ago, so the row itself must be unreadable.
+                Ok(None) => Some("unreadable".to_string()),
+                Err(e) => Some(e.to_string()),
+            };
+            if let Some(problem) = problem {
+                log::warn!("Subscription {} is invalid: {}", channel_id, problem);
+                invalid_channel_ids.push(channel_id);
+            }
+        }
+        Ok(StorageHealth {
+            integrity_errors,
+            invalid_channel_ids,
+        })
+    }
+
+    /// A JSON description of our state for attaching to bug reports. Only fields
+    /// known to be safe are copied in, so keys, auth secrets and endpoints are never
+    /// included.
+    pub fn support_dump(&self) -> Result<String> {
+        let config = self.config_summary();
+        let mut channels = Vec::new();
+        for channel_id in self.store.get_channel_list()? {
+            if let Some(record) = self.store.get_record(&channel_id)? {
+                channels.push(serde_json::json!({
+                    "channel_id": record.channel_id,
+                    "scope": record.scope,
+                    "created_at": record.ctime.as_millis(),
+                }));
+            }
+        }
+        let dump = serde_json::json!({
+            "config": {
+                "server_host": config.server_host,
+                "http_protocol": config.http_protocol.to_string(),
+                "bridge_type": config.bridge_type.to_string(),
+                "sender_id": config.sender_id,
+                "proxy_url": config.proxy_url,
+                "extra_header_names": config.extra_header_names,
+                "offline": config.offline,
+            },
+            "uaid": self.uaid,
+            "channel_count": channels.len(),
+            "channels": channels,
+            "last_verified": self.last_verified()?,
+        });
+        Ok(serde_json::to_string_pretty(&dump)?)
+    }
+
+    pub fn self_test(&self, channel_id: &str) -> Result<bool> {
+        let record = self
+            .store
+            .get_record(channel_id)?
+            .ok_or_else(|| self.record_not_found(channel_id))?;
+        let key = match Key::deserialize(&record.key) {
+            Ok(key) => key,
+            Err(e) => {
+                log::warn!("Self test of {} failed, bad keys: {}", channel_id, e);
+                return Ok(false);
+            }
+        };
+        let result = encrypt_to_key(&key, SELF_TEST_PLAINTEXT).and_then(|ciphertext| {
+            Cr::decrypt_bytes(
+                &key,
+                RawPushPayload {
+                    body: &ciphertext,
+                    encoding: "aes128gcm",
+                    salt: &[],
+                    dh: &[],
+                },
+            )
+        });
+        match result {
+            Ok(plaintext) => Ok(plaintext == SELF_TEST_PLAINTEXT),
+            Err(e) => {
+                log::warn!("Self test of {} failed: {}", channel_id, e);
+                Ok(false)
+            }
+        }
+    }
+
+    pub fn metrics_snapshot(&self) -> PushMetrics {
+        self.metrics.snapshot()
+    }
+
+    pub fn reset_metrics(&self) {
+        self.metrics.reset()
+    }
+
+    pub fn maintenance(&self) -> Result<()> {
+        self.store.vacuum()
+    }
+
+    pub fn relocate_database(&mut self, new_path: &str) -> Result<()> {
+        let Some(old_path) = self.database_path.clone() else {
+            return Err(PushError::GeneralError(
+                "Can't relocate a database connection owned by the app".to_string(),
+            ));
+        };
+        if std::path::Path::new(new_path).exists() {
+            return Err(PushError::GeneralError(format!(
+                "Can't relocate the database to {:?}, it already exists",
+                new_path
+            )));
+        }
+        // Until we've switched to the copy, the original is untouched, so any
+        // failure leaves us where we started.
+        self.store.copy_to(new_path)?;
+        let store = match S::open(new_path) {
+            Ok(store) => store,
+            Err(e) => {
+                remove_database_files(new_path);
+                return Err(e);
+            }
+        };
+        // Close the original before removing it.
+        drop(std::mem::replace(&mut self.store, store));
+        self.database_path = Some(new_path.to_string());
+        remove_database_files(&old_path);
+        Ok(())
+    }
+
+    pub fn get_uaid(&self) -> Result<Option<String>> {
+        self.store.get_uaid()
+    }
+
+    pub fn get_endpoint(&self, channel_id: &str) -> Result<Option<String>> {
+        self.store.get_endpoint(channel_id)
+    }
+
+    pub fn contains_subscription(&self, channel_id: &str) -> Result<bool> {
+        self.store.contains_record(channel_id)
+    }
+
+    pub fn get_subscriptions_page(
+        &self,
+        offset: u64,
+        limit: u64,
+    ) -> Result<Vec<SubscriptionResponse>> {
+        self.store
+            .get_records_page(offset, limit)?
+            .into_iter()
+            .map(TryInto::try_into)
+            .collect()
+    }
+
+    pub fn is_subscribed_with_key(&self, channel_id: &str, server_key: &str) -> Result<bool> {
+        Ok(match self.store.get_record(channel_id)? {
+            Some(record) => {
+                record.app_server_key.is_some()
+                    && same_server_key(record.app_server_key.as_deref(), Some(server_key))
+            }
+            None => false,
+        })
+    }
+
+    pub fn set_subscription_metadata(
+        &self,
+        channel_id: &str,
+        key: &str,
+        value: &str,
+    ) -> Result<()> {
+        if !self
+            .store
+            .set_subscription_metadata(channel_id, key, value)?
+        {
+            return Err(self.record_not_found(channel_id));
+        }
+        Ok(())
+    }
+
+    pub fn get_subscription_metadata(&self, channel_id: &str, key: &str) -> Result<Option<String>> {
+        self.store.get_subscription_metadata(channel_id, key)
+    }
+
+    pub fn is_duplicate_message(&self, channel_id: &str, message_id: &str) -> Result<bool> {
+        match self.seen_message_retention {
+            Some(retention) if retention > 0 => self
+                .store
+                .record_seen_message(channel_id, message_id, retention),
+            _ => Ok(false),
+        }
+    }
+
+    pub fn decrypt(&self, payload: HashMap<String, String>) -> Result<DecryptResponse> {
+        let push_payload = PushPayload::try_from(&payload)?;
+        let val = self
+            .store
+            .get_record(push_payload.channel_id)?
+            .ok_or_else(|| self.record_not_found(push_payload.channel_id))?;
+        Ok(self
+            .decrypt_with_record(&val, |key| Cr::decrypt(key, push_payload))?
+            .with_delivery_headers(&payload))
+    }
+
+    pub fn decrypt_for_channel(
+        &self,
+        channel_id: &str,
+        payload: HashMap<String, String>,
+    ) -> Result<DecryptResponse> {
+        let chid = PushPayload::try_from(&payload)?.channel_id;
+        // The server strips the dashes from our channel ids, so compare them like
+        // storage does.
+        if Store::normalize_uuid(chid) != Store::normalize_uuid(channel_id) {
+            return Err(PushError::ChannelMismatch {
+                expected: channel_id.to_string(),
+                actual: chid.to_string(),
+            });
+        }
+        self.decrypt(payload)
+    }
+
+    pub fn decrypt_message(&self, payload: HashMap<String, String>) -> Result<DecryptedMessage> {
+        self.decrypt(payload).map(Into::into)
+    }
+
+    pub fn decrypt_bytes(
+        &self,
+        channel_id: &str,
+        body: &[u8],
+        encoding: &str,
+        salt: &[u8],
+        dh: &[u8],
+    ) -> Result<DecryptResponse> {
+        let val = self
+            .store
+            .get_record(channel_id)?
+            .ok_or_else(|| self.record_not_found(channel_id))?;
+        self.decrypt_with_record(&val, |key| {
+            Cr::decrypt_bytes(
+                key,
+                RawPushPayload {
+                    body,
+                    encoding,
+                    salt,
+                    dh,
+                },
+            )
+        })
+    }
+
+    pub fn decrypt_envelope(&self, envelope: &str) -> Result<DecryptResponse> {
+        self.decrypt(envelope_payload(envelope)?)
+    }
+
+    pub fn decrypt_batch(
+        &self,
+        payloads: Vec<HashMap<String, String>>,
+    ) -> Vec<Result<DecryptResponse>> {
+        // A burst of queued messages tends to be for a handful of channels, so
+        // only load each record once.
+        let mut records: HashMap<String, Option<PushRecord>> = HashMap::new();
+        payloads
+            .iter()
+            .map(|payload| {
+                let push_payload = PushPayload::try_from(payload)?;
+                let channel_id = push_payload.channel_id;
+                if !records.contains_key(channel_id) {
+                    let record = self.store.get_record(channel_id)?;
+                    records.insert(channel_id.to_string(), record);
+                }
+                let record = records[channel_id]
+                    .as_ref()
+                    .ok_or_else(|| self.record_not_found(channel_id))?;
+                Ok(self
+                    .decrypt_with_record(record, |key| Cr::decrypt(key, push_payload))?
+                    .with_delivery_headers(payload))
+            })
+            .collect()
+    }
+
+    fn decrypt_with_record(
+        &self,
+        record: &PushRecord,
+        decrypt: impl FnOnce(&Key) -> Result<Vec<u8>>,
+    ) -> Result<DecryptResponse> {
+        let result = self.impl_decrypt_with_record(record, decrypt);
+        self.metrics.decrypted(result.is_ok());
+        result
+    }
+
+    fn impl_decrypt_with_record(
+        &self,
+        record: &PushRecord,
+        decrypt: impl FnOnce(&Key) -> Result<Vec<u8>>,
+    ) -> Result<DecryptResponse> {
+        if record.key.is_empty() {
+            return Err(PushError::RecordMissingKeys(record.channel_id.clone()));
+        }
+        let key = Key::deserialize(&record.key).map_err(|e| {
+            log::warn!("Failed to deserialize key for {}: {}", record.channel_id, e);
+            PushError::CorruptKeyError(record.channel_id.clone())
+        })?;
+        let decrypted = decrypt(&key).map_err(|e| {
+            log_event(PushLogEvent::DecryptFailed {
+                channel_id: record.channel_id.clone(),
+                reason: e.to_string(),
+            });
+            if !matches!(e, PushError::DecryptionFailed(_)) {
+                return e;
+            }
+            // Senders can take a while to pick up new keys, so after a rotation
+            // this is most likely a message for the old ones.
+            match self.store.keys_rotated(&record.channel_id) {
+                Ok(true) => PushError::KeysRotated(record.channel_id.clone()),
+                Ok(false) => e,
+                Err(e) => e,
+            }
+        })?;
+        // Once a sender has picked up the new keys, failures are no longer likely to
+        // be messages for the old ones.
+        if self.store.keys_rotated(&record.channel_id)? {
+            self.store.clear_keys_rotated(&record.channel_id)?;
+        }
+        // NOTE: this returns a `Vec<i8>` since the kotlin consumer is expecting
+        // signed bytes.
+        Ok(DecryptResponse {
+            result: decrypted.into_iter().map(|ub| ub as i8).collect(),
+            scope: record.scope.clone(),
+            ttl: None,
+            urgency: None,
+        })
+    }
+
+    fn wipe_local_registrations(&mut self) -> error::Result<()> {
+        self.store.delete_all_records()?;
+        self.forget_uaid()
+    }
+
+    /// Drops our uaid, so the next subscription registers for a new one.
+    fn forget_uaid(&mut self) -> error::Result<()> {
+        if let Some(uaid) = self.uaid.take() {
+            self.store.set_meta(PREVIOUS_UAID_META_KEY, &uaid)?;
+        }
+        self.auth = None;
+        // Those channels belonged to the uaid, so can't be unsubscribed any more.
+        self.set_pending_unregisters(&BTreeMap::new())?;
+        Ok(())
+    }
+
+    fn impl_subscribe_with_key(
+        &mut self,
+        scope: &str,
+        registration_id: &str,
+        server_key: Option<&str>,
+        sender_id: Option<&str>,
+        subscription_key: Key,
+    ) -> error::Result<SubscriptionResponse> {
+        if let (Some(uaid), Some(auth)) = (&self.uaid, &self.auth) {
+            self.subscribe_with_uaid(
+                scope,
+                uaid,
+                auth,
+                registration_id,
+                server_key,
+                sender_id,
+                subscription_key,
+            )
+        } else {
+            self.register(
+                scope,
+                registration_id,
+                server_key,
+                sender_id,
+                subscription_key,
+            )
+        }
+    }
+
+    /// Creates a subscription without asking autopush, see [`PushConfiguration::offline`].
+    fn subscribe_offline(
+        &self,
+        scope: &str,
+        app_server_key: Option<&str>,
+        sender_id: Option<&str>,
+        subscription_key: Key,
+    ) -> error::Result<SubscriptionResponse> {
+        let channel_id = self.channel_id_generator.generate()?;
+        let endpoint = format!("{}/{}", OFFLINE_ENDPOINT_BASE, channel_id);
+        let mut record = crate::internal::storage::PushRecord::new(
+            &channel_id,
+            &endpoint,
+            scope,
+            subscription_key.clone(),
+        )?;
+        record.app_server_key = app_server_key.map(|v| v.to_owned());
+        record.sender_id = sender_id.map(|v| v.to_owned());
+        self.store.put_record(&record)?;
+        log::debug!("subscribed offline");
+        log_event(PushLogEvent::EndpointChanged {
+            channel_id: record.channel_id,
+            scope: record.scope,
+        });
+        Ok(SubscriptionResponse {
+            channel_id,
+            subscription_info: SubscriptionInfo {
+                endpoint,
+                keys: subscription_key.into(),
+            },
+        })
+    }
+
+    /// Asks autopush for a new channel for `scope`, returning its record without
+    /// storing it.
+    #[allow(clippy::too_many_arguments)]
+    fn new_channel_record(
         &self,
         scope: &str,
         uaid: &str,
         auth: &str,
         registration_id: &str,
         app_server_key: Option<&str>,
-    ) -> error::Result<SubscriptionResponse> {
+        sender_id: Option<&str>,
+        subscription_key: &Key,
+    ) -> error::Result<PushRecord> {
         let app_server_key = app_server_key.map(|v| v.to_owned());
+        let sender_id = sender_id.map(|v| v.to_owned());
 
         let subscription_response =
             self.connection
-                .subscribe(uaid, auth, registration_id, &app_server_key)?;
-        let subscription_key = Cr::generate_key()?;
-        let mut record = crate::internal::storage::PushRecord::new(
+                .subscribe(uaid, auth, registration_id, &app_server_key, &sender_id)?;
+        let mut record = PushRecord::new(
             &subscription_response.channel_id,
             &subscription_response.endpoint,
             scope,
             subscription_key.clone(),
         )?;
         record.app_server_key = app_server_key;
+        record.sender_id = sender_id;
+        Ok(record)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn subscribe_with_uaid(
+        &self,
+        scope: &str,
+        uaid: &str,
+        auth: &str,
+        registration_id: &str,
+        app_server_key: Option<&str>,
+        sender_id: Option<&str>,
+        subscription_key: Key,
+    ) -> error::Result<SubscriptionResponse> {
+        let record = self.new_channel_record(
+            scope,
+            uaid,
+            auth,
+            registration_id,
+            app_server_key,
+            sender_id,
+            &subscription_key,
+        )?;
         self.store.put_record(&record)?;
         log::debug!("subscribed OK");
+        log_event(PushLogEvent::EndpointChanged {
+            channel_id: record.channel_id.clone(),
+            scope: record.scope,
+        });
         Ok(SubscriptionResponse {
-            channel_id: subscription_response.channel_id,
+            channel_id: record.channel_id,
             subscription_info: SubscriptionInfo {
-                endpoint: subscription_response.endpoint,
+                endpoint: record.endpoint,
                 keys: subscription_key.into(),
             },
         })
@@ -343,16 +1710,33 @@ impl<Co: Connection, Cr: Cryptography, S: Storage> PushManager<Co, Cr, S> {
         scope: &str,
         registration_id: &str,
         app_server_key: Option<&str>,
+        sender_id: Option<&str>,
+        subscription_key: Key,
     ) -> error::Result<SubscriptionResponse> {
         let app_server_key = app_server_key.map(|v| v.to_owned());
-        let register_response = self.connection.register(registration_id, &app_server_key)?;
+        let sender_id = sender_id.map(|v| v.to_owned());
+        let register_response =
+            self.connection
+                .register(registration_id, &app_server_key, &sender_id);
+        log_event(PushLogEvent::RegistrationAttempt {
+            succeeded: register_response.is_ok(),
+        });
+        let register_response = register_response?;
         // Registration successful! Before we return our registration, lets save our uaid and auth
+        let old_uaid = match &self.uaid {
+            Some(uaid) => Some(uaid.clone()),
+            None => self.store.get_meta(PREVIOUS_UAID_META_KEY)?,
+        };
         self.store.set_uaid(&register_response.uaid)?;
         self.store.set_auth(&register_response.secret)?;
         self.uaid = Some(register_response.uaid.clone());
         self.auth = Some(register_response.secret.clone());
+        if old_uaid.as_ref() != Some(&register_response.uaid) {
+            if let Some(observer) = &self.observer {
+                observer.on_uaid_changed(old_uaid, register_response.uaid.clone());
+            }
+        }
 
-        let subscription_key = Cr::generate_key()?;
         let mut record = crate::internal::storage::PushRecord::new(
             &register_response.channel_id,
             &register_response.endpoint,
@@ -360,8 +1744,13 @@ impl<Co: Connection, Cr: Cryptography, S: Storage> PushManager<Co, Cr, S> {
             subscription_key.clone(),
         )?;
         record.app_server_key = app_server_key;
+        record.sender_id = sender_id;
         self.store.put_record(&record)?;
         log::debug!("subscribed OK");
+        log_event(PushLogEvent::EndpointChanged {
+            channel_id: record.channel_id,
+            scope: record.scope,
+        });
         Ok(SubscriptionResponse {
             channel_id: register_response.channel_id,
             subscription_info: SubscriptionInfo {
@@ -376,6 +1765,7 @@ impl<Co: Connection, Cr: Cryptography, S: Storage> PushManager<Co, Cr, S> {
 mod test {
     use mockall::predicate::eq;
     use rc_crypto::ece::{self, EcKeyComponents};
+    use serde_json::json;
 
     use crate::internal::{
         communications::{MockConnection, RegisterResponse, SubscribeResponse},
@@ -428,18 +1818,45 @@ mod test {
         pm.registration_id = Some("native-id".to_string());
         Ok(pm)
     }
+
+    #[test]
+    fn test_with_connection() -> Result<()> {
+        let _m = get_lock(&MTX);
+        let ctx = MockConnection::connect_context();
+        ctx.expect().returning(|_, _| Default::default());
+
+        // Anything already in the database is picked up.
+        let db = Store::from_connection(rusqlite::Connection::open_in_memory()?)?;
+        db.set_uaid(TEST_UAID)?;
+        db.set_auth(TEST_AUTH)?;
+        let pm: PushManager<MockConnection, MockCryptography, Store> =
+            PushManager::with_connection(db.db, Default::default())?;
+        assert_eq!(pm.uaid.as_deref(), Some(TEST_UAID));
+        assert_eq!(pm.auth.as_deref(), Some(TEST_AUTH));
+
+        // An empty database is initialized.
+        let pm: PushManager<MockConnection, MockCryptography, Store> =
+            PushManager::with_connection(
+                rusqlite::Connection::open_in_memory()?,
+                Default::default(),
+            )?;
+        assert_eq!(pm.uaid, None);
+        assert!(pm.store.get_channel_list()?.is_empty());
+        Ok(())
+    }
+
     #[test]
     fn basic() -> Result<()> {
         let _m = get_lock(&MTX);
         let ctx = MockConnection::connect_context();
-        ctx.expect().returning(|_| Default::default());
+        ctx.expect().returning(|_, _| Default::default());
 
         let mut pm = get_test_manager()?;
         pm.connection
             .expect_register()
-            .with(eq("native-id"), eq(None))
+            .with(eq("native-id"), eq(None), eq(None))
             .times(1)
-            .returning(|_, _| {
+            .returning(|_, _, _| {
                 Ok(RegisterResponse {
                     uaid: TEST_UAID.to_string(),
                     channel_id: TEST_CHANNEL_ID.to_string(),
@@ -460,10 +1877,12 @@ mod test {
                 auth,
             })
         });
-        let resp = pm.subscribe("test-scope", None)?;
+        assert_eq!(pm.get_uaid()?, None);
+        let resp = pm.subscribe("test-scope", None, None)?;
         // verify that a subsequent request for the same channel ID returns the same subscription
-        let resp2 = pm.subscribe("test-scope", None)?;
+        let resp2 = pm.subscribe("test-scope", None, None)?;
         assert_eq!(Some(TEST_AUTH.to_owned()), pm.store.get_auth()?);
+        assert_eq!(pm.get_uaid()?.as_deref(), Some(TEST_UAID));
         assert_eq!(
             resp.subscription_info.endpoint,
             resp2.subscription_info.endpoint
@@ -472,9 +1891,9 @@ mod test {
 
         pm.connection
             .expect_unsubscribe()
-            .with(eq(TEST_CHANNEL_ID), eq(TEST_UAID), eq(TEST_AUTH))
+            .with(eq(TEST_CHANNEL_ID), eq(TEST_UAID), eq(TEST_AUTH), eq(None))
             .times(1)
-            .returning(|_, _, _| Ok(()));
+            .returning(|_, _, _, _| Ok(()));
         pm.connection
             .expect_unsubscribe_all()
             .with(eq(TEST_UAID), eq(TEST_AUTH))
@@ -493,14 +1912,14 @@ mod test {
         let _m = get_lock(&MTX);
         rc_crypto::ensure_initialized();
         let ctx = MockConnection::connect_context();
-        ctx.expect().returning(|_| Default::default());
+        ctx.expect().returning(|_, _| Default::default());
         let data_string = b"Mary had a little lamb, with some nice mint jelly";
         let mut pm = get_test_manager()?;
         pm.connection
             .expect_register()
-            .with(eq("native-id"), eq(None))
+            .with(eq("native-id"), eq(None), eq(None))
             .times(1)
-            .returning(|_, _| {
+            .returning(|_, _, _| {
                 Ok(RegisterResponse {
                     uaid: TEST_UAID.to_string(),
                     channel_id: TEST_CHANNEL_ID.to_string(),
@@ -522,7 +1941,7 @@ mod test {
             })
         });
 
-        let resp = pm.subscribe("test-scope", None)?;
+        let resp = pm.subscribe("test-scope", None, None)?;
         let key_info = resp.subscription_info.keys;
         let remote_pub = URL_SAFE_NO_PAD.decode(&key_info.p256dh).unwrap();
         let auth = URL_SAFE_NO_PAD.decode(&key_info.auth).unwrap();
@@ -560,26 +1979,429 @@ mod test {
     }
 
     #[test]
-    fn test_aesgcm_decryption() -> Result<()> {
+    fn test_decrypt_bytes() -> Result<()> {
         let _m = get_lock(&MTX);
-        rc_crypto::ensure_initialized();
-
         let ctx = MockConnection::connect_context();
-        ctx.expect().returning(|_| Default::default());
+        ctx.expect().returning(|_, _| Default::default());
 
-        let mut pm = get_test_manager()?;
+        let pm = get_test_manager()?;
+        put_test_record(&pm, TEST_CHANNEL_ID, "scope-1")?;
 
-        pm.connection
-            .expect_register()
-            .with(eq("native-id"), eq(None))
+        let decryp_ctx = MockCryptography::decrypt_bytes_context();
+        decryp_ctx
+            .expect()
+            .withf(|_, payload| {
+                payload.body == b"the-body"
+                    && payload.encoding == "aesgcm"
+                    && payload.salt == b"abc"
+                    && payload.dh == b"def"
+            })
             .times(1)
-            .returning(|_, _| {
-                Ok(RegisterResponse {
-                    uaid: TEST_UAID.to_string(),
-                    channel_id: TEST_CHANNEL_ID.to_string(),
-                    secret: TEST_AUTH.to_string(),
-                    endpoint: "https://example.com/dummy-endpoint".to_string(),
-                    sender_id: Some("test".to_string()),
+            .returning(|_, _| Ok(b"hello".to_vec()));
+
+        let response = pm.decrypt_bytes(TEST_CHANNEL_ID, b"the-body", "aesgcm", b"abc", b"def")?;
+        assert_eq!(response.result, b"hello".map(|b| b as i8));
+        assert_eq!(response.scope, "scope-1");
+        assert!(matches!(
+            pm.decrypt_bytes(TEST_CHANNEL_ID2, b"the-body", "aes128gcm", b"", b""),
+            Err(PushError::UnknownChannel(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_decrypt_batch() -> Result<()> {
+        let _m = get_lock(&MTX);
+        let ctx = MockConnection::connect_context();
+        ctx.expect().returning(|_, _| Default::default());
+
+        let pm = get_test_manager()?;
+        put_test_record(&pm, TEST_CHANNEL_ID, "scope-1")?;
+
+        let decryp_ctx = MockCryptography::decrypt_context();
+        decryp_ctx
+            .expect()
+            .withf(|_, push_payload| push_payload.body == "good")
+            .times(2)
+            .returning(|_, _| Ok(b"hello".to_vec()));
+        decryp_ctx
+            .expect()
+            .withf(|_, push_payload| push_payload.body == "bad")
+            .times(1)
+            .returning(|_, _| Err(PushError::CryptoError("bad body".to_string())));
+
+        let payload = |chid: &str, body: &str| {
+            HashMap::from_iter(vec![
+                ("chid".to_string(), chid.to_string()),
+                ("body".to_string(), body.to_string()),
+            ])
+        };
+        let mut with_headers = payload(TEST_CHANNEL_ID, "good");
+        with_headers.insert("ttl".to_string(), "60".to_string());
+        with_headers.insert("urgency".to_string(), "high".to_string());
+        let results = pm.decrypt_batch(vec![
+            with_headers,
+            payload(TEST_CHANNEL_ID, "bad"),
+            payload(TEST_CHANNEL_ID2, "good"),
+            HashMap::new(),
+            payload(TEST_CHANNEL_ID, "good"),
+        ]);
+        assert_eq!(results.len(), 5);
+        let response = results[0].as_ref().unwrap();
+        assert_eq!(response.scope, "scope-1");
+        assert_eq!(response.result, b"hello".map(|b| b as i8));
+        assert_eq!(response.ttl, Some(60));
+        assert_eq!(response.urgency.as_deref(), Some("high"));
+        assert!(matches!(results[1], Err(PushError::CryptoError(_))));
+        assert!(matches!(results[2], Err(PushError::UnknownChannel(_))));
+        assert!(matches!(results[3], Err(PushError::CryptoError(_))));
+        assert_eq!(results[4].as_ref().unwrap().ttl, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_subscription_metadata() -> Result<()> {
+        let _m = get_lock(&MTX);
+        let ctx = MockConnection::connect_context();
+        ctx.expect().returning(|_, _| Default::default());
+
+        let pm = get_test_manager()?;
+        assert!(matches!(
+            pm.set_subscription_metadata(TEST_CHANNEL_ID, "label", "Inbox"),
+            Err(PushError::UnknownChannel(_))
+        ));
+        put_test_record(&pm, TEST_CHANNEL_ID, "scope-1")?;
+        pm.set_subscription_metadata(TEST_CHANNEL_ID, "label", "Inbox")?;
+        assert_eq!(
+            pm.get_subscription_metadata(TEST_CHANNEL_ID, "label")?
+                .as_deref(),
+            Some("Inbox")
+        );
+        assert_eq!(
+            pm.get_subscription_metadata(TEST_CHANNEL_ID, "other")?,
+            None
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_duplicate_message() -> Result<()> {
+        let _m = get_lock(&MTX);
+        let ctx = MockConnection::connect_context();
+        ctx.expect().returning(|_, _| Default::default());
+
+        let mut pm = get_test_manager()?;
+        // We don't remember anything unless asked to.
+        assert!(!pm.is_duplicate_message(TEST_CHANNEL_ID, "m1")?);
+        assert!(!pm.is_duplicate_message(TEST_CHANNEL_ID, "m1")?);
+
+        pm.seen_message_retention = Some(10);
+        assert!(!pm.is_duplicate_message(TEST_CHANNEL_ID, "m1")?);
+        assert!(pm.is_duplicate_message(TEST_CHANNEL_ID, "m1")?);
+        assert!(!pm.is_duplicate_message(TEST_CHANNEL_ID, "m2")?);
+        assert!(!pm.is_duplicate_message(TEST_CHANNEL_ID2, "m1")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_decrypt_message() -> Result<()> {
+        let _m = get_lock(&MTX);
+        let ctx = MockConnection::connect_context();
+        ctx.expect().returning(|_, _| Default::default());
+
+        let pm = get_test_manager()?;
+        put_test_record(&pm, TEST_CHANNEL_ID, "scope-1")?;
+
+        let decryp_ctx = MockCryptography::decrypt_context();
+        decryp_ctx
+            .expect()
+            .times(1)
+            .returning(|_, _| Ok(b"hello".to_vec()));
+
+        let payload = HashMap::from([
+            ("chid".to_string(), TEST_CHANNEL_ID.to_string()),
+            ("body".to_string(), "the-body".to_string()),
+            ("ttl".to_string(), "60".to_string()),
+        ]);
+        let message = pm.decrypt_message(payload)?;
+        assert_eq!(message.scope.as_deref(), Some("scope-1"));
+        assert_eq!(message.ttl, Some(60));
+        assert_eq!(message.result, b"hello".map(|b| b as i8));
+
+        // Where the legacy response has "" for no scope, we have `None`.
+        let message = DecryptedMessage::from(DecryptResponse {
+            result: vec![],
+            scope: "".to_string(),
+            ttl: None,
+            urgency: None,
+        });
+        assert_eq!(message.scope, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_decrypt_for_channel() -> Result<()> {
+        let _m = get_lock(&MTX);
+        let ctx = MockConnection::connect_context();
+        ctx.expect().returning(|_, _| Default::default());
+
+        let pm = get_test_manager()?;
+        put_test_record(&pm, TEST_CHANNEL_ID, "scope-1")?;
+        put_test_record(&pm, TEST_CHANNEL_ID2, "scope-2")?;
+
+        let decryp_ctx = MockCryptography::decrypt_context();
+        decryp_ctx
+            .expect()
+            .times(1)
+            .returning(|_, _| Ok(b"hello".to_vec()));
+
+        let payload = |chid: &str| {
+            HashMap::from([
+                ("chid".to_string(), chid.to_string()),
+                ("body".to_string(), "the-body".to_string()),
+            ])
+        };
+        // Mismatches are caught before we try to decrypt.
+        assert!(matches!(
+            pm.decrypt_for_channel(TEST_CHANNEL_ID2, payload(TEST_CHANNEL_ID)),
+            Err(PushError::ChannelMismatch { .. })
+        ));
+        assert!(matches!(
+            pm.decrypt_for_channel(TEST_CHANNEL_ID, HashMap::new()),
+            Err(PushError::MalformedInput { .. })
+        ));
+        // Dashes and case don't make it a different channel.
+        let dashed = format!("{}-{}", &TEST_CHANNEL_ID[..8], &TEST_CHANNEL_ID[8..]).to_uppercase();
+        let response = pm.decrypt_for_channel(TEST_CHANNEL_ID, payload(&dashed))?;
+        assert_eq!(response.scope, "scope-1");
+        Ok(())
+    }
+
+    #[test]
+    fn test_decrypt_envelope() -> Result<()> {
+        let _m = get_lock(&MTX);
+        let ctx = MockConnection::connect_context();
+        ctx.expect().returning(|_, _| Default::default());
+
+        let pm = get_test_manager()?;
+        put_test_record(&pm, TEST_CHANNEL_ID, "scope-1")?;
+
+        let decryp_ctx = MockCryptography::decrypt_context();
+        decryp_ctx
+            .expect()
+            .withf(|_, push_payload| {
+                push_payload.body == "the-body"
+                    && push_payload.encoding == "aesgcm"
+                    && push_payload.salt == "salt=abc"
+                    && push_payload.dh == "dh=def"
+            })
+            .times(2)
+            .returning(|_, _| Ok(b"hello".to_vec()));
+
+        let fields = json!({
+            "chid": TEST_CHANNEL_ID,
+            "con": "aesgcm",
+            "enc": "salt=abc",
+            "crypto-key": "dh=def",
+            "body": "the-body",
+        });
+        let mut intermingled = fields.clone();
+        intermingled["ttl"] = json!(60);
+        intermingled["other"] = json!({"nested": true});
+        let response = pm.decrypt_envelope(&intermingled.to_string())?;
+        assert_eq!(response.scope, "scope-1");
+        assert_eq!(response.ttl, Some(60));
+
+        let nested = json!({"from": "someone", "payload": fields});
+        pm.decrypt_envelope(&nested.to_string())?;
+
+        assert!(matches!(
+            pm.decrypt_envelope("{\"from\": \"someone\"}"),
+            Err(PushError::MalformedInput { field }) if field == "envelope"
+        ));
+        assert!(matches!(
+            pm.decrypt_envelope("not json"),
+            Err(PushError::JSONDeserializeError(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_native_message_payload() -> Result<()> {
+        let fields = HashMap::from([
+            ("chid".to_string(), TEST_CHANNEL_ID.to_string()),
+            ("con".to_string(), "aesgcm".to_string()),
+            ("enc".to_string(), "salt=abc".to_string()),
+            ("crypto-key".to_string(), "dh=def".to_string()),
+            ("body".to_string(), "the-body".to_string()),
+        ]);
+        let expected = HashMap::from([
+            ("chid".to_string(), TEST_CHANNEL_ID.to_string()),
+            ("con".to_string(), "aesgcm".to_string()),
+            ("enc".to_string(), "salt=abc".to_string()),
+            ("cryptokey".to_string(), "dh=def".to_string()),
+            ("body".to_string(), "the-body".to_string()),
+        ]);
+
+        // Intermingled with the platform's metadata, which is dropped.
+        let mut fcm = fields.clone();
+        fcm.insert("google.message_id".to_string(), "0:123".to_string());
+        fcm.insert("from".to_string(), "1234".to_string());
+        assert_eq!(native_message_payload(BridgeType::Fcm, &fcm)?, expected);
+        let mut apns = fields.clone();
+        apns.insert("aps".to_string(), r#"{"mutable-content": 1}"#.to_string());
+        assert_eq!(native_message_payload(BridgeType::Apns, &apns)?, expected);
+
+        // As a sub-hash, which a string map can only carry as JSON.
+        let adm = HashMap::from([
+            ("adm_message_md5".to_string(), "abc".to_string()),
+            (
+                "payload".to_string(),
+                serde_json::to_string(&fields).unwrap(),
+            ),
+        ]);
+        assert_eq!(native_message_payload(BridgeType::Adm, &adm)?, expected);
+
+        assert!(matches!(
+            native_message_payload(
+                BridgeType::Fcm,
+                &HashMap::from([("from".to_string(), "1234".to_string())])
+            ),
+            Err(PushError::MalformedInput { field }) if field == "message"
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_tombstones() -> Result<()> {
+        let _m = get_lock(&MTX);
+        let ctx = MockConnection::connect_context();
+        ctx.expect().returning(|_, _| Default::default());
+
+        let mut pm = get_test_manager()?;
+        pm.uaid = Some(TEST_UAID.to_string());
+        pm.auth = Some(TEST_AUTH.to_string());
+        put_test_record(&pm, TEST_CHANNEL_ID, "scope-1")?;
+        put_test_record(&pm, TEST_CHANNEL_ID2, "scope-2")?;
+        pm.connection
+            .expect_unsubscribe()
+            .times(2)
+            .returning(|_, _, _, _| Ok(()));
+        let payload = |chid: &str| {
+            HashMap::from_iter(vec![
+                ("chid".to_string(), chid.to_string()),
+                ("body".to_string(), "body".to_string()),
+            ])
+        };
+
+        // No retention window, so we forget about it straight away.
+        assert!(pm.unsubscribe("scope-1")?);
+        assert!(matches!(
+            pm.decrypt(payload(TEST_CHANNEL_ID)),
+            Err(PushError::UnknownChannel(_))
+        ));
+
+        pm.tombstone_retention = Some(Duration::from_secs(60 * 60));
+        assert!(pm.unsubscribe("scope-2")?);
+        assert!(matches!(
+            pm.decrypt(payload(TEST_CHANNEL_ID2)),
+            Err(PushError::SubscriptionUnsubscribed(_))
+        ));
+        assert!(matches!(
+            pm.decrypt_batch(vec![payload(TEST_CHANNEL_ID2)])[0],
+            Err(PushError::SubscriptionUnsubscribed(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_decrypt_corrupt_key() -> Result<()> {
+        let _m = get_lock(&MTX);
+        let ctx = MockConnection::connect_context();
+        ctx.expect().returning(|_, _| Default::default());
+
+        let pm = get_test_manager()?;
+        let mut rec = put_test_record(&pm, TEST_CHANNEL_ID, "scope-1")?;
+        rec.key = vec![0xff; 8];
+        pm.store.put_record(&rec)?;
+
+        let payload = HashMap::from_iter(vec![
+            ("chid".to_string(), TEST_CHANNEL_ID.to_string()),
+            ("body".to_string(), "body".to_string()),
+        ]);
+        assert!(matches!(
+            pm.decrypt(payload.clone()),
+            Err(PushError::CorruptKeyError(chid)) if chid == TEST_CHANNEL_ID
+        ));
+
+        rec.key = vec![];
+        pm.store.put_record(&rec)?;
+        assert!(matches!(
+            pm.decrypt(payload),
+            Err(PushError::RecordMissingKeys(chid)) if chid == TEST_CHANNEL_ID
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_decrypt_malformed_payload() -> Result<()> {
+        let _m = get_lock(&MTX);
+        let ctx = MockConnection::connect_context();
+        ctx.expect().returning(|_, _| Default::default());
+
+        let pm = get_test_manager()?;
+        // Note there's no `decrypt` expectation, we fail before getting that far.
+        let payload = HashMap::from_iter(vec![("chid".to_string(), TEST_CHANNEL_ID.to_string())]);
+        assert!(matches!(
+            pm.decrypt(payload),
+            Err(PushError::MalformedInput { field }) if field == "body"
+        ));
+        let payload = HashMap::from_iter(vec![("body".to_string(), "body".to_string())]);
+        assert!(matches!(
+            pm.decrypt(payload),
+            Err(PushError::MalformedInput { field }) if field == "chid"
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_key_info_bytes() -> Result<()> {
+        let key = crate::internal::crypto::Crypto::generate_key()?;
+        let info = KeyInfo::from(key.clone());
+        assert_eq!(info.auth_bytes()?, key.auth_secret());
+        assert_eq!(info.p256dh_bytes()?, key.public_key());
+        let corrupt = KeyInfo {
+            auth: "not base64!".to_string(),
+            ..info
+        };
+        assert!(matches!(
+            corrupt.auth_bytes(),
+            Err(PushError::TranscodingError(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_aesgcm_decryption() -> Result<()> {
+        let _m = get_lock(&MTX);
+        rc_crypto::ensure_initialized();
+
+        let ctx = MockConnection::connect_context();
+        ctx.expect().returning(|_, _| Default::default());
+
+        let mut pm = get_test_manager()?;
+
+        pm.connection
+            .expect_register()
+            .with(eq("native-id"), eq(None), eq(None))
+            .times(1)
+            .returning(|_, _, _| {
+                Ok(RegisterResponse {
+                    uaid: TEST_UAID.to_string(),
+                    channel_id: TEST_CHANNEL_ID.to_string(),
+                    secret: TEST_AUTH.to_string(),
+                    endpoint: "https://example.com/dummy-endpoint".to_string(),
+                    sender_id: Some("test".to_string()),
                 })
             });
         let crypto_ctx = MockCryptography::generate_key_context();
@@ -594,7 +2416,7 @@ mod test {
                 auth,
             })
         });
-        let resp = pm.subscribe("test-scope", None)?;
+        let resp = pm.subscribe("test-scope", None, None)?;
         let key_info = resp.subscription_info.keys;
         let remote_pub = URL_SAFE_NO_PAD.decode(&key_info.p256dh).unwrap();
         let auth = URL_SAFE_NO_PAD.decode(&key_info.auth).unwrap();
@@ -637,15 +2459,15 @@ mod test {
         rc_crypto::ensure_initialized();
 
         let ctx = MockConnection::connect_context();
-        ctx.expect().returning(|_| Default::default());
+        ctx.expect().returning(|_, _| Default::default());
 
         let mut pm = get_test_manager()?;
 
         pm.connection
             .expect_register()
-            .with(eq("native-id"), eq(None))
+            .with(eq("native-id"), eq(None), eq(None))
             .times(1) // only once, second time we'll hit cache!
-            .returning(|_, _| {
+            .returning(|_, _, _| {
                 Ok(RegisterResponse {
                     uaid: TEST_UAID.to_string(),
                     channel_id: TEST_CHANNEL_ID.to_string(),
@@ -666,23 +2488,127 @@ mod test {
                 auth,
             })
         });
-        let sub_1 = pm.subscribe("test-scope", None)?;
-        let sub_2 = pm.subscribe("test-scope", None)?;
+        let sub_1 = pm.subscribe("test-scope", None, None)?;
+        let sub_2 = pm.subscribe("test-scope", None, None)?;
         assert_eq!(sub_1, sub_2);
         Ok(())
     }
+    #[derive(Clone, Default)]
+    struct TestObserver {
+        changes: std::sync::Arc<Mutex<Vec<PushSubscriptionChanged>>>,
+        uaid_changes: std::sync::Arc<Mutex<Vec<(Option<String>, String)>>>,
+    }
+
+    impl PushObserver for TestObserver {
+        fn on_subscription_changed(&self, change: PushSubscriptionChanged) {
+            self.changes.lock().unwrap().push(change)
+        }
+
+        fn on_uaid_changed(&self, old: Option<String>, new: String) {
+            self.uaid_changes.lock().unwrap().push((old, new))
+        }
+    }
+
+    #[derive(Default)]
+    struct TestLog {
+        events: std::sync::Arc<Mutex<Vec<PushLogEvent>>>,
+    }
+
+    impl crate::PushLog for TestLog {
+        fn log(&self, event: PushLogEvent) {
+            self.events.lock().unwrap().push(event)
+        }
+    }
+
+    struct ReentrantLog;
+
+    impl crate::PushLog for ReentrantLog {
+        fn log(&self, _event: PushLogEvent) {
+            crate::internal::logger::set_push_logger(None);
+        }
+    }
+
+    #[test]
+    fn test_push_logger_reentrant() {
+        let _m = get_lock(&MTX);
+        // A sink which swaps itself out mustn't deadlock.
+        crate::internal::logger::set_push_logger(Some(Box::new(ReentrantLog)));
+        log_event(PushLogEvent::RegistrationAttempt { succeeded: true });
+        log_event(PushLogEvent::RegistrationAttempt { succeeded: true });
+    }
+
+    #[test]
+    fn test_push_logger() -> Result<()> {
+        let _m = get_lock(&MTX);
+        let ctx = MockConnection::connect_context();
+        ctx.expect().returning(|_, _| Default::default());
+
+        let log = TestLog::default();
+        let events = log.events.clone();
+        crate::internal::logger::set_push_logger(Some(Box::new(log)));
+
+        let mut pm = get_test_manager()?;
+        pm.connection
+            .expect_register()
+            .with(eq("native-id"), eq(None), eq(None))
+            .times(1)
+            .returning(|_, _, _| {
+                Ok(RegisterResponse {
+                    uaid: TEST_UAID.to_string(),
+                    channel_id: TEST_CHANNEL_ID.to_string(),
+                    secret: TEST_AUTH.to_string(),
+                    endpoint: "https://example.com/dummy-endpoint".to_string(),
+                    sender_id: Some("test".to_string()),
+                })
+            });
+        let crypto_ctx = MockCryptography::generate_key_context();
+        crypto_ctx
+            .expect()
+            .returning(crate::internal::crypto::Crypto::generate_key);
+        pm.subscribe("test-scope", None, None)?;
+
+        let decryp_ctx = MockCryptography::decrypt_context();
+        decryp_ctx
+            .expect()
+            .returning(|_, _| Err(PushError::CryptoError("bad body".to_string())));
+        let payload = HashMap::from_iter(vec![
+            ("chid".to_string(), TEST_CHANNEL_ID.to_string()),
+            ("body".to_string(), "secret stuff".to_string()),
+        ]);
+        pm.decrypt(payload).unwrap_err();
+
+        crate::internal::logger::set_push_logger(None);
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![
+                PushLogEvent::RegistrationAttempt { succeeded: true },
+                PushLogEvent::EndpointChanged {
+                    channel_id: TEST_CHANNEL_ID.to_string(),
+                    scope: "test-scope".to_string(),
+                },
+                PushLogEvent::DecryptFailed {
+                    channel_id: TEST_CHANNEL_ID.to_string(),
+                    reason: "Crypto error: bad body".to_string(),
+                },
+            ]
+        );
+        Ok(())
+    }
+
     #[test]
     fn test_verify_wipe_uaid_if_mismatch() -> Result<()> {
         let _m = get_lock(&MTX);
         let ctx = MockConnection::connect_context();
-        ctx.expect().returning(|_| Default::default());
+        ctx.expect().returning(|_, _| Default::default());
 
         let mut pm = get_test_manager()?;
+        let observer = TestObserver::default();
+        pm.set_observer(Some(Box::new(observer.clone())));
         pm.connection
             .expect_register()
-            .with(eq("native-id"), eq(None))
+            .with(eq("native-id"), eq(None), eq(None))
             .times(2)
-            .returning(|_, _| {
+            .returning(|_, _, _| {
                 Ok(RegisterResponse {
                     uaid: TEST_UAID.to_string(),
                     channel_id: TEST_CHANNEL_ID.to_string(),
@@ -715,7 +2641,7 @@ mod test {
             .with(eq(TEST_UAID), eq(TEST_AUTH))
             .times(1)
             .returning(|_, _| Ok(()));
-        let _ = pm.subscribe("test-scope", None)?;
+        let _ = pm.subscribe("test-scope", None, None)?;
         // verify that a uaid got added to our store and
         // that there is a record associated with the channel ID provided
         assert_eq!(pm.store.get_uaid()?.unwrap(), TEST_UAID);
@@ -723,17 +2649,31 @@ mod test {
             pm.store.get_record(TEST_CHANNEL_ID)?.unwrap().channel_id,
             TEST_CHANNEL_ID
         );
+        assert_eq!(
+            *observer.uaid_changes.lock().unwrap(),
+            vec![(None, TEST_UAID.to_string())]
+        );
         let unsubscribed_channels = pm.verify_connection(false)?;
         assert_eq!(unsubscribed_channels.len(), 1);
         assert_eq!(unsubscribed_channels[0].channel_id, TEST_CHANNEL_ID);
+        // the observer was told about the same change
+        let observed = observer.changes.lock().unwrap().clone();
+        assert_eq!(observed.len(), 1);
+        assert_eq!(observed[0].channel_id, TEST_CHANNEL_ID);
+        assert_eq!(observed[0].scope, "test-scope");
         // since verify_connection failed,
         // we wipe the uaid and all associated records from our store
         assert!(pm.store.get_uaid()?.is_none());
         assert!(pm.store.get_record(TEST_CHANNEL_ID)?.is_none());
+        // ...but remember it needs resubscribing.
+        let stale = pm.get_stale_subscriptions()?;
+        assert_eq!(stale.len(), 1);
+        assert_eq!(stale[0].channel_id, TEST_CHANNEL_ID);
+        assert_eq!(stale[0].scope, "test-scope");
 
         // we now check that a new subscription will cause us to
         // re-generate a uaid and store it in our store
-        let _ = pm.subscribe("test-scope", None)?;
+        let _ = pm.subscribe("test-scope", None, None)?;
         // verify that the uaid got added to our store and
         // that there is a record associated with the channel ID provided
         assert_eq!(pm.store.get_uaid()?.unwrap(), TEST_UAID);
@@ -741,59 +2681,224 @@ mod test {
             pm.store.get_record(TEST_CHANNEL_ID)?.unwrap().channel_id,
             TEST_CHANNEL_ID
         );
+        assert!(pm.get_stale_subscriptions()?.is_empty());
         Ok(())
     }
 
     #[test]
-    fn test_verify_server_lost_uaid_not_error() -> Result<()> {
+    fn test_verify_dry_run() -> Result<()> {
         let _m = get_lock(&MTX);
         let ctx = MockConnection::connect_context();
-        ctx.expect().returning(|_| Default::default());
+        ctx.expect().returning(|_, _| Default::default());
 
         let mut pm = get_test_manager()?;
+        pm.uaid = Some(TEST_UAID.to_string());
+        pm.auth = Some(TEST_AUTH.to_string());
+        put_test_record(&pm, TEST_CHANNEL_ID, "test-scope")?;
+
         pm.connection
-            .expect_register()
-            .with(eq("native-id"), eq(None))
+            .expect_channel_list()
+            .with(eq(TEST_UAID), eq(TEST_AUTH))
             .times(1)
-            .returning(|_, _| {
-                Ok(RegisterResponse {
-                    uaid: TEST_UAID.to_string(),
-                    channel_id: TEST_CHANNEL_ID.to_string(),
-                    secret: TEST_AUTH.to_string(),
-                    endpoint: "https://example.com/dummy-endpoint".to_string(),
-                    sender_id: Some("test".to_string()),
-                })
-            });
+            .returning(|_, _| Ok(vec![TEST_CHANNEL_ID.to_string()]));
+        assert!(pm.verify_connection_dry_run()?.is_empty());
 
-        let crypto_ctx = MockCryptography::generate_key_context();
-        crypto_ctx.expect().returning(|| {
-            let components = EcKeyComponents::new(
-                URL_SAFE_NO_PAD.decode(PRIV_KEY_D).unwrap(),
-                URL_SAFE_NO_PAD.decode(PUB_KEY_RAW).unwrap(),
-            );
-            let auth = URL_SAFE_NO_PAD.decode(TEST_AUTH).unwrap();
-            Ok(Key {
-                p256key: components,
-                auth,
-            })
-        });
+        pm.connection.checkpoint();
         pm.connection
             .expect_channel_list()
             .with(eq(TEST_UAID), eq(TEST_AUTH))
             .times(1)
-            .returning(|_, _| {
-                Err(PushError::UAIDNotRecognizedError(
-                    "Couldn't find uaid".to_string(),
-                ))
-            });
+            .returning(|_, _| Ok(vec![TEST_CHANNEL_ID2.to_string()]));
+        // No `unsubscribe_all` expectation - a dry run must not touch the server state.
+        let changes = pm.verify_connection_dry_run()?;
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].channel_id, TEST_CHANNEL_ID);
+        assert_eq!(changes[0].scope, "test-scope");
+        // ...nor the local state.
+        assert!(pm.store.get_record(TEST_CHANNEL_ID)?.is_some());
+        assert_eq!(pm.uaid.as_deref(), Some(TEST_UAID));
+        Ok(())
+    }
 
-        let _ = pm.subscribe("test-scope", None)?;
-        // verify that a uaid got added to our store and
-        // that there is a record associated with the channel ID provided
-        assert_eq!(pm.store.get_uaid()?.unwrap(), TEST_UAID);
-        assert_eq!(
-            pm.store.get_record(TEST_CHANNEL_ID)?.unwrap().channel_id,
-            TEST_CHANNEL_ID
+    #[test]
+    fn test_connection_diff() -> Result<()> {
+        let _m = get_lock(&MTX);
+        let ctx = MockConnection::connect_context();
+        ctx.expect().returning(|_, _| Default::default());
+
+        let mut pm = get_test_manager()?;
+        pm.uaid = Some(TEST_UAID.to_string());
+        pm.auth = Some(TEST_AUTH.to_string());
+        put_test_record(&pm, TEST_CHANNEL_ID, "scope-1")?;
+        put_test_record(&pm, TEST_CHANNEL_ID2, "scope-2")?;
+
+        pm.connection
+            .expect_channel_list()
+            .with(eq(TEST_UAID), eq(TEST_AUTH))
+            .times(1)
+            .returning(|_, _| {
+                Ok(vec![
+                    TEST_CHANNEL_ID.to_string(),
+                    "cafebabe00000000deadbeef00000000".to_string(),
+                ])
+            });
+        // Like a dry run, there are no other expectations, so nothing is changed.
+        assert_eq!(
+            pm.connection_diff()?,
+            ConnectionDiff {
+                uaid_recognized: true,
+                server_only: vec!["cafebabe00000000deadbeef00000000".to_string()],
+                local_only: vec![TEST_CHANNEL_ID2.to_string()],
+            }
+        );
+        assert!(pm.store.get_record(TEST_CHANNEL_ID2)?.is_some());
+
+        pm.connection.checkpoint();
+        pm.connection
+            .expect_channel_list()
+            .with(eq(TEST_UAID), eq(TEST_AUTH))
+            .times(1)
+            .returning(|_, _| Err(PushError::UAIDNotRecognizedError("gone".to_string())));
+        let diff = pm.connection_diff()?;
+        assert!(!diff.uaid_recognized);
+        assert!(diff.server_only.is_empty());
+        assert_eq!(diff.local_only.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_last_verified() -> Result<()> {
+        let _m = get_lock(&MTX);
+        let ctx = MockConnection::connect_context();
+        ctx.expect().returning(|_, _| Default::default());
+
+        let mut pm = get_test_manager()?;
+        pm.uaid = Some(TEST_UAID.to_string());
+        pm.auth = Some(TEST_AUTH.to_string());
+        put_test_record(&pm, TEST_CHANNEL_ID, "test-scope")?;
+        assert_eq!(pm.last_verified()?, None);
+        assert!(pm.needs_verification(i64::MAX)?);
+
+        pm.connection
+            .expect_channel_list()
+            .with(eq(TEST_UAID), eq(TEST_AUTH))
+            .times(2)
+            .returning(|_, _| Ok(vec![TEST_CHANNEL_ID.to_string()]));
+        // A dry run doesn't count.
+        pm.verify_connection_dry_run()?;
+        assert_eq!(pm.last_verified()?, None);
+
+        let before = Timestamp::now().as_millis_i64();
+        pm.verify_connection(false)?;
+        assert!(pm.last_verified()?.unwrap() >= before);
+        assert!(!pm.needs_verification(24 * 60 * 60 * 1000)?);
+        assert!(pm.needs_verification(0)?);
+
+        // A verification which fails part way through doesn't count either.
+        let verified = pm.last_verified()?;
+        pm.connection.checkpoint();
+        pm.connection
+            .expect_channel_list()
+            .times(1)
+            .returning(|_, _| Ok(vec![]));
+        pm.connection
+            .expect_unsubscribe_all()
+            .times(1)
+            .returning(|_, _| Err(PushError::CommunicationError("oops".to_string())));
+        assert!(pm.verify_connection(true).is_err());
+        assert_eq!(pm.last_verified()?, verified);
+        Ok(())
+    }
+
+    #[test]
+    fn test_verification_jitter() -> Result<()> {
+        let _m = get_lock(&MTX);
+        let ctx = MockConnection::connect_context();
+        ctx.expect().returning(|_, _| Default::default());
+
+        let window = Duration::from_secs(60 * 60);
+        let jitter = verification_jitter(TEST_UAID, window)?;
+        // Stable for a uaid, but different for another.
+        assert_eq!(verification_jitter(TEST_UAID, window)?, jitter);
+        assert_ne!(verification_jitter("another-uaid", window)?, jitter);
+        assert!(jitter < window);
+        assert_eq!(
+            verification_jitter(TEST_UAID, Duration::ZERO)?,
+            Duration::ZERO
+        );
+
+        let mut pm = get_test_manager()?;
+        pm.uaid = Some(TEST_UAID.to_string());
+        pm.verification_jitter_window = Some(window);
+        let max_age_ms = 24 * 60 * 60 * 1000;
+        let set_last_verified = |pm: &PushManager<MockConnection, MockCryptography, Store>,
+                                 age_ms: i64| {
+            let last_verified = Timestamp::now().as_millis_i64() - age_ms;
+            pm.store
+                .set_meta(LAST_VERIFIED_META_KEY, &last_verified.to_string())
+        };
+        // Without the jitter this would be due, but our offset pushes it back.
+        set_last_verified(&pm, max_age_ms)?;
+        assert!(!pm.needs_verification(max_age_ms)?);
+        set_last_verified(&pm, max_age_ms + jitter.as_millis() as i64)?;
+        assert!(pm.needs_verification(max_age_ms)?);
+
+        pm.verification_jitter_window = None;
+        set_last_verified(&pm, max_age_ms)?;
+        assert!(pm.needs_verification(max_age_ms)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_server_lost_uaid_not_error() -> Result<()> {
+        let _m = get_lock(&MTX);
+        let ctx = MockConnection::connect_context();
+        ctx.expect().returning(|_, _| Default::default());
+
+        let mut pm = get_test_manager()?;
+        pm.connection
+            .expect_register()
+            .with(eq("native-id"), eq(None), eq(None))
+            .times(1)
+            .returning(|_, _, _| {
+                Ok(RegisterResponse {
+                    uaid: TEST_UAID.to_string(),
+                    channel_id: TEST_CHANNEL_ID.to_string(),
+                    secret: TEST_AUTH.to_string(),
+                    endpoint: "https://example.com/dummy-endpoint".to_string(),
+                    sender_id: Some("test".to_string()),
+                })
+            });
+
+        let crypto_ctx = MockCryptography::generate_key_context();
+        crypto_ctx.expect().returning(|| {
+            let components = EcKeyComponents::new(
+                URL_SAFE_NO_PAD.decode(PRIV_KEY_D).unwrap(),
+                URL_SAFE_NO_PAD.decode(PUB_KEY_RAW).unwrap(),
+            );
+            let auth = URL_SAFE_NO_PAD.decode(TEST_AUTH).unwrap();
+            Ok(Key {
+                p256key: components,
+                auth,
+            })
+        });
+        pm.connection
+            .expect_channel_list()
+            .with(eq(TEST_UAID), eq(TEST_AUTH))
+            .times(1)
+            .returning(|_, _| {
+                Err(PushError::UAIDNotRecognizedError(
+                    "Couldn't find uaid".to_string(),
+                ))
+            });
+
+        let _ = pm.subscribe("test-scope", None, None)?;
+        // verify that a uaid got added to our store and
+        // that there is a record associated with the channel ID provided
+        assert_eq!(pm.store.get_uaid()?.unwrap(), TEST_UAID);
+        assert_eq!(
+            pm.store.get_record(TEST_CHANNEL_ID)?.unwrap().channel_id,
+            TEST_CHANNEL_ID
         );
         let unsubscribed_channels = pm.verify_connection(false)?;
         assert_eq!(unsubscribed_channels.len(), 1);
@@ -809,14 +2914,14 @@ mod test {
     fn test_verify_server_hard_error() -> Result<()> {
         let _m = get_lock(&MTX);
         let ctx = MockConnection::connect_context();
-        ctx.expect().returning(|_| Default::default());
+        ctx.expect().returning(|_, _| Default::default());
 
         let mut pm = get_test_manager()?;
         pm.connection
             .expect_register()
-            .with(eq("native-id"), eq(None))
+            .with(eq("native-id"), eq(None), eq(None))
             .times(1)
-            .returning(|_, _| {
+            .returning(|_, _, _| {
                 Ok(RegisterResponse {
                     uaid: TEST_UAID.to_string(),
                     channel_id: TEST_CHANNEL_ID.to_string(),
@@ -848,7 +2953,7 @@ mod test {
                 ))
             });
 
-        let _ = pm.subscribe("test-scope", None)?;
+        let _ = pm.subscribe("test-scope", None, None)?;
         // verify that a uaid got added to our store and
         // that there is a record associated with the channel ID provided
         assert_eq!(pm.store.get_uaid()?.unwrap(), TEST_UAID);
@@ -867,14 +2972,14 @@ mod test {
     fn test_second_subscribe_hits_subscribe_endpoint() -> Result<()> {
         let _m = get_lock(&MTX);
         let ctx = MockConnection::connect_context();
-        ctx.expect().returning(|_| Default::default());
+        ctx.expect().returning(|_, _| Default::default());
 
         let mut pm = get_test_manager()?;
         pm.connection
             .expect_register()
-            .with(eq("native-id"), eq(None))
+            .with(eq("native-id"), eq(None), eq(None))
             .times(1)
-            .returning(|_, _| {
+            .returning(|_, _, _| {
                 Ok(RegisterResponse {
                     uaid: TEST_UAID.to_string(),
                     channel_id: TEST_CHANNEL_ID.to_string(),
@@ -886,9 +2991,15 @@ mod test {
 
         pm.connection
             .expect_subscribe()
-            .with(eq(TEST_UAID), eq(TEST_AUTH), eq("native-id"), eq(None))
+            .with(
+                eq(TEST_UAID),
+                eq(TEST_AUTH),
+                eq("native-id"),
+                eq(None),
+                eq(None),
+            )
             .times(1)
-            .returning(|_, _, _, _| {
+            .returning(|_, _, _, _, _| {
                 Ok(SubscribeResponse {
                     channel_id: TEST_CHANNEL_ID2.to_string(),
                     endpoint: "https://example.com/different-dummy-endpoint".to_string(),
@@ -909,8 +3020,8 @@ mod test {
             })
         });
 
-        let resp_1 = pm.subscribe("test-scope", None)?;
-        let resp_2 = pm.subscribe("another-scope", None)?;
+        let resp_1 = pm.subscribe("test-scope", None, None)?;
+        let resp_2 = pm.subscribe("another-scope", None, None)?;
         assert_eq!(
             resp_1.subscription_info.endpoint,
             "https://example.com/dummy-endpoint"
@@ -923,17 +3034,20 @@ mod test {
     }
 
     #[test]
-    fn test_verify_connection_rate_limiter() -> Result<()> {
+    fn test_check_connection() -> Result<()> {
         let _m = get_lock(&MTX);
         let ctx = MockConnection::connect_context();
-        ctx.expect().returning(|_| Default::default());
+        ctx.expect().returning(|_, _| Default::default());
 
         let mut pm = get_test_manager()?;
+        // No uaid yet, so we don't even ask the server.
+        assert!(!pm.check_connection()?);
+
         pm.connection
             .expect_register()
-            .with(eq("native-id"), eq(None))
+            .with(eq("native-id"), eq(None), eq(None))
             .times(1)
-            .returning(|_, _| {
+            .returning(|_, _, _| {
                 Ok(RegisterResponse {
                     uaid: TEST_UAID.to_string(),
                     channel_id: TEST_CHANNEL_ID.to_string(),
@@ -954,36 +3068,1373 @@ mod test {
                 auth,
             })
         });
-        let _ = pm.subscribe("test-scope", None)?;
+        let _ = pm.subscribe("test-scope", None, None)?;
         pm.connection
-            .expect_channel_list()
+            .expect_check_uaid()
             .with(eq(TEST_UAID), eq(TEST_AUTH))
-            .times(3)
-            .returning(|_, _| Ok(vec![TEST_CHANNEL_ID.to_string()]));
-        let _ = pm.verify_connection(false)?;
-        let (_, count) = pm.verify_connection_rate_limiter.get_counters(&pm.store);
-        assert_eq!(count, 1);
-        let _ = pm.verify_connection(false)?;
-        let (timestamp, count) = pm.verify_connection_rate_limiter.get_counters(&pm.store);
+            .times(1)
+            .returning(|_, _| Ok(false));
+        assert!(!pm.check_connection()?);
+        // checking the connection doesn't touch any local state
+        assert_eq!(pm.store.get_uaid()?.unwrap(), TEST_UAID);
+        assert!(pm.store.get_record(TEST_CHANNEL_ID)?.is_some());
+        Ok(())
+    }
 
-        assert_eq!(count, 2);
+    fn put_test_record(
+        pm: &PushManager<MockConnection, MockCryptography, Store>,
+        channel_id: &str,
+        scope: &str,
+    ) -> Result<PushRecord> {
+        let record = PushRecord::new(
+            channel_id,
+            &format!("https://example.com/update/{}", channel_id),
+            scope,
+            crate::internal::crypto::Crypto::generate_key()?,
+        )?;
+        pm.store.put_record(&record)?;
+        Ok(record)
+    }
 
-        pm.verify_connection_rate_limiter.persist_counters(
-            &pm.store,
-            timestamp - ONE_DAY_AND_ONE_SECOND,
-            count,
+    #[test]
+    fn test_unsubscribe_by_scope() -> Result<()> {
+        let _m = get_lock(&MTX);
+        let ctx = MockConnection::connect_context();
+        ctx.expect().returning(|_, _| Default::default());
+
+        let mut pm = get_test_manager()?;
+        pm.uaid = Some(TEST_UAID.to_string());
+        pm.auth = Some(TEST_AUTH.to_string());
+        put_test_record(&pm, TEST_CHANNEL_ID, "https://example.com/app/")?;
+        put_test_record(&pm, TEST_CHANNEL_ID2, "https://example.com/app/feature/")?;
+        let other = put_test_record(
+            &pm,
+            "cafebabe00000000deadbeef00000000",
+            "https://example.com/other/",
+        )?;
+
+        pm.connection
+            .expect_unsubscribe()
+            .withf(|chid, uaid, auth, _| {
+                (chid == TEST_CHANNEL_ID || chid == TEST_CHANNEL_ID2)
+                    && uaid == TEST_UAID
+                    && auth == TEST_AUTH
+            })
+            .times(2)
+            .returning(|_, _, _, _| Ok(()));
+
+        assert_eq!(pm.unsubscribe_by_scope("https://example.com/app/")?, 2);
+        assert!(pm.store.get_record(TEST_CHANNEL_ID)?.is_none());
+        assert!(pm.store.get_record(TEST_CHANNEL_ID2)?.is_none());
+        assert!(pm.store.get_record(&other.channel_id)?.is_some());
+        // Nothing matches any more, so no network requests.
+        assert_eq!(pm.unsubscribe_by_scope("https://example.com/app/")?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_unsubscribe_by_scope_errors() -> Result<()> {
+        let _m = get_lock(&MTX);
+        let ctx = MockConnection::connect_context();
+        ctx.expect().returning(|_, _| Default::default());
+
+        const TEST_CHANNEL_ID3: &str = "cafebabe00000000deadbeef00000000";
+        let mut pm = get_test_manager()?;
+        pm.uaid = Some(TEST_UAID.to_string());
+        pm.auth = Some(TEST_AUTH.to_string());
+        put_test_record(&pm, TEST_CHANNEL_ID, "https://example.com/app")?;
+        put_test_record(&pm, TEST_CHANNEL_ID2, "https://example.com/app/feature/")?;
+        put_test_record(&pm, TEST_CHANNEL_ID3, "https://example.com/app/other/")?;
+        let sibling = put_test_record(
+            &pm,
+            "deadbeef00000000cafebabe00000000",
+            "https://example.com/application/",
+        )?;
+
+        // One failure doesn't stop us unsubscribing the rest.
+        pm.connection
+            .expect_unsubscribe()
+            .with(eq(TEST_CHANNEL_ID), eq(TEST_UAID), eq(TEST_AUTH), eq(None))
+            .times(1)
+            .returning(|_, _, _, _| {
+                Err(PushError::HttpStatusError {
+                    status: 503,
+                    url: "https://example.com".to_string(),
+                    body: None,
+                })
+            });
+        pm.connection
+            .expect_unsubscribe()
+            .with(eq(TEST_CHANNEL_ID2), eq(TEST_UAID), eq(TEST_AUTH), eq(None))
+            .times(1)
+            .returning(|_, _, _, _| {
+                Err(PushError::HttpStatusError {
+                    status: 404,
+                    url: "https://example.com".to_string(),
+                    body: None,
+                })
+            });
+        pm.connection
+            .expect_unsubscribe()
+            .with(eq(TEST_CHANNEL_ID3), eq(TEST_UAID), eq(TEST_AUTH), eq(None))
+            .times(1)
+            .returning(|_, _, _, _| Err(PushError::UAIDNotRecognizedError("gone".to_string())));
+
+        assert_eq!(pm.unsubscribe_by_scope("https://example.com/app")?, 2);
+        assert!(pm.store.get_record(TEST_CHANNEL_ID)?.is_none());
+        assert!(pm.store.get_record(TEST_CHANNEL_ID2)?.is_none());
+        assert!(pm.store.get_record(TEST_CHANNEL_ID3)?.is_some());
+        assert!(pm.store.get_record(&sibling.channel_id)?.is_some());
+        // Only the transient failure is retried later.
+        assert_eq!(
+            pm.pending_unregisters()?.into_keys().collect::<Vec<_>>(),
+            vec![TEST_CHANNEL_ID.to_string()]
         );
+        Ok(())
+    }
 
-        let _ = pm.verify_connection(false)?;
-        let (_, count) = pm.verify_connection_rate_limiter.get_counters(&pm.store);
-        assert_eq!(count, 1);
+    #[test]
+    fn test_relocate_database() -> Result<()> {
+        let _m = get_lock(&MTX);
+        let ctx = MockConnection::connect_context();
+        ctx.expect().returning(|_, _| Default::default());
 
-        // Even though a day hasn't passed, we passed `true` to force verify
-        // so the counter is now reset
-        let _ = pm.verify_connection(true)?;
-        let (_, count) = pm.verify_connection_rate_limiter.get_counters(&pm.store);
-        assert_eq!(count, 1);
+        let tempdir = tempfile::tempdir().unwrap();
+        let old_path = tempdir.path().join("old.db");
+        let new_path = tempdir.path().join("new.db");
+        let mut pm = get_test_manager()?;
+        pm.database_path = Some(old_path.to_str().unwrap().to_string());
+        put_test_record(&pm, TEST_CHANNEL_ID, "scope-1")?;
+
+        // Tests always use an in-memory database, so check the copy directly.
+        pm.relocate_database(new_path.to_str().unwrap())?;
+        assert_eq!(pm.database_path.as_deref(), new_path.to_str());
+        assert!(Store::open(&new_path)?
+            .get_record(TEST_CHANNEL_ID)?
+            .is_some());
+
+        // We won't clobber an existing database.
+        assert!(matches!(
+            pm.relocate_database(new_path.to_str().unwrap()),
+            Err(PushError::GeneralError(_))
+        ));
+        assert_eq!(pm.database_path.as_deref(), new_path.to_str());
+
+        // Nor move a connection the app gave us.
+        pm.database_path = None;
+        assert!(matches!(
+            pm.relocate_database(old_path.to_str().unwrap()),
+            Err(PushError::GeneralError(_))
+        ));
+        assert!(!old_path.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_flush_pending_unregisters() -> Result<()> {
+        let _m = get_lock(&MTX);
+        let ctx = MockConnection::connect_context();
+        ctx.expect().returning(|_, _| Default::default());
+
+        let mut pm = get_test_manager()?;
+        pm.uaid = Some(TEST_UAID.to_string());
+        pm.auth = Some(TEST_AUTH.to_string());
+        put_test_record(&pm, TEST_CHANNEL_ID, "scope-1")?;
+        assert_eq!(pm.flush_pending_unregisters()?, 0);
+
+        let unavailable = || {
+            Err(PushError::HttpStatusError {
+                status: 503,
+                url: "https://example.com".to_string(),
+                body: None,
+            })
+        };
+        // The first attempt and the first flush fail, but we've already let go of it.
+        pm.connection
+            .expect_unsubscribe()
+            .with(eq(TEST_CHANNEL_ID), eq(TEST_UAID), eq(TEST_AUTH), eq(None))
+            .times(2)
+            .returning(move |_, _, _, _| unavailable());
+        assert!(pm.unsubscribe("scope-1")?);
+        assert!(pm.store.get_record(TEST_CHANNEL_ID)?.is_none());
+        assert_eq!(pm.flush_pending_unregisters()?, 0);
+        pm.connection.checkpoint();
+
+        pm.connection
+            .expect_unsubscribe()
+            .with(eq(TEST_CHANNEL_ID), eq(TEST_UAID), eq(TEST_AUTH), eq(None))
+            .times(1)
+            .returning(|_, _, _, _| Ok(()));
+        assert_eq!(pm.flush_pending_unregisters()?, 1);
+        assert_eq!(pm.flush_pending_unregisters()?, 0);
+
+        // Errors that won't go away on their own are still returned.
+        put_test_record(&pm, TEST_CHANNEL_ID2, "scope-2")?;
+        pm.connection
+            .expect_unsubscribe()
+            .with(eq(TEST_CHANNEL_ID2), eq(TEST_UAID), eq(TEST_AUTH), eq(None))
+            .times(1)
+            .returning(|_, _, _, _| Err(PushError::UAIDNotRecognizedError("gone".to_string())));
+        assert!(pm.unsubscribe("scope-2").is_err());
+        assert!(pm.store.get_record(TEST_CHANNEL_ID2)?.is_some());
+        assert_eq!(pm.flush_pending_unregisters()?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_unsubscribe_many() -> Result<()> {
+        let _m = get_lock(&MTX);
+        let ctx = MockConnection::connect_context();
+        ctx.expect().returning(|_, _| Default::default());
+
+        let mut pm = get_test_manager()?;
+        // Nothing we know about, so we don't even need a uaid.
+        assert_eq!(
+            pm.unsubscribe_many(&[TEST_CHANNEL_ID.to_string()])?,
+            vec![(TEST_CHANNEL_ID.to_string(), false)]
+        );
+
+        pm.uaid = Some(TEST_UAID.to_string());
+        pm.auth = Some(TEST_AUTH.to_string());
+        put_test_record(&pm, TEST_CHANNEL_ID, "scope-1")?;
+        put_test_record(&pm, TEST_CHANNEL_ID2, "scope-2")?;
+        put_test_record(&pm, "deadbeef00000000cafebabe00000000", "scope-3")?;
+        pm.tombstone_retention = Some(Duration::from_secs(60 * 60));
+
+        pm.connection
+            .expect_unsubscribe()
+            .with(eq(TEST_CHANNEL_ID), eq(TEST_UAID), eq(TEST_AUTH), eq(None))
+            .times(1)
+            .returning(|_, _, _, _| Ok(()));
+        pm.connection
+            .expect_unsubscribe()
+            .with(eq(TEST_CHANNEL_ID2), eq(TEST_UAID), eq(TEST_AUTH), eq(None))
+            .times(1)
+            .returning(|_, _, _, _| {
+                Err(PushError::HttpStatusError {
+                    status: 500,
+                    url: "https://example.com".to_string(),
+                    body: None,
+                })
+            });
+        pm.connection
+            .expect_unsubscribe()
+            .with(
+                eq("deadbeef00000000cafebabe00000000"),
+                eq(TEST_UAID),
+                eq(TEST_AUTH),
+                eq(None),
+            )
+            .times(1)
+            .returning(|_, _, _, _| {
+                Err(PushError::HttpStatusError {
+                    status: 400,
+                    url: "https://example.com".to_string(),
+                    body: None,
+                })
+            });
+
+        let before = Timestamp::now();
+        let results = pm.unsubscribe_many(&[
+            TEST_CHANNEL_ID.to_string(),
+            "cafebabe00000000deadbeef00000000".to_string(),
+            TEST_CHANNEL_ID2.to_string(),
+            "deadbeef00000000cafebabe00000000".to_string(),
+        ])?;
+        assert_eq!(
+            results,
+            vec![
+                (TEST_CHANNEL_ID.to_string(), true),
+                ("cafebabe00000000deadbeef00000000".to_string(), false),
+                (TEST_CHANNEL_ID2.to_string(), true),
+                ("deadbeef00000000cafebabe00000000".to_string(), false),
+            ]
+        );
+        assert!(pm.store.get_record(TEST_CHANNEL_ID)?.is_none());
+        assert!(pm.store.has_tombstone(TEST_CHANNEL_ID, before)?);
+        // The server was unavailable, so it's dropped now and unsubscribed later.
+        assert!(pm.store.get_record(TEST_CHANNEL_ID2)?.is_none());
+        assert!(pm.store.has_tombstone(TEST_CHANNEL_ID2, before)?);
+        assert!(pm.pending_unregisters()?.contains_key(TEST_CHANNEL_ID2));
+        // The server refused, so it still has it and we keep it too.
+        assert!(pm
+            .store
+            .get_record("deadbeef00000000cafebabe00000000")?
+            .is_some());
+        assert!(!pm
+            .store
+            .has_tombstone("deadbeef00000000cafebabe00000000", before)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_prune_subscriptions() -> Result<()> {
+        let _m = get_lock(&MTX);
+        let ctx = MockConnection::connect_context();
+        ctx.expect().returning(|_, _| Default::default());
+
+        let mut pm = get_test_manager()?;
+        pm.uaid = Some(TEST_UAID.to_string());
+        pm.auth = Some(TEST_AUTH.to_string());
+        let mut old = put_test_record(&pm, TEST_CHANNEL_ID, "scope-1")?;
+        old.ctime = Timestamp::now()
+            .checked_sub(Duration::from_secs(60 * 60))
+            .unwrap();
+        pm.store.put_record(&old)?;
+        put_test_record(&pm, TEST_CHANNEL_ID2, "scope-2")?;
 
+        pm.connection
+            .expect_unsubscribe()
+            .with(eq(TEST_CHANNEL_ID), eq(TEST_UAID), eq(TEST_AUTH), eq(None))
+            .times(1)
+            .returning(|_, _, _, _| Ok(()));
+        assert_eq!(
+            pm.prune_subscriptions(60 * 1000)?,
+            vec![TEST_CHANNEL_ID.to_string()]
+        );
+        assert!(pm.store.get_record(TEST_CHANNEL_ID)?.is_none());
+        assert!(pm.store.get_record(TEST_CHANNEL_ID2)?.is_some());
+        // Nothing else is old enough, so no network requests.
+        assert!(pm.prune_subscriptions(60 * 1000)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_handle_token_refresh() -> Result<()> {
+        let _m = get_lock(&MTX);
+        let ctx = MockConnection::connect_context();
+        ctx.expect().returning(|_, _| Default::default());
+
+        let mut pm = get_test_manager()?;
+        // Without a uaid, we only save the token.
+        assert_eq!(
+            pm.handle_token_refresh("first-native-id")?,
+            ReRegisterResult::default()
+        );
+        assert_eq!(pm.registration_id.as_deref(), Some("first-native-id"));
+
+        let observer = TestObserver::default();
+        pm.set_observer(Some(Box::new(observer.clone())));
+        pm.uaid = Some(TEST_UAID.to_string());
+        pm.auth = Some(TEST_AUTH.to_string());
+        put_test_record(&pm, TEST_CHANNEL_ID, "scope-1")?;
+
+        pm.connection
+            .expect_update()
+            .with(eq("second-native-id"), eq(TEST_UAID), eq(TEST_AUTH))
+            .times(1)
+            .returning(|_, _, _| Ok(()));
+        assert_eq!(
+            pm.handle_token_refresh("second-native-id")?,
+            ReRegisterResult::default()
+        );
+        assert_eq!(
+            pm.store.get_registration_id()?.as_deref(),
+            Some("second-native-id")
+        );
+        // The same token again doesn't hit the server.
+        assert_eq!(
+            pm.handle_token_refresh("second-native-id")?,
+            ReRegisterResult::default()
+        );
+
+        // When the server no longer knows our uaid, we re-register.
+        pm.connection
+            .expect_update()
+            .with(eq("third-native-id"), eq(TEST_UAID), eq(TEST_AUTH))
+            .times(1)
+            .returning(|_, _, _| Err(PushError::UAIDNotRecognizedError("gone".to_string())));
+        pm.connection
+            .expect_unsubscribe_all()
+            .times(1)
+            .returning(|_, _| Err(PushError::UAIDNotRecognizedError("gone".to_string())));
+        pm.connection
+            .expect_register()
+            .with(eq("third-native-id"), eq(None), eq(None))
+            .times(1)
+            .returning(|_, _, _| {
+                Ok(RegisterResponse {
+                    uaid: "new-uaid".to_string(),
+                    channel_id: "new-channel-1".to_string(),
+                    secret: "new-auth".to_string(),
+                    endpoint: "https://example.com/new-endpoint-1".to_string(),
+                    sender_id: Some("test".to_string()),
+                })
+            });
+        let changes = pm.handle_token_refresh("third-native-id")?.subscriptions;
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].channel_id, "new-channel-1");
+        assert_eq!(changes[0].scope, "scope-1");
+        assert_eq!(observer.changes.lock().unwrap().len(), 1);
+        assert_eq!(pm.store.get_uaid()?.as_deref(), Some("new-uaid"));
+        assert_eq!(
+            pm.store.get_registration_id()?.as_deref(),
+            Some("third-native-id")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_re_register() -> Result<()> {
+        let _m = get_lock(&MTX);
+        let ctx = MockConnection::connect_context();
+        ctx.expect().returning(|_, _| Default::default());
+
+        let mut pm = get_test_manager()?;
+        let observer = TestObserver::default();
+        pm.set_observer(Some(Box::new(observer.clone())));
+        pm.uaid = Some(TEST_UAID.to_string());
+        pm.auth = Some(TEST_AUTH.to_string());
+        let rec1 = put_test_record(&pm, TEST_CHANNEL_ID, "scope-1")?;
+        let rec2 = put_test_record(&pm, TEST_CHANNEL_ID2, "scope-2")?;
+
+        pm.connection
+            .expect_unsubscribe_all()
+            .with(eq(TEST_UAID), eq(TEST_AUTH))
+            .times(1)
+            .returning(|_, _| Ok(()));
+        pm.connection
+            .expect_register()
+            .with(eq("new-native-id"), eq(None), eq(None))
+            .times(1)
+            .returning(|_, _, _| {
+                Ok(RegisterResponse {
+                    uaid: "new-uaid".to_string(),
+                    channel_id: "new-channel-1".to_string(),
+                    secret: "new-auth".to_string(),
+                    endpoint: "https://example.com/new-endpoint-1".to_string(),
+                    sender_id: Some("test".to_string()),
+                })
+            });
+        pm.connection
+            .expect_subscribe()
+            .with(
+                eq("new-uaid"),
+                eq("new-auth"),
+                eq("new-native-id"),
+                eq(None),
+                eq(None),
+            )
+            .times(1)
+            .returning(|_, _, _, _, _| {
+                Ok(SubscribeResponse {
+                    channel_id: "new-channel-2".to_string(),
+                    endpoint: "https://example.com/new-endpoint-2".to_string(),
+                    sender_id: Some("test".to_string()),
+                })
+            });
+        // Note there's no `generate_key` expectation, we must re-use the existing keys.
+
+        let result = pm.re_register("new-native-id")?;
+        assert!(result.failed_scopes.is_empty());
+        let mut changes = result.subscriptions;
+        changes.sort_by(|a, b| a.scope.cmp(&b.scope));
+        assert_eq!(changes.len(), 2);
+        assert_eq!(changes[0].scope, "scope-1");
+        assert_eq!(changes[1].scope, "scope-2");
+        assert_eq!(pm.store.get_uaid()?.as_deref(), Some("new-uaid"));
+        assert_eq!(
+            *observer.uaid_changes.lock().unwrap(),
+            vec![(Some(TEST_UAID.to_string()), "new-uaid".to_string())]
+        );
+        assert_eq!(
+            pm.store.get_registration_id()?.as_deref(),
+            Some("new-native-id")
+        );
+        assert!(pm.store.get_record(TEST_CHANNEL_ID)?.is_none());
+        assert!(pm.store.get_record(TEST_CHANNEL_ID2)?.is_none());
+        for (change, old) in changes.iter().zip([rec1, rec2]) {
+            let new = pm.store.get_record(&change.channel_id)?.unwrap();
+            assert_eq!(new.scope, old.scope);
+            assert_eq!(new.key, old.key);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_re_register_partial_failure() -> Result<()> {
+        let _m = get_lock(&MTX);
+        let ctx = MockConnection::connect_context();
+        ctx.expect().returning(|_, _| Default::default());
+
+        let mut pm = get_test_manager()?;
+        pm.uaid = Some(TEST_UAID.to_string());
+        pm.auth = Some(TEST_AUTH.to_string());
+        put_test_record(&pm, TEST_CHANNEL_ID, "scope-1")?;
+        put_test_record(&pm, TEST_CHANNEL_ID2, "scope-2")?;
+
+        pm.connection
+            .expect_unsubscribe_all()
+            .times(1)
+            .returning(|_, _| Ok(()));
+        pm.connection
+            .expect_register()
+            .times(1)
+            .returning(|_, _, _| {
+                Ok(RegisterResponse {
+                    uaid: "new-uaid".to_string(),
+                    channel_id: "new-channel-1".to_string(),
+                    secret: "new-auth".to_string(),
+                    endpoint: "https://example.com/new-endpoint-1".to_string(),
+                    sender_id: Some("test".to_string()),
+                })
+            });
+        // The second subscribe fails, which mustn't lose either of them.
+        pm.connection
+            .expect_subscribe()
+            .times(1)
+            .returning(|_, _, _, _, _| Err(PushError::CommunicationError("oops".to_string())));
+
+        assert!(matches!(
+            pm.re_register("new-native-id"),
+            Err(PushError::CommunicationError(_))
+        ));
+        let renewed = pm.store.get_record("new-channel-1")?.unwrap();
+        let old = [TEST_CHANNEL_ID, TEST_CHANNEL_ID2]
+            .into_iter()
+            .filter_map(|chid| pm.store.get_record(chid).transpose())
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(old.len(), 1);
+        assert_ne!(old[0].scope, renewed.scope);
+        assert_eq!(pm.store.get_uaid()?.as_deref(), Some("new-uaid"));
+        pm.connection.checkpoint();
+
+        // Trying again re-creates both, and a scope the server refuses is dropped.
+        pm.connection
+            .expect_unsubscribe_all()
+            .with(eq("new-uaid"), eq("new-auth"))
+            .times(1)
+            .returning(|_, _| Ok(()));
+        pm.connection
+            .expect_register()
+            .times(1)
+            .returning(|_, _, _| {
+                Ok(RegisterResponse {
+                    uaid: "newer-uaid".to_string(),
+                    channel_id: "new-channel-2".to_string(),
+                    secret: "newer-auth".to_string(),
+                    endpoint: "https://example.com/new-endpoint-2".to_string(),
+                    sender_id: Some("test".to_string()),
+                })
+            });
+        pm.connection
+            .expect_subscribe()
+            .times(1)
+            .returning(|_, _, _, _, _| {
+                Err(PushError::HttpStatusError {
+                    status: 400,
+                    url: "https://example.com".to_string(),
+                    body: None,
+                })
+            });
+
+        let result = pm.re_register("new-native-id")?;
+        assert_eq!(result.subscriptions.len(), 1);
+        assert_eq!(result.subscriptions[0].channel_id, "new-channel-2");
+        assert_eq!(result.failed_scopes.len(), 1);
+        assert_ne!(result.subscriptions[0].scope, result.failed_scopes[0]);
+        let new = pm.store.get_record("new-channel-2")?.unwrap();
+        assert_eq!(new.scope, result.subscriptions[0].scope);
+        assert!(pm
+            .store
+            .get_record_by_scope(&result.failed_scopes[0])?
+            .is_none());
+        assert_eq!(pm.store.get_uaid()?.as_deref(), Some("newer-uaid"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_subscribe_existing_scope() -> Result<()> {
+        let _m = get_lock(&MTX);
+        let ctx = MockConnection::connect_context();
+        ctx.expect().returning(|_, _| Default::default());
+
+        let mut pm = get_test_manager()?;
+        pm.uaid = Some(TEST_UAID.to_string());
+        pm.auth = Some(TEST_AUTH.to_string());
+        // Note there are no connection expectations, none of these may hit the server.
+        let rec = put_test_record(&pm, TEST_CHANNEL_ID, "scope-1")?;
+        let mut keyed = put_test_record(&pm, TEST_CHANNEL_ID2, "scope-2")?;
+        keyed.app_server_key = Some(PUB_KEY_RAW.to_string());
+        pm.store.put_record(&keyed)?;
+
+        assert_eq!(
+            pm.subscribe("scope-1", None, None)?.channel_id,
+            rec.channel_id
+        );
+        assert!(matches!(
+            pm.subscribe("scope-1", Some(PUB_KEY_RAW), None),
+            Err(PushError::ServerKeyMismatch(_))
+        ));
+        // Padding doesn't make it a different key.
+        assert_eq!(
+            pm.subscribe("scope-2", Some(&format!("{}=", PUB_KEY_RAW)), None)?
+                .channel_id,
+            keyed.channel_id
+        );
+        assert!(matches!(
+            pm.subscribe("scope-2", None, None),
+            Err(PushError::ServerKeyMismatch(_))
+        ));
+        assert!(matches!(
+            pm.subscribe("scope-1", None, Some("other-sender")),
+            Err(PushError::SenderIdMismatch(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_unsubscribe_sender_id() -> Result<()> {
+        let _m = get_lock(&MTX);
+        let ctx = MockConnection::connect_context();
+        ctx.expect().returning(|_, _| Default::default());
+
+        let mut pm = get_test_manager()?;
+        pm.uaid = Some(TEST_UAID.to_string());
+        pm.auth = Some(TEST_AUTH.to_string());
+        let mut rec = put_test_record(&pm, TEST_CHANNEL_ID, "scope-1")?;
+        rec.sender_id = Some("other-sender".to_string());
+        pm.store.put_record(&rec)?;
+
+        // The server only knows the channel under the sender it was subscribed with.
+        pm.connection
+            .expect_unsubscribe()
+            .with(
+                eq(TEST_CHANNEL_ID),
+                eq(TEST_UAID),
+                eq(TEST_AUTH),
+                eq(Some("other-sender".to_string())),
+            )
+            .times(1)
+            .returning(|_, _, _, _| Ok(()));
+        assert!(pm.unsubscribe("scope-1")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_subscriptions_page() -> Result<()> {
+        let _m = get_lock(&MTX);
+        let ctx = MockConnection::connect_context();
+        ctx.expect().returning(|_, _| Default::default());
+
+        let pm = get_test_manager()?;
+        assert!(pm.get_subscriptions_page(0, 10)?.is_empty());
+        let mut first = put_test_record(&pm, TEST_CHANNEL_ID, "scope-1")?;
+        first.ctime = Timestamp::now()
+            .checked_sub(Duration::from_secs(60))
+            .unwrap();
+        pm.store.put_record(&first)?;
+        put_test_record(&pm, TEST_CHANNEL_ID2, "scope-2")?;
+
+        let page = pm.get_subscriptions_page(0, 1)?;
+        assert_eq!(page.len(), 1);
+        assert_eq!(page[0].channel_id, TEST_CHANNEL_ID);
+        let page = pm.get_subscriptions_page(1, 1)?;
+        assert_eq!(page.len(), 1);
+        assert_eq!(page[0].channel_id, TEST_CHANNEL_ID2);
+        assert!(pm.get_subscriptions_page(2, 1)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_subscribed_with_key() -> Result<()> {
+        let _m = get_lock(&MTX);
+        let ctx = MockConnection::connect_context();
+        ctx.expect().returning(|_, _| Default::default());
+
+        let pm = get_test_manager()?;
+        put_test_record(&pm, TEST_CHANNEL_ID, "scope-1")?;
+        let mut keyed = put_test_record(&pm, TEST_CHANNEL_ID2, "scope-2")?;
+        keyed.app_server_key = Some(PUB_KEY_RAW.to_string());
+        pm.store.put_record(&keyed)?;
+
+        assert!(pm.is_subscribed_with_key(TEST_CHANNEL_ID2, PUB_KEY_RAW)?);
+        assert!(pm.is_subscribed_with_key(TEST_CHANNEL_ID2, &format!("{}=", PUB_KEY_RAW))?);
+        assert!(!pm.is_subscribed_with_key(TEST_CHANNEL_ID2, TEST_AUTH)?);
+        // A subscription that isn't locked to a key doesn't match any key.
+        assert!(!pm.is_subscribed_with_key(TEST_CHANNEL_ID, PUB_KEY_RAW)?);
+        assert!(!pm.is_subscribed_with_key("deadbeef", PUB_KEY_RAW)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_subscribe_with_raw_server_key() -> Result<()> {
+        let _m = get_lock(&MTX);
+        let ctx = MockConnection::connect_context();
+        ctx.expect().returning(|_, _| Default::default());
+
+        let mut pm = get_test_manager()?;
+        pm.uaid = Some(TEST_UAID.to_string());
+        pm.auth = Some(TEST_AUTH.to_string());
+        // Note there are no connection expectations, none of these may hit the server.
+        let mut keyed = put_test_record(&pm, TEST_CHANNEL_ID, "scope-1")?;
+        keyed.app_server_key = Some(PUB_KEY_RAW.to_string());
+        pm.store.put_record(&keyed)?;
+
+        let raw_key = URL_SAFE_NO_PAD.decode(PUB_KEY_RAW).unwrap();
+        assert_eq!(
+            pm.subscribe_with_raw_server_key("scope-1", &raw_key, None)?
+                .channel_id,
+            keyed.channel_id
+        );
+        assert!(matches!(
+            pm.subscribe_with_raw_server_key("scope-1", &[], None),
+            Err(PushError::ServerKeyMismatch(_))
+        ));
+        assert!(matches!(
+            pm.subscribe_with_raw_server_key("scope-2", &raw_key[..64], None),
+            Err(PushError::InvalidServerKey(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_subscriptions() -> Result<()> {
+        let _m = get_lock(&MTX);
+        let ctx = MockConnection::connect_context();
+        ctx.expect().returning(|_, _| Default::default());
+
+        let mut pm = get_test_manager()?;
+        pm.uaid = Some(TEST_UAID.to_string());
+        pm.auth = Some(TEST_AUTH.to_string());
+        pm.max_subscriptions = Some(1);
+        let rec = put_test_record(&pm, TEST_CHANNEL_ID, "scope-1")?;
+
+        // Note there are no connection expectations, we fail before asking the server.
+        assert!(matches!(
+            pm.subscribe("scope-2", None, None),
+            Err(PushError::SubscriptionLimitReached(1))
+        ));
+        // Existing subscriptions don't count against the limit.
+        assert_eq!(
+            pm.subscribe("scope-1", None, None)?.channel_id,
+            rec.channel_id
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_refresh_subscription() -> Result<()> {
+        let _m = get_lock(&MTX);
+        let ctx = MockConnection::connect_context();
+        ctx.expect().returning(|_, _| Default::default());
+
+        let mut pm = get_test_manager()?;
+        pm.uaid = Some(TEST_UAID.to_string());
+        pm.auth = Some(TEST_AUTH.to_string());
+        let old = put_test_record(&pm, TEST_CHANNEL_ID, "scope-1")?;
+        pm.store
+            .set_subscription_metadata(TEST_CHANNEL_ID, "app-key", "app-value")?;
+
+        assert!(matches!(
+            pm.refresh_subscription(TEST_CHANNEL_ID2),
+            Err(PushError::RecordNotFoundError(_))
+        ));
+
+        pm.connection
+            .expect_unsubscribe()
+            .with(eq(TEST_CHANNEL_ID), eq(TEST_UAID), eq(TEST_AUTH), eq(None))
+            .times(1)
+            .returning(|_, _, _, _| Ok(()));
+        pm.connection
+            .expect_subscribe()
+            .with(
+                eq(TEST_UAID),
+                eq(TEST_AUTH),
+                eq("native-id"),
+                eq(None),
+                eq(None),
+            )
+            .times(1)
+            .returning(|_, _, _, _, _| {
+                Ok(SubscribeResponse {
+                    channel_id: TEST_CHANNEL_ID2.to_string(),
+                    endpoint: "https://example.com/refreshed".to_string(),
+                    sender_id: Some("test".to_string()),
+                })
+            });
+        // Note there's no `generate_key` expectation, we must re-use the existing key.
+
+        let resp = pm.refresh_subscription(TEST_CHANNEL_ID)?;
+        assert_eq!(resp.channel_id, TEST_CHANNEL_ID2);
+        assert_eq!(
+            resp.subscription_info.endpoint,
+            "https://example.com/refreshed"
+        );
+        assert!(pm.store.get_record(TEST_CHANNEL_ID)?.is_none());
+        let new = pm.store.get_record_by_scope("scope-1")?.unwrap();
+        assert_eq!(new.channel_id, TEST_CHANNEL_ID2);
+        assert_eq!(new.key, old.key);
+        // The app's metadata follows the subscription.
+        assert_eq!(
+            pm.store
+                .get_subscription_metadata(TEST_CHANNEL_ID2, "app-key")?
+                .as_deref(),
+            Some("app-value")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_refresh_subscription_unsubscribe_fails() -> Result<()> {
+        let _m = get_lock(&MTX);
+        let ctx = MockConnection::connect_context();
+        ctx.expect().returning(|_, _| Default::default());
+
+        let mut pm = get_test_manager()?;
+        pm.uaid = Some(TEST_UAID.to_string());
+        pm.auth = Some(TEST_AUTH.to_string());
+        put_test_record(&pm, TEST_CHANNEL_ID, "scope-1")?;
+
+        // If we can't get a new channel, the old one is left alone.
+        pm.connection
+            .expect_subscribe()
+            .times(1)
+            .returning(|_, _, _, _, _| Err(PushError::CommunicationError("oops".to_string())));
+        pm.connection.expect_unsubscribe().never();
+        assert!(pm.refresh_subscription(TEST_CHANNEL_ID).is_err());
+        assert!(pm.store.get_record(TEST_CHANNEL_ID)?.is_some());
+        pm.connection.checkpoint();
+
+        // Failing to drop the old channel doesn't fail the refresh; we try again later.
+        pm.connection
+            .expect_subscribe()
+            .times(1)
+            .returning(|_, _, _, _, _| {
+                Ok(SubscribeResponse {
+                    channel_id: TEST_CHANNEL_ID2.to_string(),
+                    endpoint: "https://example.com/refreshed".to_string(),
+                    sender_id: None,
+                })
+            });
+        pm.connection
+            .expect_unsubscribe()
+            .with(eq(TEST_CHANNEL_ID), eq(TEST_UAID), eq(TEST_AUTH), eq(None))
+            .times(1)
+            .returning(|_, _, _, _| Err(PushError::CommunicationError("oops".to_string())));
+        let resp = pm.refresh_subscription(TEST_CHANNEL_ID)?;
+        assert_eq!(resp.channel_id, TEST_CHANNEL_ID2);
+        assert!(pm.store.get_record(TEST_CHANNEL_ID)?.is_none());
+        assert_eq!(
+            pm.pending_unregisters()?,
+            BTreeMap::from([(TEST_CHANNEL_ID.to_string(), None)])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_subscribe_with_keys() -> Result<()> {
+        let _m = get_lock(&MTX);
+        let ctx = MockConnection::connect_context();
+        ctx.expect().returning(|_, _| Default::default());
+
+        let mut pm = get_test_manager()?;
+        pm.uaid = Some(TEST_UAID.to_string());
+        pm.auth = Some(TEST_AUTH.to_string());
+        // The prepared keys are used instead of generating new ones.
+        let crypto_ctx = MockCryptography::generate_key_context();
+        crypto_ctx.expect().never();
+        pm.connection
+            .expect_subscribe()
+            .times(1)
+            .returning(|_, _, _, _, _| {
+                Ok(SubscribeResponse {
+                    channel_id: TEST_CHANNEL_ID.to_string(),
+                    endpoint: "https://example.com/dis/a/endpoint".to_string(),
+                    sender_id: None,
+                })
+            });
+
+        let keys = crate::internal::crypto::Crypto::generate_key()?;
+        let resp = pm.subscribe_with_keys("scope-1", None, None, Some(keys.clone()))?;
+        assert_eq!(
+            resp.subscription_info.keys.p256dh_bytes()?,
+            keys.public_key()
+        );
+        assert_eq!(
+            resp.subscription_info.keys.auth_bytes()?,
+            keys.auth_secret()
+        );
+        let record = pm.store.get_record(TEST_CHANNEL_ID)?.unwrap();
+        assert_eq!(
+            Key::deserialize(&record.key)?.public_key(),
+            keys.public_key()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_subscribe_with_sender_id() -> Result<()> {
+        let _m = get_lock(&MTX);
+        let ctx = MockConnection::connect_context();
+        ctx.expect().returning(|_, _| Default::default());
+
+        let mut pm = get_test_manager()?;
+        pm.uaid = Some(TEST_UAID.to_string());
+        pm.auth = Some(TEST_AUTH.to_string());
+        let crypto_ctx = MockCryptography::generate_key_context();
+        crypto_ctx.expect().returning(|| {
+            let components = EcKeyComponents::new(
+                URL_SAFE_NO_PAD.decode(PRIV_KEY_D).unwrap(),
+                URL_SAFE_NO_PAD.decode(PUB_KEY_RAW).unwrap(),
+            );
+            let auth = URL_SAFE_NO_PAD.decode(TEST_AUTH).unwrap();
+            Ok(Key {
+                p256key: components,
+                auth,
+            })
+        });
+        // Both the subscription and the later refresh use the channel's own sender.
+        pm.connection
+            .expect_subscribe()
+            .with(
+                eq(TEST_UAID),
+                eq(TEST_AUTH),
+                eq("native-id"),
+                eq(None),
+                eq(Some("other-sender".to_string())),
+            )
+            .times(2)
+            .returning(|_, _, _, _, _| {
+                Ok(SubscribeResponse {
+                    channel_id: TEST_CHANNEL_ID.to_string(),
+                    endpoint: "https://example.com/other-sender".to_string(),
+                    sender_id: Some("other-sender".to_string()),
+                })
+            });
+        pm.connection
+            .expect_unsubscribe()
+            .with(eq(TEST_CHANNEL_ID), eq(TEST_UAID), eq(TEST_AUTH), eq(None))
+            .times(1)
+            .returning(|_, _, _, _| Ok(()));
+
+        let resp = pm.subscribe("scope-1", None, Some("other-sender"))?;
+        assert_eq!(resp.channel_id, TEST_CHANNEL_ID);
+        let record = pm.store.get_record(TEST_CHANNEL_ID)?.unwrap();
+        assert_eq!(record.sender_id.as_deref(), Some("other-sender"));
+
+        pm.refresh_subscription(TEST_CHANNEL_ID)?;
+        let record = pm.store.get_record(TEST_CHANNEL_ID)?.unwrap();
+        assert_eq!(record.sender_id.as_deref(), Some("other-sender"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_bridge_type() -> Result<()> {
+        let _m = get_lock(&MTX);
+        let ctx = MockConnection::connect_context();
+        ctx.expect().returning(|_, _| Default::default());
+
+        let mut pm = get_test_manager()?;
+        // Same bridge and token, so nothing to do.
+        assert!(!pm.set_bridge_type("fcm", "native-id")?);
+        assert!(matches!(
+            pm.set_bridge_type("carrier-pigeon", "native-id"),
+            Err(PushError::GeneralError(_))
+        ));
+
+        pm.uaid = Some(TEST_UAID.to_string());
+        pm.auth = Some(TEST_AUTH.to_string());
+        pm.connection
+            .expect_set_bridge_type()
+            .with(eq(BridgeType::Adm))
+            .times(1)
+            .return_const(());
+        pm.connection
+            .expect_update()
+            .with(eq("adm-native-id"), eq(TEST_UAID), eq(TEST_AUTH))
+            .times(1)
+            .returning(|_, _, _| Ok(()));
+        assert!(pm.set_bridge_type("ADM", "adm-native-id")?);
+        assert_eq!(pm.bridge_type, BridgeType::Adm);
+        assert_eq!(pm.config_summary().bridge_type, BridgeType::Adm);
+        assert_eq!(
+            pm.store.get_registration_id()?.as_deref(),
+            Some("adm-native-id")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_proxy_url() {
+        let test_config = PushConfiguration {
+            proxy_url: Some("not a url".to_string()),
+            ..Default::default()
+        };
+        let err = PushManager::<MockConnection, MockCryptography, Store>::new(test_config)
+            .err()
+            .unwrap();
+        assert!(matches!(err, PushError::UrlParseError(_)));
+
+        let test_config = PushConfiguration {
+            proxy_url: Some("socks5://proxy.example.com:1080".to_string()),
+            ..Default::default()
+        };
+        let err = PushManager::<MockConnection, MockCryptography, Store>::new(test_config)
+            .err()
+            .unwrap();
+        assert!(matches!(err, PushError::GeneralError(_)));
+
+        // A custom client would have to do the proxying itself.
+        let test_config = PushConfiguration {
+            proxy_url: Some("http://proxy.example.com:3128".to_string()),
+            ..Default::default()
+        };
+        let err = PushManager::<MockConnection, MockCryptography, Store>::new_with_http_client(
+            test_config,
+            Box::new(ViaductClient),
+        )
+        .err()
+        .unwrap();
+        assert!(matches!(err, PushError::GeneralError(_)));
+    }
+
+    #[test]
+    fn test_verify_connection_rate_limiter() -> Result<()> {
+        let _m = get_lock(&MTX);
+        let ctx = MockConnection::connect_context();
+        ctx.expect().returning(|_, _| Default::default());
+
+        let mut pm = get_test_manager()?;
+        pm.connection
+            .expect_register()
+            .with(eq("native-id"), eq(None), eq(None))
+            .times(1)
+            .returning(|_, _, _| {
+                Ok(RegisterResponse {
+                    uaid: TEST_UAID.to_string(),
+                    channel_id: TEST_CHANNEL_ID.to_string(),
+                    secret: TEST_AUTH.to_string(),
+                    endpoint: "https://example.com/dummy-endpoint".to_string(),
+                    sender_id: Some("test".to_string()),
+                })
+            });
+        let crypto_ctx = MockCryptography::generate_key_context();
+        crypto_ctx.expect().returning(|| {
+            let components = EcKeyComponents::new(
+                URL_SAFE_NO_PAD.decode(PRIV_KEY_D).unwrap(),
+                URL_SAFE_NO_PAD.decode(PUB_KEY_RAW).unwrap(),
+            );
+            let auth = URL_SAFE_NO_PAD.decode(TEST_AUTH).unwrap();
+            Ok(Key {
+                p256key: components,
+                auth,
+            })
+        });
+        let _ = pm.subscribe("test-scope", None, None)?;
+        pm.connection
+            .expect_channel_list()
+            .with(eq(TEST_UAID), eq(TEST_AUTH))
+            .times(3)
+            .returning(|_, _| Ok(vec![TEST_CHANNEL_ID.to_string()]));
+        let _ = pm.verify_connection(false)?;
+        let (_, count) = pm.verify_connection_rate_limiter.get_counters(&pm.store);
+        assert_eq!(count, 1);
+        let _ = pm.verify_connection(false)?;
+        let (timestamp, count) = pm.verify_connection_rate_limiter.get_counters(&pm.store);
+
+        assert_eq!(count, 2);
+
+        pm.verify_connection_rate_limiter.persist_counters(
+            &pm.store,
+            timestamp - ONE_DAY_AND_ONE_SECOND,
+            count,
+        );
+
+        let _ = pm.verify_connection(false)?;
+        let (_, count) = pm.verify_connection_rate_limiter.get_counters(&pm.store);
+        assert_eq!(count, 1);
+
+        // Even though a day hasn't passed, we passed `true` to force verify
+        // so the counter is now reset
+        let _ = pm.verify_connection(true)?;
+        let (_, count) = pm.verify_connection_rate_limiter.get_counters(&pm.store);
+        assert_eq!(count, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_storage_integrity() -> Result<()> {
+        let _m = get_lock(&MTX);
+        let ctx = MockConnection::connect_context();
+        ctx.expect().returning(|_, _| Default::default());
+
+        let pm = get_test_manager()?;
+        put_test_record(&pm, TEST_CHANNEL_ID, "scope-1")?;
+        assert_eq!(
+            pm.verify_storage_integrity()?,
+            StorageHealth {
+                integrity_errors: vec![],
+                invalid_channel_ids: vec![],
+            }
+        );
+
+        let mut keyless = put_test_record(&pm, TEST_CHANNEL_ID2, "scope-2")?;
+        keyless.key = vec![];
+        pm.store.put_record(&keyless)?;
+        let mut bad_endpoint = put_test_record(&pm, "cafebabe00000000deadbeef00000000", "scope-3")?;
+        bad_endpoint.endpoint = "not a url".to_string();
+        pm.store.put_record(&bad_endpoint)?;
+
+        let mut health = pm.verify_storage_integrity()?;
+        assert!(health.integrity_errors.is_empty());
+        health.invalid_channel_ids.sort();
+        assert_eq!(
+            health.invalid_channel_ids,
+            vec![bad_endpoint.channel_id, keyless.channel_id]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_support_dump() -> Result<()> {
+        let _m = get_lock(&MTX);
+        let ctx = MockConnection::connect_context();
+        ctx.expect().returning(|_, _| Default::default());
+
+        let mut pm = get_test_manager()?;
+        pm.uaid = Some(TEST_UAID.to_string());
+        pm.auth = Some(TEST_AUTH.to_string());
+        let record = put_test_record(&pm, TEST_CHANNEL_ID, "scope-1")?;
+
+        let dump = pm.support_dump()?;
+        let value: serde_json::Value = serde_json::from_str(&dump)?;
+        assert_eq!(value["uaid"], TEST_UAID);
+        assert_eq!(value["channel_count"], 1);
+        assert_eq!(value["channels"][0]["channel_id"], TEST_CHANNEL_ID);
+        assert_eq!(value["channels"][0]["scope"], "scope-1");
+        assert_eq!(value["channels"][0]["created_at"], record.ctime.as_millis());
+        assert_eq!(value["last_verified"], serde_json::Value::Null);
+
+        // Nothing secret makes it in.
+        let key = Key::deserialize(&record.key)?;
+        assert!(!dump.contains(TEST_AUTH));
+        assert!(!dump.contains(&record.endpoint));
+        assert!(!dump.contains(&URL_SAFE_NO_PAD.encode(key.private_key())));
+        assert!(!dump.contains(&URL_SAFE_NO_PAD.encode(key.auth_secret())));
+        Ok(())
+    }
+
+    #[test]
+    fn test_self_test() -> Result<()> {
+        let _m = get_lock(&MTX);
+        let ctx = MockConnection::connect_context();
+        ctx.expect().returning(|_, _| Default::default());
+        let decryp_ctx = MockCryptography::decrypt_bytes_context();
+        decryp_ctx
+            .expect()
+            .returning(|key, payload| crate::internal::crypto::Crypto::decrypt_bytes(key, payload));
+
+        let pm = get_test_manager()?;
+        put_test_record(&pm, TEST_CHANNEL_ID, "scope-1")?;
+        assert!(pm.self_test(TEST_CHANNEL_ID)?);
+
+        // A private key that doesn't match the public one can't decrypt messages
+        // sent to it.
+        let mut mismatched = put_test_record(&pm, TEST_CHANNEL_ID2, "scope-2")?;
+        let mut key = Key::deserialize(&mismatched.key)?;
+        let other_key = Key::deserialize(&pm.store.get_record(TEST_CHANNEL_ID)?.unwrap().key)?;
+        key.p256key =
+            EcKeyComponents::new(other_key.private_key().to_vec(), key.public_key().to_vec());
+        mismatched.key = key.serialize()?;
+        pm.store.put_record(&mismatched)?;
+        assert!(!pm.self_test(TEST_CHANNEL_ID2)?);
+
+        mismatched.key = vec![];
+        pm.store.put_record(&mismatched)?;
+        assert!(!pm.self_test(TEST_CHANNEL_ID2)?);
+
+        assert!(matches!(
+            pm.self_test("cafebabe00000000deadbeef00000000"),
+            Err(PushError::UnknownChannel(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_metrics() -> Result<()> {
+        let _m = get_lock(&MTX);
+        let ctx = MockConnection::connect_context();
+        ctx.expect().returning(|_, _| Default::default());
+        let crypto_ctx = MockCryptography::generate_key_context();
+        crypto_ctx.expect().returning(|| {
+            let components = EcKeyComponents::new(
+                URL_SAFE_NO_PAD.decode(PRIV_KEY_D).unwrap(),
+                URL_SAFE_NO_PAD.decode(PUB_KEY_RAW).unwrap(),
+            );
+            let auth = URL_SAFE_NO_PAD.decode(TEST_AUTH).unwrap();
+            Ok(Key {
+                p256key: components,
+                auth,
+            })
+        });
+        let decrypt_ctx = MockCryptography::decrypt_bytes_context();
+        decrypt_ctx
+            .expect()
+            .withf(|_, payload| payload.body == b"good")
+            .returning(|_, _| Ok(b"hello".to_vec()));
+        decrypt_ctx
+            .expect()
+            .withf(|_, payload| payload.body == b"bad")
+            .returning(|_, _| Err(PushError::DecryptionFailed("bad".to_string())));
+
+        let mut pm = get_test_manager()?;
+        pm.offline = true;
+        assert_eq!(pm.metrics_snapshot(), PushMetrics::default());
+
+        pm.set_channel_id_generator(Box::new(|| TEST_CHANNEL_ID.to_string()));
+        pm.subscribe("scope-1", None, None)?;
+        // An existing subscription isn't counted again.
+        pm.subscribe("scope-1", None, None)?;
+        pm.decrypt_bytes(TEST_CHANNEL_ID, b"good", "aes128gcm", b"", b"")?;
+        assert!(pm
+            .decrypt_bytes(TEST_CHANNEL_ID, b"bad", "aes128gcm", b"", b"")
+            .is_err());
+        assert!(pm.unsubscribe("scope-1")?);
+        assert_eq!(
+            pm.metrics_snapshot(),
+            PushMetrics {
+                subscriptions_created: 1,
+                unsubscribes: 1,
+                decrypt_successes: 1,
+                decrypt_failures: 1,
+                ..Default::default()
+            }
+        );
+
+        pm.reset_metrics();
+        assert_eq!(pm.metrics_snapshot(), PushMetrics::default());
+        Ok(())
+    }
+
+    #[test]
+    fn test_offline() -> Result<()> {
+        let _m = get_lock(&MTX);
+        let ctx = MockConnection::connect_context();
+        ctx.expect().returning(|_, _| Default::default());
+        let crypto_ctx = MockCryptography::generate_key_context();
+        crypto_ctx.expect().returning(|| {
+            let components = EcKeyComponents::new(
+                URL_SAFE_NO_PAD.decode(PRIV_KEY_D).unwrap(),
+                URL_SAFE_NO_PAD.decode(PUB_KEY_RAW).unwrap(),
+            );
+            let auth = URL_SAFE_NO_PAD.decode(TEST_AUTH).unwrap();
+            Ok(Key {
+                p256key: components,
+                auth,
+            })
+        });
+
+        let mut pm = get_test_manager()?;
+        pm.offline = true;
+        // Note there are no connection expectations, we never talk to the server.
+        let resp = pm.subscribe("scope-1", None, None)?;
+        assert!(resp
+            .subscription_info
+            .endpoint
+            .starts_with(OFFLINE_ENDPOINT_BASE));
+        assert_eq!(resp.subscription_info.keys.auth, TEST_AUTH);
+        assert!(pm.contains_subscription(&resp.channel_id)?);
+        assert_eq!(pm.get_uaid()?, None);
+        // Subscribing again returns the same subscription.
+        assert_eq!(
+            pm.subscribe("scope-1", None, None)?.channel_id,
+            resp.channel_id
+        );
+        // Tests can make the channel ids predictable.
+        pm.set_channel_id_generator(Box::new(|| TEST_CHANNEL_ID2.to_string()));
+        assert_eq!(
+            pm.subscribe("scope-2", None, None)?.channel_id,
+            TEST_CHANNEL_ID2
+        );
+        assert_eq!(
+            pm.get_endpoint(TEST_CHANNEL_ID2)?,
+            Some(format!("{}/{}", OFFLINE_ENDPOINT_BASE, TEST_CHANNEL_ID2))
+        );
+
+        assert!(pm.verify_connection(true)?.is_empty());
+        assert!(pm.verify_connection_dry_run()?.is_empty());
+
+        assert!(pm.unsubscribe("scope-1")?);
+        assert!(!pm.contains_subscription(&resp.channel_id)?);
+        pm.unsubscribe_all()?;
+        assert!(pm.get_subscription("scope-2")?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_rotate_keys() -> Result<()> {
+        let _m = get_lock(&MTX);
+        let ctx = MockConnection::connect_context();
+        ctx.expect().returning(|_, _| Default::default());
+        let crypto_ctx = MockCryptography::generate_key_context();
+        crypto_ctx.expect().returning(|| {
+            let components = EcKeyComponents::new(
+                URL_SAFE_NO_PAD.decode(PRIV_KEY_D).unwrap(),
+                URL_SAFE_NO_PAD.decode(PUB_KEY_RAW).unwrap(),
+            );
+            let auth = URL_SAFE_NO_PAD.decode(TEST_AUTH).unwrap();
+            Ok(Key {
+                p256key: components,
+                auth,
+            })
+        });
+        let decryp_ctx = MockCryptography::decrypt_context();
+        decryp_ctx
+            .expect()
+            .returning(|_, _| Err(PushError::DecryptionFailed("bad key".to_string())));
+
+        let pm = get_test_manager()?;
+        let old = put_test_record(&pm, TEST_CHANNEL_ID, "scope-1")?;
+        put_test_record(&pm, TEST_CHANNEL_ID2, "scope-2")?;
+        let payload = |chid: &str| {
+            HashMap::from_iter(vec![
+                ("chid".to_string(), chid.to_string()),
+                ("body".to_string(), "body".to_string()),
+            ])
+        };
+        assert!(matches!(
+            pm.decrypt(payload(TEST_CHANNEL_ID)),
+            Err(PushError::DecryptionFailed(_))
+        ));
+
+        // Note there are no connection expectations, the server doesn't know our keys.
+        let resp = pm.rotate_keys(TEST_CHANNEL_ID)?;
+        assert_eq!(resp.channel_id, old.channel_id);
+        assert_eq!(resp.subscription_info.endpoint, old.endpoint);
+        assert_eq!(resp.subscription_info.keys.auth, TEST_AUTH);
+        assert_eq!(resp.subscription_info.keys.p256dh, PUB_KEY_RAW);
+        let new = pm.store.get_record(TEST_CHANNEL_ID)?.unwrap();
+        assert_ne!(new.key, old.key);
+        assert_eq!(pm.get_subscription("scope-1")?, Some(resp));
+
+        assert!(matches!(
+            pm.decrypt(payload(TEST_CHANNEL_ID)),
+            Err(PushError::KeysRotated(chid)) if chid == TEST_CHANNEL_ID
+        ));
+        // Other channels aren't affected.
+        assert!(matches!(
+            pm.decrypt(payload(TEST_CHANNEL_ID2)),
+            Err(PushError::DecryptionFailed(_))
+        ));
+        assert!(matches!(
+            pm.rotate_keys("unknown"),
+            Err(PushError::RecordNotFoundError(_))
+        ));
+
+        // After the first message with the new keys, a failure is just a failure.
+        decryp_ctx.checkpoint();
+        decryp_ctx.expect().times(1).returning(|_, _| Ok(vec![]));
+        pm.decrypt(payload(TEST_CHANNEL_ID))?;
+        decryp_ctx.checkpoint();
+        decryp_ctx
+            .expect()
+            .returning(|_, _| Err(PushError::DecryptionFailed("bad key".to_string())));
+        assert!(matches!(
+            pm.decrypt(payload(TEST_CHANNEL_ID)),
+            Err(PushError::DecryptionFailed(_))
+        ));
         Ok(())
     }
 }
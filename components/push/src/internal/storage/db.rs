@@ -3,30 +3,89 @@
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 use std::{ops::Deref, path::Path};
 
-use rusqlite::Connection;
-use sql_support::{open_database, ConnExt};
+use rusqlite::{Connection, OpenFlags};
+use sql_support::open_database::{self, ConnectionInitializer};
+use sql_support::ConnExt;
+
+use types::Timestamp;
 
 use crate::error::{PushError, Result};
+use crate::MigrationReport;
 
 use super::{record::PushRecord, schema};
 
 pub trait Storage: Sized {
     fn open<P: AsRef<Path>>(path: P) -> Result<Self>;
 
+    /// Uses a connection the caller already opened, bringing its schema up to date.
+    fn from_connection(conn: Connection) -> Result<Self>;
+
     fn get_record(&self, chid: &str) -> Result<Option<PushRecord>>;
 
+    /// Whether we have a record for `chid`, without loading it.
+    fn contains_record(&self, chid: &str) -> Result<bool>;
+
+    fn get_endpoint(&self, chid: &str) -> Result<Option<String>>;
+
     fn get_record_by_scope(&self, scope: &str) -> Result<Option<PushRecord>>;
 
+    /// Records whose scope is `scope` or nested under it.
+    fn get_records_by_scope_prefix(&self, scope: &str) -> Result<Vec<PushRecord>>;
+
+    /// Records created before `before`.
+    fn get_records_created_before(&self, before: Timestamp) -> Result<Vec<PushRecord>>;
+
+    /// Up to `limit` records, oldest first, skipping the first `offset`.
+    fn get_records_page(&self, offset: u64, limit: u64) -> Result<Vec<PushRecord>>;
+
     fn put_record(&self, record: &PushRecord) -> Result<bool>;
 
     fn delete_record(&self, chid: &str) -> Result<bool>;
 
+    /// Deletes all the records in a single transaction, returning how many were deleted.
+    /// If `tombstone`, it also remembers they were unsubscribed, like [`Storage::put_tombstone`].
+    fn delete_records(&self, records: &[PushRecord], tombstone: bool) -> Result<u64>;
+
     fn delete_all_records(&self) -> Result<()>;
 
+    /// Remembers that `chid` was unsubscribed, see [`Storage::has_tombstone`].
+    fn put_tombstone(&self, chid: &str, scope: &str) -> Result<()>;
+
+    /// Whether `chid` was unsubscribed at or after `since`.
+    fn has_tombstone(&self, chid: &str, since: Timestamp) -> Result<bool>;
+
+    /// Forgets channels unsubscribed before `before`, returning how many were purged.
+    fn purge_tombstones(&self, before: Timestamp) -> Result<u64>;
+
     fn get_channel_list(&self) -> Result<Vec<String>>;
 
+    /// Remembers that `message_id` was seen for `chid`, returning whether it had been
+    /// seen before. Only the newest `retention` ids are kept for each channel.
+    fn record_seen_message(&self, chid: &str, message_id: &str, retention: u32) -> Result<bool>;
+
+    /// The value the app stored under `key` for `channel_id`.
+    fn get_subscription_metadata(&self, channel_id: &str, key: &str) -> Result<Option<String>>;
+
+    /// Stores `value` under `key` for `channel_id`, returning false if we have no
+    /// record for the channel. It's deleted along with the record.
+    fn set_subscription_metadata(&self, channel_id: &str, key: &str, value: &str) -> Result<bool>;
+
     fn update_endpoint(&self, channel_id: &str, endpoint: &str) -> Result<bool>;
 
+    /// Replaces the record for `old_chid` with `record`, which has the same scope but a
+    /// new channel, in a single transaction. The app's metadata moves to the new channel,
+    /// and everything else we kept for the old one is deleted, like [`Storage::delete_record`].
+    fn replace_record(&self, old_chid: &str, record: &PushRecord) -> Result<()>;
+
+    /// Replaces the keys for `channel_id`, remembering that they were rotated.
+    fn rotate_key(&self, channel_id: &str, key: &[u8]) -> Result<bool>;
+
+    /// Whether the keys for `channel_id` have been rotated, and not used since.
+    fn keys_rotated(&self, channel_id: &str) -> Result<bool>;
+
+    /// Forgets that the keys for `channel_id` were rotated, once a sender is using them.
+    fn clear_keys_rotated(&self, channel_id: &str) -> Result<()>;
+
     // Some of our "meta" keys are more important than others, so they get special helpers.
     fn get_uaid(&self) -> Result<Option<String>>;
     fn set_uaid(&self, uaid: &str) -> Result<()>;
@@ -40,12 +99,40 @@ pub trait Storage: Sized {
     // And general purpose meta with hard-coded key names spread everywhere.
     fn get_meta(&self, key: &str) -> Result<Option<String>>;
     fn set_meta(&self, key: &str, value: &str) -> Result<()>;
+
+    /// Rebuilds the indexes and reclaims free pages left behind by deleted records.
+    fn vacuum(&self) -> Result<()>;
+
+    /// Writes a compacted copy of the database to `path`, which mustn't exist.
+    fn copy_to(&self, path: &str) -> Result<()>;
+
+    /// Checks the database file for corruption, returning the problems found.
+    fn integrity_check(&self) -> Result<Vec<String>>;
 }
 
 pub struct PushDb {
     pub db: Connection,
 }
 
+/// The meta key which records that a channel's keys were rotated.
+fn keys_rotated_meta_key(channel_id: &str) -> String {
+    format!("keys_rotated:{}", PushDb::normalize_uuid(channel_id))
+}
+
+/// Remembers that `chid` was unsubscribed, on `conn` or a transaction on it.
+fn insert_tombstone(conn: &Connection, chid: &str, scope: &str) -> Result<()> {
+    conn.execute(
+        "INSERT OR REPLACE INTO push_tombstone (channel_id, scope, removed_at)
+         VALUES (:chid, :scope, :removed_at)",
+        rusqlite::named_params! {
+            ":chid": PushDb::normalize_uuid(chid),
+            ":scope": scope,
+            ":removed_at": Timestamp::now(),
+        },
+    )?;
+    Ok(())
+}
+
 impl PushDb {
     pub fn open(path: impl AsRef<Path>) -> Result<Self> {
         let path = path.as_ref();
@@ -62,6 +149,42 @@ impl PushDb {
         Ok(Self { db })
     }
 
+    /// Uses an already open connection, eg one shared with other components, rather
+    /// than opening our own. The connection must be writable; it's initialized or
+    /// migrated just like a database we open ourselves.
+    pub fn from_connection(mut db: Connection) -> Result<Self> {
+        let initializer = schema::PushConnectionInitializer {};
+        open_database::init_connection(&mut db, &initializer)?;
+        Ok(Self { db })
+    }
+
+    /// Brings the database at `path` up to the current schema, creating it if it
+    /// doesn't exist. A database that's already current is only read.
+    pub fn migrate(path: impl AsRef<Path>) -> Result<MigrationReport> {
+        let path = path.as_ref();
+        let from_version = Self::schema_version(path)?;
+        let to_version = if from_version == schema::PushConnectionInitializer::END_VERSION {
+            from_version
+        } else {
+            let db = Self::open(path)?;
+            db.query_one::<u32>("PRAGMA user_version")?
+        };
+        Ok(MigrationReport {
+            from_version,
+            to_version,
+        })
+    }
+
+    /// The schema version of the database at `path`, without changing it. A
+    /// database which doesn't exist yet is at version 0.
+    fn schema_version(path: &Path) -> Result<u32> {
+        if !path.exists() {
+            return Ok(0);
+        }
+        let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        Ok(conn.query_row("PRAGMA user_version", [], |row| row.get(0))?)
+    }
+
     #[cfg(test)]
     pub fn open_in_memory() -> Result<Self> {
         // A nod to our tests which use this.
@@ -108,6 +231,21 @@ impl Storage for PushDb {
         )
     }
 
+    fn contains_record(&self, chid: &str) -> Result<bool> {
+        Ok(self.exists(
+            "SELECT 1 FROM push_record WHERE channel_id = :chid",
+            &[(":chid", &Self::normalize_uuid(chid))],
+        )?)
+    }
+
+    fn get_endpoint(&self, chid: &str) -> Result<Option<String>> {
+        Ok(self.try_query_one(
+            "SELECT endpoint FROM push_record WHERE channel_id = :chid",
+            &[(":chid", &Self::normalize_uuid(chid))],
+            false,
+        )?)
+    }
+
     fn get_record_by_scope(&self, scope: &str) -> Result<Option<PushRecord>> {
         let query = format!(
             "SELECT {common_cols}
@@ -117,6 +255,60 @@ impl Storage for PushDb {
         self.try_query_row(&query, &[(":scope", scope)], PushRecord::from_row, false)
     }
 
+    fn get_records_by_scope_prefix(&self, scope: &str) -> Result<Vec<PushRecord>> {
+        // Nested scopes are separated by a `/`, so `https://example.com/app` mustn't
+        // match `https://example.com/application/`.
+        let prefix = if scope.ends_with('/') {
+            scope.to_string()
+        } else {
+            format!("{}/", scope)
+        };
+        // `substr()` rather than `LIKE`, so we don't need to escape `%` and `_` in the scope.
+        let query = format!(
+            "SELECT {common_cols}
+             FROM push_record
+             WHERE scope = :scope OR substr(scope, 1, length(:prefix)) = :prefix",
+            common_cols = schema::COMMON_COLS,
+        );
+        self.query_rows_and_then(
+            &query,
+            rusqlite::named_params! {
+                ":scope": scope,
+                ":prefix": prefix,
+            },
+            PushRecord::from_row,
+        )
+    }
+
+    fn get_records_created_before(&self, before: Timestamp) -> Result<Vec<PushRecord>> {
+        let query = format!(
+            "SELECT {common_cols}
+             FROM push_record WHERE ctime < :before",
+            common_cols = schema::COMMON_COLS,
+        );
+        self.query_rows_and_then(&query, &[(":before", &before)], PushRecord::from_row)
+    }
+
+    fn get_records_page(&self, offset: u64, limit: u64) -> Result<Vec<PushRecord>> {
+        // SQLite takes signed values, and a negative limit means no limit at all.
+        let to_sql = |n: u64| i64::try_from(n).unwrap_or(i64::MAX);
+        let query = format!(
+            "SELECT {common_cols}
+             FROM push_record
+             ORDER BY ctime, channel_id
+             LIMIT :limit OFFSET :offset",
+            common_cols = schema::COMMON_COLS,
+        );
+        self.query_rows_and_then(
+            &query,
+            rusqlite::named_params! {
+                ":limit": to_sql(limit),
+                ":offset": to_sql(offset),
+            },
+            PushRecord::from_row,
+        )
+    }
+
     fn put_record(&self, record: &PushRecord) -> Result<bool> {
         log::debug!(
             "adding push subscription for scope '{}', channel '{}', endpoint '{}'",
@@ -128,7 +320,7 @@ impl Storage for PushDb {
             "INSERT OR REPLACE INTO push_record
                  ({common_cols})
              VALUES
-                 (:channel_id, :endpoint, :scope, :key, :ctime, :app_server_key)",
+                 (:channel_id, :endpoint, :scope, :key, :ctime, :app_server_key, :sender_id)",
             common_cols = schema::COMMON_COLS,
         );
         let affected_rows = self.execute(
@@ -143,6 +335,7 @@ impl Storage for PushDb {
                 (":key", &record.key),
                 (":ctime", &record.ctime),
                 (":app_server_key", &record.app_server_key),
+                (":sender_id", &record.sender_id),
             ],
         )?;
         Ok(affected_rows == 1)
@@ -155,12 +348,81 @@ impl Storage for PushDb {
              WHERE channel_id = :chid",
             &[(":chid", &Self::normalize_uuid(chid))],
         )?;
+        self.execute(
+            "DELETE FROM meta_data WHERE key = :key",
+            &[(":key", &keys_rotated_meta_key(chid))],
+        )?;
+        self.execute(
+            "DELETE FROM push_subscription_metadata WHERE channel_id = :chid",
+            &[(":chid", &Self::normalize_uuid(chid))],
+        )?;
+        self.execute(
+            "DELETE FROM push_seen_message WHERE channel_id = :chid",
+            &[(":chid", &Self::normalize_uuid(chid))],
+        )?;
         Ok(affected_rows == 1)
     }
 
+    fn delete_records(&self, records: &[PushRecord], tombstone: bool) -> Result<u64> {
+        log::debug!("deleting {} push subscriptions", records.len());
+        let tx = self.db.unchecked_transaction()?;
+        let mut affected_rows = 0;
+        for record in records {
+            let chid = &record.channel_id;
+            affected_rows += tx.execute(
+                "DELETE FROM push_record
+                 WHERE channel_id = :chid",
+                &[(":chid", &Self::normalize_uuid(chid))],
+            )?;
+            tx.execute(
+                "DELETE FROM meta_data WHERE key = :key",
+                &[(":key", &keys_rotated_meta_key(chid))],
+            )?;
+            tx.execute(
+                "DELETE FROM push_subscription_metadata WHERE channel_id = :chid",
+                &[(":chid", &Self::normalize_uuid(chid))],
+            )?;
+            tx.execute(
+                "DELETE FROM push_seen_message WHERE channel_id = :chid",
+                &[(":chid", &Self::normalize_uuid(chid))],
+            )?;
+            if tombstone {
+                insert_tombstone(&tx, chid, &record.scope)?;
+            }
+        }
+        tx.commit()?;
+        Ok(affected_rows as u64)
+    }
+
+    fn put_tombstone(&self, chid: &str, scope: &str) -> Result<()> {
+        insert_tombstone(&self.db, chid, scope)
+    }
+
+    fn has_tombstone(&self, chid: &str, since: Timestamp) -> Result<bool> {
+        Ok(self.exists(
+            "SELECT 1 FROM push_tombstone
+             WHERE channel_id = :chid AND removed_at >= :since",
+            rusqlite::named_params! {
+                ":chid": Self::normalize_uuid(chid),
+                ":since": since,
+            },
+        )?)
+    }
+
+    fn purge_tombstones(&self, before: Timestamp) -> Result<u64> {
+        let affected_rows = self.execute(
+            "DELETE FROM push_tombstone WHERE removed_at < :before",
+            &[(":before", &before)],
+        )?;
+        log::debug!("purged {} push tombstones", affected_rows);
+        Ok(affected_rows as u64)
+    }
+
     fn delete_all_records(&self) -> Result<()> {
         log::debug!("deleting all push subscriptions and some metadata");
         self.execute("DELETE FROM push_record", [])?;
+        self.execute("DELETE FROM push_subscription_metadata", [])?;
+        self.execute("DELETE FROM push_seen_message", [])?;
         // Clean up the meta data records as well, since we probably want to reset the
         // UAID and get a new secret.
         // Note we *do not* delete the registration_id - it's possible we are deleting all
@@ -168,6 +430,7 @@ impl Storage for PushDb {
         self.execute_batch(
             "DELETE FROM meta_data WHERE key='uaid';
              DELETE FROM meta_data WHERE key='auth';
+             DELETE FROM meta_data WHERE key LIKE 'keys_rotated:%';
              ",
         )?;
         Ok(())
@@ -181,6 +444,63 @@ impl Storage for PushDb {
         )
     }
 
+    fn record_seen_message(&self, chid: &str, message_id: &str, retention: u32) -> Result<bool> {
+        let chid = Self::normalize_uuid(chid);
+        let tx = self.db.unchecked_transaction()?;
+        let inserted = tx.execute(
+            "INSERT OR IGNORE INTO push_seen_message (channel_id, message_id)
+             VALUES (:chid, :message_id)",
+            rusqlite::named_params! {
+                ":chid": chid,
+                ":message_id": message_id,
+            },
+        )?;
+        if inserted == 1 {
+            tx.execute(
+                "DELETE FROM push_seen_message
+                 WHERE channel_id = :chid AND rowid NOT IN (
+                     SELECT rowid FROM push_seen_message
+                     WHERE channel_id = :chid
+                     ORDER BY rowid DESC
+                     LIMIT :retention
+                 )",
+                rusqlite::named_params! {
+                    ":chid": chid,
+                    ":retention": retention,
+                },
+            )?;
+        }
+        tx.commit()?;
+        Ok(inserted == 0)
+    }
+
+    fn get_subscription_metadata(&self, channel_id: &str, key: &str) -> Result<Option<String>> {
+        Ok(self.try_query_one(
+            "SELECT value FROM push_subscription_metadata
+             WHERE channel_id = :channel_id AND key = :key",
+            rusqlite::named_params! {
+                ":channel_id": Self::normalize_uuid(channel_id),
+                ":key": key,
+            },
+            false,
+        )?)
+    }
+
+    fn set_subscription_metadata(&self, channel_id: &str, key: &str, value: &str) -> Result<bool> {
+        // Only insert if we have a record, so we never leave orphaned metadata behind.
+        let affected_rows = self.execute(
+            "INSERT OR REPLACE INTO push_subscription_metadata (channel_id, key, value)
+             SELECT channel_id, :key, :value FROM push_record
+             WHERE channel_id = :channel_id",
+            rusqlite::named_params! {
+                ":channel_id": Self::normalize_uuid(channel_id),
+                ":key": key,
+                ":value": value,
+            },
+        )?;
+        Ok(affected_rows == 1)
+    }
+
     fn update_endpoint(&self, channel_id: &str, endpoint: &str) -> Result<bool> {
         log::debug!("updating endpoint for '{}' to '{}'", channel_id, endpoint);
         let affected_rows = self.execute(
@@ -194,6 +514,75 @@ impl Storage for PushDb {
         Ok(affected_rows == 1)
     }
 
+    fn replace_record(&self, old_chid: &str, record: &PushRecord) -> Result<()> {
+        let tx = self.db.unchecked_transaction()?;
+        let old_chid = Self::normalize_uuid(old_chid);
+        // Delete the old record ourselves, rather than leaving it to `INSERT OR REPLACE`
+        // as the scope is unique, so we can clean up after it.
+        tx.execute(
+            "DELETE FROM push_record WHERE channel_id = :chid",
+            &[(":chid", &old_chid)],
+        )?;
+        self.put_record(record)?;
+        tx.execute(
+            "UPDATE push_subscription_metadata SET channel_id = :new_chid
+             WHERE channel_id = :old_chid",
+            rusqlite::named_params! {
+                ":new_chid": Self::normalize_uuid(&record.channel_id),
+                ":old_chid": old_chid,
+            },
+        )?;
+        tx.execute(
+            "DELETE FROM push_seen_message WHERE channel_id = :chid",
+            &[(":chid", &old_chid)],
+        )?;
+        tx.execute(
+            "DELETE FROM meta_data WHERE key = :key",
+            &[(":key", &keys_rotated_meta_key(&old_chid))],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn rotate_key(&self, channel_id: &str, key: &[u8]) -> Result<bool> {
+        log::debug!("rotating keys for '{}'", channel_id);
+        let tx = self.db.unchecked_transaction()?;
+        let affected_rows = tx.execute(
+            "UPDATE push_record set key = :key
+             WHERE channel_id = :channel_id",
+            rusqlite::named_params! {
+                ":key": key,
+                ":channel_id": Self::normalize_uuid(channel_id),
+            },
+        )?;
+        if affected_rows == 1 {
+            tx.execute(
+                "INSERT OR REPLACE INTO meta_data (key, value) VALUES (:key, :rotated_at)",
+                rusqlite::named_params! {
+                    ":key": keys_rotated_meta_key(channel_id),
+                    ":rotated_at": Timestamp::now(),
+                },
+            )?;
+        }
+        tx.commit()?;
+        Ok(affected_rows == 1)
+    }
+
+    fn keys_rotated(&self, channel_id: &str) -> Result<bool> {
+        Ok(self.exists(
+            "SELECT 1 FROM meta_data WHERE key = :key",
+            &[(":key", &keys_rotated_meta_key(channel_id))],
+        )?)
+    }
+
+    fn clear_keys_rotated(&self, channel_id: &str) -> Result<()> {
+        self.execute(
+            "DELETE FROM meta_data WHERE key = :key",
+            &[(":key", &keys_rotated_meta_key(channel_id))],
+        )?;
+        Ok(())
+    }
+
     // A couple of helpers to get/set "well known" meta keys.
     fn get_uaid(&self) -> Result<Option<String>> {
         self.get_meta("uaid")
@@ -236,6 +625,31 @@ impl Storage for PushDb {
         Ok(())
     }
 
+    fn vacuum(&self) -> Result<()> {
+        log::debug!("vacuuming push database");
+        // VACUUM can't run inside a transaction, so this must not be batched with
+        // anything that opens one.
+        self.execute_batch("REINDEX; VACUUM;")?;
+        Ok(())
+    }
+
+    fn copy_to(&self, path: &str) -> Result<()> {
+        log::debug!("copying push database");
+        // Unlike copying the file, this includes anything still in the WAL, and
+        // never leaves a half-written copy that looks like a valid database.
+        self.execute("VACUUM INTO ?", [path])?;
+        Ok(())
+    }
+
+    fn integrity_check(&self) -> Result<Vec<String>> {
+        let messages: Vec<String> =
+            self.query_rows_and_then("PRAGMA integrity_check", [], |row| -> Result<String> {
+                Ok(row.get(0)?)
+            })?;
+        // A healthy database reports a single "ok".
+        Ok(messages.into_iter().filter(|m| m != "ok").collect())
+    }
+
     #[cfg(not(test))]
     fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
         PushDb::open(path)
@@ -245,10 +659,18 @@ impl Storage for PushDb {
     fn open<P: AsRef<Path>>(_path: P) -> Result<Self> {
         PushDb::open_in_memory()
     }
+
+    fn from_connection(conn: Connection) -> Result<Self> {
+        PushDb::from_connection(conn)
+    }
 }
 
 #[cfg(test)]
 mod test {
+    use std::time::Duration;
+
+    use types::Timestamp;
+
     use crate::error::Result;
     use crate::internal::crypto::{Crypto, Cryptography};
 
@@ -291,8 +713,12 @@ mod test {
         let rec = prec(chid);
 
         assert!(db.get_record(chid)?.is_none());
+        assert!(!db.contains_record(chid)?);
+        assert!(db.get_endpoint(chid)?.is_none());
         db.put_record(&rec)?;
         assert!(db.get_record(chid)?.is_some());
+        assert!(db.contains_record(chid)?);
+        assert_eq!(db.get_endpoint(chid)?, Some(rec.endpoint.clone()));
         // don't fail if you've already added this record.
         db.put_record(&rec)?;
         // make sure that fetching the same uaid & chid returns the same record.
@@ -321,6 +747,7 @@ mod test {
         assert!(db.get_record(chid)?.is_some());
         assert!(db.delete_record(chid)?);
         assert!(db.get_record(chid)?.is_none());
+        assert!(!db.contains_record(chid)?);
         Ok(())
     }
 
@@ -347,6 +774,104 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn tombstones() -> Result<()> {
+        let db = get_db()?;
+        let chid = "deadbeef-0000-0000-0000-decafbad12345678";
+        let earlier = Timestamp::now()
+            .checked_sub(Duration::from_secs(60))
+            .unwrap();
+
+        assert!(!db.has_tombstone(chid, earlier)?);
+        db.put_tombstone(chid, "https://example.com/")?;
+        assert!(db.has_tombstone(chid, earlier)?);
+        // Lookups normalize the channel id like records do.
+        assert!(db.has_tombstone(&PushDb::normalize_uuid(chid), earlier)?);
+        let later = Timestamp::now()
+            .checked_add(Duration::from_secs(60))
+            .unwrap();
+        assert!(!db.has_tombstone(chid, later)?);
+
+        assert_eq!(db.purge_tombstones(earlier)?, 0);
+        assert_eq!(db.purge_tombstones(later)?, 1);
+        assert!(!db.has_tombstone(chid, earlier)?);
+        Ok(())
+    }
+
+    #[test]
+    fn scope_prefix() -> Result<()> {
+        let db = get_db()?;
+        let mut app = prec(&get_uuid()?);
+        app.scope = "https://example.com/app/".to_string();
+        let mut feature = prec(&get_uuid()?);
+        feature.scope = "https://example.com/app/feature/".to_string();
+        let mut other = prec(&get_uuid()?);
+        other.scope = "https://example.com/other/".to_string();
+        for rec in [&app, &feature, &other] {
+            assert!(db.put_record(rec)?);
+        }
+
+        let mut found = db.get_records_by_scope_prefix("https://example.com/app/")?;
+        found.sort_by(|a, b| a.scope.cmp(&b.scope));
+        assert_eq!(found, vec![app.clone(), feature.clone()]);
+        assert!(db
+            .get_records_by_scope_prefix("https://example.com/nope/")?
+            .is_empty());
+
+        let before = Timestamp::now();
+        assert_eq!(db.delete_records(&[app.clone(), feature.clone()], true)?, 2);
+        assert!(db.get_record(&app.channel_id)?.is_none());
+        assert!(db.get_record(&feature.channel_id)?.is_none());
+        assert!(db.get_record(&other.channel_id)?.is_some());
+        assert!(db.has_tombstone(&app.channel_id, before)?);
+        assert!(db.has_tombstone(&feature.channel_id, before)?);
+        assert!(!db.has_tombstone(&other.channel_id, before)?);
+        Ok(())
+    }
+
+    #[test]
+    fn created_before() -> Result<()> {
+        let db = get_db()?;
+        let mut old = prec(&get_uuid()?);
+        old.scope = "https://example.com/old/".to_string();
+        old.ctime = Timestamp::now()
+            .checked_sub(Duration::from_secs(60))
+            .unwrap();
+        let new = prec(&get_uuid()?);
+        for rec in [&old, &new] {
+            assert!(db.put_record(rec)?);
+        }
+
+        let cutoff = Timestamp::now()
+            .checked_sub(Duration::from_secs(30))
+            .unwrap();
+        assert_eq!(db.get_records_created_before(cutoff)?, vec![old]);
+        Ok(())
+    }
+
+    #[test]
+    fn records_page() -> Result<()> {
+        let db = get_db()?;
+        let mut recs = Vec::new();
+        for i in 0..5u64 {
+            let mut rec = prec(&get_uuid()?);
+            rec.scope = format!("https://example.com/{}/", i);
+            rec.ctime = Timestamp::now()
+                .checked_sub(Duration::from_secs(60 - i))
+                .unwrap();
+            assert!(db.put_record(&rec)?);
+            recs.push(rec);
+        }
+
+        assert_eq!(db.get_records_page(0, 2)?, recs[..2]);
+        assert_eq!(db.get_records_page(2, 2)?, recs[2..4]);
+        assert_eq!(db.get_records_page(4, 2)?, recs[4..]);
+        assert!(db.get_records_page(5, 2)?.is_empty());
+        assert!(db.get_records_page(0, 0)?.is_empty());
+        assert_eq!(db.get_records_page(1, u64::MAX)?, recs[1..]);
+        Ok(())
+    }
+
     #[test]
     fn meta() -> Result<()> {
         use super::Storage;
@@ -361,6 +886,68 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn seen_messages() -> Result<()> {
+        let db = get_db()?;
+        let chid = &get_uuid()?;
+        let other_chid = &get_uuid()?;
+
+        assert!(!db.record_seen_message(chid, "m1", 2)?);
+        assert!(db.record_seen_message(chid, "m1", 2)?);
+        // Message ids are per channel.
+        assert!(!db.record_seen_message(other_chid, "m1", 2)?);
+
+        // Only the newest are kept, so "m1" falls out.
+        assert!(!db.record_seen_message(chid, "m2", 2)?);
+        assert!(!db.record_seen_message(chid, "m3", 2)?);
+        assert!(db.record_seen_message(chid, "m3", 2)?);
+        assert!(db.record_seen_message(chid, "m2", 2)?);
+        assert!(!db.record_seen_message(chid, "m1", 2)?);
+        assert!(db.record_seen_message(other_chid, "m1", 2)?);
+
+        // Deleting the record forgets its messages.
+        db.put_record(&prec(chid))?;
+        db.delete_record(chid)?;
+        assert!(!db.record_seen_message(chid, "m1", 2)?);
+        Ok(())
+    }
+
+    #[test]
+    fn subscription_metadata() -> Result<()> {
+        let db = get_db()?;
+        let chid = &get_uuid()?;
+
+        // No metadata for channels we don't know about.
+        assert!(!db.set_subscription_metadata(chid, "label", "Inbox")?);
+        assert_eq!(db.get_subscription_metadata(chid, "label")?, None);
+
+        db.put_record(&prec(chid))?;
+        assert!(db.set_subscription_metadata(chid, "label", "Inbox")?);
+        assert!(db.set_subscription_metadata(chid, "label", "Mail")?);
+        assert!(db.set_subscription_metadata(chid, "feature", "mail")?);
+        assert_eq!(
+            db.get_subscription_metadata(chid, "label")?,
+            Some("Mail".to_owned())
+        );
+        assert_eq!(db.get_subscription_metadata(chid, "other")?, None);
+
+        // Deleting the record deletes its metadata.
+        db.delete_record(chid)?;
+        db.put_record(&prec(chid))?;
+        assert_eq!(db.get_subscription_metadata(chid, "label")?, None);
+
+        db.set_subscription_metadata(chid, "label", "Inbox")?;
+        db.delete_records(&[prec(chid)], false)?;
+        db.put_record(&prec(chid))?;
+        assert_eq!(db.get_subscription_metadata(chid, "label")?, None);
+
+        db.set_subscription_metadata(chid, "label", "Inbox")?;
+        db.delete_all_records()?;
+        db.put_record(&prec(chid))?;
+        assert_eq!(db.get_subscription_metadata(chid, "label")?, None);
+        Ok(())
+    }
+
     #[test]
     fn dash() -> Result<()> {
         let db = get_db()?;
@@ -373,4 +960,89 @@ mod test {
         assert!(db.delete_record(chid)?);
         Ok(())
     }
+
+    #[test]
+    fn scope_prefix() -> Result<()> {
+        let db = get_db()?;
+        for scope in [
+            "https://example.com/app",
+            "https://example.com/app/feature/",
+            "https://example.com/application/",
+        ] {
+            let mut rec = prec(&get_uuid()?);
+            rec.scope = scope.to_string();
+            db.put_record(&rec)?;
+        }
+        let scopes = |prefix: &str| -> Result<Vec<String>> {
+            let mut scopes: Vec<String> = db
+                .get_records_by_scope_prefix(prefix)?
+                .into_iter()
+                .map(|rec| rec.scope)
+                .collect();
+            scopes.sort();
+            Ok(scopes)
+        };
+        assert_eq!(
+            scopes("https://example.com/app")?,
+            vec![
+                "https://example.com/app",
+                "https://example.com/app/feature/"
+            ]
+        );
+        assert_eq!(
+            scopes("https://example.com/app/")?,
+            vec!["https://example.com/app/feature/"]
+        );
+        assert!(scopes("https://example.com/ap")?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn replace_record() -> Result<()> {
+        let db = get_db()?;
+        let old_chid = &get_uuid()?;
+        let old = prec(old_chid);
+        assert!(db.put_record(&old)?);
+        assert!(db.set_subscription_metadata(old_chid, "app-key", "app-value")?);
+        assert!(!db.record_seen_message(old_chid, "message-1", 10)?);
+        assert!(db.rotate_key(old_chid, &old.key)?);
+
+        let new_chid = &get_uuid()?;
+        let mut new = prec(new_chid);
+        new.key = old.key.clone();
+        db.replace_record(old_chid, &new)?;
+        assert!(db.get_record(old_chid)?.is_none());
+        assert_eq!(db.get_record(new_chid)?, Some(new));
+        assert_eq!(
+            db.get_subscription_metadata(new_chid, "app-key")?
+                .as_deref(),
+            Some("app-value")
+        );
+        assert!(db.get_subscription_metadata(old_chid, "app-key")?.is_none());
+        assert!(!db.keys_rotated(old_chid)?);
+        // Nothing is left behind for the old channel.
+        assert!(!db.record_seen_message(old_chid, "message-1", 10)?);
+        Ok(())
+    }
+
+    #[test]
+    fn vacuum() -> Result<()> {
+        let db = get_db()?;
+        let chid = &get_uuid()?;
+        let rec = prec(chid);
+
+        assert!(db.put_record(&rec)?);
+        db.vacuum()?;
+        assert_eq!(db.get_record(chid)?, Some(rec.clone()));
+        assert!(db.integrity_check()?.is_empty());
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("copy.db");
+        let path = path.to_str().unwrap();
+        db.copy_to(path)?;
+        assert_eq!(PushDb::open(path)?.get_record(chid)?, Some(rec));
+        // We never overwrite an existing database.
+        assert!(db.copy_to(path).is_err());
+        Ok(())
+    }
 }
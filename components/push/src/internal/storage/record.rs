@@ -31,6 +31,10 @@ pub struct PushRecord {
     /// VAPID public key to restrict subscription updates for only those that sign
     /// using the private VAPID key.
     pub app_server_key: Option<String>,
+
+    /// The sender this channel was subscribed with, if it wasn't the configured
+    /// `sender_id`.
+    pub sender_id: Option<String>,
 }
 
 impl PushRecord {
@@ -44,6 +48,7 @@ impl PushRecord {
             key: key.serialize()?,
             ctime: Timestamp::now(),
             app_server_key: None,
+            sender_id: None,
         })
     }
 
@@ -55,6 +60,7 @@ impl PushRecord {
             key: row.get("key")?,
             ctime: row.get("ctime")?,
             app_server_key: row.get("app_server_key")?,
+            sender_id: row.get("sender_id")?,
         })
     }
 }
@@ -10,7 +10,7 @@ pub struct PushConnectionInitializer;
 
 impl open_database::ConnectionInitializer for PushConnectionInitializer {
     const NAME: &'static str = "push db";
-    const END_VERSION: u32 = 3;
+    const END_VERSION: u32 = 7;
 
     // This is such a simple database that we do almost nothing!
     // * We have no foreign keys, so `PRAGMA foreign_keys = ON;` is pointless.
@@ -40,15 +40,33 @@ impl open_database::ConnectionInitializer for PushConnectionInitializer {
                     -- create the new table with the new schema.
                     {CREATE_TABLE_PUSH_SQL};
                     -- move the data across.
-                    INSERT OR IGNORE INTO push_record ({COMMON_COLS})
-                    SELECT {COMMON_COLS} FROM push_record_old WHERE length(scope) > 0;
+                    INSERT OR IGNORE INTO push_record ({V3_COLS})
+                    SELECT {V3_COLS} FROM push_record_old WHERE length(scope) > 0;
                     -- drop the old table
                     DROP TABLE push_record_old;",
                     CREATE_TABLE_PUSH_SQL = CREATE_TABLE_PUSH_SQL,
-                    COMMON_COLS = COMMON_COLS,
+                    V3_COLS = V3_COLS,
                 );
                 db.execute_batch(&sql)?;
             }
+            // Added the `push_tombstone` table.
+            3 => db.execute_batch(CREATE_TABLE_PUSH_SQL)?,
+            // Added `push_record.sender_id`. Upgrading from before v3 recreates the table
+            // with the current schema, so it might already be there.
+            4 => {
+                let has_sender_id = db.query_row(
+                    "SELECT COUNT(*) FROM pragma_table_info('push_record') WHERE name = 'sender_id'",
+                    [],
+                    |row| row.get::<_, u32>(0),
+                )? > 0;
+                if !has_sender_id {
+                    db.execute_batch("ALTER TABLE push_record ADD COLUMN sender_id TEXT")?;
+                }
+            }
+            // Added the `push_subscription_metadata` table.
+            5 => db.execute_batch(CREATE_TABLE_PUSH_SQL)?,
+            // Added the `push_seen_message` table.
+            6 => db.execute_batch(CREATE_TABLE_PUSH_SQL)?,
             other => {
                 log::warn!(
                     "Loaded future schema version {} (we only understand version {}). \
@@ -63,6 +81,17 @@ impl open_database::ConnectionInitializer for PushConnectionInitializer {
 }
 
 pub const COMMON_COLS: &str = "
+    channel_id,
+    endpoint,
+    scope,
+    key,
+    ctime,
+    app_server_key,
+    sender_id
+";
+
+// The columns shared by the v2 and v3 schemas.
+const V3_COLS: &str = "
     channel_id,
     endpoint,
     scope,
@@ -74,6 +103,7 @@ pub const COMMON_COLS: &str = "
 #[cfg(test)]
 mod test {
     use crate::internal::storage::db::{PushDb, Storage};
+    use crate::MigrationReport;
     use rusqlite::{Connection, OpenFlags};
     use sql_support::ConnExt;
 
@@ -140,4 +170,93 @@ mod test {
         assert_eq!(db.get_meta("key-1").unwrap().unwrap(), "value-1");
         assert_eq!(db.get_meta("key-2").unwrap().unwrap(), "value-2");
     }
+
+    #[test]
+    fn test_migrate_report() {
+        env_logger::try_init().ok();
+        let tempdir = tempfile::tempdir().unwrap();
+
+        let path = tempdir.path().join("push_v2.sql");
+        let conn = Connection::open_with_flags(path.clone(), OpenFlags::default()).unwrap();
+        conn.execute_batch(CREATE_V2_SCHEMA).unwrap();
+        conn.execute_batch("PRAGMA user_version = 2").unwrap();
+        drop(conn);
+        assert_eq!(
+            PushDb::migrate(&path).unwrap(),
+            MigrationReport {
+                from_version: 2,
+                to_version: 7,
+            }
+        );
+        // Already up to date.
+        assert_eq!(
+            PushDb::migrate(&path).unwrap(),
+            MigrationReport {
+                from_version: 7,
+                to_version: 7,
+            }
+        );
+
+        let path = tempdir.path().join("push_new.sql");
+        assert_eq!(
+            PushDb::migrate(&path).unwrap(),
+            MigrationReport {
+                from_version: 0,
+                to_version: 7,
+            }
+        );
+        assert!(PushDb::open(&path).is_ok());
+    }
+
+    #[test]
+    fn test_migrate_v4_v5() {
+        env_logger::try_init().ok();
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("push_v4.sql");
+
+        let conn = Connection::open_with_flags(path.clone(), OpenFlags::default()).unwrap();
+        conn.execute_batch(
+            r#"
+            CREATE TABLE push_record (
+                channel_id         TEXT     NOT NULL PRIMARY KEY,
+                endpoint           TEXT     NOT NULL UNIQUE,
+                scope              TEXT     NOT NULL UNIQUE,
+                key                TEXT     NOT NULL,
+                ctime              INTEGER  NOT NULL,
+                app_server_key     TEXT,
+                CHECK(length(scope) > 0)
+            );
+            CREATE TABLE push_tombstone (
+                channel_id         TEXT     NOT NULL PRIMARY KEY,
+                scope              TEXT     NOT NULL,
+                removed_at         INTEGER  NOT NULL
+            );
+            CREATE TABLE meta_data (
+                key                TEXT    PRIMARY KEY,
+                value                      NOT NULL
+            ) without ROWID;
+            INSERT INTO push_record (
+                channel_id, endpoint, scope,  key,     ctime, app_server_key
+            ) VALUES
+                ("cid1",     "ep-1",   "sc-1", x'1234', 1,    NULL);
+            PRAGMA user_version = 4;
+            "#,
+        )
+        .unwrap();
+        drop(conn);
+
+        let db = PushDb::open(path).expect("should open");
+        let mut record = db
+            .get_record("cid1")
+            .expect("should work")
+            .expect("should get a record");
+        assert_eq!(record.sender_id, None);
+
+        record.sender_id = Some("other-sender".to_string());
+        db.put_record(&record).unwrap();
+        assert_eq!(
+            db.get_record("cid1").unwrap().unwrap().sender_id.as_deref(),
+            Some("other-sender")
+        );
+    }
 }
@@ -13,13 +13,46 @@ IF NOT EXISTS push_record
     key                TEXT     NOT NULL,
     ctime              INTEGER  NOT NULL,
     app_server_key     TEXT,
+    -- NULL for channels subscribed with the configured sender.
+    sender_id          TEXT,
     -- scope must have a value!
     CHECK(length(scope) > 0)
 );
 
+-- Channels that were recently unsubscribed, so late messages for them can be
+-- told apart from messages for channels we never knew about.
+CREATE TABLE
+IF NOT EXISTS push_tombstone
+(
+    channel_id         TEXT     NOT NULL PRIMARY KEY,
+    scope              TEXT     NOT NULL,
+    removed_at         INTEGER  NOT NULL
+);
+
 CREATE TABLE
 IF NOT EXISTS meta_data
 (
     key                TEXT    PRIMARY KEY,
     value                      NOT NULL
 ) without ROWID;
+
+-- The ids of recently seen messages for each channel, so redelivered messages can
+-- be recognized. Only the newest rows (by rowid) for each channel are kept.
+CREATE TABLE
+IF NOT EXISTS push_seen_message
+(
+    channel_id         TEXT     NOT NULL,
+    message_id         TEXT     NOT NULL,
+    UNIQUE (channel_id, message_id)
+);
+
+-- Small key-value metadata apps attach to their subscriptions. There's no foreign
+-- key, so deleting a record must delete its metadata too.
+CREATE TABLE
+IF NOT EXISTS push_subscription_metadata
+(
+    channel_id         TEXT     NOT NULL,
+    key                TEXT     NOT NULL,
+    value              TEXT     NOT NULL,
+    PRIMARY KEY (channel_id, key)
+) without ROWID;
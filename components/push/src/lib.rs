@@ -183,14 +183,29 @@
 uniffi::include_scaffolding!("push");
 // All implementation detail lives in the `internal` module
 mod internal;
-use std::{collections::HashMap, sync::Mutex};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, MutexGuard},
+};
+mod async_push_manager;
 mod error;
+#[cfg(any(test, feature = "vapid"))]
+pub mod vapid;
 
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
 use error_support::handle_error;
-pub use internal::config::{BridgeType, Protocol as PushHttpProtocol, PushConfiguration};
+pub use internal::channel_id::{ChannelIdGenerator, RandomChannelIds};
+pub use internal::communications::{PushHttpClient, ViaductClient};
+pub use internal::config::{
+    BridgeType, Protocol as PushHttpProtocol, PushConfiguration, PushConfigurationBuilder,
+};
 use internal::crypto::Crypto;
-use internal::{communications::ConnectHttp, push_manager::DecryptResponse};
+use internal::{
+    communications::ConnectHttp,
+    push_manager::{BatchDecryptResult, DecryptResponse, DecryptedMessage},
+};
 
+pub use async_push_manager::{AsyncPushManager, BlockingTask};
 pub use error::{ApiResult, PushApiError, PushError};
 use internal::storage::Store;
 
@@ -209,6 +224,16 @@ pub struct PushManager {
 }
 
 impl PushManager {
+    /// Locks the internal manager. A panic while it was locked poisons the mutex,
+    /// but the state is in the database and is usually still consistent, so we
+    /// carry on rather than failing every later call.
+    fn lock(&self) -> MutexGuard<'_, internal::PushManager<ConnectHttp, Crypto, Store>> {
+        self.internal.lock().unwrap_or_else(|poisoned| {
+            log::warn!("PushManager mutex was poisoned by a panic; recovering");
+            poisoned.into_inner()
+        })
+    }
+
     /// Creates a new [`PushManager`] object, not subscribed to any
     /// channels
     ///
@@ -219,6 +244,7 @@ impl PushManager {
     /// Returns an error in the following cases:
     ///   - PushManager is unable to open the `database_path` given
     ///   - PushManager is unable to establish a connection to the autopush server
+    ///   - `config` has a `proxy_url` or `pool_idle_timeout` the HTTP backend can't honor
     #[handle_error(PushError)]
     pub fn new(config: PushConfiguration) -> ApiResult<Self> {
         log::debug!(
@@ -231,12 +257,91 @@ impl PushManager {
         })
     }
 
+    /// Creates a new [`PushManager`] object which sends its requests to the
+    /// autopush server through `http_client`, rather than the default viaduct
+    /// backend. This isn't exposed over FFI.
+    ///
+    /// # Arguments
+    ///   - `config`: [`PushConfiguration`] the configuration for this instance of PushManager
+    ///   - `http_client`: the [`PushHttpClient`] to send requests with
+    ///
+    /// # Errors
+    /// Returns an error in the following cases:
+    ///   - PushManager is unable to open the `database_path` given
+    ///   - `config` has a `proxy_url` or `pool_idle_timeout`, which `http_client` would
+    ///     have to honor itself
+    #[handle_error(PushError)]
+    pub fn new_with_http_client(
+        config: PushConfiguration,
+        http_client: Box<dyn PushHttpClient>,
+    ) -> ApiResult<Self> {
+        Ok(Self {
+            internal: Mutex::new(internal::PushManager::new_with_http_client(
+                config,
+                http_client,
+            )?),
+        })
+    }
+
+    /// Creates a new [`PushManager`] object which uses `connection` for storage,
+    /// rather than opening its own connection to `database_path`, so apps which
+    /// manage their SQLite connections centrally can share one. This isn't
+    /// exposed over FFI.
+    ///
+    /// # Arguments
+    ///   - `connection`: a writable connection to the push database. It's
+    ///     initialized or migrated as needed, so may be to a new, empty database.
+    ///   - `config`: [`PushConfiguration`] the configuration for this instance of
+    ///     PushManager. `database_path` is ignored.
+    ///
+    /// # Errors
+    /// Returns an error in the following cases:
+    ///   - The database is from a newer version of the component
+    ///   - An error occurred initializing or migrating the database
+    #[handle_error(PushError)]
+    pub fn with_connection(
+        connection: rusqlite::Connection,
+        config: PushConfiguration,
+    ) -> ApiResult<Self> {
+        Ok(Self {
+            internal: Mutex::new(internal::PushManager::with_connection(connection, config)?),
+        })
+    }
+
+    /// Runs any pending schema migrations on the database at `database_path`,
+    /// creating it if it doesn't exist.
+    ///
+    /// [`PushManager::new`] migrates the database as it opens it, which can be slow on
+    /// a constrained device, so apps can call this first to control when that happens.
+    /// A database that's already up to date is only read, so this is cheap.
+    ///
+    /// # Arguments
+    ///   - `database_path`: the path to the database, as given in the [`PushConfiguration`]
+    ///
+    /// # Returns
+    /// A [`MigrationReport`] with the schema versions before and after migrating
+    ///
+    /// # Errors
+    /// Returns an error in the following cases:
+    ///   - The database can't be opened
+    ///   - The database is from a newer version of the component
+    ///   - An error occurred migrating the database
+    #[handle_error(PushError)]
+    pub fn migrate(database_path: &str) -> ApiResult<MigrationReport> {
+        internal::storage::Store::migrate(database_path)
+    }
+
     /// Subscribes to a new channel and gets the Subscription Info block
     ///
+    /// If the `scope` is already subscribed with the same `server_key`, the existing
+    /// subscription is returned without contacting the autopush server.
+    ///
     /// # Arguments
     ///   - `channel_id` - Channel ID (UUID4) for new subscription, either pre-generated or "" and one will be created.
     ///   - `scope` - Site scope string (defaults to "" for no site scope string).
     ///   - `server_key` - optional VAPID public key to "lock" subscriptions (defaults to "" for no key)
+    ///   - `sender_id` - optional sender to subscribe with, instead of the configured `sender_id`.
+    ///     The channel keeps using this sender when it's later re-subscribed.
     ///
     /// # Returns
     /// A Subscription response that includes the following:
@@ -246,19 +351,94 @@ impl PushManager {
     ///
     /// # Errors
     /// Returns an error in the following cases:
+    ///   - The `server_key` is not a base64url encoded P-256 public key
+    ///   - The `scope` is already subscribed with a different `server_key` or `sender_id`
+    ///   - Subscribing would exceed the configured `max_subscriptions`
     ///   - PushManager was unable to access its persisted storage
     ///   - An error occurred sending a subscription request to the autopush server
+    ///   - The autopush server is throttling requests, see `PushApiError::Throttled`
     ///   - An error occurred generating or deserializing the cryptographic keys
     #[handle_error(PushError)]
     pub fn subscribe(
         &self,
         scope: &str,
         server_key: &Option<String>,
+        sender_id: &Option<String>,
     ) -> ApiResult<SubscriptionResponse> {
-        self.internal
-            .lock()
-            .unwrap()
-            .subscribe(scope, server_key.as_deref())
+        self.lock()
+            .subscribe(scope, server_key.as_deref(), sender_id.as_deref())
+    }
+
+    /// Subscribes to a new channel like [`PushManager::subscribe`], for apps that hold
+    /// the raw bytes of the VAPID public key rather than its base64url encoding.
+    ///
+    /// # Arguments
+    ///   - `scope` - Site scope string (defaults to "" for no site scope string).
+    ///   - `server_key` - the uncompressed P-256 VAPID public key, or empty for no key
+    ///   - `sender_id` - optional sender to subscribe with, instead of the configured `sender_id`.
+    ///
+    /// # Returns
+    /// The same response as [`PushManager::subscribe`]
+    ///
+    /// # Errors
+    /// Returns an error in the same cases as [`PushManager::subscribe`]
+    #[handle_error(PushError)]
+    pub fn subscribe_with_raw_server_key(
+        &self,
+        scope: &str,
+        server_key: &[u8],
+        sender_id: &Option<String>,
+    ) -> ApiResult<SubscriptionResponse> {
+        self.lock()
+            .subscribe_with_raw_server_key(scope, server_key, sender_id.as_deref())
+    }
+
+    /// Generates the keys for a subscription ahead of time, as that's the slow part
+    /// of [`PushManager::subscribe`]. Apps that know they'll subscribe soon can call
+    /// this on a background thread and pass the result to
+    /// [`PushManager::subscribe_with_keys`].
+    ///
+    /// # Returns
+    /// The [`PreparedKeys`], which can be used for a single subscription
+    ///
+    /// # Errors
+    /// Returns an error if the cryptographic keys couldn't be generated
+    #[handle_error(PushError)]
+    pub fn prepare_keys(&self) -> ApiResult<Arc<PreparedKeys>> {
+        // We don't take the lock, so this doesn't hold up anything else.
+        let key = internal::PushManager::<ConnectHttp, Crypto, Store>::prepare_keys()?;
+        Ok(Arc::new(PreparedKeys { key }))
+    }
+
+    /// Subscribes to a new channel like [`PushManager::subscribe`], using keys from
+    /// [`PushManager::prepare_keys`] instead of generating them.
+    ///
+    /// # Arguments
+    ///   - `scope` - Site scope string (defaults to "" for no site scope string).
+    ///   - `server_key` - optional VAPID public key to "lock" subscriptions (defaults to "" for no key)
+    ///   - `sender_id` - optional sender to subscribe with, instead of the configured `sender_id`.
+    ///   - `keys` - the keys for the new subscription. They're ignored if the `scope`
+    ///     is already subscribed, in which case the existing subscription is returned.
+    ///
+    /// # Returns
+    /// The same response as [`PushManager::subscribe`]
+    ///
+    /// # Errors
+    /// Returns an error in the same cases as [`PushManager::subscribe`]
+    #[handle_error(PushError)]
+    pub fn subscribe_with_keys(
+        &self,
+        scope: &str,
+        server_key: &Option<String>,
+        sender_id: &Option<String>,
+        keys: Arc<PreparedKeys>,
+    ) -> ApiResult<SubscriptionResponse> {
+        self.lock().subscribe_with_keys(
+            scope,
+            server_key.as_deref(),
+            sender_id.as_deref(),
+            Some(keys.key.clone()),
+        )
     }
 
     /// Retrieves an existing push subscription
@@ -278,11 +458,14 @@ impl PushManager {
     ///   - An error occurred generating or deserializing the cryptographic keys
     #[handle_error(PushError)]
     pub fn get_subscription(&self, scope: &str) -> ApiResult<Option<SubscriptionResponse>> {
-        self.internal.lock().unwrap().get_subscription(scope)
+        self.lock().get_subscription(scope)
     }
 
     /// Unsubscribe from given channelID, ending that subscription for the user.
     ///
+    /// If the autopush server can't be reached, the subscription is still removed
+    /// locally, and the server is told later by [`PushManager::flush_pending_unregisters`].
+    ///
     /// # Arguments
     ///   - `channel_id` - Channel ID (UUID) for subscription to remove
     ///
@@ -293,11 +476,151 @@ impl PushManager {
     /// # Errors
     /// Returns an error in the following cases:
     ///   - The PushManager does not contain a valid UAID
-    ///   - An error occurred sending an unsubscribe request to the autopush server
+    ///   - The autopush server rejected the unsubscribe request
     ///   - An error occurred accessing the PushManager's persisted storage
     #[handle_error(PushError)]
     pub fn unsubscribe(&self, channel_id: &str) -> ApiResult<bool> {
-        self.internal.lock().unwrap().unsubscribe(channel_id)
+        self.lock().unsubscribe(channel_id)
+    }
+
+    /// Retries unsubscribing from the autopush server for subscriptions
+    /// [`PushManager::unsubscribe`] removed while the server couldn't be reached.
+    /// They're remembered across restarts, so apps can call this whenever the
+    /// network comes back, or on startup.
+    ///
+    /// # Returns
+    /// The number of subscriptions the server was told about
+    ///
+    /// # Errors
+    /// Returns an error in the following cases:
+    ///   - The PushManager does not contain a valid UAID
+    ///   - An error occurred accessing the PushManager's persisted storage
+    #[handle_error(PushError)]
+    pub fn flush_pending_unregisters(&self) -> ApiResult<u64> {
+        Ok(self.lock().flush_pending_unregisters()? as u64)
+    }
+
+    /// Unsubscribe from all channels with the given scope, or a scope nested under it.
+    ///
+    /// For example, `https://example.com/app/` matches both `https://example.com/app/`
+    /// and `https://example.com/app/feature/`, which lets an app tear down all the
+    /// subscriptions belonging to a feature at once. Scopes only nest at a `/`, so
+    /// `https://example.com/app` doesn't match `https://example.com/application/`.
+    ///
+    /// A channel the server fails to unsubscribe doesn't stop the others. If the failure
+    /// is temporary, the channel is removed anyway and unsubscribed from the server
+    /// later, see `flush_pending_unregisters`; otherwise it's kept.
+    ///
+    /// # Arguments
+    ///   - `scope` - The scope for the channels to remove
+    ///
+    /// # Returns
+    /// Returns the number of channels removed, which is 0 if no channels matched
+    ///
+    /// # Errors
+    /// Returns an error in the following cases:
+    ///   - The PushManager does not contain a valid UAID
+    ///   - An error occurred accessing the PushManager's persisted storage
+    #[handle_error(PushError)]
+    pub fn unsubscribe_by_scope(&self, scope: &str) -> ApiResult<u64> {
+        self.lock().unsubscribe_by_scope(scope)
+    }
+
+    /// Unsubscribe from a known set of channels.
+    ///
+    /// The server is asked to drop each channel, then all the channels it dropped are
+    /// removed from storage at once. Like [`PushManager::unsubscribe`], a channel is also
+    /// removed if the server can't be reached, and is unsubscribed from the server later.
+    ///
+    /// # Arguments
+    ///   - `channel_ids` - The channel IDs of the subscriptions to remove
+    ///
+    /// # Returns
+    /// One [`UnsubscribeResult`] per channel ID, in the same order. A channel we don't
+    /// have a subscription for, or which the server refused to unsubscribe, is reported
+    /// as not unsubscribed without affecting the rest.
+    ///
+    /// # Errors
+    /// Returns an error in the following cases:
+    ///   - The PushManager does not contain a valid UAID
+    ///   - An error occurred accessing the PushManager's persisted storage
+    #[handle_error(PushError)]
+    pub fn unsubscribe_many(&self, channel_ids: &[String]) -> ApiResult<Vec<UnsubscribeResult>> {
+        Ok(self
+            .lock()
+            .unsubscribe_many(channel_ids)?
+            .into_iter()
+            .map(Into::into)
+            .collect())
+    }
+
+    /// Unsubscribe from all channels created more than `older_than_ms` ago, for apps
+    /// which create short-lived subscriptions and want to clean up any they forgot
+    /// to unsubscribe from.
+    ///
+    /// This works like [`PushManager::unsubscribe_many`], so the channels the server
+    /// dropped are removed from storage at once.
+    ///
+    /// # Arguments
+    ///   - `older_than_ms` - The age in milliseconds after which a subscription is pruned
+    ///
+    /// # Returns
+    /// The channel IDs of the subscriptions removed. A channel the server refused to
+    /// unsubscribe is kept, and may be pruned next time.
+    ///
+    /// # Errors
+    /// Returns an error in the following cases:
+    ///   - The PushManager does not contain a valid UAID
+    ///   - An error occurred accessing the PushManager's persisted storage
+    #[handle_error(PushError)]
+    pub fn prune_subscriptions(&self, older_than_ms: i64) -> ApiResult<Vec<String>> {
+        self.lock().prune_subscriptions(older_than_ms)
+    }
+
+    /// Refreshes the endpoint of a single subscription, for when the app learns that
+    /// one channel's endpoint has been rotated and doesn't want a full [`PushManager::verify_connection`].
+    ///
+    /// The subscription keeps its scope, keys and metadata, but gets a new channel ID and
+    /// endpoint. The old channel is only dropped once we have the new one.
+    ///
+    /// # Arguments
+    ///   - `channel_id` - Channel ID (UUID) of the subscription to refresh
+    ///
+    /// # Returns
+    /// The new subscription info, as returned from [`PushManager::subscribe`]
+    ///
+    /// # Errors
+    /// Returns an error in the following cases:
+    ///   - There is no subscription with the given `channel_id`
+    ///   - The PushManager does not contain a valid UAID
+    ///   - An error occurred sending a subscription request to the autopush server
+    ///   - An error occurred accessing the PushManager's persisted storage
+    #[handle_error(PushError)]
+    pub fn refresh_subscription(&self, channel_id: &str) -> ApiResult<SubscriptionResponse> {
+        self.lock().refresh_subscription(channel_id)
+    }
+
+    /// Replaces the keys of a single subscription with a freshly generated pair, for
+    /// apps that want to rotate the keys of long-lived subscriptions.
+    ///
+    /// The subscription keeps its channel ID and endpoint. Once rotated, messages
+    /// which were encrypted with the old keys fail to decrypt with a `KeysRotated` error,
+    /// until the first message encrypted with the new keys arrives.
+    ///
+    /// # Arguments
+    ///   - `channel_id` - Channel ID (UUID) of the subscription to rotate the keys of
+    ///
+    /// # Returns
+    /// The subscription info with the new keys, which need to be given to the sender
+    ///
+    /// # Errors
+    /// Returns an error in the following cases:
+    ///   - There is no subscription with the given `channel_id`
+    ///   - An error occurred generating the cryptographic keys
+    ///   - An error occurred accessing the PushManager's persisted storage
+    #[handle_error(PushError)]
+    pub fn rotate_keys(&self, channel_id: &str) -> ApiResult<SubscriptionResponse> {
+        self.lock().rotate_keys(channel_id)
     }
 
     /// Unsubscribe all channels for the user
@@ -309,7 +632,7 @@ impl PushManager {
     ///   - An error occurred accessing the PushManager's persisted storage
     #[handle_error(PushError)]
     pub fn unsubscribe_all(&self) -> ApiResult<()> {
-        self.internal.lock().unwrap().unsubscribe_all()
+        self.lock().unsubscribe_all()
     }
 
     /// Updates the Native OS push registration ID.
@@ -320,10 +643,87 @@ impl PushManager {
     /// Return an error in the following cases:
     ///   - The PushManager does not contain a valid UAID
     ///   - An error occurred sending an update request to the autopush server
+    ///   - The autopush server is throttling requests, see `PushApiError::Throttled`
     ///   - An error occurred accessing the PushManager's persisted storage
     #[handle_error(PushError)]
     pub fn update(&self, new_token: &str) -> ApiResult<()> {
-        self.internal.lock().unwrap().update(new_token)
+        self.lock().update(new_token)
+    }
+
+    /// Re-registers with the autopush server, re-creating all our subscriptions
+    ///
+    /// This is the way to recover when our UAID is no longer valid. A new UAID is
+    /// obtained and every subscription is re-created with its existing scope and keys,
+    /// so only the channel ids and endpoints change.
+    ///
+    /// # Arguments
+    ///   - `new_token` - the Native OS push registration ID to register with
+    ///
+    /// # Returns
+    /// Returns a [`ReRegisterResult`], with a [`PushSubscriptionChanged`] for each
+    /// re-created subscription, with its new channel id. Consumers should use
+    /// [`PushManager::get_subscription`] to fetch the new endpoints and redistribute them.
+    /// A subscription the server refuses to re-create is dropped, and its scope reported,
+    /// without affecting the rest.
+    ///
+    /// # Errors
+    /// Return an error in the following cases:
+    ///   - An error occurred unsubscribing the old UAID from the autopush server
+    ///   - A network error occurred, or the autopush server was unavailable or throttling
+    ///     requests, while re-creating the subscriptions. The ones not yet re-created are
+    ///     kept, and calling this again re-creates them all.
+    ///   - An error occurred accessing the PushManager's persisted storage
+    #[handle_error(PushError)]
+    pub fn re_register(&self, new_token: &str) -> ApiResult<ReRegisterResult> {
+        self.lock().re_register(new_token)
+    }
+
+    /// Handles the Native OS push registration ID changing, recovering if the
+    /// change left our registration stale.
+    ///
+    /// This is [`PushManager::update`], except that it isn't rate limited, and if the
+    /// autopush server no longer recognizes our UAID it goes on to
+    /// [`PushManager::re_register`] with the new token, rather than leaving that for
+    /// the next [`PushManager::verify_connection`].
+    ///
+    /// # Arguments
+    ///   - `new_token` - the new Native OS push registration ID
+    ///
+    /// # Returns
+    /// Returns the [`ReRegisterResult`] of re-registering, which is empty if we didn't
+    /// need to. The [`PushObserver`] is also notified of each re-created subscription.
+    ///
+    /// # Errors
+    /// Return an error in the following cases:
+    ///   - An error occurred sending an update or unsubscribe request to the autopush server
+    ///   - The autopush server is throttling requests, see `PushApiError::Throttled`
+    ///   - An error occurred accessing the PushManager's persisted storage
+    #[handle_error(PushError)]
+    pub fn handle_token_refresh(&self, new_token: &str) -> ApiResult<ReRegisterResult> {
+        self.lock().handle_token_refresh(new_token)
+    }
+
+    /// Switches to a different native push bridge, keeping all existing subscriptions.
+    ///
+    /// This is useful when migrating from one native push provider to another.
+    /// The change only lasts for the lifetime of this `PushManager`, so consumers
+    /// should also update the `bridge_type` they pass in the [`PushConfiguration`].
+    ///
+    /// # Arguments
+    ///   - `bridge_type` - the new bridge type, one of "fcm", "adm" or "apns"
+    ///   - `registration_id` - the Native OS push registration ID for the new bridge
+    ///
+    /// # Returns
+    /// Returns `false` if we were already using that bridge and registration ID
+    ///
+    /// # Errors
+    /// Return an error in the following cases:
+    ///   - The `bridge_type` isn't a bridge we support
+    ///   - An error occurred sending an update request to the autopush server
+    ///   - An error occurred accessing the PushManager's persisted storage
+    #[handle_error(PushError)]
+    pub fn set_bridge_type(&self, bridge_type: &str, registration_id: &str) -> ApiResult<bool> {
+        self.lock().set_bridge_type(bridge_type, registration_id)
     }
 
     /// Verifies the connection state
@@ -348,10 +748,418 @@ impl PushManager {
     ///   - An error occurred accessing the PushManager's persisted storage
     #[handle_error(PushError)]
     pub fn verify_connection(&self, force_verify: bool) -> ApiResult<Vec<PushSubscriptionChanged>> {
-        self.internal
-            .lock()
-            .unwrap()
-            .verify_connection(force_verify)
+        self.lock().verify_connection(force_verify)
+    }
+
+    /// Reports what [`PushManager::verify_connection`] would change, without changing anything
+    ///
+    /// This isn't rate limited, and leaves both the local and the server state alone,
+    /// so it's suitable for diagnosing subscription problems.
+    ///
+    /// # Returns
+    /// Returns the list of [`PushSubscriptionChanged`] that `verify_connection`
+    /// would currently return
+    ///
+    /// # Errors
+    /// Return an error in the following cases:
+    ///   - The PushManager does not contain a valid UAID
+    ///   - An error occurred sending an channel list retrieval request to the autopush server
+    ///   - An error occurred accessing the PushManager's persisted storage
+    #[handle_error(PushError)]
+    pub fn verify_connection_dry_run(&self) -> ApiResult<Vec<PushSubscriptionChanged>> {
+        self.lock().verify_connection_dry_run()
+    }
+
+    /// Compares our channels with the ones the autopush server knows about, listing
+    /// the channels only one side has.
+    ///
+    /// Where [`PushManager::verify_connection_dry_run`] only reports which subscriptions
+    /// would be dropped, this says how the lists differ, for debugging. Like the dry
+    /// run, it isn't rate limited and doesn't change anything.
+    ///
+    /// # Returns
+    /// A [`ConnectionDiff`] of the two channel lists
+    ///
+    /// # Errors
+    /// Return an error in the following cases:
+    ///   - The PushManager does not contain a valid UAID
+    ///   - An error occurred sending an channel list retrieval request to the autopush server
+    ///   - An error occurred accessing the PushManager's persisted storage
+    #[handle_error(PushError)]
+    pub fn connection_diff(&self) -> ApiResult<ConnectionDiff> {
+        self.lock().connection_diff()
+    }
+
+    /// Gets the subscriptions [`PushManager::verify_connection`] dropped which
+    /// haven't been resubscribed yet
+    ///
+    /// This lets the app retry just those channels, on its own schedule, if it didn't
+    /// resubscribe them all when they were reported. A subscription is removed from the
+    /// list once its scope is successfully subscribed again, or unsubscribed.
+    ///
+    /// # Returns
+    /// One entry for each dropped subscription, with its old channel id
+    ///
+    /// # Errors
+    /// Return an error in the following cases:
+    ///   - An error occurred accessing the PushManager's persisted storage
+    #[handle_error(PushError)]
+    pub fn get_stale_subscriptions(&self) -> ApiResult<Vec<PushSubscriptionChanged>> {
+        self.lock().get_stale_subscriptions()
+    }
+
+    /// When [`PushManager::verify_connection`] last checked our subscriptions with the
+    /// autopush server
+    ///
+    /// Calls skipped by the rate limiter don't count.
+    ///
+    /// # Returns
+    /// The time of the last verification in milliseconds since the epoch, or `None`
+    /// if we've never verified
+    ///
+    /// # Errors
+    /// Return an error in the following cases:
+    ///   - An error occurred accessing the PushManager's persisted storage
+    #[handle_error(PushError)]
+    pub fn last_verified(&self) -> ApiResult<Option<i64>> {
+        self.lock().last_verified()
+    }
+
+    /// Whether it's time to call [`PushManager::verify_connection`] again, for apps
+    /// following the advice to verify regularly (eg, daily)
+    ///
+    /// # Arguments
+    ///   - `max_age_ms` - How long a verification stays good for, in milliseconds
+    ///
+    /// # Returns
+    /// Returns `true` if we've never verified, or the last verification is at least
+    /// `max_age_ms` old, plus this device's offset within the
+    /// [`PushConfiguration::verification_jitter_window`]. Always `false` when
+    /// [`PushConfiguration::offline`] is set.
+    ///
+    /// # Errors
+    /// Return an error in the following cases:
+    ///   - An error occurred accessing the PushManager's persisted storage
+    #[handle_error(PushError)]
+    pub fn needs_verification(&self, max_age_ms: i64) -> ApiResult<bool> {
+        self.lock().needs_verification(max_age_ms)
+    }
+
+    /// Describes the autopush server this PushManager talks to, for support to confirm
+    /// a device is pointed at the right deployment
+    ///
+    /// # Returns
+    /// A [`ConfigSummary`] of the effective configuration, with secrets redacted
+    pub fn config_summary(&self) -> ConfigSummary {
+        self.lock().config_summary()
+    }
+
+    /// Checks whether the autopush server still recognizes our UAID
+    ///
+    /// Unlike [`PushManager::verify_connection`], this has no side effects
+    /// on either the local or the server state.
+    ///
+    /// # Returns
+    /// Returns `false` if the server doesn't know about our UAID, or we never
+    /// registered one, meaning the next subscription will re-register
+    ///
+    /// # Errors
+    /// Return an error in the following cases:
+    ///   - An error occurred sending the request to the autopush server
+    #[handle_error(PushError)]
+    pub fn check_connection(&self) -> ApiResult<bool> {
+        self.lock().check_connection()
+    }
+
+    /// Registers an observer to be told about subscriptions that changed
+    ///
+    /// The observer is called for every [`PushSubscriptionChanged`] that
+    /// [`PushManager::verify_connection`] returns, after the local state has been
+    /// updated, and whenever the UAID changes. It's called while the `PushManager` is locked, so it must not call back
+    /// into the `PushManager` on the same thread.
+    ///
+    /// # Arguments
+    ///   - `observer` - The observer to notify, or `None` to remove the current one
+    pub fn set_observer(&self, observer: Option<Box<dyn PushObserver>>) {
+        self.lock().set_observer(observer)
+    }
+
+    /// Replaces how channel IDs are generated for subscriptions created without
+    /// asking the autopush server, see [`PushConfiguration::offline`]. This isn't
+    /// exposed over FFI.
+    ///
+    /// By default these are random UUIDv4s; tests and replay tools can supply a
+    /// generator which returns predictable ids. Ids should be formatted without
+    /// dashes, like those autopush hands out.
+    ///
+    /// # Arguments
+    ///   - `generator` - The [`ChannelIdGenerator`] to use for new subscriptions
+    pub fn set_channel_id_generator(&self, generator: Box<dyn ChannelIdGenerator>) {
+        self.lock().set_channel_id_generator(generator)
+    }
+
+    /// Returns counts of what this PushManager has done, so apps can report push
+    /// health in their own telemetry. They're only kept in memory, so start from
+    /// zero with each new PushManager.
+    ///
+    /// # Returns
+    /// A [`PushMetrics`] with the counts since the PushManager was created, or since
+    /// [`PushManager::reset_metrics`] was called
+    pub fn metrics_snapshot(&self) -> PushMetrics {
+        self.lock().metrics_snapshot()
+    }
+
+    /// Resets all the counts returned by [`PushManager::metrics_snapshot`] to zero,
+    /// eg after reporting them.
+    pub fn reset_metrics(&self) {
+        self.lock().reset_metrics()
+    }
+
+    /// Compacts the PushManager's persisted storage, reclaiming the space left
+    /// behind by removed subscriptions.
+    ///
+    /// This rewrites the whole database, so it may block other calls on this
+    /// PushManager briefly. It's best called while the app is idle.
+    ///
+    /// # Errors
+    /// Returns an error in the following cases:
+    ///   - An error occurred accessing the PushManager's persisted storage
+    #[handle_error(PushError)]
+    pub fn maintenance(&self) -> ApiResult<()> {
+        self.lock().maintenance()
+    }
+
+    /// Moves the PushManager's persisted storage to `new_path`, eg onto an
+    /// encrypted volume, and carries on using it from there.
+    ///
+    /// The database is copied, including anything not yet checkpointed from its
+    /// WAL, and the original is only removed once the copy has been opened. If
+    /// anything fails before then, the original is still in use and unchanged.
+    ///
+    /// # Arguments
+    ///   - `new_path` - where to move the database to. It mustn't already exist.
+    ///
+    /// # Errors
+    /// Returns an error in the following cases:
+    ///   - Something already exists at `new_path`
+    ///   - The PushManager was created with [`PushManager::with_connection`]
+    ///   - An error occurred copying the database, or opening the copy
+    #[handle_error(PushError)]
+    pub fn relocate_database(&self, new_path: &str) -> ApiResult<()> {
+        self.lock().relocate_database(new_path)
+    }
+
+    /// Checks the PushManager's persisted storage for corruption, and that every
+    /// subscription in it is usable.
+    ///
+    /// Like [`PushManager::maintenance`], this reads the whole database, so is best
+    /// called while the app is idle. If problems are found, the app can recover by
+    /// calling [`PushManager::re_register`] or unsubscribing the invalid channels.
+    ///
+    /// # Returns
+    /// A [`StorageHealth`] listing any problems found
+    ///
+    /// # Errors
+    /// Returns an error in the following cases:
+    ///   - An error occurred accessing the PushManager's persisted storage
+    #[handle_error(PushError)]
+    pub fn verify_storage_integrity(&self) -> ApiResult<StorageHealth> {
+        self.lock().verify_storage_integrity()
+    }
+
+    /// Describes the PushManager's state as JSON, for attaching to bug reports.
+    ///
+    /// The dump includes the configuration (as in [`PushManager::config_summary`]),
+    /// the UAID, each subscription's channel ID, scope and creation time, and when
+    /// the connection was last verified. Only fields known to be safe are copied
+    /// in, so keys, auth secrets and endpoints are never included.
+    ///
+    /// # Returns
+    /// The JSON dump
+    ///
+    /// # Errors
+    /// Returns an error in the following cases:
+    ///   - An error occurred accessing the PushManager's persisted storage
+    #[handle_error(PushError)]
+    pub fn support_dump(&self) -> ApiResult<String> {
+        self.lock().support_dump()
+    }
+
+    /// Checks that a subscription's keys can decrypt a message, by encrypting a
+    /// known message to the subscription's public key and decrypting it again.
+    ///
+    /// This is meant for diagnosing reports of push messages never decrypting. It
+    /// doesn't touch the network or change the PushManager's persisted storage.
+    ///
+    /// # Arguments
+    ///   - `channel_id` - The subscription's ChannelID
+    ///
+    /// # Returns
+    /// true if the message decrypted correctly, false if the subscription's keys
+    /// are missing or corrupt, or the message didn't decrypt
+    ///
+    /// # Errors
+    /// Returns an error in the following cases:
+    ///   - There is no subscription for `channel_id` (`UnknownChannel`)
+    ///   - An error occurred accessing the PushManager's persisted storage
+    #[handle_error(PushError)]
+    pub fn self_test(&self, channel_id: &str) -> ApiResult<bool> {
+        self.lock().self_test(channel_id)
+    }
+
+    /// Gets the UAID (User Agent ID) the autopush server knows us by, eg to
+    /// correlate with server logs when diagnosing problems.
+    ///
+    /// This never registers with the server, even if we haven't done so yet.
+    ///
+    /// # Returns
+    /// The UAID, or `None` if we haven't registered with the server yet
+    ///
+    /// # Errors
+    /// Returns an error in the following cases:
+    ///   - An error occurred accessing the PushManager's persisted storage
+    #[handle_error(PushError)]
+    pub fn get_uaid(&self) -> ApiResult<Option<String>> {
+        self.lock().get_uaid()
+    }
+
+    /// Gets the endpoint of an existing subscription, without the rest of the
+    /// subscription info, eg to share it with the app server again.
+    ///
+    /// # Arguments
+    ///   - `channel_id` - Channel ID (UUID) of the subscription
+    ///
+    /// # Returns
+    /// The endpoint, or `None` if there's no subscription for the channel
+    ///
+    /// # Errors
+    /// Returns an error in the following cases:
+    ///   - An error occurred accessing the PushManager's persisted storage
+    #[handle_error(PushError)]
+    pub fn get_endpoint(&self, channel_id: &str) -> ApiResult<Option<String>> {
+        self.lock().get_endpoint(channel_id)
+    }
+
+    /// Checks whether we have a subscription for a channel id, without loading it.
+    ///
+    /// This is a cheap way to recognize messages for subscriptions that have since
+    /// been removed before trying to decrypt them.
+    ///
+    /// # Arguments
+    ///   - `channel_id` - the ChannelID (included in the envelope of the message)
+    ///
+    /// # Errors
+    /// Returns an error in the following cases:
+    ///   - An error occurred accessing the PushManager's persisted storage
+    #[handle_error(PushError)]
+    pub fn contains_subscription(&self, channel_id: &str) -> ApiResult<bool> {
+        self.lock().contains_subscription(channel_id)
+    }
+
+    /// Returns a page of our subscriptions, oldest first, so a UI can page through
+    /// large numbers of them without loading them all at once.
+    ///
+    /// # Arguments
+    ///   - `offset` - the number of subscriptions to skip
+    ///   - `limit` - the most subscriptions to return
+    ///
+    /// # Returns
+    /// Up to `limit` subscriptions. Fewer than `limit` means there are no more.
+    /// Pages can skip or repeat subscriptions if they're added or removed while paging.
+    ///
+    /// # Errors
+    /// Returns an error in the following cases:
+    ///   - An error occurred accessing the PushManager's persisted storage
+    #[handle_error(PushError)]
+    pub fn get_subscriptions_page(
+        &self,
+        offset: u64,
+        limit: u64,
+    ) -> ApiResult<Vec<SubscriptionResponse>> {
+        self.lock().get_subscriptions_page(offset, limit)
+    }
+
+    /// Checks whether a subscription is locked to a VAPID server key, so apps can
+    /// reject messages that claim to come from a sender the subscription isn't for.
+    ///
+    /// # Arguments
+    ///   - `channel_id` - the ChannelID of the subscription
+    ///   - `server_key` - the base64url-encoded VAPID public key of the sender
+    ///
+    /// # Returns
+    /// Whether the subscription was made with `server_key`. This is false if we
+    /// don't have a subscription for `channel_id`, or it wasn't made with a server key.
+    ///
+    /// # Errors
+    /// Returns an error in the following cases:
+    ///   - An error occurred accessing the PushManager's persisted storage
+    #[handle_error(PushError)]
+    pub fn is_subscribed_with_key(&self, channel_id: &str, server_key: &str) -> ApiResult<bool> {
+        self.lock().is_subscribed_with_key(channel_id, server_key)
+    }
+
+    /// Stores a small piece of app metadata, such as a label, alongside a subscription.
+    /// It's deleted along with the subscription.
+    ///
+    /// # Arguments
+    ///   - `channel_id` - the ChannelID of the subscription
+    ///   - `key` - the name of the metadata
+    ///   - `value` - the value to store, replacing any previous value for `key`
+    ///
+    /// # Errors
+    /// Returns an error in the following cases:
+    ///   - There's no subscription for the channel (`UnknownChannel`)
+    ///   - An error occurred accessing the PushManager's persisted storage
+    #[handle_error(PushError)]
+    pub fn set_subscription_metadata(
+        &self,
+        channel_id: &str,
+        key: &str,
+        value: &str,
+    ) -> ApiResult<()> {
+        self.lock()
+            .set_subscription_metadata(channel_id, key, value)
+    }
+
+    /// Checks whether a message was already seen, so apps can drop messages the
+    /// native push platform redelivers. The first call for a message id records it,
+    /// and later calls return `true`.
+    ///
+    /// Only the newest `seen_message_retention` ids from the [`PushConfiguration`]
+    /// are remembered for each channel. If that isn't set, nothing is remembered
+    /// and this always returns `false`.
+    ///
+    /// # Arguments
+    ///   - `channel_id` - the ChannelID (included in the envelope of the message)
+    ///   - `message_id` - the message's id, as given by the native push platform
+    ///
+    /// # Errors
+    /// Returns an error in the following cases:
+    ///   - An error occurred accessing the PushManager's persisted storage
+    #[handle_error(PushError)]
+    pub fn is_duplicate_message(&self, channel_id: &str, message_id: &str) -> ApiResult<bool> {
+        self.lock().is_duplicate_message(channel_id, message_id)
+    }
+
+    /// Retrieves metadata stored by [`PushManager::set_subscription_metadata`].
+    ///
+    /// # Arguments
+    ///   - `channel_id` - the ChannelID of the subscription
+    ///   - `key` - the name of the metadata
+    ///
+    /// # Returns
+    /// The value, or `None` if nothing was stored under `key` for the subscription
+    ///
+    /// # Errors
+    /// Returns an error in the following cases:
+    ///   - An error occurred accessing the PushManager's persisted storage
+    #[handle_error(PushError)]
+    pub fn get_subscription_metadata(
+        &self,
+        channel_id: &str,
+        key: &str,
+    ) -> ApiResult<Option<String>> {
+        self.lock().get_subscription_metadata(channel_id, key)
     }
 
     /// Decrypts a raw push message.
@@ -363,21 +1171,136 @@ impl PushManager {
     ///   - `encoding` - The Content Encoding "enc" field of the message (defaults to "aes128gcm")
     ///   - `salt` - The "salt" field (if present in the raw message, defaults to "")
     ///   - `dh` - The "dh" field (if present in the raw message, defaults to "")
+    ///   - `ttl` - The "ttl" field (if the bridge forwarded the message's TTL header)
+    ///   - `urgency` - The "urgency" field (if the bridge forwarded the message's Urgency header)
     ///
     /// # Returns
     /// Decrypted message body as a signed byte array
     /// they byte array is signed to allow consumers (Kotlin only at the time of this documentation)
     /// to work easily with the message. (They can directly call `.toByteArray` on it)
+    /// along with the message's TTL and urgency, when they were present in the payload
     ///
     /// # Errors
     /// Returns an error in the following cases:
-    ///   - The PushManager does not contain a valid UAID
-    ///   - There are no records associated with the UAID the [`PushManager`] contains
-    ///   - An error occurred while decrypting the message
+    ///   - The message is for a channel we have no subscription for (`UnknownChannel`)
+    ///   - The subscription was unsubscribed within the configured `tombstone_retention`
+    ///   - A field of the message is missing or malformed (`MalformedInput`)
+    ///   - The stored keys for the subscription are missing (`RecordMissingKeys`) or corrupt
+    ///   - The message couldn't be decrypted with the subscription's keys (`DecryptionFailed`),
+    ///     or with its new keys after `rotate_keys` (`KeysRotated`)
+    ///   - The decrypted message's padding is invalid (`InvalidPadding`)
     ///   - An error occurred accessing the PushManager's persisted storage
     #[handle_error(PushError)]
     pub fn decrypt(&self, payload: HashMap<String, String>) -> ApiResult<DecryptResponse> {
-        self.internal.lock().unwrap().decrypt(payload)
+        self.lock().decrypt(payload)
+    }
+
+    /// Decrypts a raw push message like [`PushManager::decrypt`], but returns the
+    /// subscription's scope as `None` rather than "" when it doesn't have one.
+    ///
+    /// # Arguments:
+    ///   - `payload` - The Push payload, as accepted by [`PushManager::decrypt`]
+    ///
+    /// # Returns
+    /// A [`DecryptedMessage`] with the decrypted body, the subscription's scope if it
+    /// has one, and the message's TTL and urgency when they were present in the payload
+    ///
+    /// # Errors
+    /// Returns an error in the same cases as [`PushManager::decrypt`]
+    #[handle_error(PushError)]
+    pub fn decrypt_message(&self, payload: HashMap<String, String>) -> ApiResult<DecryptedMessage> {
+        self.lock().decrypt_message(payload)
+    }
+
+    /// Decrypts a raw push message like [`PushManager::decrypt`], but first checks the
+    /// message's `chid` is the channel the caller expects it to be for, to catch
+    /// messages routed to the wrong subscription.
+    ///
+    /// # Arguments:
+    ///   - `channel_id` - The ChannelID the caller expects the message to be for
+    ///   - `payload` - The Push payload, as accepted by [`PushManager::decrypt`]
+    ///
+    /// # Returns
+    /// The same as [`PushManager::decrypt`]
+    ///
+    /// # Errors
+    /// Returns an error in the same cases as [`PushManager::decrypt`], and also:
+    ///   - The message's `chid` isn't `channel_id` (see `PushApiError::ChannelMismatch`)
+    #[handle_error(PushError)]
+    pub fn decrypt_for_channel(
+        &self,
+        channel_id: &str,
+        payload: HashMap<String, String>,
+    ) -> ApiResult<DecryptResponse> {
+        self.lock().decrypt_for_channel(channel_id, payload)
+    }
+
+    /// Decrypts a raw push message whose fields have already been base64 decoded.
+    ///
+    /// Some native bridges deliver the message as bytes; this saves re-encoding them
+    /// just to pass them to [`PushManager::decrypt`].
+    /// # Arguments:
+    ///   - `channel_id` - the ChannelID (included in the envelope of the message)
+    ///   - `body` - The encrypted body of the message
+    ///   - `encoding` - The Content Encoding of the message, "aes128gcm" or "aesgcm"
+    ///   - `salt` - The decoded "salt" value for "aesgcm" messages, empty otherwise
+    ///   - `dh` - The decoded "dh" value for "aesgcm" messages, empty otherwise
+    ///
+    /// # Returns
+    /// The same response as [`PushManager::decrypt`]
+    ///
+    /// # Errors
+    /// Returns an error in the same cases as [`PushManager::decrypt`]
+    #[handle_error(PushError)]
+    pub fn decrypt_bytes(
+        &self,
+        channel_id: &str,
+        body: &[u8],
+        encoding: &str,
+        salt: &[u8],
+        dh: &[u8],
+    ) -> ApiResult<DecryptResponse> {
+        self.lock()
+            .decrypt_bytes(channel_id, body, encoding, salt, dh)
+    }
+
+    /// Decrypts a raw push message delivered as a single JSON envelope.
+    ///
+    /// The envelope contains the "chid", "con", "enc", "crypto-key" and "body" fields
+    /// described above, either intermingled with other data fields or as a sub-hash.
+    /// # Arguments:
+    ///   - `envelope` - The JSON envelope as received by the client from Push
+    ///
+    /// # Returns
+    /// The same response as [`PushManager::decrypt`]
+    ///
+    /// # Errors
+    /// Returns an error in the following cases:
+    ///   - The envelope isn't JSON, or doesn't contain the push message fields
+    ///   - Any of the reasons [`PushManager::decrypt`] can fail
+    #[handle_error(PushError)]
+    pub fn decrypt_envelope(&self, envelope: &str) -> ApiResult<DecryptResponse> {
+        self.lock().decrypt_envelope(envelope)
+    }
+
+    /// Decrypts a batch of raw push messages, such as the backlog delivered when a
+    /// device comes back online.
+    ///
+    /// This is equivalent to calling [`PushManager::decrypt`] for each message, but
+    /// only takes the lock and loads each subscription once for the whole batch.
+    /// # Arguments:
+    ///   - `payloads` - The Push payloads as received by the client from Push, in the
+    ///     same format accepted by [`PushManager::decrypt`]
+    ///
+    /// # Returns
+    /// One [`BatchDecryptResult`] per payload, in the same order. A message that can't
+    /// be decrypted is reported as a failure without affecting the rest of the batch.
+    pub fn decrypt_batch(&self, payloads: Vec<HashMap<String, String>>) -> Vec<BatchDecryptResult> {
+        self.lock()
+            .decrypt_batch(payloads)
+            .into_iter()
+            .map(Into::into)
+            .collect()
     }
 }
 
@@ -387,6 +1310,25 @@ pub struct KeyInfo {
     pub auth: String,
     pub p256dh: String,
 }
+
+impl KeyInfo {
+    /// The raw bytes of the base64url encoded `auth` secret
+    pub fn auth_bytes(&self) -> Result<Vec<u8>, PushError> {
+        Ok(URL_SAFE_NO_PAD.decode(&self.auth)?)
+    }
+
+    /// The raw bytes of the base64url encoded `p256dh` public key
+    pub fn p256dh_bytes(&self) -> Result<Vec<u8>, PushError> {
+        Ok(URL_SAFE_NO_PAD.decode(&self.p256dh)?)
+    }
+}
+
+/// Keys for a subscription generated ahead of time by [`PushManager::prepare_keys`].
+/// The private key stays in Rust.
+pub struct PreparedKeys {
+    key: internal::crypto::Key,
+}
+
 /// Subscription Information, the endpoint to send push messages to and
 /// the key information that can be used to encrypt payloads
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
@@ -402,12 +1344,196 @@ pub struct SubscriptionResponse {
     pub subscription_info: SubscriptionInfo,
 }
 
+/// The effective autopush configuration of a [`PushManager`], for confirming which
+/// server a device is talking to. Secrets, such as proxy passwords and the values
+/// of `extra_headers`, are left out.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigSummary {
+    pub server_host: String,
+    pub http_protocol: PushHttpProtocol,
+    /// The bridge currently in use, which may have changed since construction
+    /// through [`PushManager::set_bridge_type`]
+    pub bridge_type: BridgeType,
+    /// The url all autopush requests are made relative to, including the API version
+    pub api_base_url: String,
+    pub sender_id: String,
+    /// The proxy requests go through, with any password redacted
+    pub proxy_url: Option<String>,
+    /// The names of the `extra_headers` sent with every request
+    pub extra_header_names: Vec<String>,
+    pub offline: bool,
+}
+
+/// How our channels compare with the ones the autopush server knows about, as
+/// reported by [`PushManager::connection_diff`]. Autopush only reports channel IDs,
+/// not their endpoints, so a channel on both sides is assumed to match.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionDiff {
+    /// Whether the server still knows our UAID. If not, it has none of our channels.
+    pub uaid_recognized: bool,
+    /// The channel IDs only the server knows about
+    pub server_only: Vec<String>,
+    /// The channel IDs only we know about
+    pub local_only: Vec<String>,
+}
+
+/// Counts of what a [`PushManager`] has done since it was created, or since
+/// [`PushManager::reset_metrics`] was last called, see [`PushManager::metrics_snapshot`].
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct PushMetrics {
+    /// New subscriptions, not including existing ones returned by `subscribe`
+    pub subscriptions_created: u64,
+    /// Subscriptions removed by any of the unsubscribe methods
+    pub unsubscribes: u64,
+    /// Messages decrypted
+    pub decrypt_successes: u64,
+    /// Messages for one of our subscriptions which couldn't be decrypted. Messages
+    /// for unknown channels aren't counted.
+    pub decrypt_failures: u64,
+    /// 4xx responses from autopush
+    pub client_errors: u64,
+    /// 5xx responses from autopush
+    pub server_errors: u64,
+    /// Attempts to send unsubscribes which failed earlier
+    pub retries: u64,
+}
+
+/// The problems found by [`PushManager::verify_storage_integrity`]. The storage is
+/// healthy if both lists are empty.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StorageHealth {
+    /// The problems SQLite found with the database file itself
+    pub integrity_errors: Vec<String>,
+    /// The channel IDs of subscriptions with missing or corrupt keys, or an
+    /// invalid endpoint
+    pub invalid_channel_ids: Vec<String>,
+}
+
+/// The schema versions of a database migrated by [`PushManager::migrate`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MigrationReport {
+    /// The version before migrating, or 0 if the database didn't exist
+    pub from_version: u32,
+    /// The version after migrating
+    pub to_version: u32,
+}
+
 /// An dictionary describing the push subscription that changed, the caller
 /// will receive a list of [`PushSubscriptionChanged`] when calling
 /// [`PushManager::verify_connection`], one entry for each channel that the
 /// caller should resubscribe to
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct PushSubscriptionChanged {
     pub channel_id: String,
     pub scope: String,
 }
+
+/// The outcome of [`PushManager::re_register`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReRegisterResult {
+    /// One for each re-created subscription, with its new channel id
+    pub subscriptions: Vec<PushSubscriptionChanged>,
+    /// The scopes of the subscriptions which couldn't be re-created. They've been
+    /// dropped, as their channels belonged to the old UAID, so need subscribing again.
+    pub failed_scopes: Vec<String>,
+}
+
+/// The outcome of unsubscribing a single channel passed to [`PushManager::unsubscribe_many`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnsubscribeResult {
+    pub channel_id: String,
+    pub unsubscribed: bool,
+}
+
+impl From<(String, bool)> for UnsubscribeResult {
+    fn from((channel_id, unsubscribed): (String, bool)) -> Self {
+        Self {
+            channel_id,
+            unsubscribed,
+        }
+    }
+}
+
+/// An observer that is notified when a push subscription is changed by the
+/// component, see [`PushManager::set_observer`]
+pub trait PushObserver: Send + Sync {
+    fn on_subscription_changed(&self, change: PushSubscriptionChanged);
+
+    /// Called whenever we persist a new UAID, which means every endpoint handed out
+    /// under the `old` one is now invalid. This fires on the first registration, when
+    /// `old` is `None`, during [`PushManager::re_register`], and on the first
+    /// subscription after [`PushManager::verify_connection`] dropped our subscriptions.
+    fn on_uaid_changed(&self, old: Option<String>, new: String);
+}
+
+/// A structured event from the push internals, see [`set_push_logger`].
+/// Events never include decrypted message contents or key material.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PushLogEvent {
+    /// We tried to register with the autopush server to get a new UAID
+    RegistrationAttempt { succeeded: bool },
+    /// A channel got a new endpoint
+    EndpointChanged { channel_id: String, scope: String },
+    /// A message for a channel we know about couldn't be decrypted
+    DecryptFailed { channel_id: String, reason: String },
+}
+
+/// A sink for [`PushLogEvent`]s, see [`set_push_logger`]
+pub trait PushLog: Send + Sync {
+    fn log(&self, event: PushLogEvent);
+}
+
+/// Sets a sink that receives structured events from the push internals, so they
+/// can be captured into the app's own telemetry. This applies to every
+/// [`PushManager`], and replaces any sink that was previously set.
+///
+/// # Arguments
+///   - `sink` - The sink to send events to, or `None` to stop sending events
+pub fn set_push_logger(sink: Option<Box<dyn PushLog>>) {
+    internal::logger::set_push_logger(sink)
+}
+
+/// Extracts the push message fields from a message as delivered by a native push
+/// platform, ready to pass to [`PushManager::decrypt`].
+///
+/// Autopush normally puts the "chid", "con", "enc", "cryptokey" and "body" fields
+/// at the top level of the message, alongside the platform's own metadata, but they
+/// may also be in a sub-hash, which FCM and ADM deliver as a JSON string. Either way,
+/// only the push message fields are returned.
+///
+/// # Arguments
+///   - `bridge_type` - the native platform which delivered the message
+///   - `message` - the message's data, eg the FCM `RemoteMessage.data`
+///
+/// # Errors
+/// Returns an error in the following cases:
+///   - The message doesn't contain the push message fields
+#[handle_error(PushError)]
+pub fn native_message_payload(
+    bridge_type: BridgeType,
+    message: HashMap<String, String>,
+) -> ApiResult<HashMap<String, String>> {
+    internal::push_manager::native_message_payload(bridge_type, &message)
+}
+
+/// Runs any pending schema migrations on the push database, see [`PushManager::migrate`]
+pub fn migrate_push_database(database_path: &str) -> ApiResult<MigrationReport> {
+    PushManager::migrate(database_path)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_recovers_from_poisoned_lock() {
+        let pm = PushManager::new(PushConfiguration::default()).unwrap();
+        let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let _guard = pm.internal.lock().unwrap();
+            panic!("oops");
+        }));
+        assert!(panicked.is_err());
+        assert!(pm.internal.is_poisoned());
+        assert!(pm.get_subscription("scope").unwrap().is_none());
+    }
+}
@@ -0,0 +1,237 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Signs and checks VAPID (RFC 8292) `Authorization` headers, so integration tests
+//! and examples can push a message to their own subscription, as a sender would.
+//!
+//! Sending messages is the sender's job rather than ours, so this is only built
+//! with the `vapid` feature.
+//!
+//! ```rust,ignore
+//! let vapid_key = VapidKey::generate()?;
+//! let subscription = push_manager.subscribe("scope", &Some(vapid_key.server_key()?), &None)?;
+//! let endpoint = subscription.subscription_info.endpoint;
+//! let header = vapid_key.authorization_header(&endpoint, "mailto:me@example.com", Duration::from_secs(60 * 60))?;
+//! // POST the encrypted message to `endpoint`, with `Authorization: {header}`
+//! ```
+
+use std::time::Duration;
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use rc_crypto::signature::{EcdsaKeyPair, UnparsedPublicKey, ECDSA_P256_SHA256};
+use serde::{Deserialize, Serialize};
+use types::Timestamp;
+use url::Url;
+
+use crate::error::{PushError, Result};
+use crate::internal::crypto::validate_server_key;
+
+/// RFC 8292 section 2: the `exp` claim can't be more than 24 hours away.
+const MAX_EXPIRY: Duration = Duration::from_secs(24 * 60 * 60);
+
+const JWT_HEADER: &str = r#"{"typ":"JWT","alg":"ES256"}"#;
+
+#[derive(Debug, Deserialize)]
+struct JwtHeader {
+    alg: String,
+}
+
+/// The claims of a VAPID JWT.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VapidClaims {
+    /// The origin of the push endpoint
+    #[serde(rename = "aud")]
+    pub audience: String,
+    /// When the JWT expires, in seconds since the epoch
+    #[serde(rename = "exp")]
+    pub expires_at: u64,
+    /// How the push service can contact the sender, eg a `mailto:` URL
+    #[serde(rename = "sub", skip_serializing_if = "Option::is_none")]
+    pub subject: Option<String>,
+}
+
+fn vapid_error(message: impl Into<String>) -> PushError {
+    PushError::VapidError(message.into())
+}
+
+fn crypto_error(e: rc_crypto::Error) -> PushError {
+    PushError::CryptoError(e.to_string())
+}
+
+fn audience(endpoint: &str) -> Result<String> {
+    Ok(Url::parse(endpoint)?.origin().ascii_serialization())
+}
+
+fn now_secs() -> u64 {
+    Timestamp::now().as_millis() / 1000
+}
+
+/// A sender's VAPID key pair.
+pub struct VapidKey {
+    key_pair: EcdsaKeyPair,
+}
+
+impl VapidKey {
+    pub fn generate() -> Result<Self> {
+        rc_crypto::ensure_initialized();
+        Ok(Self {
+            key_pair: EcdsaKeyPair::generate(&ECDSA_P256_SHA256).map_err(crypto_error)?,
+        })
+    }
+
+    /// The public key, base64url encoded, to pass as the `server_key` when subscribing.
+    pub fn server_key(&self) -> Result<String> {
+        let public_key = self.key_pair.public_key_bytes().map_err(crypto_error)?;
+        Ok(URL_SAFE_NO_PAD.encode(public_key))
+    }
+
+    /// Returns the value of the `Authorization` header for pushing a message to
+    /// `endpoint`, which is valid for `expires_in`.
+    pub fn authorization_header(
+        &self,
+        endpoint: &str,
+        subject: &str,
+        expires_in: Duration,
+    ) -> Result<String> {
+        if expires_in > MAX_EXPIRY {
+            return Err(vapid_error("expiry is more than 24 hours away"));
+        }
+        let claims = VapidClaims {
+            audience: audience(endpoint)?,
+            expires_at: now_secs() + expires_in.as_secs(),
+            subject: Some(subject.to_string()),
+        };
+        let signing_input = format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(JWT_HEADER),
+            URL_SAFE_NO_PAD.encode(serde_json::to_vec(&claims)?)
+        );
+        let signature = self
+            .key_pair
+            .sign(signing_input.as_bytes())
+            .map_err(crypto_error)?;
+        Ok(format!(
+            "vapid t={}.{}, k={}",
+            signing_input,
+            URL_SAFE_NO_PAD.encode(signature),
+            self.server_key()?
+        ))
+    }
+}
+
+/// Checks a VAPID `Authorization` header for a message sent to `endpoint`, as the
+/// push service would, and that it was signed with `server_key`.
+///
+/// Returns the claims of a valid header.
+pub fn verify_authorization_header(
+    header: &str,
+    endpoint: &str,
+    server_key: &str,
+) -> Result<VapidClaims> {
+    let params = match header.split_once(' ') {
+        Some((scheme, params)) if scheme.eq_ignore_ascii_case("vapid") => params,
+        _ => return Err(vapid_error("not a vapid authorization header")),
+    };
+    let (mut token, mut key) = (None, None);
+    for param in params.split(',') {
+        match param.trim().split_once('=') {
+            Some(("t", value)) => token = Some(value),
+            Some(("k", value)) => key = Some(value),
+            _ => (),
+        }
+    }
+    let token = token.ok_or_else(|| vapid_error("missing t"))?;
+    let key = key.ok_or_else(|| vapid_error("missing k"))?;
+    validate_server_key(key)?;
+    if key.trim_end_matches('=') != server_key.trim_end_matches('=') {
+        return Err(vapid_error("signed with a different key"));
+    }
+
+    let (signing_input, signature) = token
+        .rsplit_once('.')
+        .ok_or_else(|| vapid_error("malformed token"))?;
+    let (jwt_header, claims) = signing_input
+        .split_once('.')
+        .ok_or_else(|| vapid_error("malformed token"))?;
+    let jwt_header: JwtHeader = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(jwt_header)?)?;
+    if jwt_header.alg != "ES256" {
+        return Err(vapid_error(format!("unsupported alg {}", jwt_header.alg)));
+    }
+    let public_key = URL_SAFE_NO_PAD.decode(key.trim_end_matches('='))?;
+    UnparsedPublicKey::new(&ECDSA_P256_SHA256, &public_key)
+        .verify(
+            signing_input.as_bytes(),
+            &URL_SAFE_NO_PAD.decode(signature)?,
+        )
+        .map_err(|_| vapid_error("bad signature"))?;
+
+    let claims: VapidClaims = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(claims)?)?;
+    if claims.audience != audience(endpoint)? {
+        return Err(vapid_error(format!("wrong audience {}", claims.audience)));
+    }
+    let now = now_secs();
+    if claims.expires_at <= now {
+        return Err(vapid_error("expired"));
+    }
+    if claims.expires_at > now + MAX_EXPIRY.as_secs() {
+        return Err(vapid_error("expiry is more than 24 hours away"));
+    }
+    Ok(claims)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const ENDPOINT: &str = "https://updates.push.services.mozilla.com/wpush/v2/abcd";
+    const HOUR: Duration = Duration::from_secs(60 * 60);
+
+    #[test]
+    fn test_round_trip() -> Result<()> {
+        let vapid_key = VapidKey::generate()?;
+        let server_key = vapid_key.server_key()?;
+        validate_server_key(&server_key)?;
+
+        let header = vapid_key.authorization_header(ENDPOINT, "mailto:me@example.com", HOUR)?;
+        let claims = verify_authorization_header(&header, ENDPOINT, &server_key)?;
+        assert_eq!(claims.audience, "https://updates.push.services.mozilla.com");
+        assert_eq!(claims.subject.as_deref(), Some("mailto:me@example.com"));
+        assert!(claims.expires_at > now_secs());
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_failures() -> Result<()> {
+        let vapid_key = VapidKey::generate()?;
+        let server_key = vapid_key.server_key()?;
+        let header = vapid_key.authorization_header(ENDPOINT, "mailto:me@example.com", HOUR)?;
+
+        // A different sender's key.
+        let other_key = VapidKey::generate()?.server_key()?;
+        assert!(verify_authorization_header(&header, ENDPOINT, &other_key).is_err());
+        // A different push service.
+        assert!(
+            verify_authorization_header(&header, "https://example.com/wpush", &server_key).is_err()
+        );
+        // Claims that weren't signed.
+        let (token, key) = header.split_once(", k=").unwrap();
+        let parts: Vec<&str> = token.trim_start_matches("vapid t=").split('.').collect();
+        let forged = VapidClaims {
+            audience: "https://updates.push.services.mozilla.com".to_string(),
+            expires_at: now_secs() + 60,
+            subject: None,
+        };
+        let forged = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&forged)?);
+        let tampered = format!("vapid t={}.{}.{}, k={}", parts[0], forged, parts[2], key);
+        assert!(matches!(
+            verify_authorization_header(&tampered, ENDPOINT, &server_key),
+            Err(PushError::VapidError(_))
+        ));
+        assert!(verify_authorization_header("Bearer abc", ENDPOINT, &server_key).is_err());
+        assert!(vapid_key
+            .authorization_header(ENDPOINT, "mailto:me@example.com", 25 * HOUR)
+            .is_err());
+        Ok(())
+    }
+}
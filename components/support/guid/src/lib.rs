@@ -218,9 +218,17 @@ impl Guid {
                 .all(|b| (b' '..=b'~').contains(&b) && b != b',')
     }
 
+    /// Returns true for Guids in the format Sync clients generate: 12 base64url
+    /// characters, as produced by [`Guid::random`]. The server accepts a wider
+    /// range of ids (see [`Guid::is_valid_for_sync_server`]), so engines which
+    /// want to insist on the standard format can use this to check incoming ones.
+    pub fn is_valid_sync_format(&self) -> bool {
+        self.len() == 12 && self.bytes().all(Guid::is_valid_places_byte)
+    }
+
     /// Returns true for Guids that are valid places guids, and false for all others.
     pub fn is_valid_for_places(&self) -> bool {
-        self.len() == 12 && self.bytes().all(Guid::is_valid_places_byte)
+        self.is_valid_sync_format()
     }
 
     /// Returns true if the byte `b` is a valid base64url byte.
@@ -426,6 +434,19 @@ mod test {
         assert!(!Guid::empty().is_valid_for_places()); // empty isn't valid to insert.
     }
 
+    #[test]
+    fn test_valid_sync_format() {
+        assert!(Guid::from("aaaabbbbcccc").is_valid_sync_format());
+        assert!(Guid::from_slice(b"09_az-AZ_09-").is_valid_sync_format());
+        assert!(!Guid::from("aaaabbbbccccd").is_valid_sync_format()); // too long
+        assert!(!Guid::from("aaaabbbbccc").is_valid_sync_format()); // too short
+        assert!(!Guid::from("aaaabbbb+/cc").is_valid_sync_format()); // standard base64
+        assert!(!Guid::from("{9a8b7c6d-1234-5678-9abc-def012345678}").is_valid_sync_format());
+        // ...but the server is happy with those.
+        assert!(Guid::from("aaaabbbb+/cc").is_valid_for_sync_server());
+        assert!(Guid::from("{9a8b7c6d-1234-5678-9abc-def012345678}").is_valid_for_sync_server());
+    }
+
     #[test]
     fn test_valid_for_sync_server() {
         assert!(!Guid::empty().is_valid_for_sync_server()); // empty isn't valid remotely.
@@ -477,6 +498,7 @@ mod test {
             let g = Guid::random();
             assert_eq!(g.len(), 12);
             assert!(g.is_valid_for_places());
+            assert!(g.is_valid_sync_format());
             let decoded = URL_SAFE_NO_PAD.decode(&g).unwrap();
             assert_eq!(decoded.len(), 9);
             let no_collision = seen.insert(g.clone().into_string());
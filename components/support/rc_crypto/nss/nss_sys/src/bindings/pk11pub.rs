@@ -84,6 +84,14 @@ extern "C" {
         hash: *const SECItem,
         wincx: *mut c_void,
     ) -> SECStatus;
+    pub fn PK11_SignWithMechanism(
+        key: *mut SECKEYPrivateKey,
+        mechanism: CK_MECHANISM_TYPE,
+        param: *const SECItem,
+        sig: *mut SECItem,
+        hash: *const SECItem,
+    ) -> SECStatus;
+    pub fn PK11_SignatureLen(key: *mut SECKEYPrivateKey) -> c_int;
     pub fn PK11_MapSignKeyType(keyType: u32 /* KeyType */) -> CK_MECHANISM_TYPE;
     pub fn PK11_DestroyContext(context: *mut PK11Context, freeit: PRBool);
     pub fn PK11_CreateContextBySymKey(
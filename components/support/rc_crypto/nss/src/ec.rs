@@ -175,6 +175,35 @@ impl PrivateKey {
         self.curve
     }
 
+    /// ECDSA sign operation, returning the signature as the raw `r || s` values
+    /// that [`PublicKey::verify`] accepts.
+    pub fn sign(&self, message: &[u8], hash_algorithm: HashAlgorithm) -> Result<Vec<u8>> {
+        let hash = pk11::context::hash_buf(&hash_algorithm, message)?;
+        let hash = nss_sys::SECItem {
+            len: u32::try_from(hash.len())?,
+            data: hash.as_ptr() as *mut u8,
+            type_: 0,
+        };
+        let signature_len = unsafe { nss_sys::PK11_SignatureLen(self.as_mut_ptr()) };
+        let mut out = vec![0u8; usize::try_from(signature_len)?];
+        let mut signature = nss_sys::SECItem {
+            len: u32::try_from(out.len())?,
+            data: out.as_mut_ptr(),
+            type_: 0,
+        };
+        map_nss_secstatus(|| unsafe {
+            nss_sys::PK11_SignWithMechanism(
+                self.as_mut_ptr(),
+                nss_sys::PK11_MapSignKeyType((*self.wrapped.as_ptr()).keyType),
+                ptr::null(),
+                &mut signature,
+                &hash,
+            )
+        })?;
+        out.truncate(usize::try_from(signature.len)?);
+        Ok(out)
+    }
+
     pub fn private_value(&self) -> Result<Vec<u8>> {
         let mut private_value = self.read_raw_attribute(nss_sys::CKA_VALUE.into()).unwrap();
         let private_key = unsafe { sec_item_as_slice(private_value.as_mut_ref())?.to_vec() };
@@ -19,8 +19,11 @@
 // OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF OR IN
 // CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
 
-use crate::Result;
-use nss::{ec::Curve, ec::PublicKey, pbkdf2::HashAlgorithm};
+use crate::{ErrorKind, Result};
+use nss::{
+    ec::{self, Curve, EcKey, PublicKey},
+    pbkdf2::HashAlgorithm,
+};
 
 /// A signature verification algorithm.
 pub struct VerificationAlgorithm {
@@ -66,6 +69,55 @@ impl<'a> UnparsedPublicKey<'a> {
     }
 }
 
+/// A key pair for creating signatures that [`UnparsedPublicKey::verify`] accepts.
+pub struct EcdsaKeyPair {
+    alg: &'static VerificationAlgorithm,
+    private_key: ec::PrivateKey,
+    public_key: PublicKey,
+}
+
+impl EcdsaKeyPair {
+    /// Generate a new key pair for the given algorithm.
+    pub fn generate(alg: &'static VerificationAlgorithm) -> Result<Self> {
+        let (private_key, public_key) = ec::generate_keypair(alg.curve)?;
+        Ok(Self {
+            alg,
+            private_key,
+            public_key,
+        })
+    }
+
+    pub fn import(alg: &'static VerificationAlgorithm, ec_key: &EcKey) -> Result<Self> {
+        if ec_key.curve() != alg.curve {
+            return Err(ErrorKind::InternalError.into());
+        }
+        let private_key = ec::PrivateKey::import(ec_key)?;
+        let public_key = private_key.convert_to_public_key()?;
+        Ok(Self {
+            alg,
+            private_key,
+            public_key,
+        })
+    }
+
+    pub fn export(&self) -> Result<EcKey> {
+        Ok(self.private_key.export()?)
+    }
+
+    /// The uncompressed public key, as accepted by [`UnparsedPublicKey::new`].
+    pub fn public_key_bytes(&self) -> Result<Vec<u8>> {
+        Ok(self.public_key.to_bytes()?)
+    }
+
+    pub fn sign(&self, message: &[u8]) -> Result<Vec<u8>> {
+        Ok(self.private_key.sign(message, self.alg.digest_alg)?)
+    }
+
+    pub fn algorithm(&self) -> &'static VerificationAlgorithm {
+        self.alg
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -106,4 +158,24 @@ mod tests {
         // Happy case.
         assert!(public_key.verify(&message, &signature).is_ok());
     }
+
+    #[test]
+    fn test_ecdsa_p256_sha256_sign() {
+        let message = b"Amidst the mists and coldest frosts";
+        let key_pair = EcdsaKeyPair::generate(&ECDSA_P256_SHA256).unwrap();
+        let signature = key_pair.sign(message).unwrap();
+        let pub_key_bytes = key_pair.public_key_bytes().unwrap();
+        let public_key = UnparsedPublicKey::new(&ECDSA_P256_SHA256, &pub_key_bytes);
+        assert!(public_key.verify(message, &signature).is_ok());
+        assert!(public_key.verify(b"something else", &signature).is_err());
+
+        // An imported key signs the same as the original.
+        let imported =
+            EcdsaKeyPair::import(&ECDSA_P256_SHA256, &key_pair.export().unwrap()).unwrap();
+        assert_eq!(imported.public_key_bytes().unwrap(), pub_key_bytes);
+        assert!(public_key
+            .verify(message, &imported.sign(message).unwrap())
+            .is_ok());
+        assert!(EcdsaKeyPair::import(&ECDSA_P384_SHA384, &key_pair.export().unwrap()).is_err());
+    }
 }
@@ -27,6 +27,10 @@
 ///      - If the connection is not writable, `finish()` will be called (ie, `finish()`, like
 ///        `prepare()`, is called for all connections)
 ///
+///    - Or, if the connection is managed elsewhere (eg, shared between components), call
+///      init_connection() on it, which does the same as open_database() for a writable
+///      connection, but can't replace a corrupt database since it doesn't own the file.
+///
 ///  See the autofill DB code for an example.
 ///
 use crate::ConnExt;
@@ -124,13 +128,35 @@ fn do_open_database_with_flags<CI: ConnectionInitializer, P: AsRef<Path>>(
     // Try running the migration logic with an existing file
     log::debug!("{}: opening database", CI::NAME);
     let mut conn = Connection::open_with_flags(path, open_flags)?;
+    do_init_connection(
+        &mut conn,
+        open_flags.contains(OpenFlags::SQLITE_OPEN_READ_WRITE),
+        connection_initializer,
+    )?;
+    Ok(conn)
+}
+
+/// Initializes or upgrades the database behind a writable connection which was
+/// opened by the caller, for when the connection is shared with other code.
+pub fn init_connection<CI: ConnectionInitializer>(
+    conn: &mut Connection,
+    connection_initializer: &CI,
+) -> Result<()> {
+    do_init_connection(conn, true, connection_initializer)
+}
+
+fn do_init_connection<CI: ConnectionInitializer>(
+    conn: &mut Connection,
+    writable: bool,
+    connection_initializer: &CI,
+) -> Result<()> {
     log::debug!("{}: checking if initialization is necessary", CI::NAME);
-    let db_empty = is_db_empty(&conn)?;
+    let db_empty = is_db_empty(conn)?;
 
     log::debug!("{}: preparing", CI::NAME);
-    connection_initializer.prepare(&conn, db_empty)?;
+    connection_initializer.prepare(conn, db_empty)?;
 
-    if open_flags.contains(OpenFlags::SQLITE_OPEN_READ_WRITE) {
+    if writable {
         let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
         if db_empty {
             log::debug!("{}: initializing new database", CI::NAME);
@@ -159,14 +185,14 @@ fn do_open_database_with_flags<CI: ConnectionInitializer, P: AsRef<Path>>(
         // writable, so read-only connections do much less, but panic if stuff is wrong
         assert!(!db_empty, "existing writer must have initialized");
         assert!(
-            get_schema_version(&conn)? == CI::END_VERSION,
+            get_schema_version(conn)? == CI::END_VERSION,
             "existing writer must have migrated"
         );
         log::debug!("{}: finishing readonly database open", CI::NAME);
-        connection_initializer.finish(&conn)?;
+        connection_initializer.finish(conn)?;
     }
     log::debug!("{}: database open successful", CI::NAME);
-    Ok(conn)
+    Ok(())
 }
 
 pub fn open_memory_database_with_flags<CI: ConnectionInitializer>(
@@ -452,6 +478,26 @@ mod test {
         connection_initializer.check_calls(vec!["prep", "init", "finish"]);
     }
 
+    #[test]
+    fn test_init_connection() {
+        let connection_initializer = TestConnectionInitializer::new();
+        let mut conn = Connection::open_in_memory().unwrap();
+        init_connection(&mut conn, &connection_initializer).unwrap();
+        check_final_data(&conn);
+        connection_initializer.check_calls(vec!["prep", "init", "finish"]);
+
+        let db_file = MigratedDatabaseFile::new(TestConnectionInitializer::new(), INIT_V2);
+        let mut conn = db_file.open();
+        init_connection(&mut conn, &db_file.connection_initializer).unwrap();
+        check_final_data(&conn);
+        db_file.connection_initializer.check_calls(vec![
+            "prep",
+            "upgrade_from_v2",
+            "upgrade_from_v3",
+            "finish",
+        ]);
+    }
+
     #[test]
     fn test_upgrades() {
         let db_file = MigratedDatabaseFile::new(TestConnectionInitializer::new(), INIT_V2);
@@ -4,13 +4,13 @@
 
 use once_cell::sync::Lazy;
 use std::{io::Read, sync::Once};
-use viaduct::{settings::GLOBAL_SETTINGS, Backend};
+use viaduct::{settings::GLOBAL_SETTINGS, Backend, BackendClient, ClientSettings};
 
 // Note: we don't `use` things from reqwest or the viaduct crate because
 // it would be rather confusing given that we have the same name for
 // most things as them.
 
-static CLIENT: Lazy<reqwest::blocking::Client> = Lazy::new(|| {
+fn client_builder() -> reqwest::blocking::ClientBuilder {
     let settings = GLOBAL_SETTINGS.read();
     let mut builder = reqwest::blocking::ClientBuilder::new()
         .timeout(settings.read_timeout)
@@ -30,6 +30,10 @@ static CLIENT: Lazy<reqwest::blocking::Client> = Lazy::new(|| {
     }
     // Note: no cookie or cache support.
     builder
+}
+
+static CLIENT: Lazy<reqwest::blocking::Client> = Lazy::new(|| {
+    client_builder()
         .build()
         .expect("Failed to initialize global reqwest::Client")
 });
@@ -60,43 +64,79 @@ fn into_reqwest(request: viaduct::Request) -> Result<reqwest::blocking::Request,
     Ok(result)
 }
 
+fn send(
+    client: &reqwest::blocking::Client,
+    request: viaduct::Request,
+) -> Result<viaduct::Response, viaduct::Error> {
+    viaduct::note_backend("reqwest (untrusted)");
+    let request_method = request.method;
+    let req = into_reqwest(request)?;
+    let mut resp = client
+        .execute(req)
+        .map_err(|e| viaduct::Error::NetworkError(e.to_string()))?;
+    let status = resp.status().as_u16();
+    let url = resp.url().clone();
+    let mut body = Vec::with_capacity(resp.content_length().unwrap_or_default() as usize);
+    resp.read_to_end(&mut body).map_err(|e| {
+        log::error!("Failed to get body from response: {:?}", e);
+        viaduct::Error::NetworkError(e.to_string())
+    })?;
+    let mut headers = viaduct::Headers::with_capacity(resp.headers().len());
+    for (k, v) in resp.headers() {
+        let val = String::from_utf8_lossy(v.as_bytes()).to_string();
+        let hname = match viaduct::HeaderName::new(k.as_str().to_owned()) {
+            Ok(name) => name,
+            Err(e) => {
+                // Ignore headers with invalid names, since nobody can look for them anyway.
+                log::warn!("Server sent back invalid header name: '{}'", e);
+                continue;
+            }
+        };
+        // Not using Header::new since the error it returns is for request headers.
+        headers.insert_header(viaduct::Header::new_unchecked(hname, val));
+    }
+    Ok(viaduct::Response {
+        request_method,
+        url,
+        status,
+        headers,
+        body,
+    })
+}
+
 pub struct ReqwestBackend;
 impl Backend for ReqwestBackend {
     fn send(&self, request: viaduct::Request) -> Result<viaduct::Response, viaduct::Error> {
-        viaduct::note_backend("reqwest (untrusted)");
-        let request_method = request.method;
-        let req = into_reqwest(request)?;
-        let mut resp = CLIENT
-            .execute(req)
-            .map_err(|e| viaduct::Error::NetworkError(e.to_string()))?;
-        let status = resp.status().as_u16();
-        let url = resp.url().clone();
-        let mut body = Vec::with_capacity(resp.content_length().unwrap_or_default() as usize);
-        resp.read_to_end(&mut body).map_err(|e| {
-            log::error!("Failed to get body from response: {:?}", e);
-            viaduct::Error::NetworkError(e.to_string())
-        })?;
-        let mut headers = viaduct::Headers::with_capacity(resp.headers().len());
-        for (k, v) in resp.headers() {
-            let val = String::from_utf8_lossy(v.as_bytes()).to_string();
-            let hname = match viaduct::HeaderName::new(k.as_str().to_owned()) {
-                Ok(name) => name,
-                Err(e) => {
-                    // Ignore headers with invalid names, since nobody can look for them anyway.
-                    log::warn!("Server sent back invalid header name: '{}'", e);
-                    continue;
-                }
-            };
-            // Not using Header::new since the error it returns is for request headers.
-            headers.insert_header(viaduct::Header::new_unchecked(hname, val));
+        send(&CLIENT, request)
+    }
+
+    fn new_client(
+        &self,
+        settings: &ClientSettings,
+    ) -> Result<Box<dyn BackendClient>, viaduct::Error> {
+        let mut builder = client_builder();
+        if let Some(proxy) = &settings.proxy {
+            // reqwest takes care of any `user:pass@` credentials in the proxy url.
+            let proxy = reqwest::Proxy::all(proxy.clone())
+                .map_err(|e| viaduct::Error::BackendError(e.to_string()))?;
+            builder = builder.proxy(proxy);
         }
-        Ok(viaduct::Response {
-            request_method,
-            url,
-            status,
-            headers,
-            body,
-        })
+        // Connections (and their TLS sessions) are kept alive and reused by later
+        // requests through this client to the same host.
+        if let Some(timeout) = settings.pool_idle_timeout {
+            builder = builder.pool_idle_timeout(timeout);
+        }
+        let client = builder
+            .build()
+            .map_err(|e| viaduct::Error::BackendError(e.to_string()))?;
+        Ok(Box::new(ReqwestClient(client)))
+    }
+}
+
+struct ReqwestClient(reqwest::blocking::Client);
+impl BackendClient for ReqwestClient {
+    fn send(&self, request: viaduct::Request) -> Result<viaduct::Response, viaduct::Error> {
+        send(&self.0, request)
     }
 }
 
@@ -0,0 +1,188 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+#![allow(unknown_lints)]
+#![warn(rust_2018_idioms)]
+
+//! Runs blocking work on a fixed set of background threads, and hands back a
+//! [`BlockingTask`] future for the result. This doesn't depend on any particular
+//! async runtime.
+
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::{mpsc, Arc, Mutex, PoisonError};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// A fixed number of threads that run jobs in the order they were spawned.
+///
+/// The threads exit once the pool is dropped and they've finished any jobs
+/// already spawned on it.
+pub struct WorkerPool {
+    // `mpsc::Sender` isn't `Sync` on all the Rust versions we support.
+    sender: Mutex<mpsc::Sender<Job>>,
+}
+
+impl WorkerPool {
+    /// Starts a pool with `threads` threads (at least one), named after `name`.
+    ///
+    /// A pool with a single thread runs its jobs one at a time, which suits
+    /// wrapping an API that isn't safe to call concurrently anyway.
+    pub fn new(name: &str, threads: usize) -> Self {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for i in 0..threads.max(1) {
+            let receiver = receiver.clone();
+            thread::Builder::new()
+                .name(format!("{}-{}", name, i))
+                .spawn(move || loop {
+                    // Only hold the lock while waiting, so the others can pick up jobs
+                    // while we run this one.
+                    let job = receiver
+                        .lock()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .recv();
+                    match job {
+                        Ok(job) => job(),
+                        Err(_) => break,
+                    }
+                })
+                .expect("failed to spawn worker thread");
+        }
+        Self {
+            sender: Mutex::new(sender),
+        }
+    }
+
+    /// Runs `f` on one of the pool's threads.
+    pub fn spawn<T: Send + 'static>(
+        &self,
+        f: impl FnOnce() -> T + Send + 'static,
+    ) -> BlockingTask<T> {
+        let state = Arc::new(Mutex::new(TaskState {
+            result: None,
+            waker: None,
+        }));
+        let job_state = state.clone();
+        let job: Job = Box::new(move || {
+            // If `f` panics, we hand the panic to whoever is awaiting us rather than
+            // leaving them waiting forever, and keep the thread for the next job.
+            let result = panic::catch_unwind(AssertUnwindSafe(f));
+            let mut state = job_state.lock().unwrap_or_else(PoisonError::into_inner);
+            state.result = Some(result);
+            if let Some(waker) = state.waker.take() {
+                waker.wake();
+            }
+        });
+        // The threads only stop once we're dropped, so they're always listening.
+        self.sender
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .send(job)
+            .expect("worker threads have exited");
+        BlockingTask { state }
+    }
+}
+
+/// A future that resolves with the result of work running on a [`WorkerPool`].
+///
+/// Dropping it doesn't cancel the work.
+pub struct BlockingTask<T> {
+    state: Arc<Mutex<TaskState<T>>>,
+}
+
+struct TaskState<T> {
+    result: Option<thread::Result<T>>,
+    waker: Option<Waker>,
+}
+
+impl<T> Future for BlockingTask<T> {
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        match state.result.take() {
+            Some(Ok(result)) => Poll::Ready(result),
+            Some(Err(panic)) => panic::resume_unwind(panic),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::task::Wake;
+
+    struct ThreadWaker(thread::Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    // The simplest executor that works, so we don't need a runtime to test with.
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = Box::pin(future);
+        let waker: Waker = Arc::new(ThreadWaker(thread::current())).into();
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            match future.as_mut().poll(&mut cx) {
+                Poll::Ready(output) => return output,
+                Poll::Pending => thread::park(),
+            }
+        }
+    }
+
+    #[test]
+    fn test_single_thread() {
+        let pool = WorkerPool::new("test", 1);
+        let caller = thread::current().id();
+        let ids: Vec<_> = (0..10)
+            .map(|_| pool.spawn(|| thread::current().id()))
+            .collect::<Vec<_>>()
+            .into_iter()
+            .map(block_on)
+            .collect();
+        assert!(ids.iter().all(|id| *id == ids[0] && *id != caller));
+    }
+
+    #[test]
+    fn test_bounded() {
+        let pool = WorkerPool::new("test", 2);
+        let running = Arc::new(AtomicUsize::new(0));
+        let most = Arc::new(AtomicUsize::new(0));
+        let tasks: Vec<_> = (0..8)
+            .map(|_| {
+                let running = running.clone();
+                let most = most.clone();
+                pool.spawn(move || {
+                    let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    most.fetch_max(now, Ordering::SeqCst);
+                    thread::sleep(std::time::Duration::from_millis(10));
+                    running.fetch_sub(1, Ordering::SeqCst);
+                })
+            })
+            .collect();
+        tasks.into_iter().for_each(block_on);
+        assert!(most.load(Ordering::SeqCst) <= 2);
+    }
+
+    #[test]
+    fn test_panic() {
+        let pool = WorkerPool::new("test", 1);
+        let task = pool.spawn(|| -> () { panic!("oh no") });
+        assert!(panic::catch_unwind(AssertUnwindSafe(|| block_on(task))).is_err());
+        // The thread survives to run the next job.
+        assert_eq!(block_on(pool.spawn(|| 1 + 1)), 2);
+    }
+}
@@ -0,0 +1,142 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Support for gzip-compressing cleartext payloads before they're encrypted.
+//!
+//! A compressed payload is a JSON object with a single `__gzip__` field, holding
+//! the base64-encoded, gzipped cleartext. Anything else is treated as an
+//! uncompressed payload, so compressed and uncompressed records can be mixed in
+//! the same collection while clients are being updated.
+
+use super::{IncomingBso, OutgoingBso, OutgoingEnvelope};
+use crate::EnvelopeError;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CompressedPayload {
+    #[serde(rename = "__gzip__")]
+    gzip: String,
+}
+
+impl OutgoingBso {
+    /// Like `OutgoingBso::new`, but compresses the payload.
+    pub fn new_compressed<T: Serialize>(
+        envelope: OutgoingEnvelope,
+        val: &T,
+    ) -> crate::Result<Self> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(serde_json::to_string(val)?.as_bytes())?;
+        let payload = serde_json::to_string(&CompressedPayload {
+            gzip: STANDARD.encode(encoder.finish()?),
+        })?;
+        Ok(Self { envelope, payload })
+    }
+}
+
+impl IncomingBso {
+    /// Returns true if the payload was compressed by `OutgoingBso::new_compressed`.
+    pub fn is_compressed(&self) -> bool {
+        self.compressed_payload().is_some()
+    }
+
+    // We can't tell from the text alone, as other serializers may not put
+    // `__gzip__` first, and an uncompressed payload may have a field by that name.
+    fn compressed_payload(&self) -> Option<CompressedPayload> {
+        serde_json::from_str(&self.payload).ok()
+    }
+
+    /// Replaces a compressed payload with the cleartext, failing if the
+    /// cleartext is larger than `max_bytes`. Uncompressed payloads are left
+    /// alone. `into_content` calls this, so most engines never need to.
+    pub fn decompress(&mut self, max_bytes: usize) -> crate::Result<()> {
+        let compressed = match self.compressed_payload() {
+            Some(compressed) => compressed,
+            None => return Ok(()),
+        };
+        let bytes = STANDARD.decode(compressed.gzip)?;
+        // Don't let a tiny payload which inflates to something huge get past
+        // the size limit - we stop reading once we know it's too big, so the
+        // size reported is only a lower bound.
+        let mut cleartext = String::new();
+        GzDecoder::new(bytes.as_slice())
+            .take(max_bytes as u64 + 1)
+            .read_to_string(&mut cleartext)?;
+        if cleartext.len() > max_bytes {
+            return Err(EnvelopeError::PayloadTooLarge(cleartext.len(), max_bytes).into());
+        }
+        self.payload = cleartext;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bso::{IncomingEnvelope, IncomingKind, DEFAULT_MAX_PAYLOAD_BYTES};
+    use crate::{Guid, ServerTimestamp};
+    use serde_json::json;
+
+    fn incoming(outgoing: OutgoingBso) -> IncomingBso {
+        IncomingBso::new(
+            IncomingEnvelope {
+                id: outgoing.envelope.id,
+                modified: ServerTimestamp::default(),
+                sortindex: None,
+                ttl: None,
+            },
+            outgoing.payload,
+        )
+    }
+
+    #[test]
+    fn test_compressed_roundtrip() {
+        let val = json!({"id": "test", "data": "x".repeat(1000)});
+        let outgoing = OutgoingBso::new_compressed(Guid::new("test").into(), &val).unwrap();
+        assert!(outgoing.payload.len() < 1000);
+        let incoming = incoming(outgoing);
+        assert!(incoming.is_compressed());
+        let content = incoming.into_content::<serde_json::Value>();
+        assert!(matches!(content.kind, IncomingKind::Content(v) if v == val));
+    }
+
+    #[test]
+    fn test_mixed_payloads() {
+        // An uncompressed record is left alone, even if it has a field with our name.
+        let val = json!({"id": "test", "__gzip__": "not really"});
+        let mut incoming = incoming(OutgoingBso::new(Guid::new("test").into(), &val).unwrap());
+        assert!(!incoming.is_compressed());
+        incoming.decompress(DEFAULT_MAX_PAYLOAD_BYTES).unwrap();
+        let content = incoming.into_content::<serde_json::Value>();
+        assert!(matches!(content.kind, IncomingKind::Content(v) if v == val));
+    }
+
+    #[test]
+    fn test_compressed_whitespace() {
+        // Another client's serializer needn't format the payload like we do.
+        let val = json!({"id": "test"});
+        let outgoing = OutgoingBso::new_compressed(Guid::new("test").into(), &val).unwrap();
+        let mut incoming = incoming(outgoing);
+        incoming.payload = incoming.payload.replace(':', " : ");
+        assert!(incoming.is_compressed());
+        let content = incoming.into_content::<serde_json::Value>();
+        assert!(matches!(content.kind, IncomingKind::Content(v) if v == val));
+    }
+
+    #[test]
+    fn test_decompress_too_large() {
+        let val = json!({"id": "test", "data": "x".repeat(1000)});
+        let outgoing = OutgoingBso::new_compressed(Guid::new("test").into(), &val).unwrap();
+        let mut incoming = incoming(outgoing);
+        assert!(matches!(
+            incoming.decompress(100),
+            Err(crate::Error::EnvelopeError(EnvelopeError::PayloadTooLarge(
+                101, 100
+            )))
+        ));
+    }
+}
@@ -9,7 +9,10 @@
 //! * Parse JSON into some <T> while handling tombstones and invalid json.
 //! * Turn arbitrary <T> objects with an `id` field into an OutgoingBso.
 
-use super::{IncomingBso, IncomingContent, IncomingKind, OutgoingBso, OutgoingEnvelope};
+use super::{
+    IncomingBso, IncomingContent, IncomingKind, OutgoingBso, OutgoingEnvelope,
+    DEFAULT_MAX_PAYLOAD_BYTES,
+};
 use crate::Guid;
 use error_support::report_error;
 use serde::Serialize;
@@ -49,9 +52,26 @@ impl IncomingBso {
     /// Like into_content, but adds an additional fixup step where the caller can adjust the
     /// `serde_json::Value'
     pub fn into_content_with_fixup<T: for<'de> serde::Deserialize<'de>>(
-        self,
+        #[allow(unused_mut)] mut self,
         fixup: impl FnOnce(&mut serde_json::Value),
     ) -> IncomingContent<T> {
+        if let Err(e) = self.check_payload_size(DEFAULT_MAX_PAYLOAD_BYTES) {
+            log::warn!("Invalid incoming cleartext {}: {}", self.envelope.id, e);
+            report_error!("incoming-payload-too-large", "{}", e);
+            return IncomingContent {
+                envelope: self.envelope,
+                kind: IncomingKind::Malformed,
+            };
+        }
+        #[cfg(feature = "compression")]
+        if let Err(e) = self.decompress(DEFAULT_MAX_PAYLOAD_BYTES) {
+            log::warn!("Invalid compressed cleartext {}: {}", self.envelope.id, e);
+            report_error!("incoming-invalid-compressed-payload", "{}", e);
+            return IncomingContent {
+                envelope: self.envelope,
+                kind: IncomingKind::Malformed,
+            };
+        }
         match serde_json::from_str(&self.payload) {
             Ok(mut json) => {
                 // We got a good serde_json::Value, run the fixup method
@@ -112,6 +132,52 @@ impl OutgoingBso {
             payload: serde_json::to_string(&json)?,
         })
     }
+
+    /// Starts building an outgoing record from some <T> with a valid `id`, like
+    /// `from_content_with_id`, but allowing the `sortindex` and `ttl` to be
+    /// set on the envelope.
+    pub fn builder<T: Serialize>(record: T) -> OutgoingBsoBuilder<T> {
+        OutgoingBsoBuilder {
+            record,
+            sortindex: None,
+            ttl: None,
+        }
+    }
+}
+
+/// Builds an [OutgoingBso] with envelope metadata - see [OutgoingBso::builder].
+pub struct OutgoingBsoBuilder<T> {
+    record: T,
+    sortindex: Option<i32>,
+    ttl: Option<u32>,
+}
+
+impl<T: Serialize> OutgoingBsoBuilder<T> {
+    pub fn sortindex(mut self, sortindex: i32) -> Self {
+        self.sortindex = Some(sortindex);
+        self
+    }
+
+    /// The number of seconds the server should keep the record for.
+    pub fn ttl(mut self, ttl: u32) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    /// Will panic if there's no good `id` in the json, like
+    /// [OutgoingBso::from_content_with_id].
+    pub fn build(self) -> Result<OutgoingBso> {
+        let (json, id) = content_with_id_to_json(self.record)?;
+        Ok(OutgoingBso {
+            // `content_with_id_to_json` has already checked the id.
+            envelope: OutgoingEnvelope {
+                id,
+                sortindex: self.sortindex,
+                ttl: self.ttl,
+            },
+            payload: serde_json::to_string(&json)?,
+        })
+    }
 }
 
 // Helpers for packing and unpacking serde objects to and from a <T>. In particular:
@@ -269,6 +335,24 @@ mod tests {
         assert_eq!(record, expected);
     }
 
+    #[test]
+    fn test_content_deser_too_large() {
+        env_logger::try_init().ok();
+        let json = json!({
+            "id": "test",
+            "payload": json!({"data": 1, "padding": "x".repeat(DEFAULT_MAX_PAYLOAD_BYTES)}).to_string(),
+        });
+        let incoming: IncomingBso = serde_json::from_value(json).unwrap();
+        assert!(matches!(
+            incoming.check_payload_size(DEFAULT_MAX_PAYLOAD_BYTES),
+            Err(crate::EnvelopeError::PayloadTooLarge(size, DEFAULT_MAX_PAYLOAD_BYTES))
+                if size > DEFAULT_MAX_PAYLOAD_BYTES
+        ));
+        assert!(incoming.check_payload_size(usize::MAX).is_ok());
+        let content = incoming.into_content::<TestStruct>();
+        assert!(matches!(content.kind, IncomingKind::Malformed));
+    }
+
     #[test]
     fn test_content_deser_empty_id() {
         env_logger::try_init().ok();
@@ -350,6 +434,37 @@ mod tests {
         assert_eq!(ct_value, json!({"data": 1, "id": "test"}));
     }
 
+    #[test]
+    fn test_content_ser_with_builder() {
+        env_logger::try_init().ok();
+        let val = TestStruct {
+            id: Guid::new("test"),
+            data: 1,
+        };
+        let outgoing = OutgoingBso::builder(val)
+            .sortindex(100)
+            .ttl(3600)
+            .build()
+            .unwrap();
+        assert_eq!(outgoing.envelope.id, Guid::new("test"));
+
+        // The metadata should end up in the serialized envelope, but not the payload.
+        let value = serde_json::to_value(&outgoing).unwrap();
+        assert_eq!(value["sortindex"], json!(100));
+        assert_eq!(value["ttl"], json!(3600));
+        let ct_value = serde_json::from_str::<serde_json::Value>(&outgoing.payload).unwrap();
+        assert_eq!(ct_value, json!({"data": 1, "id": "test"}));
+
+        // And when there's no metadata, it's not serialized at all.
+        let val = TestStruct {
+            id: Guid::new("test"),
+            data: 1,
+        };
+        let value = serde_json::to_value(OutgoingBso::builder(val).build().unwrap()).unwrap();
+        assert!(value.get("sortindex").is_none());
+        assert!(value.get("ttl").is_none());
+    }
+
     #[test]
     #[should_panic]
     fn test_content_ser_no_ids() {
@@ -71,7 +71,7 @@
 ///
 /// Given our definitions above, these are not any kind of "bso", so are
 /// not relevant to this module
-use crate::{Guid, ServerTimestamp};
+use crate::{EnvelopeError, Guid, ServerTimestamp};
 use serde::{Deserialize, Serialize};
 
 #[cfg(feature = "crypto")]
@@ -79,17 +79,26 @@ mod crypto;
 #[cfg(feature = "crypto")]
 pub use crypto::{IncomingEncryptedBso, OutgoingEncryptedBso};
 
+#[cfg(feature = "compression")]
+mod compression;
+
 mod content;
+pub use content::OutgoingBsoBuilder;
 
 // A feature for this would be ideal, but (a) the module is small and (b) it
 // doesn't really fit the "features" model for sync15 to have a dev-dependency
 // against itself but with a different feature set.
 pub mod test_utils;
 
+/// The largest cleartext payload we'll try to parse by default, matching the
+/// limit the server places on BSO payloads. This stops a corrupt or malicious
+/// record from forcing a huge allocation while we parse it.
+pub const DEFAULT_MAX_PAYLOAD_BYTES: usize = 256 * 1024;
+
 /// An envelope for an incoming item. Envelopes carry all the metadata for
 /// a Sync BSO record (`id`, `modified`, `sortindex`), *but not* the payload
 /// itself.
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct IncomingEnvelope {
     /// The ID of the record.
     pub id: Guid,
@@ -97,14 +106,16 @@ pub struct IncomingEnvelope {
     // XXX - we should probably fix the tests and kill this?
     #[serde(default = "ServerTimestamp::default")]
     pub modified: ServerTimestamp,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub sortindex: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub ttl: Option<u32>,
 }
 
 /// An envelope for an outgoing item. This is conceptually identical to
 /// [IncomingEnvelope], but omits fields that are only set by the server,
 /// like `modified`.
-#[derive(Debug, Default, Clone, Serialize)]
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct OutgoingEnvelope {
     /// The ID of the record.
     pub id: Guid,
@@ -114,6 +125,25 @@ pub struct OutgoingEnvelope {
     pub ttl: Option<u32>,
 }
 
+impl OutgoingEnvelope {
+    /// Creates an envelope, checking the ID is one the server will accept.
+    pub fn new(id: Guid, sortindex: Option<i32>, ttl: Option<u32>) -> Result<Self, EnvelopeError> {
+        let problem = if id.is_empty() {
+            Some("it's empty")
+        } else if id.bytes().any(|b| b.is_ascii_whitespace()) {
+            Some("it contains whitespace")
+        } else if !id.is_valid_for_sync_server() {
+            Some("it's too long or contains invalid characters")
+        } else {
+            None
+        };
+        match problem {
+            Some(problem) => Err(EnvelopeError::InvalidGuid(id.into_string(), problem)),
+            None => Ok(Self { id, sortindex, ttl }),
+        }
+    }
+}
+
 /// Allow an outgoing envelope to be constructed with just a guid when default
 /// values for the other fields are OK.
 impl From<Guid> for OutgoingEnvelope {
@@ -130,7 +160,7 @@ impl From<Guid> for OutgoingEnvelope {
 /// * From environments where the encryption is done externally (eg, Rust syncing in Desktop
 ///   Firefox has the encryption/decryption done by Firefox and the cleartext BSOs are passed in.
 /// * Read from the server as an EncryptedBso; see EncryptedBso description above.
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct IncomingBso {
     #[serde(flatten)]
     pub envelope: IncomingEnvelope,
@@ -143,9 +173,51 @@ impl IncomingBso {
     pub fn new(envelope: IncomingEnvelope, payload: String) -> Self {
         Self { envelope, payload }
     }
+
+    /// Checks the payload is no larger than `max_bytes`. `into_content` does
+    /// this with [DEFAULT_MAX_PAYLOAD_BYTES]; engines which need a different
+    /// limit can check before calling it.
+    pub fn check_payload_size(&self, max_bytes: usize) -> Result<(), EnvelopeError> {
+        let size = self.payload.len();
+        if size > max_bytes {
+            return Err(EnvelopeError::PayloadTooLarge(size, max_bytes));
+        }
+        Ok(())
+    }
+
+    /// Parses the cleartext payload as JSON, checking its size against
+    /// [DEFAULT_MAX_PAYLOAD_BYTES] and that any `id` it has agrees with the
+    /// envelope. Unlike `into_content`, which turns all of these into
+    /// [IncomingKind::Malformed], this says what the problem was.
+    pub fn payload_json(&self) -> Result<serde_json::Value, EnvelopeError> {
+        self.check_payload_size(DEFAULT_MAX_PAYLOAD_BYTES)?;
+        let json: serde_json::Value = serde_json::from_str(&self.payload)?;
+        match json.get("id") {
+            Some(serde_json::Value::String(id)) if id != &self.envelope.id => {
+                return Err(EnvelopeError::MismatchedId {
+                    envelope: self.envelope.id.to_string(),
+                    payload: id.clone(),
+                });
+            }
+            Some(serde_json::Value::String(_)) | None => {}
+            Some(v) => {
+                return Err(EnvelopeError::MismatchedId {
+                    envelope: self.envelope.id.to_string(),
+                    payload: v.to_string(),
+                });
+            }
+        }
+        if !self.envelope.id.is_valid_for_sync_server() {
+            return Err(EnvelopeError::InvalidGuid(
+                self.envelope.id.to_string(),
+                "it's too long or contains invalid characters",
+            ));
+        }
+        Ok(json)
+    }
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct OutgoingBso {
     #[serde(flatten)]
     pub envelope: OutgoingEnvelope,
@@ -166,6 +238,23 @@ impl OutgoingBso {
             payload: serde_json::to_string(&val)?,
         })
     }
+
+    /// Return the length of the serialized cleartext payload.
+    pub fn payload_serialized_len(&self) -> usize {
+        self.payload.len()
+    }
+
+    /// Checks the cleartext payload is no larger than `max_bytes`, so engines
+    /// can find oversized records when building them rather than when the
+    /// upload is rejected, and decide whether to split or drop them.
+    /// [DEFAULT_MAX_PAYLOAD_BYTES] matches the server's limit.
+    pub fn check_payload_size(&self, max_bytes: usize) -> Result<(), EnvelopeError> {
+        let size = self.payload_serialized_len();
+        if size > max_bytes {
+            return Err(EnvelopeError::PayloadTooLarge(size, max_bytes));
+        }
+        Ok(())
+    }
 }
 
 /// We also have the concept of "content", which helps work with a `T` which
@@ -202,3 +291,91 @@ pub enum IncomingKind<T> {
     /// Either not JSON, or can't be made into a T.
     Malformed,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_outgoing_envelope_ids() {
+        let envelope = OutgoingEnvelope::new(Guid::new("record-1"), Some(1), Some(60)).unwrap();
+        assert_eq!(envelope.id, "record-1");
+        assert_eq!(envelope.sortindex, Some(1));
+        assert_eq!(envelope.ttl, Some(60));
+
+        let too_long = "X".repeat(65);
+        for id in ["", " ", "record 1", "\trecord", too_long.as_str(), "a,b"] {
+            assert!(
+                matches!(
+                    OutgoingEnvelope::new(Guid::new(id), None, None),
+                    Err(EnvelopeError::InvalidGuid(ref bad, _)) if bad == id
+                ),
+                "{:?} should be invalid",
+                id
+            );
+        }
+    }
+
+    #[test]
+    fn test_outgoing_payload_size() {
+        let bso = OutgoingBso::new(Guid::new("record-1").into(), &"x".repeat(10)).unwrap();
+        // The payload is the JSON string, so includes the quotes.
+        assert_eq!(bso.payload_serialized_len(), 12);
+        assert!(bso.check_payload_size(12).is_ok());
+        assert!(matches!(
+            bso.check_payload_size(11),
+            Err(EnvelopeError::PayloadTooLarge(12, 11))
+        ));
+
+        let big = OutgoingBso::new(
+            Guid::new("record-2").into(),
+            &"x".repeat(DEFAULT_MAX_PAYLOAD_BYTES),
+        )
+        .unwrap();
+        assert!(big.check_payload_size(DEFAULT_MAX_PAYLOAD_BYTES).is_err());
+    }
+
+    #[test]
+    fn test_incoming_payload_json() {
+        let incoming = |id: &str, payload: &str| IncomingBso {
+            envelope: IncomingEnvelope {
+                id: Guid::new(id),
+                modified: ServerTimestamp::default(),
+                sortindex: None,
+                ttl: None,
+            },
+            payload: payload.to_string(),
+        };
+
+        let json = incoming("record-1", r#"{"id": "record-1", "data": 1}"#)
+            .payload_json()
+            .unwrap();
+        assert_eq!(json["data"], 1);
+        assert!(incoming("record-1", r#"{"data": 1}"#)
+            .payload_json()
+            .is_ok());
+
+        assert!(matches!(
+            incoming("record-1", "not json").payload_json(),
+            Err(EnvelopeError::InvalidJson(_))
+        ));
+        assert!(matches!(
+            incoming("record-1", r#"{"id": "record-2"}"#).payload_json(),
+            Err(EnvelopeError::MismatchedId { ref envelope, ref payload })
+                if envelope == "record-1" && payload == "record-2"
+        ));
+        assert!(matches!(
+            incoming("record-1", r#"{"id": 1}"#).payload_json(),
+            Err(EnvelopeError::MismatchedId { .. })
+        ));
+        assert!(matches!(
+            incoming("record 1", "{}").payload_json(),
+            Err(EnvelopeError::InvalidGuid(..))
+        ));
+        let big = format!(r#"{{"data": "{}"}}"#, "x".repeat(DEFAULT_MAX_PAYLOAD_BYTES));
+        assert!(matches!(
+            incoming("record-1", &big).payload_json(),
+            Err(EnvelopeError::PayloadTooLarge(..))
+        ));
+    }
+}
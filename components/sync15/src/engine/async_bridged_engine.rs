@@ -0,0 +1,401 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use anyhow::Result;
+use worker_support::WorkerPool;
+
+use crate::bso::IncomingBso;
+use crate::{CollectionName, Guid};
+
+use super::{
+    AbortSignal, ApplyOptions, ApplyResults, BridgedEngine, ConflictStrategy,
+    EngineStateChangeReason, UploadFailure,
+};
+
+/// The futures returned by an [AsyncBridgedEngine]. They're boxed so the trait
+/// stays object safe.
+pub type BridgedEngineFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T>> + Send + 'a>>;
+
+/// An async version of [BridgedEngine], for consumers that drive engines from
+/// async code and don't want to block their executor.
+///
+/// Each method mirrors the [BridgedEngine] method of the same name, but takes
+/// owned arguments so the returned futures don't need to borrow them.
+/// Engines that can do true async I/O can implement this directly, while any
+/// existing [BridgedEngine] can be used via [BlockingBridgedEngine].
+pub trait AsyncBridgedEngine: Send + Sync {
+    fn collection_name(&self) -> CollectionName;
+
+    fn last_sync(&self) -> BridgedEngineFuture<'_, i64>;
+
+    fn set_last_sync(&self, last_sync_millis: i64) -> BridgedEngineFuture<'_, ()>;
+
+    fn needs_first_sync(&self) -> BridgedEngineFuture<'_, bool>;
+
+    fn sync_id(&self) -> BridgedEngineFuture<'_, Option<String>>;
+
+    fn reset_sync_id(&self) -> BridgedEngineFuture<'_, String>;
+
+    fn ensure_current_sync_id(&self, new_sync_id: String) -> BridgedEngineFuture<'_, String>;
+
+    fn prepare_for_sync(&self, client_data: String) -> BridgedEngineFuture<'_, ()>;
+
+    fn sync_started(&self) -> BridgedEngineFuture<'_, ()>;
+
+    fn preferred_batch_size(&self) -> usize;
+
+    fn store_incoming(
+        &self,
+        incoming_records: Vec<IncomingBso>,
+        signal: AbortSignal,
+    ) -> BridgedEngineFuture<'_, ()>;
+
+    fn store_incoming_with_progress(
+        &self,
+        incoming_records: Vec<IncomingBso>,
+        signal: AbortSignal,
+        progress: Box<dyn FnMut(usize, usize) + Send>,
+    ) -> BridgedEngineFuture<'_, ()>;
+
+    fn expire_staged(&self, older_than_ms: i64) -> BridgedEngineFuture<'_, usize>;
+
+    fn apply(&self, signal: AbortSignal) -> BridgedEngineFuture<'_, ApplyResults>;
+
+    fn apply_with_options(
+        &self,
+        options: ApplyOptions,
+        signal: AbortSignal,
+    ) -> BridgedEngineFuture<'_, ApplyResults>;
+
+    fn supports_conflict_strategy(&self, strategy: ConflictStrategy) -> bool;
+
+    fn resume_apply(
+        &self,
+        checkpoint: String,
+        signal: AbortSignal,
+    ) -> BridgedEngineFuture<'_, Option<ApplyResults>>;
+
+    fn pending_outgoing_count(&self) -> BridgedEngineFuture<'_, usize>;
+
+    fn outgoing_ids(&self) -> BridgedEngineFuture<'_, Vec<Guid>>;
+
+    fn debug_state(&self) -> BridgedEngineFuture<'_, serde_json::Value>;
+
+    fn set_uploaded(
+        &self,
+        server_modified_millis: i64,
+        ids: Vec<Guid>,
+    ) -> BridgedEngineFuture<'_, ()>;
+
+    fn set_uploaded_with_failures(
+        &self,
+        server_modified_millis: i64,
+        ids: Vec<Guid>,
+        failures: Vec<UploadFailure>,
+    ) -> BridgedEngineFuture<'_, ()>;
+
+    fn sync_finished(&self) -> BridgedEngineFuture<'_, ()>;
+
+    fn reset(&self, reason: EngineStateChangeReason) -> BridgedEngineFuture<'_, ()>;
+
+    fn on_collection_wiped(&self) -> BridgedEngineFuture<'_, ()>;
+
+    fn wipe(&self, reason: EngineStateChangeReason) -> BridgedEngineFuture<'_, ()>;
+
+    fn supports_wipe(&self) -> bool;
+}
+
+/// Adapts a synchronous [BridgedEngine] to an [AsyncBridgedEngine] by running
+/// its methods on a background thread, one at a time and in the order they were
+/// called. This doesn't depend on any particular async runtime.
+pub struct BlockingBridgedEngine<E: ?Sized> {
+    engine: Arc<E>,
+    pool: WorkerPool,
+}
+
+impl<E: BridgedEngine + ?Sized + 'static> BlockingBridgedEngine<E> {
+    pub fn new(engine: Arc<E>) -> Self {
+        Self {
+            engine,
+            pool: WorkerPool::new("bridged-engine", 1),
+        }
+    }
+
+    fn spawn<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&E) -> Result<T> + Send + 'static,
+    ) -> BridgedEngineFuture<'static, T> {
+        let engine = self.engine.clone();
+        Box::pin(self.pool.spawn(move || f(&engine)))
+    }
+}
+
+impl<E: BridgedEngine + ?Sized + 'static> AsyncBridgedEngine for BlockingBridgedEngine<E> {
+    fn collection_name(&self) -> CollectionName {
+        self.engine.collection_name()
+    }
+
+    fn last_sync(&self) -> BridgedEngineFuture<'_, i64> {
+        self.spawn(|engine| engine.last_sync())
+    }
+
+    fn set_last_sync(&self, last_sync_millis: i64) -> BridgedEngineFuture<'_, ()> {
+        self.spawn(move |engine| engine.set_last_sync(last_sync_millis))
+    }
+
+    fn needs_first_sync(&self) -> BridgedEngineFuture<'_, bool> {
+        self.spawn(|engine| engine.needs_first_sync())
+    }
+
+    fn sync_id(&self) -> BridgedEngineFuture<'_, Option<String>> {
+        self.spawn(|engine| engine.sync_id())
+    }
+
+    fn reset_sync_id(&self) -> BridgedEngineFuture<'_, String> {
+        self.spawn(|engine| engine.reset_sync_id())
+    }
+
+    fn ensure_current_sync_id(&self, new_sync_id: String) -> BridgedEngineFuture<'_, String> {
+        self.spawn(move |engine| engine.ensure_current_sync_id(&new_sync_id))
+    }
+
+    fn prepare_for_sync(&self, client_data: String) -> BridgedEngineFuture<'_, ()> {
+        self.spawn(move |engine| engine.prepare_for_sync(&client_data))
+    }
+
+    fn sync_started(&self) -> BridgedEngineFuture<'_, ()> {
+        self.spawn(|engine| engine.sync_started())
+    }
+
+    fn preferred_batch_size(&self) -> usize {
+        self.engine.preferred_batch_size()
+    }
+
+    fn store_incoming(
+        &self,
+        incoming_records: Vec<IncomingBso>,
+        signal: AbortSignal,
+    ) -> BridgedEngineFuture<'_, ()> {
+        self.spawn(move |engine| engine.store_incoming(incoming_records, &signal))
+    }
+
+    fn store_incoming_with_progress(
+        &self,
+        incoming_records: Vec<IncomingBso>,
+        signal: AbortSignal,
+        mut progress: Box<dyn FnMut(usize, usize) + Send>,
+    ) -> BridgedEngineFuture<'_, ()> {
+        self.spawn(move |engine| {
+            engine.store_incoming_with_progress(incoming_records, &signal, &mut *progress)
+        })
+    }
+
+    fn expire_staged(&self, older_than_ms: i64) -> BridgedEngineFuture<'_, usize> {
+        self.spawn(move |engine| engine.expire_staged(older_than_ms))
+    }
+
+    fn apply(&self, signal: AbortSignal) -> BridgedEngineFuture<'_, ApplyResults> {
+        self.spawn(move |engine| engine.apply(&signal))
+    }
+
+    fn apply_with_options(
+        &self,
+        options: ApplyOptions,
+        signal: AbortSignal,
+    ) -> BridgedEngineFuture<'_, ApplyResults> {
+        self.spawn(move |engine| engine.apply_with_options(&options, &signal))
+    }
+
+    fn supports_conflict_strategy(&self, strategy: ConflictStrategy) -> bool {
+        self.engine.supports_conflict_strategy(strategy)
+    }
+
+    fn resume_apply(
+        &self,
+        checkpoint: String,
+        signal: AbortSignal,
+    ) -> BridgedEngineFuture<'_, Option<ApplyResults>> {
+        self.spawn(move |engine| engine.resume_apply(&checkpoint, &signal))
+    }
+
+    fn pending_outgoing_count(&self) -> BridgedEngineFuture<'_, usize> {
+        self.spawn(|engine| engine.pending_outgoing_count())
+    }
+
+    fn outgoing_ids(&self) -> BridgedEngineFuture<'_, Vec<Guid>> {
+        self.spawn(|engine| engine.outgoing_ids())
+    }
+
+    fn debug_state(&self) -> BridgedEngineFuture<'_, serde_json::Value> {
+        self.spawn(|engine| engine.debug_state())
+    }
+
+    fn set_uploaded(
+        &self,
+        server_modified_millis: i64,
+        ids: Vec<Guid>,
+    ) -> BridgedEngineFuture<'_, ()> {
+        self.spawn(move |engine| engine.set_uploaded(server_modified_millis, &ids))
+    }
+
+    fn set_uploaded_with_failures(
+        &self,
+        server_modified_millis: i64,
+        ids: Vec<Guid>,
+        failures: Vec<UploadFailure>,
+    ) -> BridgedEngineFuture<'_, ()> {
+        self.spawn(move |engine| {
+            engine.set_uploaded_with_failures(server_modified_millis, &ids, &failures)
+        })
+    }
+
+    fn sync_finished(&self) -> BridgedEngineFuture<'_, ()> {
+        self.spawn(|engine| engine.sync_finished())
+    }
+
+    fn reset(&self, reason: EngineStateChangeReason) -> BridgedEngineFuture<'_, ()> {
+        self.spawn(move |engine| engine.reset(reason))
+    }
+
+    fn on_collection_wiped(&self) -> BridgedEngineFuture<'_, ()> {
+        self.spawn(|engine| engine.on_collection_wiped())
+    }
+
+    fn wipe(&self, reason: EngineStateChangeReason) -> BridgedEngineFuture<'_, ()> {
+        self.spawn(move |engine| engine.wipe(reason))
+    }
+
+    fn supports_wipe(&self) -> bool {
+        self.engine.supports_wipe()
+    }
+}
+
+// A future that resolves with the result of work running on another thread.
+#[cfg(test)]
+mod test {
+    use super::*;
+    use interrupt_support::Interruptee;
+    use std::sync::Mutex;
+    use std::task::{Context, Poll, Wake, Waker};
+    use std::thread;
+
+    struct ThreadWaker(thread::Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    // The simplest executor that works, so we don't need a runtime to test with.
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = Box::pin(future);
+        let waker: Waker = Arc::new(ThreadWaker(thread::current())).into();
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            match future.as_mut().poll(&mut cx) {
+                Poll::Ready(output) => return output,
+                Poll::Pending => thread::park(),
+            }
+        }
+    }
+
+    #[derive(Default)]
+    struct TestEngine {
+        last_sync: Mutex<i64>,
+        uploaded: Mutex<Vec<Guid>>,
+    }
+
+    impl BridgedEngine for TestEngine {
+        fn collection_name(&self) -> CollectionName {
+            "test".into()
+        }
+
+        fn last_sync(&self) -> Result<i64> {
+            Ok(*self.last_sync.lock().unwrap())
+        }
+
+        fn set_last_sync(&self, last_sync_millis: i64) -> Result<()> {
+            *self.last_sync.lock().unwrap() = last_sync_millis;
+            Ok(())
+        }
+
+        fn sync_id(&self) -> Result<Option<String>> {
+            Ok(None)
+        }
+
+        fn reset_sync_id(&self) -> Result<String> {
+            Ok("sync-id".into())
+        }
+
+        fn ensure_current_sync_id(&self, new_sync_id: &str) -> Result<String> {
+            Ok(new_sync_id.into())
+        }
+
+        fn sync_started(&self) -> Result<()> {
+            Ok(())
+        }
+
+        fn store_incoming(
+            &self,
+            _incoming_records: Vec<IncomingBso>,
+            _signal: &AbortSignal,
+        ) -> Result<()> {
+            Ok(())
+        }
+
+        fn apply(&self, signal: &AbortSignal) -> Result<ApplyResults> {
+            signal.err_if_interrupted()?;
+            Ok(ApplyResults::default())
+        }
+
+        fn set_uploaded(&self, _server_modified_millis: i64, ids: &[Guid]) -> Result<()> {
+            self.uploaded.lock().unwrap().extend_from_slice(ids);
+            Ok(())
+        }
+
+        fn sync_finished(&self) -> Result<()> {
+            Ok(())
+        }
+
+        fn reset(&self, _reason: EngineStateChangeReason) -> Result<()> {
+            anyhow::bail!("can't reset")
+        }
+
+        fn wipe(&self, _reason: EngineStateChangeReason) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_blocking_bridged_engine() {
+        let engine = Arc::new(TestEngine::default());
+        let bridged: Box<dyn AsyncBridgedEngine> =
+            Box::new(BlockingBridgedEngine::new(engine.clone()));
+
+        assert_eq!(bridged.collection_name(), "test");
+        assert_eq!(bridged.preferred_batch_size(), 1000);
+        block_on(bridged.set_last_sync(123)).unwrap();
+        assert_eq!(block_on(bridged.last_sync()).unwrap(), 123);
+        assert_eq!(
+            block_on(bridged.ensure_current_sync_id("new-id".into())).unwrap(),
+            "new-id"
+        );
+        block_on(bridged.set_uploaded(456, vec![Guid::new("record-1")])).unwrap();
+        assert_eq!(
+            *engine.uploaded.lock().unwrap(),
+            vec![Guid::new("record-1")]
+        );
+        assert!(block_on(bridged.reset(EngineStateChangeReason::UserRequested)).is_err());
+
+        let signal = AbortSignal::new();
+        assert!(block_on(bridged.apply(signal.clone())).is_ok());
+        signal.abort();
+        assert!(block_on(bridged.apply(signal)).is_err());
+    }
+}
@@ -2,14 +2,23 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
-use crate::{telemetry, ServerTimestamp};
+use crate::{telemetry, CollectionName, ServerTimestamp};
 use anyhow::Result;
+use interrupt_support::Interruptee;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
 use crate::bso::{IncomingBso, OutgoingBso};
 use crate::Guid;
 
 use super::{CollSyncIds, EngineSyncAssociation, SyncEngine};
 
+/// The number of incoming records passed to each `store_incoming` call, unless
+/// the engine asks for something different.
+pub const DEFAULT_PREFERRED_BATCH_SIZE: usize = 1000;
+
 /// A BridgedEngine acts as a bridge between application-services, rust
 /// implemented sync engines and sync engines as defined by Desktop Firefox.
 ///
@@ -20,6 +29,10 @@ use super::{CollSyncIds, EngineSyncAssociation, SyncEngine};
 /// [SyncEngine](crate::SyncEngine) trait), so this BridgedEngine trait adapts
 /// between the 2.
 pub trait BridgedEngine: Send + Sync {
+    /// Returns the name of the Sync collection this engine manages, so
+    /// consumers with several engines can tell them apart.
+    fn collection_name(&self) -> CollectionName;
+
     /// Returns the last sync time, in milliseconds, for this engine's
     /// collection. This is called before each sync, to determine the lower
     /// bound for new records to fetch from the server.
@@ -30,6 +43,15 @@ pub trait BridgedEngine: Send + Sync {
     /// timestamp on the uploaded records.
     fn set_last_sync(&self, last_sync_millis: i64) -> Result<()>;
 
+    /// Returns true if the next sync should be treated as a first sync; for
+    /// example, because the engine has never synced or has been reset since.
+    /// Drivers can use this to fetch the whole collection rather than just the
+    /// records changed since `last_sync`. The default implementation checks
+    /// whether the last sync time is 0, which `reset` should ensure.
+    fn needs_first_sync(&self) -> Result<bool> {
+        Ok(self.last_sync()? == 0)
+    }
+
     /// Returns the sync ID for this engine's collection. This is only used in
     /// tests.
     fn sync_id(&self) -> Result<Option<String>>;
@@ -62,21 +84,217 @@ pub trait BridgedEngine: Send + Sync {
     /// once per sync, and always before `store_incoming`.
     fn sync_started(&self) -> Result<()>;
 
+    /// Returns the maximum number of records the engine would like passed to
+    /// each `store_incoming` call. The sync driver consults this when chunking
+    /// incoming records, so engines with heavy per-record work can ask for
+    /// smaller batches, and engines that want to stage everything in a single
+    /// transaction can ask for larger ones.
+    fn preferred_batch_size(&self) -> usize {
+        DEFAULT_PREFERRED_BATCH_SIZE
+    }
+
     /// Stages a batch of incoming Sync records. This is called multiple
     /// times per sync, once for each batch. Implementations can use the
     /// signal to check if the operation was aborted, and cancel any
     /// pending work.
-    fn store_incoming(&self, incoming_records: Vec<IncomingBso>) -> Result<()>;
+    fn store_incoming(
+        &self,
+        incoming_records: Vec<IncomingBso>,
+        signal: &AbortSignal,
+    ) -> Result<()>;
+
+    /// Like `store_incoming`, but calls `progress` with the number of records
+    /// processed so far and the total in the batch, so the driver can report
+    /// progress through a large sync. Engines which stage records one at a time
+    /// can call it periodically; the default implementation calls
+    /// `store_incoming` and reports the whole batch once it's done.
+    fn store_incoming_with_progress(
+        &self,
+        incoming_records: Vec<IncomingBso>,
+        signal: &AbortSignal,
+        progress: &mut dyn FnMut(usize, usize),
+    ) -> Result<()> {
+        let total = incoming_records.len();
+        self.store_incoming(incoming_records, signal)?;
+        progress(total, total);
+        Ok(())
+    }
+
+    /// Like `store_incoming`, but takes the batch as an iterator, so engines
+    /// applying very large numbers of records can write them as they're
+    /// decrypted instead of holding the whole batch in memory. The default
+    /// implementation collects the records and calls `store_incoming`, so only
+    /// engines that want to stream need to implement it.
+    fn store_incoming_iter(
+        &self,
+        incoming_records: &mut dyn Iterator<Item = IncomingBso>,
+        signal: &AbortSignal,
+    ) -> Result<()> {
+        self.store_incoming(incoming_records.collect(), signal)
+    }
+
+    /// Drops records staged by `store_incoming` more than `older_than_ms`
+    /// milliseconds ago, returning how many were dropped. If a sync is
+    /// interrupted before `apply`, its staged records are abandoned, so
+    /// drivers can call this at the start of a sync to stop them accumulating.
+    /// Engines which stage in temporary tables, or which clear staged records
+    /// in `sync_started`, don't need this; the default implementation does
+    /// nothing.
+    fn expire_staged(&self, _older_than_ms: i64) -> Result<usize> {
+        Ok(0)
+    }
 
     /// Applies all staged records, reconciling changes on both sides and
-    /// resolving conflicts. Returns a list of records to upload.
-    fn apply(&self) -> Result<ApplyResults>;
+    /// resolving conflicts. Returns a list of records to upload. As with
+    /// `store_incoming`, implementations can use the signal to stop early and
+    /// roll back their changes.
+    fn apply(&self, signal: &AbortSignal) -> Result<ApplyResults>;
+
+    /// Like `apply`, but lets the driver override how conflicts are resolved
+    /// and whether records are returned for upload; for example, to force
+    /// remote-wins while recovering from a bad sync. The default implementation
+    /// calls `apply`, and fails for any other strategy than the engine's
+    /// default, so engines which can honor them need to implement this and
+    /// `supports_conflict_strategy` themselves.
+    fn apply_with_options(
+        &self,
+        options: &ApplyOptions,
+        signal: &AbortSignal,
+    ) -> Result<ApplyResults> {
+        if options.conflict_strategy != ConflictStrategy::EngineDefault {
+            anyhow::bail!(
+                "The {} engine doesn't support conflict strategy {:?}",
+                self.collection_name(),
+                options.conflict_strategy
+            );
+        }
+        let mut results = self.apply(signal)?;
+        if !options.produce_outgoing {
+            results.records.clear();
+        }
+        Ok(results)
+    }
+
+    /// Returns true if `apply_with_options` honors `strategy`. Drivers should
+    /// check this before asking for anything other than
+    /// `ConflictStrategy::EngineDefault`, which every engine supports.
+    fn supports_conflict_strategy(&self, strategy: ConflictStrategy) -> bool {
+        strategy == ConflictStrategy::EngineDefault
+    }
+
+    /// Continues an `apply` which was interrupted before all its records were
+    /// uploaded, from the `checkpoint` it returned in its [ApplyResults].
+    /// Returns the remaining records to upload, or `None` if the engine can't
+    /// resume from that checkpoint, in which case the driver should call
+    /// `apply` again. The default implementation can't resume.
+    fn resume_apply(
+        &self,
+        _checkpoint: &str,
+        _signal: &AbortSignal,
+    ) -> Result<Option<ApplyResults>> {
+        Ok(None)
+    }
+
+    /// Calls `resume_apply` if there's a checkpoint from an earlier, interrupted
+    /// sync, falling back to a full `apply` if there isn't or the engine can't
+    /// resume from it.
+    fn apply_or_resume(
+        &self,
+        checkpoint: Option<&str>,
+        signal: &AbortSignal,
+    ) -> Result<ApplyResults> {
+        if let Some(checkpoint) = checkpoint {
+            if let Some(results) = self.resume_apply(checkpoint, signal)? {
+                return Ok(results);
+            }
+            log::info!(
+                "The {} engine can't resume from its checkpoint; applying everything",
+                self.collection_name()
+            );
+        }
+        self.apply(signal)
+    }
+
+    /// Returns up to `batch_size` records to upload, or `None` once there are
+    /// no more. Engines which implement this can implement `apply` by calling
+    /// `apply_in_batches`, rather than draining their staging tables
+    /// themselves. The default implementation has nothing to upload.
+    fn next_outgoing_batch(&self, _batch_size: usize) -> Result<Option<Vec<OutgoingBso>>> {
+        Ok(None)
+    }
+
+    /// Assembles the records to upload by calling `next_outgoing_batch` until
+    /// it's exhausted, checking the signal between batches.
+    fn apply_in_batches(&self, batch_size: usize, signal: &AbortSignal) -> Result<ApplyResults> {
+        let mut records = Vec::new();
+        loop {
+            signal.err_if_interrupted()?;
+            match self.next_outgoing_batch(batch_size)? {
+                Some(batch) if !batch.is_empty() => records.extend(batch),
+                _ => break,
+            }
+        }
+        Ok(records.into())
+    }
+
+    /// Returns the number of local changes waiting to be uploaded, without
+    /// applying anything, so consumers can tell the user about unsynced changes
+    /// before a sync. Engines which track changes can usually implement this
+    /// cheaply; the default implementation fails, because it can't know.
+    fn pending_outgoing_count(&self) -> Result<usize> {
+        anyhow::bail!(
+            "The {} engine can't count pending changes",
+            self.collection_name()
+        )
+    }
+
+    /// Returns the IDs of the records the next `apply` would upload, without
+    /// building their payloads, so consumers can preview what's about to be
+    /// synced. Engines backed by a change-tracking table can usually implement
+    /// this cheaply; the default implementation fails, because it can't know.
+    fn outgoing_ids(&self) -> Result<Vec<Guid>> {
+        anyhow::bail!(
+            "The {} engine can't list pending changes",
+            self.collection_name()
+        )
+    }
+
+    /// Returns the engine's Sync metadata, like its last sync time, sync ID and
+    /// the number of records in its mirror, for diagnostic tools to dump when
+    /// debugging a desync. Implementations shouldn't include record contents
+    /// or anything else that identifies the user. The default implementation
+    /// returns an empty object.
+    fn debug_state(&self) -> Result<serde_json::Value> {
+        Ok(serde_json::json!({}))
+    }
 
     /// Indicates that the given record IDs were uploaded successfully to the
     /// server. This is called multiple times per sync, once for each batch
     /// upload.
     fn set_uploaded(&self, server_modified_millis: i64, ids: &[Guid]) -> Result<()>;
 
+    /// Like `set_uploaded`, but also given the records the server rejected in
+    /// the batch, and why, so engines can handle them specifically; for
+    /// example, by splitting records which were too large. The default
+    /// implementation logs the failures and calls `set_uploaded`, leaving
+    /// them to be treated as failed by `sync_finished`.
+    fn set_uploaded_with_failures(
+        &self,
+        server_modified_millis: i64,
+        ids: &[Guid],
+        failures: &[UploadFailure],
+    ) -> Result<()> {
+        for failure in failures {
+            log::warn!(
+                "The server rejected {} record {}: {}",
+                self.collection_name(),
+                failure.guid,
+                failure.reason
+            );
+        }
+        self.set_uploaded(server_modified_millis, ids)
+    }
+
     /// Indicates that all records have been uploaded. At this point, any record
     /// IDs marked for upload that haven't been passed to `set_uploaded`, can be
     /// assumed to have failed: for example, because the server rejected a record
@@ -85,12 +303,41 @@ pub trait BridgedEngine: Send + Sync {
 
     /// Resets all local Sync state, including any change flags, mirrors, and
     /// the last sync time, such that the next sync is treated as a first sync
-    /// with all new local data. Does not erase any local user data.
-    fn reset(&self) -> Result<()>;
+    /// with all new local data. Does not erase any local user data. The reason
+    /// is for the engine's logging and telemetry, and can help it decide how
+    /// much to re-upload.
+    fn reset(&self, reason: EngineStateChangeReason) -> Result<()>;
+
+    /// Called when the server's copy of the collection has disappeared; for
+    /// example, because another client wiped it, or it returned a 404. Unlike a
+    /// normal reset, everything local needs to be reuploaded, so engines which
+    /// adjust their reupload strategy can override this. The default
+    /// implementation calls `reset` with `EngineStateChangeReason::CollectionWiped`.
+    fn on_collection_wiped(&self) -> Result<()> {
+        self.reset(EngineStateChangeReason::CollectionWiped)
+    }
 
     /// Erases all local user data for this collection, and any Sync metadata.
     /// This method is destructive, and unused for most collections.
-    fn wipe(&self) -> Result<()>;
+    fn wipe(&self, reason: EngineStateChangeReason) -> Result<()>;
+
+    /// Returns true if `wipe` meaningfully erases this collection's data.
+    /// Drivers should check this, or use `checked_wipe`, rather than calling
+    /// `wipe` on engines which don't support it.
+    fn supports_wipe(&self) -> bool {
+        false
+    }
+
+    /// Calls `wipe` if the engine supports it, and fails otherwise.
+    fn checked_wipe(&self, reason: EngineStateChangeReason) -> Result<()> {
+        if !self.supports_wipe() {
+            anyhow::bail!(
+                "The {} engine doesn't support wiping",
+                self.collection_name()
+            );
+        }
+        self.wipe(reason)
+    }
 }
 
 // This is an adaptor trait - the idea is that engines can implement this
@@ -107,11 +354,18 @@ pub trait BridgedEngineAdaptor: Send + Sync {
     fn sync_started(&self) -> Result<()> {
         Ok(())
     }
+    fn supports_wipe(&self) -> bool {
+        false
+    }
 
     fn engine(&self) -> &dyn SyncEngine;
 }
 
 impl<A: BridgedEngineAdaptor> BridgedEngine for A {
+    fn collection_name(&self) -> CollectionName {
+        self.engine().collection_name()
+    }
+
     fn last_sync(&self) -> Result<i64> {
         self.last_sync()
     }
@@ -166,13 +420,21 @@ impl<A: BridgedEngineAdaptor> BridgedEngine for A {
         A::sync_started(self)
     }
 
-    fn store_incoming(&self, incoming_records: Vec<IncomingBso>) -> Result<()> {
+    fn store_incoming(
+        &self,
+        incoming_records: Vec<IncomingBso>,
+        signal: &AbortSignal,
+    ) -> Result<()> {
+        // SyncEngine has no way to stop part way through, so the best we can
+        // do is not start.
+        signal.err_if_interrupted()?;
         let engine = self.engine();
         let mut telem = telemetry::Engine::new(engine.collection_name());
         engine.stage_incoming(incoming_records, &mut telem)
     }
 
-    fn apply(&self) -> Result<ApplyResults> {
+    fn apply(&self, signal: &AbortSignal) -> Result<ApplyResults> {
+        signal.err_if_interrupted()?;
         let engine = self.engine();
         let mut telem = telemetry::Engine::new(engine.collection_name());
         // Desktop tells a bridged engine to apply the records without telling it
@@ -183,12 +445,13 @@ impl<A: BridgedEngineAdaptor> BridgedEngine for A {
         // but nothing too bad should happen if it doesn't - we'll just end up applying
         // the same records again next sync.
         let records = engine.apply(ServerTimestamp::from_millis(0), &mut telem)?;
+        let incoming = telem.get_incoming().as_ref();
         Ok(ApplyResults {
             records,
-            num_reconciled: telem
-                .get_incoming()
-                .as_ref()
-                .map(|i| i.get_reconciled() as usize),
+            num_reconciled: incoming.map(|i| i.get_reconciled() as usize),
+            num_applied: incoming.map(|i| i.get_applied() as usize),
+            num_failed_to_apply: incoming.map(|i| i.get_failed() as usize),
+            ..Default::default()
         })
     }
 
@@ -201,18 +464,157 @@ impl<A: BridgedEngineAdaptor> BridgedEngine for A {
         self.engine().sync_finished()
     }
 
-    fn reset(&self) -> Result<()> {
-        self.engine().reset(&EngineSyncAssociation::Disconnected)
+    fn reset(&self, reason: EngineStateChangeReason) -> Result<()> {
+        let engine = self.engine();
+        log::info!(
+            "Resetting {} engine: {:?}",
+            engine.collection_name(),
+            reason
+        );
+        engine.reset(&EngineSyncAssociation::Disconnected)
+    }
+
+    fn wipe(&self, reason: EngineStateChangeReason) -> Result<()> {
+        let engine = self.engine();
+        log::info!("Wiping {} engine: {:?}", engine.collection_name(), reason);
+        engine.wipe()
+    }
+
+    fn supports_wipe(&self) -> bool {
+        A::supports_wipe(self)
     }
+}
+
+/// What `check_duplicate_ids` should do when an incoming batch has more than
+/// one record with the same ID.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DuplicateIdPolicy {
+    /// Fail, so strict engines don't stage anything from a suspect batch.
+    Reject,
+    /// Keep the last record with each ID, which is what most engines would
+    /// end up doing anyway, and log a warning.
+    KeepLast,
+}
 
-    fn wipe(&self) -> Result<()> {
-        self.engine().wipe()
+/// Checks an incoming batch for records with duplicate IDs before an engine
+/// stages it, handling any according to `policy`. Otherwise, engines silently
+/// apply whichever record comes last, which can confuse reconciliation.
+pub fn check_duplicate_ids(
+    incoming_records: Vec<IncomingBso>,
+    policy: DuplicateIdPolicy,
+) -> Result<Vec<IncomingBso>> {
+    let mut seen = HashSet::with_capacity(incoming_records.len());
+    let mut num_duplicates = 0;
+    // Walk backwards so the first time we see an ID is its last record.
+    let mut keep = Vec::with_capacity(incoming_records.len());
+    for record in incoming_records.into_iter().rev() {
+        if seen.insert(record.envelope.id.clone()) {
+            keep.push(record);
+        } else if policy == DuplicateIdPolicy::Reject {
+            anyhow::bail!(
+                "Incoming batch has more than one record with ID {}",
+                record.envelope.id
+            );
+        } else {
+            num_duplicates += 1;
+        }
+    }
+    if num_duplicates > 0 {
+        log::warn!(
+            "Ignoring {} incoming records with duplicate IDs",
+            num_duplicates
+        );
+    }
+    keep.reverse();
+    Ok(keep)
+}
+
+/// How `apply_with_options` should resolve records which changed both locally
+/// and on the server.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ConflictStrategy {
+    /// Whatever the engine normally does, which is often a merge.
+    #[default]
+    EngineDefault,
+    /// Keep the local changes, and upload them over the server's.
+    LocalWins,
+    /// Take the server's changes, dropping the local ones.
+    RemoteWins,
+}
+
+/// Options for `apply_with_options`. The default is what `apply` does.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ApplyOptions {
+    pub conflict_strategy: ConflictStrategy,
+    /// Whether to return the records to upload. If false, local changes stay
+    /// pending until a later sync.
+    pub produce_outgoing: bool,
+}
+
+impl Default for ApplyOptions {
+    fn default() -> Self {
+        Self {
+            conflict_strategy: ConflictStrategy::EngineDefault,
+            produce_outgoing: true,
+        }
+    }
+}
+
+/// Why a bridged engine is being reset or wiped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EngineStateChangeReason {
+    /// The collection's sync ID changed, so another client reset the server.
+    SyncIdChanged,
+    /// The user's data was moved to a different storage node.
+    NodeReassigned,
+    /// The user asked for it, for example by signing out.
+    UserRequested,
+    /// This is the first time the engine is being synced.
+    FirstSync,
+    /// The server's copy of the collection was wiped, see
+    /// [BridgedEngine::on_collection_wiped].
+    CollectionWiped,
+    /// The caller doesn't know why; for example, because it's been asked to
+    /// by something which didn't say.
+    Unknown,
+}
+
+/// Lets a consumer tell a bridged engine that a long-running operation, like
+/// `store_incoming` or `apply`, should stop as soon as possible; for example,
+/// because the user cancelled the sync. Clones share the same state, so the
+/// consumer can keep a clone and call `abort()` on it while the engine checks
+/// `is_aborted()` on another thread.
+///
+/// This implements [Interruptee], so can be passed to code which already
+/// checks for interruption.
+#[derive(Clone, Debug, Default)]
+pub struct AbortSignal(Arc<AtomicBool>);
+
+impl AbortSignal {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn abort(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_aborted(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+impl Interruptee for AbortSignal {
+    fn was_interrupted(&self) -> bool {
+        self.is_aborted()
     }
 }
 
 // TODO: We should see if we can remove this to reduce the number of types engines need to deal
 // with. num_reconciled is only used for telemetry on desktop.
-#[derive(Debug, Default)]
+/// This is serializable so bridges which cross a process boundary can pass
+/// the results as a single JSON value.
+#[derive(Debug, Default, Serialize, Deserialize)]
 pub struct ApplyResults {
     /// List of records
     pub records: Vec<OutgoingBso>,
@@ -220,6 +622,35 @@ pub struct ApplyResults {
     /// changed on both sides. None indicates we aren't reporting this
     /// information.
     pub num_reconciled: Option<usize>,
+    /// The number of incoming records which were applied. None indicates we
+    /// aren't reporting this information.
+    pub num_applied: Option<usize>,
+    /// The number of incoming records which failed to apply. None indicates we
+    /// aren't reporting this information.
+    pub num_failed_to_apply: Option<usize>,
+    /// Why individual incoming records failed to apply, if the engine reports it.
+    pub failures: Option<Vec<ApplyFailure>>,
+    /// The TTL, in seconds, to give records which don't have their own. See
+    /// `with_default_ttl`.
+    pub default_ttl: Option<u32>,
+    /// An opaque token the driver can pass to `resume_apply` if the sync is
+    /// interrupted before these records are uploaded. None if the engine can't
+    /// resume.
+    pub checkpoint: Option<String>,
+}
+
+/// An incoming record which failed to apply, and why.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ApplyFailure {
+    pub guid: Guid,
+    pub reason: String,
+}
+
+/// An outgoing record which the server refused to store, and its reason.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UploadFailure {
+    pub guid: Guid,
+    pub reason: String,
 }
 
 impl ApplyResults {
@@ -227,6 +658,73 @@ impl ApplyResults {
         Self {
             records,
             num_reconciled: num_reconciled.into(),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_num_applied(mut self, num_applied: usize) -> Self {
+        self.num_applied = Some(num_applied);
+        self
+    }
+
+    pub fn with_num_failed_to_apply(mut self, num_failed_to_apply: usize) -> Self {
+        self.num_failed_to_apply = Some(num_failed_to_apply);
+        self
+    }
+
+    /// Records why an incoming record failed to apply. This doesn't change
+    /// `num_failed_to_apply`, as engines may not report a reason for every
+    /// failure.
+    pub fn with_failure(mut self, guid: Guid, reason: impl Into<String>) -> Self {
+        self.failures
+            .get_or_insert_with(Vec::new)
+            .push(ApplyFailure {
+                guid,
+                reason: reason.into(),
+            });
+        self
+    }
+
+    /// Sets a TTL, in seconds, for the server to keep records for, so engines
+    /// can have their records expire without setting the TTL on each one. A
+    /// record which sets its own `ttl` in its envelope keeps it. Drivers should
+    /// use `into_records` to get the records with the default applied.
+    pub fn with_default_ttl(mut self, ttl: u32) -> Self {
+        self.default_ttl = Some(ttl);
+        self
+    }
+
+    /// Sets the token the driver should pass to `resume_apply` if the sync is
+    /// interrupted before the records are uploaded.
+    pub fn with_checkpoint(mut self, checkpoint: impl Into<String>) -> Self {
+        self.checkpoint = Some(checkpoint.into());
+        self
+    }
+
+    /// Consumes the results, returning the records to upload with any default
+    /// TTL applied.
+    pub fn into_records(self) -> Vec<OutgoingBso> {
+        let mut records = self.records;
+        if let Some(ttl) = self.default_ttl {
+            for record in &mut records {
+                record.envelope.ttl.get_or_insert(ttl);
+            }
+        }
+        records
+    }
+
+    /// Adds the counts we have to the telemetry for the engine's incoming
+    /// records. Per-record failure reasons aren't part of the sync ping, so
+    /// aren't recorded.
+    pub fn record_telemetry(&self, incoming: &mut telemetry::EngineIncoming) {
+        if let Some(n) = self.num_applied {
+            incoming.applied(n as u32);
+        }
+        if let Some(n) = self.num_failed_to_apply {
+            incoming.failed(n as u32);
+        }
+        if let Some(n) = self.num_reconciled {
+            incoming.reconciled(n as u32);
         }
     }
 }
@@ -236,7 +734,340 @@ impl From<Vec<OutgoingBso>> for ApplyResults {
     fn from(records: Vec<OutgoingBso>) -> Self {
         Self {
             records,
-            num_reconciled: None,
+            ..Default::default()
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bso::OutgoingEnvelope;
+    use std::sync::Mutex;
+
+    // An engine with a "staging table" of outgoing record ids.
+    struct BatchingEngine {
+        outgoing: Mutex<Vec<Guid>>,
+    }
+
+    impl BridgedEngine for BatchingEngine {
+        fn collection_name(&self) -> CollectionName {
+            "test".into()
+        }
+
+        fn last_sync(&self) -> Result<i64> {
+            Ok(0)
+        }
+
+        fn set_last_sync(&self, _last_sync_millis: i64) -> Result<()> {
+            Ok(())
+        }
+
+        fn sync_id(&self) -> Result<Option<String>> {
+            Ok(None)
+        }
+
+        fn reset_sync_id(&self) -> Result<String> {
+            Ok("sync-id".into())
+        }
+
+        fn ensure_current_sync_id(&self, new_sync_id: &str) -> Result<String> {
+            Ok(new_sync_id.into())
+        }
+
+        fn sync_started(&self) -> Result<()> {
+            Ok(())
+        }
+
+        fn store_incoming(
+            &self,
+            _incoming_records: Vec<IncomingBso>,
+            _signal: &AbortSignal,
+        ) -> Result<()> {
+            Ok(())
+        }
+
+        fn apply(&self, signal: &AbortSignal) -> Result<ApplyResults> {
+            self.apply_in_batches(2, signal)
+        }
+
+        fn next_outgoing_batch(&self, batch_size: usize) -> Result<Option<Vec<OutgoingBso>>> {
+            let mut outgoing = self.outgoing.lock().unwrap();
+            if outgoing.is_empty() {
+                return Ok(None);
+            }
+            let n = batch_size.min(outgoing.len());
+            Ok(Some(
+                outgoing
+                    .drain(..n)
+                    .map(|id| OutgoingBso::new_tombstone(id.into()))
+                    .collect(),
+            ))
+        }
+
+        fn set_uploaded(&self, _server_modified_millis: i64, _ids: &[Guid]) -> Result<()> {
+            Ok(())
+        }
+
+        fn sync_finished(&self) -> Result<()> {
+            Ok(())
+        }
+
+        fn reset(&self, _reason: EngineStateChangeReason) -> Result<()> {
+            Ok(())
+        }
+
+        fn wipe(&self, _reason: EngineStateChangeReason) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_default_ttl() {
+        let with_ttl = OutgoingBso::new_tombstone(OutgoingEnvelope {
+            id: Guid::new("with-ttl"),
+            ttl: Some(60),
+            ..Default::default()
+        });
+        let without_ttl = OutgoingBso::new_tombstone(Guid::new("without-ttl").into());
+        let records = ApplyResults::from(vec![with_ttl, without_ttl])
+            .with_default_ttl(3600)
+            .into_records();
+        assert_eq!(records[0].envelope.ttl, Some(60));
+        assert_eq!(records[1].envelope.ttl, Some(3600));
+
+        // No default means no change.
+        let records = ApplyResults::from(vec![OutgoingBso::new_tombstone(Guid::new("id").into())])
+            .into_records();
+        assert_eq!(records[0].envelope.ttl, None);
+    }
+
+    #[test]
+    fn test_check_duplicate_ids() {
+        let batch = || {
+            vec![
+                IncomingBso::from_test_content(serde_json::json!({"id": "a", "n": 1})),
+                IncomingBso::from_test_content(serde_json::json!({"id": "b", "n": 2})),
+                IncomingBso::from_test_content(serde_json::json!({"id": "a", "n": 3})),
+            ]
+        };
+        assert!(check_duplicate_ids(batch(), DuplicateIdPolicy::Reject).is_err());
+
+        let records = check_duplicate_ids(batch(), DuplicateIdPolicy::KeepLast).unwrap();
+        let payloads: Vec<_> = records
+            .into_iter()
+            .map(|r| r.into_content::<serde_json::Value>().content().unwrap())
+            .collect();
+        assert_eq!(
+            payloads,
+            vec![
+                serde_json::json!({"id": "b", "n": 2}),
+                serde_json::json!({"id": "a", "n": 3}),
+            ]
+        );
+
+        // No duplicates is fine either way.
+        let unique = vec![IncomingBso::from_test_content(
+            serde_json::json!({"id": "a"}),
+        )];
+        assert_eq!(
+            check_duplicate_ids(unique, DuplicateIdPolicy::Reject)
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_checked_wipe() {
+        let engine = BatchingEngine {
+            outgoing: Mutex::new(vec![]),
+        };
+        assert!(!engine.supports_wipe());
+        let err = engine
+            .checked_wipe(EngineStateChangeReason::UserRequested)
+            .unwrap_err();
+        assert_eq!(err.to_string(), "The test engine doesn't support wiping");
+
+        assert_eq!(engine.debug_state().unwrap(), serde_json::json!({}));
+        assert_eq!(engine.expire_staged(0).unwrap(), 0);
+        assert!(engine.needs_first_sync().unwrap());
+
+        let err = engine.outgoing_ids().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "The test engine can't list pending changes"
+        );
+    }
+
+    #[test]
+    fn test_apply_in_batches() {
+        let ids = ["a", "b", "c", "d", "e"].map(Guid::new);
+        let engine = BatchingEngine {
+            outgoing: Mutex::new(ids.to_vec()),
+        };
+        let results = engine.apply(&AbortSignal::new()).unwrap();
+        let applied: Vec<_> = results
+            .records
+            .iter()
+            .map(|r| r.envelope.id.clone())
+            .collect();
+        assert_eq!(applied, ids);
+        assert!(engine.outgoing.lock().unwrap().is_empty());
+
+        // An aborted signal stops us before we drain anything.
+        let engine = BatchingEngine {
+            outgoing: Mutex::new(ids.to_vec()),
+        };
+        let signal = AbortSignal::new();
+        signal.abort();
+        assert!(engine.apply(&signal).is_err());
+        assert_eq!(engine.outgoing.lock().unwrap().len(), ids.len());
+    }
+
+    #[test]
+    fn test_apply_or_resume() {
+        let ids = ["a", "b", "c"].map(Guid::new);
+        let engine = BatchingEngine {
+            outgoing: Mutex::new(ids.to_vec()),
+        };
+        let signal = AbortSignal::new();
+        assert!(engine
+            .resume_apply("checkpoint", &signal)
+            .unwrap()
+            .is_none());
+
+        // We can't resume, so should apply everything.
+        let results = engine.apply_or_resume(Some("checkpoint"), &signal).unwrap();
+        assert_eq!(results.records.len(), ids.len());
+        assert_eq!(results.checkpoint, None);
+        assert!(engine.outgoing.lock().unwrap().is_empty());
+
+        let results = ApplyResults::from(vec![]).with_checkpoint("checkpoint");
+        assert_eq!(results.checkpoint.as_deref(), Some("checkpoint"));
+    }
+
+    #[test]
+    fn test_store_incoming_with_progress() {
+        let engine = BatchingEngine {
+            outgoing: Mutex::new(vec![]),
+        };
+        let records = vec![
+            IncomingBso::from_test_content(serde_json::json!({"id": "a"})),
+            IncomingBso::from_test_content(serde_json::json!({"id": "b"})),
+        ];
+        let mut reports = Vec::new();
+        engine
+            .store_incoming_with_progress(records, &AbortSignal::new(), &mut |done, total| {
+                reports.push((done, total))
+            })
+            .unwrap();
+        assert_eq!(reports, vec![(2, 2)]);
+    }
+
+    #[test]
+    fn test_apply_with_options() {
+        let ids = ["a", "b", "c"].map(Guid::new);
+        let signal = AbortSignal::new();
+        let engine = BatchingEngine {
+            outgoing: Mutex::new(ids.to_vec()),
+        };
+        let results = engine
+            .apply_with_options(&ApplyOptions::default(), &signal)
+            .unwrap();
+        assert_eq!(results.records.len(), ids.len());
+
+        let engine = BatchingEngine {
+            outgoing: Mutex::new(ids.to_vec()),
+        };
+        let options = ApplyOptions {
+            produce_outgoing: false,
+            ..Default::default()
+        };
+        let results = engine.apply_with_options(&options, &signal).unwrap();
+        assert!(results.records.is_empty());
+        assert!(engine.outgoing.lock().unwrap().is_empty());
+
+        // The test engine can't honor other strategies, so refuses to apply.
+        let engine = BatchingEngine {
+            outgoing: Mutex::new(ids.to_vec()),
+        };
+        assert!(engine.supports_conflict_strategy(ConflictStrategy::EngineDefault));
+        assert!(!engine.supports_conflict_strategy(ConflictStrategy::RemoteWins));
+        let options = ApplyOptions {
+            conflict_strategy: ConflictStrategy::RemoteWins,
+            ..Default::default()
+        };
+        assert!(engine.apply_with_options(&options, &signal).is_err());
+        assert_eq!(engine.outgoing.lock().unwrap().len(), ids.len());
+    }
+
+    #[test]
+    fn test_abort_signal() {
+        let signal = AbortSignal::new();
+        let clone = signal.clone();
+        assert!(!signal.is_aborted());
+        assert!(signal.err_if_interrupted().is_ok());
+        clone.abort();
+        assert!(signal.is_aborted());
+        assert!(signal.err_if_interrupted().is_err());
+    }
+
+    #[test]
+    fn test_apply_results_telemetry() {
+        let results = ApplyResults::new(vec![], 1)
+            .with_num_applied(5)
+            .with_num_failed_to_apply(2)
+            .with_failure(Guid::new("record-1"), "bad record");
+        assert_eq!(
+            results.failures,
+            Some(vec![ApplyFailure {
+                guid: Guid::new("record-1"),
+                reason: "bad record".into(),
+            }])
+        );
+
+        let mut incoming = telemetry::EngineIncoming::new();
+        results.record_telemetry(&mut incoming);
+        assert_eq!(incoming.get_applied(), 5);
+        assert_eq!(incoming.get_failed(), 2);
+        assert_eq!(incoming.get_reconciled(), 1);
+
+        // Nothing reported means nothing recorded.
+        let mut incoming = telemetry::EngineIncoming::new();
+        ApplyResults::from(vec![]).record_telemetry(&mut incoming);
+        assert_eq!(incoming.get_applied(), 0);
+        assert_eq!(incoming.get_failed(), 0);
+        assert_eq!(incoming.get_reconciled(), 0);
+    }
+
+    #[test]
+    fn test_apply_results_serde() {
+        let envelope = OutgoingEnvelope::new(Guid::new("record-1"), Some(10), None).unwrap();
+        let results = ApplyResults::new(
+            vec![OutgoingBso::new(envelope, &serde_json::json!({"data": 1})).unwrap()],
+            1,
+        )
+        .with_failure(Guid::new("record-2"), "bad record")
+        .with_default_ttl(60)
+        .with_checkpoint("checkpoint");
+
+        let json = serde_json::to_string(&results).unwrap();
+        let results: ApplyResults = serde_json::from_str(&json).unwrap();
+        assert_eq!(results.num_reconciled, Some(1));
+        assert_eq!(results.checkpoint.as_deref(), Some("checkpoint"));
+        assert_eq!(
+            results.failures,
+            Some(vec![ApplyFailure {
+                guid: Guid::new("record-2"),
+                reason: "bad record".into(),
+            }])
+        );
+        let records = results.into_records();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].envelope.id, "record-1");
+        assert_eq!(records[0].envelope.sortindex, Some(10));
+        assert_eq!(records[0].envelope.ttl, Some(60));
+        assert_eq!(records[0].payload, r#"{"data":1}"#);
+    }
+}
@@ -0,0 +1,301 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! An in-memory [BridgedEngine] for tests, so consumers can test the code
+//! driving their engines without each writing their own fake engine.
+
+use std::sync::{Mutex, MutexGuard};
+
+use anyhow::Result;
+use interrupt_support::Interruptee;
+
+use crate::bso::{IncomingBso, OutgoingBso};
+use crate::{CollectionName, Guid};
+
+use super::{AbortSignal, ApplyResults, BridgedEngine, EngineStateChangeReason, UploadFailure};
+
+/// A [BridgedEngine] which keeps everything in memory. It records the
+/// incoming records it's given and the calls made to it, and `apply` returns
+/// whatever was passed to `set_apply_results`.
+pub struct MemoryBridgedEngine {
+    collection_name: CollectionName,
+    state: Mutex<MemoryState>,
+}
+
+#[derive(Default)]
+struct MemoryState {
+    last_sync: i64,
+    sync_id: Option<String>,
+    stored: Vec<IncomingBso>,
+    apply_results: Vec<OutgoingBso>,
+    uploaded: Vec<Guid>,
+    upload_failures: Vec<UploadFailure>,
+    num_syncs_started: usize,
+    num_syncs_finished: usize,
+    was_reset: bool,
+    was_wiped: bool,
+    last_reason: Option<EngineStateChangeReason>,
+}
+
+impl MemoryBridgedEngine {
+    pub fn new(collection_name: impl Into<CollectionName>) -> Self {
+        Self {
+            collection_name: collection_name.into(),
+            state: Mutex::default(),
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, MemoryState> {
+        self.state.lock().unwrap()
+    }
+
+    /// Sets the records the next call to `apply` will return.
+    pub fn set_apply_results(&self, records: Vec<OutgoingBso>) {
+        self.state().apply_results = records;
+    }
+
+    /// The IDs of all records passed to `store_incoming`, in the order they
+    /// were stored.
+    pub fn stored_ids(&self) -> Vec<Guid> {
+        self.state()
+            .stored
+            .iter()
+            .map(|bso| bso.envelope.id.clone())
+            .collect()
+    }
+
+    /// The IDs of all records passed to `set_uploaded`.
+    pub fn uploaded_ids(&self) -> Vec<Guid> {
+        self.state().uploaded.clone()
+    }
+
+    /// The failures passed to `set_uploaded_with_failures`.
+    pub fn upload_failures(&self) -> Vec<UploadFailure> {
+        self.state().upload_failures.clone()
+    }
+
+    pub fn num_syncs_started(&self) -> usize {
+        self.state().num_syncs_started
+    }
+
+    pub fn num_syncs_finished(&self) -> usize {
+        self.state().num_syncs_finished
+    }
+
+    pub fn was_reset(&self) -> bool {
+        self.state().was_reset
+    }
+
+    pub fn was_wiped(&self) -> bool {
+        self.state().was_wiped
+    }
+
+    /// The reason given to the most recent call to `reset` or `wipe`.
+    pub fn last_reason(&self) -> Option<EngineStateChangeReason> {
+        self.state().last_reason
+    }
+}
+
+impl BridgedEngine for MemoryBridgedEngine {
+    fn collection_name(&self) -> CollectionName {
+        self.collection_name.clone()
+    }
+
+    fn last_sync(&self) -> Result<i64> {
+        Ok(self.state().last_sync)
+    }
+
+    fn set_last_sync(&self, last_sync_millis: i64) -> Result<()> {
+        self.state().last_sync = last_sync_millis;
+        Ok(())
+    }
+
+    fn sync_id(&self) -> Result<Option<String>> {
+        Ok(self.state().sync_id.clone())
+    }
+
+    fn reset_sync_id(&self) -> Result<String> {
+        let sync_id = Guid::random().into_string();
+        let mut state = self.state();
+        state.sync_id = Some(sync_id.clone());
+        state.last_sync = 0;
+        state.was_reset = true;
+        Ok(sync_id)
+    }
+
+    fn ensure_current_sync_id(&self, new_sync_id: &str) -> Result<String> {
+        let mut state = self.state();
+        if state.sync_id.as_deref() != Some(new_sync_id) {
+            state.sync_id = Some(new_sync_id.to_string());
+            state.last_sync = 0;
+            state.was_reset = true;
+        }
+        Ok(new_sync_id.to_string())
+    }
+
+    fn sync_started(&self) -> Result<()> {
+        self.state().num_syncs_started += 1;
+        Ok(())
+    }
+
+    fn store_incoming(
+        &self,
+        incoming_records: Vec<IncomingBso>,
+        signal: &AbortSignal,
+    ) -> Result<()> {
+        signal.err_if_interrupted()?;
+        self.state().stored.extend(incoming_records);
+        Ok(())
+    }
+
+    fn apply(&self, signal: &AbortSignal) -> Result<ApplyResults> {
+        signal.err_if_interrupted()?;
+        Ok(std::mem::take(&mut self.state().apply_results).into())
+    }
+
+    fn pending_outgoing_count(&self) -> Result<usize> {
+        Ok(self.state().apply_results.len())
+    }
+
+    fn outgoing_ids(&self) -> Result<Vec<Guid>> {
+        Ok(self
+            .state()
+            .apply_results
+            .iter()
+            .map(|bso| bso.envelope.id.clone())
+            .collect())
+    }
+
+    fn debug_state(&self) -> Result<serde_json::Value> {
+        let state = self.state();
+        Ok(serde_json::json!({
+            "last_sync": state.last_sync,
+            "sync_id": state.sync_id,
+            "num_stored": state.stored.len(),
+            "num_outgoing": state.apply_results.len(),
+        }))
+    }
+
+    fn set_uploaded(&self, _server_modified_millis: i64, ids: &[Guid]) -> Result<()> {
+        self.state().uploaded.extend_from_slice(ids);
+        Ok(())
+    }
+
+    fn set_uploaded_with_failures(
+        &self,
+        server_modified_millis: i64,
+        ids: &[Guid],
+        failures: &[UploadFailure],
+    ) -> Result<()> {
+        self.state().upload_failures.extend_from_slice(failures);
+        self.set_uploaded(server_modified_millis, ids)
+    }
+
+    fn sync_finished(&self) -> Result<()> {
+        self.state().num_syncs_finished += 1;
+        Ok(())
+    }
+
+    fn reset(&self, reason: EngineStateChangeReason) -> Result<()> {
+        let mut state = self.state();
+        state.sync_id = None;
+        state.last_sync = 0;
+        state.was_reset = true;
+        state.last_reason = Some(reason);
+        Ok(())
+    }
+
+    fn wipe(&self, reason: EngineStateChangeReason) -> Result<()> {
+        let mut state = self.state();
+        state.stored.clear();
+        state.apply_results.clear();
+        state.was_wiped = true;
+        state.last_reason = Some(reason);
+        Ok(())
+    }
+
+    fn supports_wipe(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_memory_bridged_engine() {
+        let engine = MemoryBridgedEngine::new("test");
+        let signal = AbortSignal::new();
+        assert!(engine.needs_first_sync().unwrap());
+        engine.sync_started().unwrap();
+        engine
+            .store_incoming(
+                vec![
+                    IncomingBso::from_test_content(json!({"id": "record-1"})),
+                    IncomingBso::from_test_content(json!({"id": "record-2"})),
+                ],
+                &signal,
+            )
+            .unwrap();
+        assert_eq!(
+            engine.stored_ids(),
+            vec![Guid::new("record-1"), Guid::new("record-2")]
+        );
+
+        engine.set_apply_results(vec![OutgoingBso::new_tombstone(
+            Guid::new("record-3").into(),
+        )]);
+        assert_eq!(engine.pending_outgoing_count().unwrap(), 1);
+        assert_eq!(engine.outgoing_ids().unwrap(), vec![Guid::new("record-3")]);
+        let results = engine.apply(&signal).unwrap();
+        assert_eq!(results.records.len(), 1);
+        assert_eq!(engine.pending_outgoing_count().unwrap(), 0);
+
+        assert_eq!(
+            engine.debug_state().unwrap(),
+            json!({"last_sync": 0, "sync_id": null, "num_stored": 2, "num_outgoing": 0})
+        );
+
+        engine.set_uploaded(1000, &[Guid::new("record-3")]).unwrap();
+        let failure = UploadFailure {
+            guid: Guid::new("record-4"),
+            reason: "retry bso".into(),
+        };
+        engine
+            .set_uploaded_with_failures(1000, &[], &[failure.clone()])
+            .unwrap();
+        engine.sync_finished().unwrap();
+        assert_eq!(engine.uploaded_ids(), vec![Guid::new("record-3")]);
+        assert_eq!(engine.upload_failures(), vec![failure]);
+        assert_eq!(engine.num_syncs_started(), 1);
+        assert_eq!(engine.num_syncs_finished(), 1);
+
+        engine.set_last_sync(1000).unwrap();
+        assert!(!engine.needs_first_sync().unwrap());
+        assert!(!engine.was_reset());
+        engine
+            .reset(EngineStateChangeReason::NodeReassigned)
+            .unwrap();
+        assert!(engine.was_reset());
+        assert!(engine.needs_first_sync().unwrap());
+        assert_eq!(
+            engine.last_reason(),
+            Some(EngineStateChangeReason::NodeReassigned)
+        );
+        assert!(!engine.was_wiped());
+        engine
+            .checked_wipe(EngineStateChangeReason::UserRequested)
+            .unwrap();
+        assert!(engine.was_wiped());
+        assert!(engine.stored_ids().is_empty());
+
+        engine.on_collection_wiped().unwrap();
+        assert_eq!(
+            engine.last_reason(),
+            Some(EngineStateChangeReason::CollectionWiped)
+        );
+    }
+}
@@ -25,11 +25,21 @@
 //! We intend merging these engines - the first step will be to merge the
 //! types and payload management used by these traits, then to combine the
 //! requirements into a single trait that captures both use-cases.
+mod async_bridged_engine;
 mod bridged_engine;
+#[cfg(feature = "testing")]
+mod memory_bridged_engine;
 mod request;
 mod sync_engine;
 
-pub use bridged_engine::{ApplyResults, BridgedEngine, BridgedEngineAdaptor};
+pub use async_bridged_engine::{AsyncBridgedEngine, BlockingBridgedEngine, BridgedEngineFuture};
+pub use bridged_engine::{
+    check_duplicate_ids, AbortSignal, ApplyFailure, ApplyOptions, ApplyResults, BridgedEngine,
+    BridgedEngineAdaptor, ConflictStrategy, DuplicateIdPolicy, EngineStateChangeReason,
+    UploadFailure, DEFAULT_PREFERRED_BATCH_SIZE,
+};
+#[cfg(feature = "testing")]
+pub use memory_bridged_engine::MemoryBridgedEngine;
 #[cfg(feature = "sync-client")]
 pub(crate) use request::CollectionPost;
 
@@ -21,6 +21,24 @@ pub enum ErrorResponse {
 
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// Problems with a single record's envelope or payload. These only affect the
+/// one record, so engines can match on them to skip it and carry on with the
+/// rest of the batch.
+#[derive(Debug, thiserror::Error)]
+pub enum EnvelopeError {
+    #[error("Invalid payload JSON: {0}")]
+    InvalidJson(#[from] serde_json::Error),
+
+    #[error("Envelope id {envelope:?} doesn't match the payload id {payload:?}")]
+    MismatchedId { envelope: String, payload: String },
+
+    #[error("Payload is {0} bytes, but the maximum is {1}")]
+    PayloadTooLarge(usize, usize),
+
+    #[error("Invalid record id {0:?}: {1}")]
+    InvalidGuid(String, &'static str),
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[cfg(feature = "crypto")]
@@ -35,7 +53,7 @@ pub enum Error {
     #[error("Crypto/NSS error: {0}")]
     CryptoError(#[from] rc_crypto::Error),
 
-    #[cfg(feature = "crypto")]
+    #[cfg(any(feature = "crypto", feature = "compression"))]
     #[error("Base64 decode error: {0}")]
     Base64Decode(#[from] base64::DecodeError),
 
@@ -45,6 +63,13 @@ pub enum Error {
     #[error("Bad cleartext UTF8: {0}")]
     BadCleartextUtf8(#[from] std::string::FromUtf8Error),
 
+    #[error("Envelope error: {0}")]
+    EnvelopeError(#[from] EnvelopeError),
+
+    #[cfg(feature = "compression")]
+    #[error("Compression error: {0}")]
+    CompressionError(#[from] std::io::Error),
+
     #[cfg(feature = "crypto")]
     #[error("HAWK error: {0}")]
     HawkError(#[from] rc_crypto::hawk::Error),
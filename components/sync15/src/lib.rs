@@ -29,7 +29,7 @@ pub mod telemetry;
 
 pub use crate::client_types::{ClientData, RemoteClient};
 pub use crate::device_type::DeviceType;
-pub use crate::error::{Error, Result};
+pub use crate::error::{EnvelopeError, Error, Result};
 #[cfg(feature = "crypto")]
 pub use enc_payload::EncryptedPayload;
 #[cfg(feature = "crypto")]
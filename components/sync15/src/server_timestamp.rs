@@ -1,11 +1,12 @@
 /* This Source Code Form is subject to the terms of the Mozilla Public
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+use std::ops::{Add, Sub};
 use std::time::Duration;
 
 /// Typesafe way to manage server timestamps without accidentally mixing them up with
 /// local ones.
-#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Default)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
 pub struct ServerTimestamp(pub i64);
 
 impl ServerTimestamp {
@@ -72,6 +73,32 @@ impl ServerTimestamp {
     }
 }
 
+// Arithmetic with durations, so engines don't need to juggle seconds and
+// milliseconds themselves. Like `from_millis`, we never go before the epoch.
+impl Add<Duration> for ServerTimestamp {
+    type Output = ServerTimestamp;
+
+    fn add(self, rhs: Duration) -> ServerTimestamp {
+        let millis = i64::try_from(rhs.as_millis()).unwrap_or(i64::MAX);
+        ServerTimestamp(self.0.saturating_add(millis))
+    }
+}
+
+impl Sub<Duration> for ServerTimestamp {
+    type Output = ServerTimestamp;
+
+    fn sub(self, rhs: Duration) -> ServerTimestamp {
+        let millis = i64::try_from(rhs.as_millis()).unwrap_or(i64::MAX);
+        ServerTimestamp(self.0.saturating_sub(millis).max(0))
+    }
+}
+
+impl From<ServerTimestamp> for i64 {
+    fn from(ts: ServerTimestamp) -> i64 {
+        ts.as_millis()
+    }
+}
+
 impl serde::ser::Serialize for ServerTimestamp {
     fn serialize<S: serde::ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
         serializer.serialize_f64(self.0 as f64 / 1000.0)
@@ -99,6 +126,23 @@ mod test {
         assert_eq!(dur.subsec_nanos(), 100_000_000);
     }
 
+    #[test]
+    fn test_server_timestamp_arithmetic() {
+        let last_sync = ServerTimestamp::from_millis(10_000);
+        let later = last_sync + Duration::from_secs(2);
+        assert_eq!(later.as_millis(), 12_000);
+        assert!(later > last_sync);
+        assert_eq!(later - Duration::from_secs(2), last_sync);
+        assert_eq!(i64::from(later), 12_000);
+        // We clamp rather than wrapping or going negative.
+        assert_eq!(last_sync - Duration::from_secs(60), ServerTimestamp::EPOCH);
+        assert_eq!((last_sync + Duration::MAX).as_millis(), i64::MAX);
+        assert_eq!(
+            [later, ServerTimestamp::EPOCH, last_sync].iter().max(),
+            Some(&later)
+        );
+    }
+
     #[test]
     fn test_serde() {
         let ts = ServerTimestamp(123_456);
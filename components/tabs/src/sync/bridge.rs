@@ -7,7 +7,7 @@ use crate::TabsStore;
 use anyhow::Result;
 use std::sync::Arc;
 use sync15::bso::{IncomingBso, OutgoingBso};
-use sync15::engine::{BridgedEngine, BridgedEngineAdaptor};
+use sync15::engine::{AbortSignal, BridgedEngine, BridgedEngineAdaptor, EngineStateChangeReason};
 use sync15::ServerTimestamp;
 use sync_guid::Guid as SyncGuid;
 
@@ -100,14 +100,15 @@ impl TabsBridgedEngine {
         Ok(bsos)
     }
 
+    // Desktop doesn't abort tabs syncs, so we never signal the engine.
     pub fn store_incoming(&self, incoming: Vec<String>) -> Result<()> {
         self.bridge_impl
-            .store_incoming(self.convert_incoming_bsos(incoming)?)
+            .store_incoming(self.convert_incoming_bsos(incoming)?, &AbortSignal::new())
     }
 
     pub fn apply(&self) -> Result<Vec<String>> {
-        let apply_results = self.bridge_impl.apply()?;
-        self.convert_outgoing_bsos(apply_results.records)
+        let apply_results = self.bridge_impl.apply(&AbortSignal::new())?;
+        self.convert_outgoing_bsos(apply_results.into_records())
     }
 
     pub fn set_uploaded(&self, server_modified_millis: i64, guids: Vec<SyncGuid>) -> Result<()> {
@@ -119,12 +120,13 @@ impl TabsBridgedEngine {
         self.bridge_impl.sync_finished()
     }
 
+    // Desktop doesn't tell us why it's resetting or wiping.
     pub fn reset(&self) -> Result<()> {
-        self.bridge_impl.reset()
+        self.bridge_impl.reset(EngineStateChangeReason::Unknown)
     }
 
     pub fn wipe(&self) -> Result<()> {
-        self.bridge_impl.wipe()
+        self.bridge_impl.wipe(EngineStateChangeReason::Unknown)
     }
 }
 
@@ -2,7 +2,7 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
-use crate::GLOBAL_SETTINGS;
+use crate::{settings::ClientSettings, GLOBAL_SETTINGS};
 use ffi::FfiBackend;
 use once_cell::sync::OnceCell;
 mod ffi;
@@ -24,6 +24,22 @@ pub fn note_backend(which: &str) {
 
 pub trait Backend: Send + Sync + 'static {
     fn send(&self, request: crate::Request) -> Result<crate::Response, crate::Error>;
+
+    /// Creates a client which sends requests with `settings`. Backends which
+    /// can't honor them return an error, rather than ignoring them.
+    fn new_client(
+        &self,
+        _settings: &ClientSettings,
+    ) -> Result<Box<dyn BackendClient>, crate::Error> {
+        Err(crate::Error::BackendError(
+            "This backend doesn't support custom client settings".to_string(),
+        ))
+    }
+}
+
+/// A client created by [`Backend::new_client`].
+pub trait BackendClient: Send + Sync {
+    fn send(&self, request: crate::Request) -> Result<crate::Response, crate::Error>;
 }
 
 static BACKEND: OnceCell<&'static dyn Backend> = OnceCell::new();
@@ -38,6 +54,17 @@ pub(crate) fn get_backend() -> &'static dyn Backend {
     *BACKEND.get_or_init(|| Box::leak(Box::new(FfiBackend)))
 }
 
+pub(crate) fn new_client(
+    settings: &ClientSettings,
+) -> Result<Box<dyn BackendClient>, crate::Error> {
+    // Don't lock in the FFI backend if it hasn't been set yet, the app may
+    // still set another one.
+    match BACKEND.get() {
+        Some(backend) => backend.new_client(settings),
+        None => FfiBackend.new_client(settings),
+    }
+}
+
 pub fn send(request: crate::Request) -> Result<crate::Response, crate::Error> {
     validate_request(&request)?;
     get_backend().send(request)
@@ -151,4 +178,18 @@ mod tests {
         assert!(validate_request(&request).is_ok());
         assert!(validate_request(&request_ftp).is_err());
     }
+
+    #[test]
+    fn test_new_client_unsupported() {
+        // No backend has been set, and the FFI backend can't honor client settings.
+        let settings = ClientSettings {
+            proxy: Some(url::Url::parse("http://proxy.example.com:3128").unwrap()),
+            pool_idle_timeout: None,
+        };
+        assert!(matches!(
+            new_client(&settings),
+            Err(crate::Error::BackendError(_))
+        ));
+        assert!(BACKEND.get().is_none());
+    }
 }
@@ -14,9 +14,9 @@ pub mod error;
 pub mod settings;
 pub use error::*;
 
-pub use backend::{note_backend, set_backend, Backend};
+pub use backend::{note_backend, set_backend, Backend, BackendClient};
 pub use headers::{consts as header_names, Header, HeaderName, Headers, InvalidHeaderName};
-pub use settings::GLOBAL_SETTINGS;
+pub use settings::{ClientSettings, GLOBAL_SETTINGS};
 
 #[allow(clippy::derive_partial_eq_without_eq)]
 pub(crate) mod msg_types {
@@ -225,6 +225,32 @@ impl Request {
     }
 }
 
+/// A client with its own [`ClientSettings`], for requests which shouldn't use
+/// the backend's shared one.
+pub struct Client {
+    inner: Box<dyn BackendClient>,
+}
+
+impl Client {
+    /// Fails if the backend can't honor `settings`.
+    pub fn new(settings: &ClientSettings) -> Result<Self, Error> {
+        Ok(Self {
+            inner: backend::new_client(settings)?,
+        })
+    }
+
+    pub fn send(&self, request: Request) -> Result<Response, Error> {
+        backend::validate_request(&request)?;
+        self.inner.send(request)
+    }
+}
+
+impl std::fmt::Debug for Client {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Client").finish_non_exhaustive()
+    }
+}
+
 /// A response from the server.
 #[derive(Clone, Debug)]
 pub struct Response {
@@ -44,3 +44,15 @@ pub static GLOBAL_SETTINGS: Lazy<RwLock<Settings>> = Lazy::new(|| {
         addn_allowed_insecure_url: None,
     })
 });
+
+/// Settings for a [`Client`](crate::Client), which only apply to the requests
+/// sent through it, on top of the [`GLOBAL_SETTINGS`].
+#[derive(Debug, Clone, Default)]
+pub struct ClientSettings {
+    /// An HTTP(S) proxy to send requests through. Any `user:pass@` credentials
+    /// in the url are used to authenticate with it.
+    pub proxy: Option<Url>,
+    /// How long an idle connection is kept open for reuse by later requests to
+    /// the same host. Uses the backend's default if not set.
+    pub pool_idle_timeout: Option<Duration>,
+}
@@ -3,17 +3,21 @@
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
 use anyhow::Result;
+use interrupt_support::Interruptee;
 use rusqlite::Transaction;
 use std::sync::{Arc, Weak};
 use sync15::bso::IncomingBso;
-use sync15::engine::ApplyResults;
+use sync15::engine::{AbortSignal, ApplyResults, EngineStateChangeReason};
 use sync_guid::Guid as SyncGuid;
 
 use crate::db::{delete_meta, get_meta, put_meta, ThreadSafeStorageDb};
 use crate::schema;
 use crate::sync::incoming::{apply_actions, get_incoming, plan_incoming, stage_incoming};
-use crate::sync::outgoing::{get_outgoing, record_uploaded, stage_outgoing};
+use crate::sync::outgoing::{
+    count_pending_outgoing, get_outgoing, record_uploaded, stage_outgoing,
+};
 
+const COLLECTION_NAME: &str = "storage-sync-v2";
 const LAST_SYNC_META_KEY: &str = "last_sync_time";
 const SYNC_ID_META_KEY: &str = "sync_id";
 
@@ -55,6 +59,10 @@ impl BridgedEngine {
 }
 
 impl sync15::engine::BridgedEngine for BridgedEngine {
+    fn collection_name(&self) -> sync15::CollectionName {
+        COLLECTION_NAME.into()
+    }
+
     fn last_sync(&self) -> Result<i64> {
         let shared_db = self.thread_safe_storage_db()?;
         let db = shared_db.lock();
@@ -109,7 +117,7 @@ impl sync15::engine::BridgedEngine for BridgedEngine {
         Ok(())
     }
 
-    fn store_incoming(&self, incoming_bsos: Vec<IncomingBso>) -> Result<()> {
+    fn store_incoming(&self, incoming_bsos: Vec<IncomingBso>, abort: &AbortSignal) -> Result<()> {
         let shared_db = self.thread_safe_storage_db()?;
         let db = shared_db.lock();
         let signal = db.begin_interrupt_scope()?;
@@ -119,11 +127,13 @@ impl sync15::engine::BridgedEngine for BridgedEngine {
             .map(IncomingBso::into_content::<super::WebextRecord>)
             .collect();
         stage_incoming(&tx, &incoming_content, &signal)?;
+        // Returning before the commit rolls back the transaction.
+        abort.err_if_interrupted()?;
         tx.commit()?;
         Ok(())
     }
 
-    fn apply(&self) -> Result<ApplyResults> {
+    fn apply(&self, abort: &AbortSignal) -> Result<ApplyResults> {
         let shared_db = self.thread_safe_storage_db()?;
         let db = shared_db.lock();
         let signal = db.begin_interrupt_scope()?;
@@ -136,11 +146,35 @@ impl sync15::engine::BridgedEngine for BridgedEngine {
             .collect();
         apply_actions(&tx, actions, &signal)?;
         stage_outgoing(&tx)?;
+        abort.err_if_interrupted()?;
         tx.commit()?;
 
         Ok(get_outgoing(&db, &signal)?.into())
     }
 
+    fn pending_outgoing_count(&self) -> Result<usize> {
+        let shared_db = self.thread_safe_storage_db()?;
+        let db = shared_db.lock();
+        Ok(count_pending_outgoing(&db)?)
+    }
+
+    fn debug_state(&self) -> Result<serde_json::Value> {
+        let shared_db = self.thread_safe_storage_db()?;
+        let db = shared_db.lock();
+        let last_sync: Option<i64> = get_meta(&db, LAST_SYNC_META_KEY)?;
+        let sync_id: Option<String> = get_meta(&db, SYNC_ID_META_KEY)?;
+        let num_mirrored: i64 =
+            db.query_row("SELECT COUNT(*) FROM storage_sync_mirror", [], |row| {
+                row.get(0)
+            })?;
+        Ok(serde_json::json!({
+            "last_sync": last_sync,
+            "sync_id": sync_id,
+            "num_mirrored": num_mirrored,
+            "num_pending_outgoing": count_pending_outgoing(&db)?,
+        }))
+    }
+
     fn set_uploaded(&self, _server_modified_millis: i64, ids: &[SyncGuid]) -> Result<()> {
         let shared_db = self.thread_safe_storage_db()?;
         let db = shared_db.lock();
@@ -159,7 +193,8 @@ impl sync15::engine::BridgedEngine for BridgedEngine {
         Ok(())
     }
 
-    fn reset(&self) -> Result<()> {
+    fn reset(&self, reason: EngineStateChangeReason) -> Result<()> {
+        log::info!("Resetting storage.sync: {:?}", reason);
         let shared_db = self.thread_safe_storage_db()?;
         let db = shared_db.lock();
         let tx = db.unchecked_transaction()?;
@@ -169,7 +204,8 @@ impl sync15::engine::BridgedEngine for BridgedEngine {
         Ok(())
     }
 
-    fn wipe(&self) -> Result<()> {
+    fn wipe(&self, reason: EngineStateChangeReason) -> Result<()> {
+        log::info!("Wiping storage.sync: {:?}", reason);
         let shared_db = self.thread_safe_storage_db()?;
         let db = shared_db.lock();
         let tx = db.unchecked_transaction()?;
@@ -180,6 +216,10 @@ impl sync15::engine::BridgedEngine for BridgedEngine {
         tx.commit()?;
         Ok(())
     }
+
+    fn supports_wipe(&self) -> bool {
+        true
+    }
 }
 
 impl From<anyhow::Error> for crate::error::Error {
@@ -274,7 +314,7 @@ mod tests {
 
         setup_mock_data(&engine)?;
 
-        engine.wipe()?;
+        engine.wipe(EngineStateChangeReason::UserRequested)?;
 
         let shared = engine.thread_safe_storage_db()?;
         let db = shared.lock();
@@ -297,7 +337,7 @@ mod tests {
             &"sync-id".to_string(),
         )?;
 
-        engine.reset()?;
+        engine.reset(EngineStateChangeReason::UserRequested)?;
         assert_reset(&engine)?;
         // Only an explicit reset kills the sync-id, so check that here.
         assert_eq!(
@@ -308,6 +348,29 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_debug_state() -> Result<()> {
+        let strong = new_mem_thread_safe_storage_db();
+        let engine = super::BridgedEngine::new(&strong);
+
+        setup_mock_data(&engine)?;
+        put_meta(
+            &engine.thread_safe_storage_db()?.lock(),
+            SYNC_ID_META_KEY,
+            &"sync-id".to_string(),
+        )?;
+        assert_eq!(
+            engine.debug_state()?,
+            serde_json::json!({
+                "last_sync": 1,
+                "sync_id": "sync-id",
+                "num_mirrored": 1,
+                "num_pending_outgoing": 1,
+            })
+        );
+        Ok(())
+    }
+
     #[test]
     fn test_ensure_missing_sync_id() -> Result<()> {
         let strong = new_mem_thread_safe_storage_db();
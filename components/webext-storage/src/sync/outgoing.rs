@@ -83,6 +83,14 @@ pub fn get_outgoing(conn: &Connection, signal: &dyn Interruptee) -> Result<Vec<O
     Ok(elts.into_iter().collect())
 }
 
+/// Returns the number of local changes which haven't been uploaded yet.
+pub fn count_pending_outgoing(conn: &Connection) -> Result<usize> {
+    let count: i64 = conn
+        .conn()
+        .query_one("SELECT COUNT(*) FROM storage_sync_data WHERE sync_change_counter > 0")?;
+    Ok(count as usize)
+}
+
 /// Record the fact that items were uploaded. This updates the state of the
 /// local DB to reflect the state of the server we just updated.
 /// Note that this call is almost certainly going to be made in a *different*
@@ -136,6 +144,7 @@ mod tests {
         "#,
         )?;
 
+        assert_eq!(count_pending_outgoing(&tx)?, 1);
         stage_outgoing(&tx)?;
         let changes = get_outgoing(&tx, &NeverInterrupts)?;
         assert_eq!(changes.len(), 1);
@@ -158,6 +167,7 @@ mod tests {
             "SELECT sync_change_counter FROM storage_sync_data WHERE ext_id = 'ext_with_changes'",
         )?;
         assert_eq!(counter, 0);
+        assert_eq!(count_pending_outgoing(&tx)?, 0);
         Ok(())
     }
 
@@ -17,15 +17,14 @@ fn test_live_server() {
     let tempdir = tempfile::tempdir().unwrap();
     viaduct_reqwest::use_reqwest_backend();
 
-    let push_config = PushConfiguration {
-        server_host: "localhost:8082".to_string(),
-
-        http_protocol: push::PushHttpProtocol::Http,
-        bridge_type: BridgeType::Fcm,
-        sender_id: "".to_string(),
-        database_path: tempdir.path().join("test.db").to_string_lossy().to_string(),
-        verify_connection_rate_limiter: Some(0),
-    };
+    let push_config = PushConfiguration::builder(
+        BridgeType::Fcm,
+        tempdir.path().join("test.db").to_string_lossy(),
+    )
+    .server_host("localhost:8082")
+    .http_protocol(push::PushHttpProtocol::Http)
+    .verify_connection_rate_limiter(Some(0))
+    .build();
 
     let pm = PushManager::new(push_config).unwrap();
     let scope1 = "scope1";
@@ -36,11 +35,13 @@ fn test_live_server() {
     println!("Scopes: [{}, {}]", scope1, scope2);
 
     println!("\n == Subscribing channels");
-    let sub1 = pm.subscribe(scope1, &None).expect("subscribe failed");
+    let sub1 = pm
+        .subscribe(scope1, &None, &None)
+        .expect("subscribe failed");
 
     println!("## Subscription 1: {:?}", sub1);
     println!("## Info: {:?}", pm.get_subscription(scope1));
-    let sub2 = pm.subscribe(scope2, &None).unwrap();
+    let sub2 = pm.subscribe(scope2, &None, &None).unwrap();
     println!("## Subscription 2: {:?}", sub2);
 
     println!("\n == Unsubscribing single channel");
@@ -0,0 +1,87 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+#![warn(rust_2018_idioms)]
+
+//! Measures a batch of sequential subscribes against a local mock autopush, with
+//! and without idle connections being kept open for reuse between them.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use mockito::{mock, server_address};
+use push::{BridgeType, PushConfiguration, PushHttpProtocol, PushManager};
+use serde_json::json;
+
+const SUBSCRIBES: usize = 10;
+const SENDER_ID: &str = "FakeSenderID";
+const UAID: &str = "abad1dea00000000aabbccdd00000000";
+
+fn push_manager(db_path: String, pool_idle_timeout: u64) -> PushManager {
+    let config = PushConfiguration::builder(BridgeType::Fcm, db_path)
+        .server_host(server_address().to_string())
+        .http_protocol(PushHttpProtocol::Http)
+        .sender_id(SENDER_ID)
+        .pool_idle_timeout(pool_idle_timeout)
+        .build();
+    let pm = PushManager::new(config).unwrap();
+    pm.update("new-token").unwrap();
+    pm
+}
+
+fn bench_sequential_subscribes(c: &mut Criterion) {
+    viaduct_reqwest::use_reqwest_backend();
+    let _register = mock("POST", &*format!("/v1/fcm/{}/registration", SENDER_ID))
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body(
+            json!({
+                "uaid": UAID,
+                "channelID": "deadbeef00000000decafbad00000000",
+                "endpoint": "https://example.com/update",
+                "secret": "SuP3rS1kRet",
+            })
+            .to_string(),
+        )
+        .create();
+    let _subscribe = mock(
+        "POST",
+        &*format!("/v1/fcm/{}/registration/{}/subscription", SENDER_ID, UAID),
+    )
+    .with_status(200)
+    .with_header("content-type", "application/json")
+    .with_body(
+        json!({
+            "channelID": "decafbad00000000deadbeef00000000",
+            "endpoint": "https://example.com/update",
+        })
+        .to_string(),
+    )
+    .create();
+
+    let mut group = c.benchmark_group("sequential_subscribes");
+    // A zero timeout expires connections as soon as they're idle, so every
+    // subscribe sets up a new one.
+    for (name, pool_idle_timeout) in [("new_connections", 0), ("reused_connections", 90)] {
+        let dir = tempfile::tempdir().unwrap();
+        let pm = push_manager(
+            dir.path().join("push.db").to_string_lossy().into_owned(),
+            pool_idle_timeout,
+        );
+        // Registers, so the batches below are all subscribes.
+        pm.subscribe("registration", &None, &None).unwrap();
+        group.bench_function(BenchmarkId::new(name, SUBSCRIBES), |b| {
+            let mut batch = 0;
+            b.iter(|| {
+                batch += 1;
+                for i in 0..SUBSCRIBES {
+                    pm.subscribe(&format!("scope-{}-{}", batch, i), &None, &None)
+                        .unwrap();
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(bench_http, bench_sequential_subscribes);
+criterion_main!(bench_http);